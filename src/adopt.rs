@@ -0,0 +1,440 @@
+//! Importing dotfiles managed by other tools, and taking back targets edited in place of
+//! their dotfile.
+
+use std::path::{Component, Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    checksum, encryption,
+    fs::FileType,
+    journal::Action,
+    layered_dotfiles, link_entry,
+    merge::{self, Outcome, Sides},
+    template, DofiError, Fs, Journal, LinkOptions, Strategy,
+};
+
+/// Files stow ignores at the root of every package by default
+const STOW_IGNORED: [&str; 3] = ["README", "LICENSE", "COPYING"];
+
+/// Moves every package of the GNU stow directory `stow_directory` into `dotfiles_directory`
+/// and repoints the stow symlinks in `base_directory` at the new locations.
+///
+/// Stow packages are merged into the single dofi tree, `<stow>/<package>/.zshrc` becomes
+/// `<dotfiles>/.zshrc`. Directories stow folded into a single symlink are unfolded into real
+/// directories. With `dot_prefix`, stow's `--dotfiles` naming (`dot-zshrc`) is translated.
+/// Returns the number of adopted files.
+pub fn adopt_stow(
+    fs: &dyn Fs,
+    stow_directory: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    dot_prefix: bool,
+    journal: &mut Journal,
+) -> Result<usize, DofiError> {
+    let mut adopted = 0;
+
+    for package in fs.walk(stow_directory)? {
+        let Ok(relative) = package.strip_prefix(stow_directory) else {
+            continue;
+        };
+        let mut components = relative.components();
+        let Some(package_name) = components.next() else {
+            continue;
+        };
+        let package_directory = stow_directory.join(package_name);
+        let relative = components.as_path();
+
+        if relative.as_os_str().is_empty() || is_stow_ignored(relative) {
+            continue;
+        }
+
+        let relative = if dot_prefix {
+            translate_dot_prefix(relative)
+        } else {
+            relative.to_path_buf()
+        };
+        let new_file = dotfiles_directory.join(&relative);
+        let target = base_directory.join(&relative);
+
+        unfold_directories(fs, &target, base_directory, &package_directory, journal)?;
+
+        if fs.exists(&new_file) {
+            if fs.read(&new_file)? == fs.read(&package)? {
+                info!("Reusing existing dotfile '{}'", new_file.display());
+                journal.remove_file(fs, &package)?;
+            } else {
+                warn!(
+                    "Skipping '{}', '{}' already exists with different content",
+                    package.display(),
+                    new_file.display()
+                );
+                continue;
+            }
+        } else {
+            if let Some(parent) = new_file.parent() {
+                journal.create_dir_all(fs, parent)?;
+            }
+            info!("Moving '{}' to '{}'", package.display(), new_file.display());
+            fs.rename(&package, &new_file)?;
+            journal.record(Action::Moved {
+                from: package.clone(),
+                to: new_file.clone(),
+            });
+        }
+
+        match fs.symlink_metadata(&target).map(|m| m.file_type) {
+            Ok(FileType::Symlink) => {
+                let original = resolve_link(&target, &fs.read_link(&target)?);
+                if original != package {
+                    warn!(
+                        "Not relinking '{}', it points to '{}'",
+                        target.display(),
+                        original.display()
+                    );
+                    adopted += 1;
+                    continue;
+                }
+                journal.remove_file(fs, &target)?;
+            }
+            Ok(_) => {
+                warn!(
+                    "Not linking '{}', a file already exists there",
+                    target.display()
+                );
+                adopted += 1;
+                continue;
+            }
+            Err(_) => {
+                if let Some(parent) = target.parent() {
+                    journal.create_dir_all(fs, parent)?;
+                }
+            }
+        }
+
+        info!(
+            "Symlinking '{}' at '{}'",
+            new_file.display(),
+            target.display()
+        );
+        fs.symlink(&new_file, &target)?;
+        journal.record(Action::Symlinked {
+            link: target,
+            target: new_file,
+        });
+        adopted += 1;
+    }
+
+    Ok(adopted)
+}
+
+fn is_stow_ignored(relative: &Path) -> bool {
+    relative.components().count() == 1
+        && STOW_IGNORED.iter().any(|ignored| {
+            relative
+                .to_str()
+                .is_some_and(|name| name.starts_with(ignored))
+        })
+}
+
+/// Translates stow's `dot-` prefix, e.g. `dot-config/dot-foo` to `.config/.foo`
+fn translate_dot_prefix(relative: &Path) -> PathBuf {
+    relative
+        .components()
+        .map(|component| {
+            let name = component.as_os_str().to_string_lossy();
+            match name.strip_prefix("dot-") {
+                Some(rest) => format!(".{rest}"),
+                None => name.into_owned(),
+            }
+        })
+        .collect()
+}
+
+/// Replaces directories stow folded into a symlink pointing into `package_directory` with
+/// real directories, so the files inside can be linked individually
+fn unfold_directories(
+    fs: &dyn Fs,
+    target: &Path,
+    base_directory: &Path,
+    package_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let Some(parent) = target.parent() else {
+        return Ok(());
+    };
+
+    let mut folded = parent
+        .ancestors()
+        .take_while(|ancestor| ancestor.starts_with(base_directory) && *ancestor != base_directory)
+        .collect::<Vec<_>>();
+    folded.reverse();
+
+    for directory in folded {
+        let Ok(original) = fs.read_link(directory) else {
+            continue;
+        };
+        if resolve_link(directory, &original).starts_with(package_directory) {
+            info!("Unfolding stow directory '{}'", directory.display());
+            journal.remove_file(fs, directory)?;
+            journal.create_dir_all(fs, directory)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves a symlink's target relative to the symlink, without touching the filesystem
+fn resolve_link(link: &Path, original: &Path) -> PathBuf {
+    let joined = match link.parent() {
+        Some(parent) => parent.join(original),
+        None => original.to_path_buf(),
+    };
+
+    let mut resolved = PathBuf::new();
+    for component in joined.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+/// How a single chezmoi source path translates to dofi
+enum ChezmoiEntry {
+    Import {
+        relative: PathBuf,
+        mode: u32,
+        template: bool,
+    },
+    Unsupported(&'static str),
+}
+
+/// How [`adopt_target`] went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Adopted {
+    /// The dotfile took the contents of the target, which is linked to it now
+    Linked(PathBuf),
+    /// Merging left conflict markers in the target to resolve before adopting it again
+    Conflicts,
+}
+
+/// Takes the contents of the regular file `target` into the dotfile of the layered
+/// `dotfiles_directories` linking to it and links the target, the reverse of linking with
+/// `force`. With `merge` the target is first merged with the dotfile, from what dofi last
+/// wrote to it if that is known and from nothing otherwise. Rendered and decrypted targets
+/// cannot be adopted, their dotfile does not hold their contents.
+pub fn adopt_target(
+    fs: &dyn Fs,
+    target: &Path,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    merge: bool,
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<Adopted, DofiError> {
+    if !fs
+        .symlink_metadata(target)
+        .is_ok_and(|metadata| metadata.file_type == FileType::File)
+    {
+        return Err(DofiError::FileIsNotRegular(target.to_path_buf()));
+    }
+    let dotfile = layered_dotfiles(fs, base_directory, dotfiles_directories)?
+        .into_iter()
+        .find(|dotfile| dotfile.target == target)
+        .ok_or_else(|| DofiError::FileIsNotADotfile(target.to_path_buf()))?;
+    let source = &dotfile.source;
+    if dotfile.strategy == Strategy::Template
+        || template::is_template(source)
+        || encryption::is_encrypted(source)
+    {
+        return Err(DofiError::CannotAdoptRendered(target.to_path_buf()));
+    }
+
+    let contents = fs.read(source)?;
+    if merge {
+        let checksums = checksum::load(fs, journal.directory())?;
+        let base = checksum::base(fs, journal.directory(), &checksums, target).unwrap_or_default();
+        let sides = Sides {
+            base: &base,
+            new: &contents,
+            source: &contents,
+        };
+        if merge::merge(fs, target, sides, source, journal)? == Outcome::Conflicts {
+            return Ok(Adopted::Conflicts);
+        }
+    }
+
+    let adopted = fs.read(target)?;
+    if adopted != contents {
+        let mode = fs.symlink_metadata(source)?.mode;
+        journal.write_file(fs, source, &adopted)?;
+        fs.set_mode(source, mode)?;
+    }
+    journal.remove_file(fs, target)?;
+    link_entry(fs, &dotfile, false, options, journal)?;
+    if !dotfile.strategy.is_copied() {
+        // A merge records the target as written by dofi, which only holds for copies
+        checksum::forget(fs, target, journal)?;
+    }
+    Ok(Adopted::Linked(source.clone()))
+}
+
+/// Copies the chezmoi source directory `source_directory` into `dotfiles_directory`,
+/// translating chezmoi's attribute prefixes (`dot_`, `private_`, `executable_`, ...) into
+/// file names and permissions. Returns the number of imported files.
+///
+/// Templates without any template directives are imported as plain files, actual templates,
+/// encrypted files, scripts and other special entries are skipped with a warning. The source
+/// directory is left untouched and nothing is linked.
+pub fn import_chezmoi(
+    fs: &dyn Fs,
+    source_directory: &Path,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<usize, DofiError> {
+    let root_file = source_directory.join(".chezmoiroot");
+    let source_directory = match fs.read(&root_file) {
+        Ok(root) => source_directory.join(String::from_utf8_lossy(&root).trim()),
+        Err(_) => source_directory.to_path_buf(),
+    };
+    if fs.exists(&source_directory.join(".chezmoiignore")) {
+        warn!("'.chezmoiignore' is not translated, review the imported files");
+    }
+
+    let mut imported = 0;
+    for file in fs.walk(&source_directory)? {
+        let Ok(relative) = file.strip_prefix(&source_directory) else {
+            continue;
+        };
+        if relative
+            .components()
+            .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+        {
+            continue;
+        }
+
+        let (relative, mode, template) = match translate_chezmoi_path(relative) {
+            ChezmoiEntry::Import {
+                relative,
+                mode,
+                template,
+            } => (relative, mode, template),
+            ChezmoiEntry::Unsupported(reason) => {
+                warn!("Skipping '{}', {reason}", file.display());
+                continue;
+            }
+        };
+
+        let contents = fs.read(&file)?;
+        if template && contents.windows(2).any(|window| window == b"{{") {
+            warn!(
+                "Skipping '{}', chezmoi templates are not supported",
+                file.display()
+            );
+            continue;
+        }
+
+        let new_file = dotfiles_directory.join(&relative);
+        if fs.exists(&new_file) {
+            warn!(
+                "Skipping '{}', '{}' already exists",
+                file.display(),
+                new_file.display()
+            );
+            continue;
+        }
+
+        if let Some(parent) = new_file.parent() {
+            journal.create_dir_all(fs, parent)?;
+        }
+        info!("Copying '{}' to '{}'", file.display(), new_file.display());
+        fs.write(&new_file, &contents)?;
+        journal.record(Action::CreatedFile {
+            path: new_file.clone(),
+        });
+        fs.set_mode(&new_file, mode)?;
+        imported += 1;
+    }
+
+    Ok(imported)
+}
+
+fn translate_chezmoi_path(relative: &Path) -> ChezmoiEntry {
+    let mut translated = PathBuf::new();
+    let mut mode = 0o644;
+    let mut template = false;
+    let components = relative.components().collect::<Vec<_>>();
+
+    for (index, component) in components.iter().enumerate() {
+        let is_file = index == components.len() - 1;
+        let mut name = &*component.as_os_str().to_string_lossy();
+        let mut dot = false;
+
+        loop {
+            if let Some(rest) = name.strip_prefix("literal_") {
+                name = rest;
+                break;
+            }
+            for unsupported in [
+                "encrypted_",
+                "symlink_",
+                "modify_",
+                "remove_",
+                "run_",
+                "external_",
+            ] {
+                if name.starts_with(unsupported) {
+                    return ChezmoiEntry::Unsupported("this chezmoi attribute is not supported");
+                }
+            }
+
+            let stripped = if let Some(rest) = name.strip_prefix("dot_") {
+                dot = true;
+                rest
+            } else if let Some(rest) = name.strip_prefix("private_") {
+                if is_file {
+                    mode &= 0o700;
+                }
+                rest
+            } else if let Some(rest) = name.strip_prefix("executable_") {
+                mode |= 0o111;
+                rest
+            } else if let Some(rest) = name.strip_prefix("readonly_") {
+                mode &= !0o222;
+                rest
+            } else if let Some(rest) = name
+                .strip_prefix("empty_")
+                .or_else(|| name.strip_prefix("exact_"))
+                .or_else(|| name.strip_prefix("create_"))
+            {
+                rest
+            } else {
+                break;
+            };
+            name = stripped;
+        }
+
+        let mut name = name.to_string();
+        if is_file {
+            if let Some(rest) = name.strip_suffix(".literal") {
+                name = rest.to_string();
+            } else if let Some(rest) = name.strip_suffix(".tmpl") {
+                name = rest.to_string();
+                template = true;
+            }
+        }
+
+        translated.push(if dot { format!(".{name}") } else { name });
+    }
+
+    ChezmoiEntry::Import {
+        relative: translated,
+        mode,
+        template,
+    }
+}
@@ -0,0 +1,184 @@
+//! Splitting a stream of items into batches that fit in memory.
+//!
+//! [`Batches`] holds the items it is given as long as they fit a single batch. Once there are
+//! more it spreads them over files in a scratch directory by the hash of their key, so the
+//! items sharing a key always end up in the same batch however many there are. Files that
+//! still hold more than a batch are split again while they are read back.
+
+use std::{
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::fs::ScratchDirectory;
+
+/// How many files a batch that is too large is split into
+const FANOUT: u64 = 16;
+
+/// How often a file can be split again before the bits of the hashes run out
+const LEVELS: u32 = u64::BITS / FANOUT.trailing_zeros();
+
+/// Tells the scratch directories of one process apart
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+/// The hash [`Batches`] groups an item with the key `key` by
+pub fn key(key: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Items split into batches of at most `size`, see the [module documentation](self)
+pub struct Batches<T> {
+    size: usize,
+    items: Vec<(u64, T)>,
+    spill: Option<Spill>,
+    len: usize,
+}
+
+/// The files the items of [`Batches`] went to once they no longer fitted a batch
+struct Spill {
+    scratch: ScratchDirectory,
+    files: Vec<Bucket>,
+}
+
+/// A file of items, with how many it holds
+struct Bucket {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: usize,
+}
+
+impl<T: Serialize + DeserializeOwned> Batches<T> {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            items: Vec::new(),
+            spill: None,
+            len: 0,
+        }
+    }
+
+    /// How many items were added
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds `item`, whose key hashes to `key`
+    pub fn push(&mut self, key: u64, item: T) -> io::Result<()> {
+        self.len += 1;
+        if let Some(spill) = &mut self.spill {
+            return write(&mut spill.files, 0, key, &item);
+        }
+        self.items.push((key, item));
+        if self.items.len() <= self.size {
+            return Ok(());
+        }
+
+        let name = format!("dofi-batches-{}", SCRATCH.fetch_add(1, Ordering::Relaxed));
+        let scratch = ScratchDirectory::new(&std::env::temp_dir(), &name)?;
+        let mut files = buckets(scratch.path(), "")?;
+        for (key, item) in self.items.drain(..) {
+            write(&mut files, 0, key, &item)?;
+        }
+        self.spill = Some(Spill { scratch, files });
+        Ok(())
+    }
+
+    /// Calls `visit` with each batch in turn, until it fails
+    pub fn try_for_each<E: From<io::Error>>(
+        self,
+        mut visit: impl FnMut(Vec<T>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let Some(spill) = self.spill else {
+            if self.items.is_empty() {
+                return Ok(());
+            }
+            return visit(self.items.into_iter().map(|(_, item)| item).collect());
+        };
+
+        let mut pending = Vec::new();
+        for file in spill.files {
+            pending.push((finish(file)?, 0));
+        }
+        // Depth first, so only the files of a single split wait at each level
+        pending.reverse();
+        while let Some(((path, len), level)) = pending.pop() {
+            if len == 0 {
+                continue;
+            }
+            if len <= self.size || level + 1 == LEVELS {
+                visit(read(&path)?)?;
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let mut files = buckets(spill.scratch.path(), &format!("{name}-"))?;
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let (key, item) =
+                    serde_json::from_str::<(u64, T)>(&line?).map_err(io::Error::from)?;
+                write(&mut files, level + 1, key, &item)?;
+            }
+            std::fs::remove_file(&path)?;
+            let mut split = Vec::new();
+            for file in files {
+                split.push((finish(file)?, level + 1));
+            }
+            pending.extend(split.into_iter().rev());
+        }
+
+        Ok(())
+    }
+}
+
+/// The [`FANOUT`] empty files `<prefix><n>` in `directory`
+fn buckets(directory: &Path, prefix: &str) -> io::Result<Vec<Bucket>> {
+    (0..FANOUT)
+        .map(|n| {
+            let path = directory.join(format!("{prefix}{n}"));
+            let writer = BufWriter::new(File::create(&path)?);
+            Ok(Bucket {
+                path,
+                writer,
+                len: 0,
+            })
+        })
+        .collect()
+}
+
+/// Appends `item` to the one of `files` its `key` picks at `level`
+fn write<T: Serialize>(files: &mut [Bucket], level: u32, key: u64, item: &T) -> io::Result<()> {
+    let index = (key >> (level * FANOUT.trailing_zeros())) % FANOUT;
+    let file = &mut files[index as usize];
+    serde_json::to_writer(&mut file.writer, &(key, item))?;
+    file.writer.write_all(b"\n")?;
+    file.len += 1;
+    Ok(())
+}
+
+/// Flushes `file`, returning its path and how many items it holds
+fn finish(file: Bucket) -> io::Result<(PathBuf, usize)> {
+    file.writer.into_inner().map_err(|e| e.into_error())?;
+    Ok((file.path, file.len))
+}
+
+/// The items in the file at `path`, in the order they were written
+fn read<T: DeserializeOwned>(path: &Path) -> io::Result<Vec<T>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| {
+            let (_, item) = serde_json::from_str::<(u64, T)>(&line?)?;
+            Ok(item)
+        })
+        .collect()
+}
@@ -0,0 +1,195 @@
+//! Validation of the dotfiles repo itself, e.g. in its CI.
+//!
+//! Unlike [`doctor`](crate::doctor), which looks at the targets, checking only reads the
+//! dotfiles directories: every template has to render, every encrypted dotfile has to decrypt,
+//! the manifest may only refer to files that exist and no two dotfiles of a layer may share a
+//! target under the same condition.
+//!
+//! Templates are [linted](template::lint) before they are rendered, so every syntax error,
+//! unknown function and variable missing from the vars files is reported at once, pointing at
+//! the expression in the template.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    condition, encryption, list_files, matching, private, secrets::SecretProvider, template,
+    DofiError, Fs, LinkOptions, Manifest, Strategy,
+};
+
+/// A problem found in a dotfiles directory
+#[derive(Debug)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub problem: Problem,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The template does not render
+    Template(String),
+    /// An expression of the template is wrong, an [`DofiError::InvalidTemplate`] spanning it
+    Lint(DofiError),
+    /// The encrypted dotfile does not decrypt
+    Decryption(String),
+    /// A `section` of the manifest refers to a file that does not exist
+    MissingFile { section: &'static str },
+    /// The dotfile has the same target as `other`
+    DuplicateTarget { target: PathBuf, other: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Template(error) => write!(f, "does not render, {error}"),
+            Problem::Lint(DofiError::InvalidTemplate {
+                message,
+                span,
+                source_code,
+            }) => {
+                let line = source_code.inner()[..span.offset()].matches('\n').count() + 1;
+                write!(f, "does not render, line {line}: {message}")
+            }
+            Problem::Lint(error) => write!(f, "{error}"),
+            Problem::Decryption(error) => write!(f, "does not decrypt, {error}"),
+            Problem::MissingFile { section } => {
+                write!(
+                    f,
+                    "listed in [{section}] of the manifest but does not exist"
+                )
+            }
+            Problem::DuplicateTarget { target, other } => write!(
+                f,
+                "targets '{}' just like '{}'",
+                target.display(),
+                other.display()
+            ),
+        }
+    }
+}
+
+/// Looks up every secret as an empty string, so templates can be checked offline
+pub struct OfflineSecrets;
+
+impl SecretProvider for OfflineSecrets {
+    fn lookup(&self, _name: &str) -> Result<String, DofiError> {
+        Ok(String::new())
+    }
+}
+
+/// Checks the layered `dotfiles_directories`, rendering and decrypting with `options`.
+/// `base_directory` is only used to work out the targets, nothing in it is read.
+pub fn check(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+) -> Result<Vec<Finding>, DofiError> {
+    let mut findings = Vec::new();
+
+    for layer in dotfiles_directories {
+        let manifest = Manifest::load(fs, layer)?;
+        let sections = [
+            ("targets", manifest.targets.keys().collect::<Vec<_>>()),
+            ("roots", manifest.roots.keys().collect()),
+            ("tags", manifest.tags.values().flatten().collect()),
+            ("files", manifest.files.keys().collect()),
+            ("scripts", manifest.scripts.keys().collect()),
+        ];
+        for (section, paths) in sections {
+            for path in paths {
+                let path = layer.join(path);
+                if !fs.exists(&path) {
+                    findings.push(Finding {
+                        path,
+                        problem: Problem::MissingFile { section },
+                    });
+                }
+            }
+        }
+
+        let strategies = manifest.strategy_matchers()?;
+        let mut targets = BTreeMap::<_, PathBuf>::new();
+        for source in list_files(fs, layer)? {
+            let target = manifest.target_path(&source, base_directory, layer)?;
+            let strategy = Strategy::resolve(
+                &source,
+                private::is_private(&source, std::slice::from_ref(layer)),
+                matching(&strategies, target.strip_prefix(base_directory).ok()),
+            );
+            for problem in check_contents(fs, &source, layer, strategy, options) {
+                findings.push(Finding {
+                    path: source.clone(),
+                    problem,
+                });
+            }
+
+            match targets.entry((target.clone(), condition::condition(&source))) {
+                Entry::Occupied(other) => findings.push(Finding {
+                    path: source,
+                    problem: Problem::DuplicateTarget {
+                        target,
+                        other: other.get().clone(),
+                    },
+                }),
+                Entry::Vacant(entry) => {
+                    entry.insert(source);
+                }
+            }
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Lints and renders or decrypts `source` if it is a template or encrypted, returning what
+/// went wrong. Dotfiles encrypted for other machines are not decrypted.
+fn check_contents(
+    fs: &dyn Fs,
+    source: &Path,
+    layer: &Path,
+    strategy: Strategy,
+    options: &LinkOptions,
+) -> Vec<Problem> {
+    match encryption::is_encrypted_for_others(fs, &options.encryption, source, layer) {
+        Ok(true) => return Vec::new(),
+        Ok(false) => {}
+        Err(e) => return vec![Problem::Decryption(e.to_string())],
+    }
+    if encryption::is_encrypted(source) {
+        let result = options
+            .encryption
+            .iter()
+            .find(|backend| source.extension().is_some_and(|e| e == backend.extension()))
+            .ok_or(DofiError::NoEncryptionKey)
+            .and_then(|backend| backend.decrypt(&fs.read(source)?));
+        result
+            .err()
+            .map(|e| Problem::Decryption(e.to_string()))
+            .into_iter()
+            .collect()
+    } else if strategy == Strategy::Template {
+        let template = match fs.read(source) {
+            Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+            Err(e) => return vec![Problem::Template(e.to_string())],
+        };
+        let lints = template::lint(fs, &template, source, &options.templates);
+        if !lints.is_empty() {
+            return lints.into_iter().map(Problem::Lint).collect();
+        }
+        // What linting cannot tell, like unset environment variables or failing secrets
+        match template::render(fs, &template, source, &options.templates) {
+            Ok(_) => Vec::new(),
+            Err(DofiError::InvalidTemplate { message, span, .. }) => {
+                let line = template[..span.offset()].matches('\n').count() + 1;
+                vec![Problem::Template(format!("line {line}: {message}"))]
+            }
+            Err(e) => vec![Problem::Template(e.to_string())],
+        }
+    } else {
+        Vec::new()
+    }
+}
@@ -0,0 +1,189 @@
+//! Checksums of the targets dofi writes instead of symlinking.
+//!
+//! Rendered templates and decrypted dotfiles are copies, edits made to them are not reflected
+//! in the dotfiles. Whenever dofi writes such a target it records a SHA-256 of the contents in
+//! `checksums.json` in the state directory, so `verify` can report targets that were changed
+//! since and `link` refuses to overwrite them. The SHA-256 of the dotfile the target was
+//! written from goes to `sources.json`, so `status` and `diff` can tell a target edited
+//! locally apart from a dotfile changed in the repo, see [`Drift`]. What was written is kept in
+//! `bases/` in the state directory, readable only by the user, as the base to
+//! [merge](crate::merge) local edits with changes to the dotfile.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{DofiError, Fs, Journal};
+
+/// The file in the state directory holding the checksums
+pub const CHECKSUMS_FILE: &str = "checksums.json";
+/// The file in the state directory holding the checksums of the dotfiles the targets were
+/// written from
+pub const SOURCES_FILE: &str = "sources.json";
+/// The directory in the state directory holding what was written, named by checksum
+pub const BASES_DIRECTORY: &str = "bases";
+
+/// How a written target and its dotfile changed since dofi last wrote the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drift {
+    /// The target was edited locally
+    Target,
+    /// The dotfile was changed in the repo
+    Source,
+    /// Both were changed
+    Both,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Drift::Target => "edited locally",
+            Drift::Source => "changed in the repo",
+            Drift::Both => "edited locally and changed in the repo",
+        })
+    }
+}
+
+/// The hex encoded SHA-256 of `contents`
+pub fn digest(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// Loads the recorded checksums keyed by target, a missing file holds none
+pub fn load(fs: &dyn Fs, state_directory: &Path) -> Result<BTreeMap<PathBuf, String>, DofiError> {
+    load_file(fs, &state_directory.join(CHECKSUMS_FILE))
+}
+
+/// Loads the recorded checksums of the dotfiles keyed by the target written from them
+pub fn load_sources(
+    fs: &dyn Fs,
+    state_directory: &Path,
+) -> Result<BTreeMap<PathBuf, String>, DofiError> {
+    load_file(fs, &state_directory.join(SOURCES_FILE))
+}
+
+/// Records that `contents` were written to `target` from a dotfile holding `source`. The
+/// checksums files are written through the journal, so undoing the write restores the
+/// previous checksums as well.
+pub fn record(
+    fs: &dyn Fs,
+    target: &Path,
+    contents: &[u8],
+    source: &[u8],
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let state_directory = journal.directory().to_path_buf();
+    fs.create_dir_all(&state_directory)?;
+
+    let base = state_directory.join(BASES_DIRECTORY).join(digest(contents));
+    if !fs.exists(&base) {
+        journal.create_dir_all(fs, &state_directory.join(BASES_DIRECTORY))?;
+        journal.write_file(fs, &base, contents)?;
+        fs.set_mode(&base, 0o600)?;
+    }
+
+    for (file, contents) in [(CHECKSUMS_FILE, contents), (SOURCES_FILE, source)] {
+        let path = state_directory.join(file);
+        let mut checksums = load_file(fs, &path)?;
+        checksums.insert(target.to_path_buf(), digest(contents));
+        let contents =
+            serde_json::to_vec_pretty(&Stored(checksums)).map_err(DofiError::InvalidChecksums)?;
+        journal.write_file(fs, &path, &contents)?;
+    }
+
+    Ok(())
+}
+
+/// Drops what [`record`] recorded for `target`, once dofi no longer writes it
+pub fn forget(fs: &dyn Fs, target: &Path, journal: &mut Journal) -> Result<(), DofiError> {
+    let state_directory = journal.directory().to_path_buf();
+    for file in [CHECKSUMS_FILE, SOURCES_FILE] {
+        let path = state_directory.join(file);
+        let mut checksums = load_file(fs, &path)?;
+        if checksums.remove(target).is_some() {
+            let contents = serde_json::to_vec_pretty(&Stored(checksums))
+                .map_err(DofiError::InvalidChecksums)?;
+            journal.write_file(fs, &path, &contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// What dofi last wrote to `target` according to `checksums`, `None` if it was never recorded
+/// or written by a version that did not keep it
+pub fn base(
+    fs: &dyn Fs,
+    state_directory: &Path,
+    checksums: &BTreeMap<PathBuf, String>,
+    target: &Path,
+) -> Option<Vec<u8>> {
+    let recorded = checksums.get(target)?;
+    fs.read(&state_directory.join(BASES_DIRECTORY).join(recorded))
+        .ok()
+}
+
+/// Fails if `target` was changed since dofi last wrote it, it is about to be overwritten
+pub fn ensure_unmodified(fs: &dyn Fs, target: &Path, journal: &Journal) -> Result<(), DofiError> {
+    let checksums = load(fs, journal.directory())?;
+
+    match modified(fs, &checksums, target) {
+        Some(true) => Err(DofiError::TargetModified(target.to_path_buf())),
+        _ => Ok(()),
+    }
+}
+
+/// How `target` and the dotfile `source` it was written from changed since dofi last wrote
+/// it, according to `checksums` and `sources`. `None` if neither changed or the target was
+/// never recorded. Dotfiles recorded by older versions count as unchanged.
+pub fn drift(
+    fs: &dyn Fs,
+    checksums: &BTreeMap<PathBuf, String>,
+    sources: &BTreeMap<PathBuf, String>,
+    source: &Path,
+    target: &Path,
+) -> Option<Drift> {
+    let target_changed = modified(fs, checksums, target)?;
+    let source_changed = sources
+        .get(target)
+        .zip(fs.read(source).ok())
+        .is_some_and(|(recorded, contents)| digest(&contents) != *recorded);
+
+    match (target_changed, source_changed) {
+        (true, true) => Some(Drift::Both),
+        (true, false) => Some(Drift::Target),
+        (false, true) => Some(Drift::Source),
+        (false, false) => None,
+    }
+}
+
+/// Whether `target` was changed since dofi last wrote it, `None` if it was never recorded or
+/// does not exist
+pub fn modified(fs: &dyn Fs, checksums: &BTreeMap<PathBuf, String>, target: &Path) -> Option<bool> {
+    let recorded = checksums.get(target)?;
+    let contents = fs.read(target).ok()?;
+
+    Some(digest(&contents) != *recorded)
+}
+
+/// Checksums as they are stored, keyed by target
+#[derive(Serialize, Deserialize)]
+struct Stored(#[serde(with = "crate::paths::serde::map")] BTreeMap<PathBuf, String>);
+
+fn load_file(fs: &dyn Fs, path: &Path) -> Result<BTreeMap<PathBuf, String>, DofiError> {
+    match fs.read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(|Stored(checksums)| checksums)
+            .map_err(DofiError::InvalidChecksums),
+        Err(_) => Ok(BTreeMap::new()),
+    }
+}
@@ -0,0 +1,62 @@
+//! Colored terminal output.
+//!
+//! Colors are used when stdout is a terminal and `NO_COLOR` is not set, unless forced either
+//! way with `--color`.
+
+use std::io::{self, IsTerminal};
+
+use crate::status::State;
+
+/// The colors dofi paints its output with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Green,
+    Yellow,
+    Red,
+    Cyan,
+}
+
+impl Color {
+    fn code(self) -> &'static str {
+        match self {
+            Color::Green => "32",
+            Color::Yellow => "33",
+            Color::Red => "31",
+            Color::Cyan => "36",
+        }
+    }
+}
+
+/// Whether output should be colored when left to auto-detection
+pub fn auto() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+/// Wraps `text` in the escape codes for `color` if `enabled`
+pub fn paint(text: &str, color: Color, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{text}\x1b[0m", color.code())
+    } else {
+        text.to_string()
+    }
+}
+
+/// Wraps `text` in the escape codes for `color` with foreground and background swapped if
+/// `enabled`, to make part of an already painted line stand out
+pub fn emphasize(text: &str, color: Color, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{};7m{text}\x1b[0m", color.code())
+    } else {
+        text.to_string()
+    }
+}
+
+/// The color showing `state`: linked is fine, unlinked has yet to be done and anything else
+/// needs attention
+pub fn of_state(state: State) -> Color {
+    match state {
+        State::Linked => Color::Green,
+        State::Unlinked => Color::Yellow,
+        State::Conflict | State::Broken => Color::Red,
+    }
+}
@@ -0,0 +1,98 @@
+//! Dotfiles linked only on some platforms or hosts.
+//!
+//! A dotfile whose name ends in an operating system suffix, `<dotfiles>/.gitconfig.macos`, or
+//! a host suffix, `<dotfiles>/.zshrc.hostname-foo`, is only linked where the suffix matches and
+//! targets its path without the suffix. The suffix goes before any encryption or template
+//! extension, e.g. `.gitconfig.linux.tmpl`. Where both match, the conditional dotfile wins over
+//! an unconditional one with the same target. The manifest can limit files to some hosts as
+//! well, without renaming them, see [`manifest`](crate::manifest).
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use crate::{encryption, template};
+
+/// The operating systems recognized as suffixes, named as in [`std::env::consts::OS`]
+pub const OPERATING_SYSTEMS: [&str; 6] =
+    ["linux", "macos", "windows", "freebsd", "openbsd", "netbsd"];
+
+const HOSTNAME_PREFIX: &str = "hostname-";
+
+/// When a conditional dotfile is linked
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    Os(String),
+    Hostname(String),
+}
+
+impl Condition {
+    /// Whether the condition holds on this machine
+    pub fn holds(&self) -> bool {
+        match self {
+            Condition::Os(os) => os == std::env::consts::OS,
+            Condition::Hostname(name) => {
+                hostname().is_some_and(|hostname| hostname.eq_ignore_ascii_case(name))
+            }
+        }
+    }
+}
+
+/// The condition encoded in the name of the dotfile `path`, if any
+pub fn condition(path: &Path) -> Option<Condition> {
+    let suffix = conditional_suffix(path)?;
+
+    match suffix.strip_prefix(HOSTNAME_PREFIX) {
+        Some(name) => Some(Condition::Hostname(name.to_string())),
+        None => Some(Condition::Os(suffix)),
+    }
+}
+
+/// Removes the conditional suffix from `path`, which must not carry an encryption or
+/// template extension anymore
+pub fn strip_suffix(path: &Path) -> PathBuf {
+    match conditional_suffix(path) {
+        Some(_) => path.with_extension(""),
+        None => path.to_path_buf(),
+    }
+}
+
+fn conditional_suffix(path: &Path) -> Option<String> {
+    let path = if encryption::is_encrypted(path) || template::is_template(path) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    };
+    let extension = path.extension()?.to_str()?;
+
+    let conditional = OPERATING_SYSTEMS.contains(&extension)
+        || extension
+            .strip_prefix(HOSTNAME_PREFIX)
+            .is_some_and(|name| !name.is_empty());
+    conditional.then(|| extension.to_string())
+}
+
+/// The short name of this machine, without any domain
+pub(crate) fn hostname() -> Option<&'static str> {
+    static HOSTNAME: OnceLock<Option<String>> = OnceLock::new();
+
+    HOSTNAME
+        .get_or_init(|| {
+            let name = std::env::var("HOSTNAME")
+                .or_else(|_| std::env::var("COMPUTERNAME"))
+                .ok()
+                .or_else(|| {
+                    let output = Command::new("hostname").output().ok()?;
+                    output
+                        .status
+                        .success()
+                        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+                })?;
+            let name = name.trim();
+            let name = name.split('.').next().unwrap_or(name);
+            (!name.is_empty()).then(|| name.to_string())
+        })
+        .as_deref()
+}
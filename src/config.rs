@@ -0,0 +1,505 @@
+//! The user configuration file, `$XDG_CONFIG_HOME/dofi/config.toml` by default.
+//!
+//! ```toml
+//! default_workspace = "personal"
+//! assume_yes = false
+//!
+//! [workspaces.personal]
+//! dotfiles = "~/dotfiles"
+//!
+//! [workspaces.work]
+//! dotfiles = "~/src/work-dotfiles"
+//! base = "~/work"
+//! layers = ["~/src/work-private"]
+//!
+//! [conflicts]
+//! "~/.ssh/**" = "never-force"
+//!
+//! [git]
+//! auto_commit = true
+//! auto_push = true
+//!
+//! [alias]
+//! up = ["sync"]
+//! deploy = ["link", "--force", "--tag", "work"]
+//! ```
+//!
+//! Without a dotfiles directory given in any other way, the first existing one of
+//! `default_dotfiles` is used, which defaults to `$XDG_DATA_HOME/dofi` and `~/.dotfiles`.
+//! `dofi init` creates the first of them if none exists.
+//!
+//! Paths may refer to environment variables, `$HOME/dotfiles` or `${XDG_CONFIG_HOME}/dofi`,
+//! which are expanded when the configuration is loaded.
+//!
+//! An alias is a command of its own, `dofi deploy ~/.config` runs
+//! `dofi link --force --tag work ~/.config`. Aliases may use other aliases, built-in commands
+//! and their short forms always win over an alias of the same name, and aliases win over
+//! [plugins](crate::plugin).
+//!
+//! `dofi config` reads and changes single settings by their dotted keys, `git.auto_push` or
+//! `conflicts."~/.ssh/**"`, keeping the rest of the file as it is. Values are TOML, anything
+//! that does not parse as TOML is a string, and a change is only written if the configuration
+//! stays valid.
+//!
+//! See [`conflict`](crate::conflict) for the conflict policies and
+//! [`encryption`](crate::encryption) for the keys of encrypted dotfiles,
+//! [`secrets`](crate::secrets) for the secrets available to templates and
+//! [`track`](crate::track) for the directories watched for new config files,
+//! [`guard`](crate::guard) for the files `add` refuses and
+//! [`permissions`](crate::permissions) for the modes of the directories dofi creates and
+//! [`fs`](crate::fs) for retrying on flaky network filesystems.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use miette::{NamedSource, SourceSpan};
+use serde::Deserialize;
+use toml_edit::{value, DocumentMut, Item, Key, Table, Value};
+
+use crate::{
+    conflict::ConflictPolicy, encryption::EncryptionConfig, fs::FsConfig, guard::AddConfig,
+    permissions::DirectoriesConfig, platform, secrets::SecretsConfig, track::TrackConfig,
+    DofiError,
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The workspace used when neither a dotfiles directory nor a workspace is given
+    pub default_workspace: Option<String>,
+
+    /// The dotfiles directories tried in order when none is given or selected
+    #[serde(default)]
+    pub default_dotfiles: Vec<PathBuf>,
+
+    #[serde(default)]
+    pub workspaces: BTreeMap<String, Workspace>,
+
+    /// Glob patterns on target paths mapped to how conflicts with existing files are handled
+    #[serde(default)]
+    pub conflicts: BTreeMap<String, ConflictPolicy>,
+
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+
+    #[serde(default)]
+    pub secrets: SecretsConfig,
+
+    #[serde(default)]
+    pub git: GitConfig,
+
+    #[serde(default)]
+    pub track: TrackConfig,
+
+    #[serde(default)]
+    pub add: AddConfig,
+
+    #[serde(default)]
+    pub directories: DirectoriesConfig,
+
+    #[serde(default)]
+    pub filesystem: FsConfig,
+
+    /// Names of commands of their own mapped to the arguments they stand for
+    #[serde(default)]
+    pub alias: BTreeMap<String, Vec<String>>,
+
+    /// Run destructive commands without asking, as if `--yes` were given
+    #[serde(default)]
+    pub assume_yes: bool,
+}
+
+/// What dofi does with the git repository of the dotfiles after `add` and `remove`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitConfig {
+    /// Commit the files each command changed in the dotfiles
+    #[serde(default)]
+    pub auto_commit: bool,
+    /// Push after committing. A push that fails, e.g. while offline, is retried with the next.
+    #[serde(default)]
+    pub auto_push: bool,
+}
+
+/// A registered dotfiles directory together with the base directory it is linked to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Workspace {
+    pub dotfiles: PathBuf,
+    /// Defaults to the home directory
+    pub base: Option<PathBuf>,
+    /// Further dotfiles directories layered on top of `dotfiles`, later ones win
+    #[serde(default)]
+    pub layers: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Loads the configuration at `path`, a missing file is an empty configuration
+    pub fn load(path: &Path) -> Result<Self, DofiError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut config: Self =
+            toml::from_str(&contents).map_err(|e| invalid_config(path, contents, e))?;
+        config.expand_variables()?;
+
+        Ok(config)
+    }
+
+    /// Expands the environment variables in the configured paths
+    fn expand_variables(&mut self) -> Result<(), DofiError> {
+        for directory in &mut self.default_dotfiles {
+            *directory = expand_variables_in_path(directory)?;
+        }
+        for workspace in self.workspaces.values_mut() {
+            workspace.dotfiles = expand_variables_in_path(&workspace.dotfiles)?;
+            if let Some(base) = &workspace.base {
+                workspace.base = Some(expand_variables_in_path(base)?);
+            }
+            for layer in &mut workspace.layers {
+                *layer = expand_variables_in_path(layer)?;
+            }
+        }
+        if let Some(identity) = &self.encryption.identity {
+            self.encryption.identity = Some(expand_variables_in_path(identity)?);
+        }
+        for directory in self.track.review.iter_mut().chain(&mut self.track.add) {
+            *directory = expand_variables_in_path(directory)?;
+        }
+        for pattern in &mut self.track.ignore {
+            *pattern = expand_variables(pattern)?;
+        }
+        self.conflicts = std::mem::take(&mut self.conflicts)
+            .into_iter()
+            .map(|(pattern, policy)| Ok((expand_variables(&pattern)?, policy)))
+            .collect::<Result<_, DofiError>>()?;
+
+        Ok(())
+    }
+
+    /// The dotfiles directory used when none is given or selected: the first of the
+    /// `default_dotfiles` below `base_directory` that exists, or with `create` the first of them
+    /// if none does
+    pub fn default_dotfiles_directory(
+        &self,
+        base_directory: &Path,
+        create: bool,
+    ) -> Option<PathBuf> {
+        let candidates = if self.default_dotfiles.is_empty() {
+            let data = std::env::var_os("XDG_DATA_HOME")
+                .map(PathBuf::from)
+                .filter(|path| path.is_absolute())
+                .unwrap_or_else(|| base_directory.join(".local/share"));
+            vec![data.join("dofi"), base_directory.join(".dotfiles")]
+        } else {
+            self.default_dotfiles
+                .iter()
+                .map(|d| expand_path(d))
+                .collect()
+        };
+
+        let existing = candidates
+            .iter()
+            .find(|candidate| candidate.is_dir())
+            .cloned();
+        existing.or_else(|| candidates.into_iter().next().filter(|_| create))
+    }
+
+    /// Looks up the workspace called `name`
+    pub fn workspace(&self, name: &str) -> Result<&Workspace, DofiError> {
+        self.workspaces
+            .get(name)
+            .ok_or_else(|| DofiError::UnknownWorkspace(name.to_string()))
+    }
+}
+
+pub(crate) fn invalid_config(path: &Path, contents: String, error: toml::de::Error) -> DofiError {
+    DofiError::InvalidConfig {
+        message: error.message().to_string(),
+        span: error.span().map(SourceSpan::from),
+        source_code: NamedSource::new(path.display().to_string(), contents),
+    }
+}
+
+/// The default location of the configuration file
+pub fn config_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| platform::home_directory().map(|home| home.join(".config")))
+        .map(|directory| directory.join("dofi").join("config.toml"))
+}
+
+/// Replaces every `$NAME` and `${NAME}` in `text` with the value of the environment variable,
+/// failing if it is not set. `$$` is a literal `$`.
+pub fn expand_variables(text: &str) -> Result<String, DofiError> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let (name, next) = if let Some(braced) = after.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => (braced, ""),
+            }
+        } else if let Some(next) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = next;
+            continue;
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = std::env::var(name)
+            .map_err(|_| DofiError::UnsetVariable(name.to_string(), text.to_string()))?;
+        expanded.push_str(&value);
+        rest = next;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// [`expand_variables`] for paths, which must be valid UTF-8 to contain variables
+pub fn expand_variables_in_path(path: &Path) -> Result<PathBuf, DofiError> {
+    match path.to_str() {
+        Some(text) => expand_variables(text).map(PathBuf::from),
+        None => Ok(path.to_path_buf()),
+    }
+}
+
+/// Expands a leading `~` to the home directory
+pub fn expand_path(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), platform::home_directory()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Reads the configuration file at `path` for editing, a missing file is an empty document
+fn document(path: &Path) -> Result<DocumentMut, DofiError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    contents
+        .parse::<DocumentMut>()
+        .map_err(|e| DofiError::InvalidConfig {
+            message: e.message().to_string(),
+            span: e.span().map(SourceSpan::from),
+            source_code: NamedSource::new(path.display().to_string(), contents.clone()),
+        })
+}
+
+/// Writes `document` to `path` if it is a valid configuration
+fn save(path: &Path, document: &DocumentMut) -> Result<(), DofiError> {
+    let contents = document.to_string();
+    toml::from_str::<Config>(&contents).map_err(|e| invalid_config(path, contents.clone(), e))?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)?;
+
+    Ok(())
+}
+
+/// Edits the configuration file in place, preserving its formatting and comments
+fn edit(path: &Path, change: impl FnOnce(&mut DocumentMut)) -> Result<(), DofiError> {
+    let mut document = document(path)?;
+    change(&mut document);
+    save(path, &document)
+}
+
+/// Every setting in the configuration file at `path` as its dotted key and TOML value
+pub fn settings(path: &Path) -> Result<Vec<(String, String)>, DofiError> {
+    fn collect(prefix: &str, item: &Item, settings: &mut Vec<(String, String)>) {
+        if let Some(table) = item.as_table_like() {
+            for (name, item) in table.iter() {
+                let key = Key::new(name).to_string();
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                collect(&key, item, settings);
+            }
+        } else if let Some(value) = item.as_value() {
+            settings.push((prefix.to_string(), display_value(value)));
+        }
+    }
+
+    let mut settings = Vec::new();
+    collect("", document(path)?.as_item(), &mut settings);
+    Ok(settings)
+}
+
+/// The TOML value of the setting `key` in the configuration file at `path`, a table as all
+/// settings inside it, `None` if it is not set
+pub fn setting(path: &Path, key: &str) -> Result<Option<String>, DofiError> {
+    let document = document(path)?;
+    let mut item = document.as_item();
+    for part in parse_key(key)? {
+        match item.as_table_like().and_then(|table| table.get(part.get())) {
+            Some(inner) => item = inner,
+            None => return Ok(None),
+        }
+    }
+
+    Ok(match item.as_value() {
+        Some(value) => Some(display_value(value)),
+        None => {
+            let mut table = DocumentMut::new();
+            if let Some(inner) = item.as_table_like() {
+                for (name, item) in inner.iter() {
+                    table.insert(name, item.clone());
+                }
+            }
+            Some(table.to_string().trim_end().to_string())
+        }
+    })
+}
+
+/// Sets `key` in the configuration file at `path` to `value`, which is a string unless it is
+/// a TOML value like `true`, `0o600` or `["~/dotfiles"]`
+pub fn set_setting(path: &Path, key: &str, value: &str) -> Result<(), DofiError> {
+    let mut result = Ok(());
+    for value in candidate_values(value) {
+        let mut document = document(path)?;
+        set_value(&mut document, key, value)?;
+
+        // A value failing as TOML may still be fine as a string, the TOML error is reported
+        match save(path, &document) {
+            Ok(()) => return Ok(()),
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(_) => {}
+        }
+    }
+    result
+}
+
+/// The values `value` given on the command line may stand for, the TOML value it parses as,
+/// if any, before the plain string
+pub(crate) fn candidate_values(value: &str) -> impl Iterator<Item = Value> {
+    value
+        .parse::<Value>()
+        .ok()
+        .into_iter()
+        .chain([Value::from(value)])
+}
+
+/// Sets the dotted `key` in `document` to `value`, creating the tables on the way and keeping
+/// the comments around an old value
+pub(crate) fn set_value(
+    document: &mut DocumentMut,
+    key: &str,
+    value: Value,
+) -> Result<(), DofiError> {
+    let keys = parse_key(key)?;
+    let (name, tables) = keys.split_last().expect("a parsed key has a part");
+    let mut table = document.as_table_mut() as &mut dyn toml_edit::TableLike;
+    for (depth, part) in tables.iter().enumerate() {
+        if table.get(part.get()).is_none() {
+            let mut inner = Table::new();
+            inner.set_implicit(true);
+            table.insert(part.get(), Item::Table(inner));
+        }
+        table = table
+            .get_mut(part.get())
+            .and_then(Item::as_table_like_mut)
+            .ok_or_else(|| {
+                let path = keys[..=depth]
+                    .iter()
+                    .map(Key::to_string)
+                    .collect::<Vec<_>>();
+                DofiError::InvalidConfigKey(
+                    key.to_string(),
+                    format!("'{}' is not a table", path.join(".")),
+                )
+            })?;
+    }
+    match table.get_mut(name.get()).and_then(Item::as_value_mut) {
+        Some(old) => {
+            let decor = old.decor().clone();
+            *old = value;
+            *old.decor_mut() = decor;
+        }
+        None => {
+            table.insert(name.get(), Item::Value(value.decorated(" ", "")));
+        }
+    }
+    Ok(())
+}
+
+fn parse_key(key: &str) -> Result<Vec<Key>, DofiError> {
+    Key::parse(key)
+        .map_err(|e| DofiError::InvalidConfigKey(key.to_string(), e.message().to_string()))
+}
+
+fn display_value(value: &Value) -> String {
+    value.clone().decorated("", "").to_string()
+}
+
+/// Registers a workspace in the configuration file at `path`
+pub fn add_workspace(
+    path: &Path,
+    name: &str,
+    dotfiles: &Path,
+    base: Option<&Path>,
+) -> Result<(), DofiError> {
+    edit(path, |document| {
+        if !document.get("workspaces").is_some_and(Item::is_table_like) {
+            let mut table = Table::new();
+            table.set_implicit(true);
+            document.insert("workspaces", Item::Table(table));
+        }
+        let workspaces = document["workspaces"]
+            .as_table_like_mut()
+            .expect("workspaces was just made a table");
+
+        let mut workspace = Table::new();
+        workspace.insert("dotfiles", value(dotfiles.to_string_lossy().as_ref()));
+        if let Some(base) = base {
+            workspace.insert("base", value(base.to_string_lossy().as_ref()));
+        }
+        workspaces.insert(name, Item::Table(workspace));
+    })
+}
+
+/// Removes a workspace from the configuration file at `path`
+pub fn remove_workspace(path: &Path, name: &str) -> Result<(), DofiError> {
+    let mut found = false;
+    edit(path, |document| {
+        if let Some(workspaces) = document
+            .get_mut("workspaces")
+            .and_then(Item::as_table_like_mut)
+        {
+            found = workspaces.remove(name).is_some();
+        }
+        if document.get("default_workspace").and_then(Item::as_str) == Some(name) {
+            document.remove("default_workspace");
+        }
+    })?;
+
+    if found {
+        Ok(())
+    } else {
+        Err(DofiError::UnknownWorkspace(name.to_string()))
+    }
+}
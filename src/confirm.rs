@@ -0,0 +1,32 @@
+//! Asking before destructive commands, so a mistyped `remove` or `link --force-all` can still
+//! be called off.
+//!
+//! The binary only asks when stdin is a terminal, listing the paths the command would delete
+//! or replace. `--yes` or `assume_yes = true` in the configuration proceed without asking, and
+//! so does anything reading its input from a pipe.
+
+use std::{
+    io::{BufRead, Write},
+    path::PathBuf,
+};
+
+use crate::DofiError;
+
+/// Lists `affected` and asks `question` on `output`, true only if the answer read from `input`
+/// is `y` or `yes`. The end of the input declines.
+pub fn confirm(
+    question: &str,
+    affected: &[PathBuf],
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<bool, DofiError> {
+    for path in affected {
+        writeln!(output, "  {}", path.display())?;
+    }
+    write!(output, "{question} [y/N] ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
@@ -0,0 +1,109 @@
+//! Per path handling of targets that already exist when linking.
+//!
+//! The user configuration maps glob patterns on target paths to policies:
+//!
+//! ```toml
+//! [conflicts]
+//! "~/.ssh/**" = "never-force"
+//! "~/.config/Code/**" = "copy-backup"
+//! ```
+//!
+//! Relative patterns and those starting with `~` are relative to the base directory, which is
+//! the home directory unless `-b` says otherwise. If several patterns match a target the
+//! longest one wins. `link --force-path` adds patterns with the `force` policy for a single
+//! run, so `dofi link --force-path '~/.zshrc'` replaces that target and nothing else.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use globset::{Glob, GlobMatcher};
+use serde::Deserialize;
+
+use crate::DofiError;
+
+/// What to do when a dotfile's target already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Replace the existing target, as with `link --force-all`
+    Force,
+    /// Never replace the existing target, even with `link --force-all`
+    NeverForce,
+    /// Replace the existing target, keeping a copy of it next to the target
+    CopyBackup,
+}
+
+/// The suffix of the copies kept by [`ConflictPolicy::CopyBackup`]
+pub const BACKUP_SUFFIX: &str = "dofi-backup";
+
+/// The configured conflict policies, matched against target paths
+#[derive(Debug, Default)]
+pub struct ConflictPolicies {
+    rules: Vec<(String, GlobMatcher, ConflictPolicy)>,
+}
+
+impl ConflictPolicies {
+    pub fn new(
+        patterns: &BTreeMap<String, ConflictPolicy>,
+        base_directory: &Path,
+    ) -> Result<Self, DofiError> {
+        let rules = patterns
+            .iter()
+            .map(|(pattern, policy)| {
+                rule(pattern, *policy, base_directory, DofiError::InvalidPattern)
+            })
+            .collect::<Result<_, DofiError>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Adds `patterns` with the [`ConflictPolicy::Force`] policy, for `link --force-path`
+    pub fn force(&mut self, patterns: &[String], base_directory: &Path) -> Result<(), DofiError> {
+        for pattern in patterns {
+            self.rules.push(rule(
+                pattern,
+                ConflictPolicy::Force,
+                base_directory,
+                DofiError::InvalidGlob,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// The policy of the longest pattern matching `target`, if any
+    pub fn policy(&self, target: &Path) -> Option<ConflictPolicy> {
+        self.rules
+            .iter()
+            .filter(|(_, matcher, _)| matcher.is_match(target))
+            .max_by_key(|(pattern, _, _)| pattern.len())
+            .map(|(_, _, policy)| *policy)
+    }
+}
+
+/// `pattern` matched against absolute target paths, with its `policy`. An invalid `pattern` fails
+/// with `error`, which tells the configured patterns from the ones given on the command line.
+fn rule(
+    pattern: &str,
+    policy: ConflictPolicy,
+    base_directory: &Path,
+    error: fn(String, String) -> DofiError,
+) -> Result<(String, GlobMatcher, ConflictPolicy), DofiError> {
+    let pattern_path = Path::new(pattern);
+    let absolute = match pattern_path.strip_prefix("~") {
+        Ok(rest) => base_directory.join(rest),
+        Err(_) => base_directory.join(pattern_path),
+    };
+    let glob = Glob::new(&absolute.to_string_lossy())
+        .map_err(|e| error(pattern.to_string(), e.to_string()))?;
+    Ok((pattern.to_string(), glob.compile_matcher(), policy))
+}
+
+/// Where [`ConflictPolicy::CopyBackup`] keeps the copy of `target`
+pub fn backup_path(target: &Path) -> PathBuf {
+    let mut name = target.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(BACKUP_SUFFIX);
+    target.with_file_name(name)
+}
@@ -0,0 +1,498 @@
+//! Differences between dotfiles and what currently exists at their targets.
+//!
+//! The lines are compared with Myers' algorithm, without any external `diff`, and printed as
+//! a unified diff with three lines of context. In color, the words that changed between a
+//! removed line and the added line replacing it are highlighted as well. Files that are not
+//! UTF-8 are only reported as differing.
+
+use std::{ops::Range, path::Path};
+
+use crate::{
+    color::{self, Color},
+    DofiError, Fs,
+};
+
+/// The lines of unchanged context around each hunk
+const CONTEXT: usize = 3;
+
+/// The number of differences beyond which two sequences count as entirely different, which
+/// keeps the memory the comparison needs bounded for huge, unrelated files
+const MAX_DIFFERENCES: usize = 1000;
+
+/// The widest `+`/`-` bar of a [`stat`] line
+const STAT_WIDTH: usize = 40;
+
+/// How a sequence turns into another, element by element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    /// The element at the first index of the old sequence is kept, at the second of the new
+    Equal(usize, usize),
+    /// The element at this index of the old sequence is removed
+    Delete(usize),
+    /// The element at this index of the new sequence is added
+    Insert(usize),
+}
+
+/// The lines added and removed between two files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Either file is not UTF-8, its lines are not counted
+    pub binary: bool,
+}
+
+/// Prints a unified diff from the dotfile `source` to `target`, labelling the source side
+/// with `label`. Returns whether the files differ.
+pub fn print_diff(
+    fs: &dyn Fs,
+    source: &Path,
+    target: &Path,
+    label: &Path,
+    color: bool,
+) -> Result<bool, DofiError> {
+    print_contents_diff(fs, &fs.read(source)?, target, label, color)
+}
+
+/// Prints a unified diff from `contents`, labelled `label`, to `target`. Returns whether they
+/// differ.
+pub fn print_contents_diff(
+    fs: &dyn Fs,
+    contents: &[u8],
+    target: &Path,
+    label: &Path,
+    color: bool,
+) -> Result<bool, DofiError> {
+    let diff = unified(contents, &fs.read(target)?, label, target, color);
+    print!("{diff}");
+    Ok(!diff.is_empty())
+}
+
+/// The unified diff from `old`, labelled `old_label`, to `new`, labelled `new_label`, colored
+/// if `color` is set. Empty if they are the same.
+pub fn unified(old: &[u8], new: &[u8], old_label: &Path, new_label: &Path, color: bool) -> String {
+    if old == new {
+        return String::new();
+    }
+    let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(new)) else {
+        return format!(
+            "Binary files {} and {} differ\n",
+            old_label.display(),
+            new_label.display()
+        );
+    };
+    let old = old.split_inclusive('\n').collect::<Vec<_>>();
+    let new = new.split_inclusive('\n').collect::<Vec<_>>();
+    let edits = edits(&old, &new);
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_label.display(), new_label.display());
+    for hunk in hunks(&edits) {
+        let (before, hunk) = (&edits[..hunk.start], &edits[hunk]);
+        let (old_start, old_count) = range(hunk.iter().filter_map(|edit| match edit {
+            Edit::Equal(i, _) | Edit::Delete(i) => Some(*i),
+            Edit::Insert(_) => None,
+        }));
+        let (new_start, new_count) = range(hunk.iter().filter_map(|edit| match edit {
+            Edit::Equal(_, j) | Edit::Insert(j) => Some(*j),
+            Edit::Delete(_) => None,
+        }));
+        // An empty side starts at the line before the hunk
+        let old_start = old_start.unwrap_or_else(|| preceding(before, true));
+        let new_start = new_start.unwrap_or_else(|| preceding(before, false));
+        let header = format!(
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_count),
+            hunk_range(new_start, new_count)
+        );
+        diff.push_str(&color::paint(&header, Color::Cyan, color));
+        diff.push('\n');
+
+        let mut index = 0;
+        while index < hunk.len() {
+            if let Edit::Equal(i, _) = hunk[index] {
+                push_line(&mut diff, ' ', old[i], None);
+                index += 1;
+                continue;
+            }
+            // A block of removed lines followed by the added lines replacing them
+            let deleted = hunk[index..]
+                .iter()
+                .take_while(|edit| matches!(edit, Edit::Delete(_)))
+                .map(|edit| match edit {
+                    Edit::Delete(i) => old[*i],
+                    _ => unreachable!("only deletions are taken"),
+                })
+                .collect::<Vec<_>>();
+            index += deleted.len();
+            let inserted = hunk[index..]
+                .iter()
+                .take_while(|edit| matches!(edit, Edit::Insert(_)))
+                .map(|edit| match edit {
+                    Edit::Insert(j) => new[*j],
+                    _ => unreachable!("only insertions are taken"),
+                })
+                .collect::<Vec<_>>();
+            index += inserted.len();
+
+            for (n, line) in deleted.iter().enumerate() {
+                let paired = inserted.get(n).filter(|_| color);
+                let painted = paired.map(|other| highlight(line, other, Color::Red, true));
+                push_painted(&mut diff, '-', line, painted, Color::Red, color);
+            }
+            for (n, line) in inserted.iter().enumerate() {
+                let paired = deleted.get(n).filter(|_| color);
+                let painted = paired.map(|other| highlight(line, other, Color::Green, false));
+                push_painted(&mut diff, '+', line, painted, Color::Green, color);
+            }
+        }
+    }
+    diff
+}
+
+/// The lines added to and removed from `old` to get `new`
+pub fn stat(old: &[u8], new: &[u8]) -> Stat {
+    let (Ok(old), Ok(new)) = (std::str::from_utf8(old), std::str::from_utf8(new)) else {
+        return Stat {
+            binary: old != new,
+            ..Stat::default()
+        };
+    };
+    let old = old.split_inclusive('\n').collect::<Vec<_>>();
+    let new = new.split_inclusive('\n').collect::<Vec<_>>();
+
+    let mut stat = Stat::default();
+    for edit in edits(&old, &new) {
+        match edit {
+            Edit::Insert(_) => stat.insertions += 1,
+            Edit::Delete(_) => stat.deletions += 1,
+            Edit::Equal(..) => {}
+        }
+    }
+    stat
+}
+
+/// Renders the `stats` of the labelled files like `git diff --stat`, a line per file with a
+/// bar of `+` and `-` and a summary line at the end
+pub fn format_stats(stats: &[(String, Stat)], color: bool) -> String {
+    let width = stats
+        .iter()
+        .map(|(label, _)| label.len())
+        .max()
+        .unwrap_or(0);
+    let largest = stats
+        .iter()
+        .map(|(_, stat)| stat.insertions + stat.deletions)
+        .max()
+        .unwrap_or(0);
+    let digits = largest.to_string().len();
+
+    let mut output = String::new();
+    for (label, stat) in stats {
+        if stat.binary {
+            output.push_str(&format!(" {label:width$} | Bin\n"));
+            continue;
+        }
+        let changed = stat.insertions + stat.deletions;
+        // Scaled down to the widest bar, keeping at least one sign for any change
+        let scale = |count: usize| {
+            if largest <= STAT_WIDTH || count == 0 {
+                count
+            } else {
+                (count * STAT_WIDTH / largest).max(1)
+            }
+        };
+        output.push_str(&format!(
+            " {label:width$} | {changed:>digits$} {}{}\n",
+            color::paint(&"+".repeat(scale(stat.insertions)), Color::Green, color),
+            color::paint(&"-".repeat(scale(stat.deletions)), Color::Red, color),
+        ));
+    }
+
+    let plural = |count: usize, singular: &str, plural: &str| match count {
+        1 => format!("{count} {singular}"),
+        _ => format!("{count} {plural}"),
+    };
+    let insertions = stats.iter().map(|(_, stat)| stat.insertions).sum();
+    let deletions = stats.iter().map(|(_, stat)| stat.deletions).sum();
+    output.push_str(&format!(
+        " {}, {}, {}\n",
+        plural(stats.len(), "file changed", "files changed"),
+        plural(insertions, "insertion(+)", "insertions(+)"),
+        plural(deletions, "deletion(-)", "deletions(-)")
+    ));
+    output
+}
+
+/// The elements `old` and `new` have in common, in order, along the [shortest edit
+/// script](edits) between them
+pub(crate) fn common<T: PartialEq + Clone>(old: &[T], new: &[T]) -> Vec<T> {
+    edits(old, new)
+        .into_iter()
+        .filter_map(|edit| match edit {
+            Edit::Equal(i, _) => Some(old[i].clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The shortest edit script from `old` to `new` after Myers, "An O(ND) Difference Algorithm
+/// and Its Variations"
+fn edits<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut edits = (0..prefix).map(|i| Edit::Equal(i, i)).collect::<Vec<_>>();
+    match middle(a, b) {
+        Some(middle) => edits.extend(middle.into_iter().map(|edit| match edit {
+            Edit::Equal(i, j) => Edit::Equal(prefix + i, prefix + j),
+            Edit::Delete(i) => Edit::Delete(prefix + i),
+            Edit::Insert(j) => Edit::Insert(prefix + j),
+        })),
+        None => {
+            edits.extend((0..a.len()).map(|i| Edit::Delete(prefix + i)));
+            edits.extend((0..b.len()).map(|j| Edit::Insert(prefix + j)));
+        }
+    }
+    edits.extend((0..suffix).map(|n| Edit::Equal(old.len() - suffix + n, new.len() - suffix + n)));
+    edits
+}
+
+/// The edit script from `a` to `b`, `None` if they differ in more than [`MAX_DIFFERENCES`]
+fn middle<T: PartialEq>(a: &[T], b: &[T]) -> Option<Vec<Edit>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (a.len() + b.len()).min(MAX_DIFFERENCES) as isize;
+    let offset = max + 1;
+    // The furthest x reached on each diagonal k = x - y, at index k + offset
+    let mut furthest = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+
+    let mut found = None;
+    'search: for d in 0..=max {
+        trace.push(furthest.clone());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && furthest[index - 1] < furthest[index + 1]) {
+                furthest[index + 1]
+            } else {
+                furthest[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            furthest[index] = x;
+            if x >= n && y >= m {
+                found = Some(d);
+                break 'search;
+            }
+        }
+    }
+    found?;
+
+    // Walks the trace back from the end, every step of d being one insertion or deletion
+    // after a run of equal elements
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, furthest) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let index = (k + offset) as usize;
+        let previous_k = if k == -d || (k != d && furthest[index - 1] < furthest[index + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = furthest[(previous_k + offset) as usize];
+        let previous_y = previous_x - previous_k;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == previous_x {
+                edits.push(Edit::Insert(previous_y as usize));
+            } else {
+                edits.push(Edit::Delete(previous_x as usize));
+            }
+        }
+        (x, y) = (previous_x, previous_y);
+    }
+    edits.reverse();
+    Some(edits)
+}
+
+/// Splits `edits` into hunks of changes with up to [`CONTEXT`] equal lines around them,
+/// merging changes closer than twice that
+fn hunks(edits: &[Edit]) -> Vec<Range<usize>> {
+    let changes = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Equal(..)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for index in changes {
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + CONTEXT + 1).min(edits.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks.into_iter().map(|(start, end)| start..end).collect()
+}
+
+/// The first of `indices` and how many there are
+fn range(mut indices: impl Iterator<Item = usize>) -> (Option<usize>, usize) {
+    let first = indices.next();
+    (first, first.map_or(0, |_| 1 + indices.count()))
+}
+
+/// The number of lines on the old or new side of the edits `before` a hunk
+fn preceding(before: &[Edit], old: bool) -> usize {
+    before
+        .iter()
+        .filter(|edit| match edit {
+            Edit::Equal(..) => true,
+            Edit::Delete(_) => old,
+            Edit::Insert(_) => !old,
+        })
+        .count()
+}
+
+/// A side of a hunk header, 1-based and without the count if it is 1
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        count => format!("{},{count}", start + 1),
+    }
+}
+
+/// Appends `line` with `prefix`, noting a missing newline at the end of the file
+fn push_line(diff: &mut String, prefix: char, line: &str, painted: Option<String>) {
+    let text = line.strip_suffix('\n').unwrap_or(line);
+    match painted {
+        Some(painted) => diff.push_str(&painted),
+        None => {
+            diff.push(prefix);
+            diff.push_str(text);
+        }
+    }
+    diff.push('\n');
+    if !line.ends_with('\n') {
+        diff.push_str("\\ No newline at end of file\n");
+    }
+}
+
+/// Appends the removed or added `line`, word highlighted if `highlighted`, painted otherwise
+fn push_painted(
+    diff: &mut String,
+    prefix: char,
+    line: &str,
+    highlighted: Option<String>,
+    line_color: Color,
+    color: bool,
+) {
+    let text = line.strip_suffix('\n').unwrap_or(line);
+    let painted = match highlighted {
+        Some(highlighted) => {
+            format!(
+                "{}{highlighted}",
+                color::paint(&prefix.to_string(), line_color, color)
+            )
+        }
+        None => color::paint(&format!("{prefix}{text}"), line_color, color),
+    };
+    push_line(diff, prefix, line, Some(painted));
+}
+
+/// `line` painted in `line_color` with the words it does not share with `other` emphasized,
+/// `removed` telling whether `line` is the old side. Without shared words the whole line
+/// changed and nothing is emphasized.
+fn highlight(line: &str, other: &str, line_color: Color, removed: bool) -> String {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let other = other.strip_suffix('\n').unwrap_or(other);
+    let (words, other_words) = (words(line), words(other));
+    let edits = if removed {
+        edits(&words, &other_words)
+    } else {
+        edits(&other_words, &words)
+    };
+
+    let shared = edits.iter().any(|edit| match edit {
+        Edit::Equal(i, _) if removed => !words[*i].trim().is_empty(),
+        Edit::Equal(_, j) => !words[*j].trim().is_empty(),
+        _ => false,
+    });
+    if !shared {
+        return color::paint(line, line_color, true);
+    }
+
+    // Runs of equally emphasized words, painted together
+    let mut runs: Vec<(bool, String)> = Vec::new();
+    for (word, emphasized) in edits.iter().filter_map(|edit| match (edit, removed) {
+        (Edit::Equal(i, _), true) | (Edit::Delete(i), true) => {
+            Some((words[*i], matches!(edit, Edit::Delete(_))))
+        }
+        (Edit::Equal(_, j), false) | (Edit::Insert(j), false) => {
+            Some((words[*j], matches!(edit, Edit::Insert(_))))
+        }
+        _ => None,
+    }) {
+        match runs.last_mut() {
+            Some((last, run)) if *last == emphasized => run.push_str(word),
+            _ => runs.push((emphasized, word.to_string())),
+        }
+    }
+    runs.into_iter()
+        .map(|(emphasized, run)| {
+            if emphasized {
+                color::emphasize(&run, line_color, true)
+            } else {
+                color::paint(&run, line_color, true)
+            }
+        })
+        .collect()
+}
+
+/// Splits `line` into words, runs of whitespace and single other characters
+fn words(line: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut previous = None;
+    for (index, c) in line.char_indices() {
+        let current = class(c);
+        if previous.is_some_and(|previous| previous != current || current == 2) {
+            words.push(&line[start..index]);
+            start = index;
+        }
+        previous = Some(current);
+    }
+    if start < line.len() {
+        words.push(&line[start..]);
+    }
+    words
+}
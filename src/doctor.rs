@@ -0,0 +1,455 @@
+//! Health checks for the dotfiles and their link targets.
+//!
+//! `doctor --fix` repairs the symlinks it can without risking anyone's data: it recreates the
+//! symlinks dofi created that went missing, together with their parent directories, points
+//! symlinks into a repo that moved to the dotfiles again and removes symlinks dofi created to
+//! dotfiles that no longer exist. It also applies the configured modes to the directories dofi
+//! created, see [`permissions`]. Everything else is only reported.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    journal, layered_dotfiles, link_file, link_original, mode_violation, paths,
+    permissions::{self, DirectoryModes},
+    DofiError, Dotfile, Fs, Journal, Strategy,
+};
+
+/// A problem found for a single managed target
+#[derive(Debug)]
+pub struct Finding {
+    pub target: PathBuf,
+    pub problem: Problem,
+}
+
+#[derive(Debug)]
+pub enum Problem {
+    /// The target lives on a filesystem mounted with `nosymfollow`, so the symlink is never followed
+    NoSymfollow { mount_point: PathBuf },
+    /// The dotfile is executable but its target lives on a filesystem mounted with `noexec`
+    NoExec { mount_point: PathBuf },
+    /// The target is inside a sandbox that cannot see the dotfiles directory
+    Sandboxed { sandbox: String },
+    /// The permission bits differ from the mode the manifest requires
+    WrongMode { expected: u32, actual: u32 },
+    /// A directory dofi created has other permission bits than the configuration requires
+    WrongDirectoryMode { expected: u32, actual: u32 },
+    /// The symlink dofi created to the dotfile `source` is gone
+    Missing { source: PathBuf },
+    /// The symlink points to `original`, where the dotfile `source` was before the repo moved
+    StaleLink { original: PathBuf, source: PathBuf },
+    /// A symlink dofi created points to `original`, a dotfile that no longer exists
+    Dangling { original: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::NoSymfollow { mount_point } => write!(
+                f,
+                "filesystem at '{}' is mounted nosymfollow, the symlink will not be followed",
+                mount_point.display()
+            ),
+            Problem::NoExec { mount_point } => write!(
+                f,
+                "filesystem at '{}' is mounted noexec, the executable cannot be run",
+                mount_point.display()
+            ),
+            Problem::Sandboxed { sandbox } => write!(
+                f,
+                "{sandbox} cannot read through the symlink into the dotfiles directory"
+            ),
+            Problem::WrongMode { expected, actual } => {
+                write!(
+                    f,
+                    "mode is {actual:o} but the manifest requires {expected:o}"
+                )
+            }
+            Problem::WrongDirectoryMode { expected, actual } => write!(
+                f,
+                "directory mode is {actual:o} but the configuration requires {expected:o}"
+            ),
+            Problem::Missing { source } => write!(
+                f,
+                "the symlink to '{}' dofi created is missing",
+                source.display()
+            ),
+            Problem::StaleLink { original, .. } => write!(
+                f,
+                "points to '{}', where the dotfile was before the repo moved",
+                original.display()
+            ),
+            Problem::Dangling { original } => write!(
+                f,
+                "points to '{}', a dotfile that no longer exists",
+                original.display()
+            ),
+        }
+    }
+}
+
+impl Problem {
+    /// A suggestion for how to resolve the problem
+    pub fn help(&self) -> &'static str {
+        match self {
+            Problem::NoSymfollow { .. } | Problem::Sandboxed { .. } => {
+                "copy the file to the target instead of symlinking it"
+            }
+            Problem::NoExec { .. } => "run the file through an interpreter or remount with exec",
+            Problem::WrongMode { .. } => "run `dofi link` to apply the mode",
+            Problem::WrongDirectoryMode { .. } => "run `dofi doctor --fix` to apply the mode",
+            Problem::Missing { .. } => "run `dofi doctor --fix` to recreate it",
+            Problem::StaleLink { .. } => "run `dofi doctor --fix` to point it at the dotfile",
+            Problem::Dangling { .. } => "run `dofi doctor --fix` to remove it",
+        }
+    }
+
+    /// Whether [`fix`] repairs the problem
+    pub fn is_fixable(&self) -> bool {
+        matches!(
+            self,
+            Problem::WrongDirectoryMode { .. }
+                | Problem::Missing { .. }
+                | Problem::StaleLink { .. }
+                | Problem::Dangling { .. }
+        )
+    }
+}
+
+/// Repairs the problem of `finding`, recording it in `journal`. Returns whether it was
+/// [fixable](Problem::is_fixable), other problems are left alone.
+pub fn fix(fs: &dyn Fs, finding: &Finding, journal: &mut Journal) -> Result<bool, DofiError> {
+    match &finding.problem {
+        Problem::Missing { source } => link_file(fs, source, &finding.target, false, journal)?,
+        Problem::StaleLink { source, .. } => link_file(fs, source, &finding.target, true, journal)?,
+        Problem::Dangling { .. } => journal.remove_file(fs, &finding.target)?,
+        Problem::WrongDirectoryMode { expected, .. } => {
+            journal.set_mode(fs, &finding.target, *expected)?
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+struct Mount {
+    mount_point: PathBuf,
+    options: Vec<String>,
+}
+
+/// Checks every dotfile in `dotfiles_directory` for targets in `base_directory` that
+/// will not work as symlinks or whose symlinks broke, the symlinks dofi created according
+/// to the journal in `state_directory` for ones left behind and the directories it created
+/// for ones without their `directory_modes`
+pub fn diagnose(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    state_directory: &Path,
+    directory_modes: &DirectoryModes,
+) -> Result<Vec<Finding>, DofiError> {
+    let mounts = read_mounts(fs);
+    let sandboxes = read_firejail_profiles(fs, base_directory)?;
+    let created = journal::created_symlinks(fs, state_directory)?;
+    let mut findings = Vec::new();
+    let mut targets = BTreeSet::new();
+
+    for Dotfile {
+        source: file,
+        target,
+        mode,
+        strategy,
+        ..
+    } in layered_dotfiles(fs, base_directory, &[dotfiles_directory.to_path_buf()])?
+    {
+        targets.insert(target.clone());
+        if strategy == Strategy::Symlink {
+            if let Some(problem) = broken_link(fs, &file, &target, dotfiles_directory, &created) {
+                findings.push(Finding {
+                    target: target.clone(),
+                    problem,
+                });
+            }
+        }
+
+        if let Some(actual) = mode_violation(fs, &file, &target, strategy, mode) {
+            findings.push(Finding {
+                target: target.clone(),
+                problem: Problem::WrongMode {
+                    expected: mode.unwrap_or_default(),
+                    actual,
+                },
+            });
+        }
+
+        if let Some(mount) = mount_for(&mounts, &target) {
+            if mount.options.iter().any(|option| option == "nosymfollow") {
+                findings.push(Finding {
+                    target: target.clone(),
+                    problem: Problem::NoSymfollow {
+                        mount_point: mount.mount_point.clone(),
+                    },
+                });
+            }
+
+            let executable = fs
+                .symlink_metadata(&file)
+                .is_ok_and(|metadata| metadata.mode & 0o111 != 0);
+            if executable && mount.options.iter().any(|option| option == "noexec") {
+                findings.push(Finding {
+                    target: target.clone(),
+                    problem: Problem::NoExec {
+                        mount_point: mount.mount_point.clone(),
+                    },
+                });
+            }
+        }
+
+        if let Some(sandbox) = sandbox_for(&sandboxes, &target, base_directory, dotfiles_directory)
+        {
+            findings.push(Finding {
+                target,
+                problem: Problem::Sandboxed { sandbox },
+            });
+        }
+    }
+
+    let mut dangling = BTreeSet::new();
+    for (link, original) in created {
+        let resolved = link.parent().unwrap_or(&link).join(&original);
+        if !targets.contains(&link)
+            && !fs.exists(&resolved)
+            && fs.read_link(&link).is_ok_and(|current| current == original)
+            && dangling.insert(link.clone())
+        {
+            findings.push(Finding {
+                target: link,
+                problem: Problem::Dangling { original },
+            });
+        }
+    }
+
+    for (directory, expected, actual) in
+        permissions::violations(fs, directory_modes, state_directory)?
+    {
+        findings.push(Finding {
+            target: directory,
+            problem: Problem::WrongDirectoryMode { expected, actual },
+        });
+    }
+
+    Ok(findings)
+}
+
+/// The problem with the symlink at `target` to the dotfile `source`, if it was created but is
+/// gone now or points to where the dotfile was in an older location of the repo
+fn broken_link(
+    fs: &dyn Fs,
+    source: &Path,
+    target: &Path,
+    dotfiles_directory: &Path,
+    created: &BTreeSet<(PathBuf, PathBuf)>,
+) -> Option<Problem> {
+    let Ok(original) = fs.read_link(target) else {
+        let was_created = created.iter().any(|(link, _)| link == target);
+        return (was_created && !fs.exists(target)).then(|| Problem::Missing {
+            source: source.to_path_buf(),
+        });
+    };
+
+    let relative_source = source.strip_prefix(dotfiles_directory).ok()?;
+    let resolved = target.parent().unwrap_or(target).join(&original);
+    let moved = original != link_original(fs, source)
+        && original.ends_with(relative_source)
+        && !fs.exists(&resolved);
+    moved.then(|| Problem::StaleLink {
+        original,
+        source: source.to_path_buf(),
+    })
+}
+
+/// Reads the mount table from `/proc`, or from the output of `mount` on the BSDs and macOS,
+/// which have no `/proc`. Returns nothing if neither is available.
+fn read_mounts(fs: &dyn Fs) -> Vec<Mount> {
+    let Ok(contents) = fs.read(Path::new("/proc/self/mounts")) else {
+        return if cfg!(all(unix, not(target_os = "linux"))) {
+            run_mount()
+        } else {
+            Vec::new()
+        };
+    };
+
+    contents
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| {
+            let mut fields = line
+                .split(u8::is_ascii_whitespace)
+                .filter(|field| !field.is_empty());
+            let mount_point = unescape_mount_field(fields.nth(1)?);
+            let options = String::from_utf8_lossy(fields.nth(1)?)
+                .split(',')
+                .map(str::to_string)
+                .collect();
+            Some(Mount {
+                mount_point,
+                options,
+            })
+        })
+        .collect()
+}
+
+/// Parses the lines `mount` prints, `<device> on <mount point> (<type>, <options>)`, with
+/// `type <type>` before the options on OpenBSD and NetBSD
+fn run_mount() -> Vec<Mount> {
+    let Ok(output) = std::process::Command::new("mount").output() else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once(" on ")?;
+            let (mount_point, options) = rest.rsplit_once(" (")?;
+            let mount_point = match mount_point.rsplit_once(" type ") {
+                Some((mount_point, _)) => mount_point,
+                None => mount_point,
+            };
+            Some(Mount {
+                mount_point: PathBuf::from(mount_point),
+                options: options
+                    .trim_end_matches(')')
+                    .split(", ")
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Decodes the octal escapes (e.g. `\040` for space) used in `/proc/self/mounts`. The escaped
+/// bytes are collected with the others and decoded together, so those of a multibyte
+/// character, or of a name that is not UTF-8, become the path they were.
+fn unescape_mount_field(field: &[u8]) -> PathBuf {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = after
+            .get(..3)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match escaped {
+            Some(escaped) if byte == b'\\' => {
+                bytes.push(escaped);
+                rest = &after[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    paths::from_bytes(bytes)
+}
+
+fn mount_for<'a>(mounts: &'a [Mount], path: &Path) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+}
+
+/// The home directory paths a Firejail profile makes visible, anything else is hidden
+struct FirejailProfile {
+    name: String,
+    whitelist: Vec<PathBuf>,
+}
+
+fn read_firejail_profiles(
+    fs: &dyn Fs,
+    base_directory: &Path,
+) -> Result<Vec<FirejailProfile>, DofiError> {
+    let mut profiles = Vec::new();
+
+    for directory in [
+        PathBuf::from("/etc/firejail"),
+        base_directory.join(".config/firejail"),
+    ] {
+        if !fs.exists(&directory) {
+            continue;
+        }
+
+        for file in fs.walk(&directory)? {
+            if file
+                .extension()
+                .is_none_or(|extension| extension != "profile")
+            {
+                continue;
+            }
+            let Ok(contents) = fs.read(&file) else {
+                continue;
+            };
+
+            let whitelist = String::from_utf8_lossy(&contents)
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix("whitelist "))
+                .map(|path| expand_home(path.trim(), base_directory))
+                .filter(|path| path.starts_with(base_directory))
+                .collect::<Vec<_>>();
+
+            if !whitelist.is_empty() {
+                profiles.push(FirejailProfile {
+                    name: file
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    whitelist,
+                });
+            }
+        }
+    }
+
+    Ok(profiles)
+}
+
+fn expand_home(path: &str, base_directory: &Path) -> PathBuf {
+    match path
+        .strip_prefix("${HOME}")
+        .or_else(|| path.strip_prefix('~'))
+    {
+        Some(rest) => base_directory.join(rest.trim_start_matches('/')),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Finds a sandbox that can see `target` but not the dotfiles directory it links into
+fn sandbox_for(
+    profiles: &[FirejailProfile],
+    target: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+) -> Option<String> {
+    // Flatpak and Snap applications run under bubblewrap/AppArmor confinement with a private
+    // view of their own data directory, which never includes the dotfiles directory.
+    for (directory, sandbox) in [(".var/app", "Flatpak"), ("snap", "Snap")] {
+        let directory = base_directory.join(directory);
+        if target.starts_with(&directory) && !dotfiles_directory.starts_with(&directory) {
+            return Some(format!("{sandbox} sandbox"));
+        }
+    }
+
+    profiles
+        .iter()
+        .find(|profile| {
+            profile
+                .whitelist
+                .iter()
+                .any(|path| target.starts_with(path))
+                && !profile
+                    .whitelist
+                    .iter()
+                    .any(|path| dotfiles_directory.starts_with(path))
+        })
+        .map(|profile| format!("Firejail profile '{}'", profile.name))
+}
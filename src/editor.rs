@@ -0,0 +1,32 @@
+//! Opening dotfiles in the user's editor.
+
+use std::{path::Path, process::Command};
+
+use crate::DofiError;
+
+/// Opens `file` in `$VISUAL` or `$EDITOR` and waits for the editor to exit. The variables may
+/// contain arguments, e.g. `code --wait`. Falls back to `vi`, or `notepad` on Windows.
+pub fn edit(file: &Path) -> Result<(), DofiError> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|variable| std::env::var(variable).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+
+    let mut words = editor.split_whitespace();
+    let program = words.next().expect("editor is not empty");
+    let status = Command::new(program)
+        .args(words)
+        .arg(file)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(editor.clone(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            editor,
+            format!("exited with {status}"),
+        ))
+    }
+}
@@ -0,0 +1,143 @@
+//! Linking system files, targets outside the base directory that usually belong to root.
+//!
+//! A manifest target given as an absolute path, `"etc/hosts" = "/etc/hosts"`, is linked with
+//! the filesystem operations run under `sudo`. Without elevation `link` fails with the exact
+//! commands to run instead.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::info;
+
+use crate::{conflict, journal::Action, DofiError, Fs, Journal};
+
+/// A single command run as root, program first
+pub type Elevated = Vec<OsString>;
+
+/// Whether `target` lies outside `base_directory` and needs elevation to be linked
+pub fn is_privileged(target: &Path, base_directory: &Path) -> bool {
+    !target.starts_with(base_directory)
+}
+
+/// The commands symlinking `source` at the system `target`, none if it is already linked. An
+/// existing file at `target` is moved aside if `force` is set.
+pub fn plan(
+    fs: &dyn Fs,
+    source: &Path,
+    target: &Path,
+    force: bool,
+) -> Result<Vec<Elevated>, DofiError> {
+    if fs
+        .read_link(target)
+        .is_ok_and(|original| original == source)
+    {
+        return Ok(Vec::new());
+    }
+
+    let mut commands = Vec::new();
+    match target.parent() {
+        Some(parent) if !fs.exists(parent) => {
+            commands.push(elevated("mkdir", &["-p".as_ref(), parent.as_os_str()]));
+        }
+        _ => {}
+    }
+    if fs.symlink_metadata(target).is_ok() {
+        if !force {
+            return Err(DofiError::FileExists(target.to_path_buf()));
+        }
+        let backup = conflict::backup_path(target);
+        commands.push(elevated("mv", &[target.as_os_str(), backup.as_os_str()]));
+    }
+    commands.push(elevated(
+        "ln",
+        &["-s".as_ref(), source.as_os_str(), target.as_os_str()],
+    ));
+
+    Ok(commands)
+}
+
+/// Runs the `commands` planned for linking `source` at `target` with `sudo` and records the
+/// symlink
+pub fn link(
+    source: &Path,
+    target: &Path,
+    commands: &[Elevated],
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    info!(
+        "Symlinking '{}' at '{}' as root",
+        source.display(),
+        target.display()
+    );
+    run(commands)?;
+
+    let backup = conflict::backup_path(target);
+    journal.record(Action::ElevatedSymlinked {
+        link: target.to_path_buf(),
+        target: source.to_path_buf(),
+        backup: commands
+            .iter()
+            .any(|command| command[0] == "mv")
+            .then_some(backup),
+    });
+
+    Ok(())
+}
+
+/// Reverts [`link`], removing the symlink at `link` and moving any replaced file back
+pub fn unlink(link: &Path, backup: Option<&PathBuf>) -> Result<(), DofiError> {
+    let mut commands = vec![elevated("rm", &[link.as_os_str()])];
+    if let Some(backup) = backup {
+        commands.push(elevated("mv", &[backup.as_os_str(), link.as_os_str()]));
+    }
+    run(&commands)
+}
+
+/// Runs each of `commands` with `sudo`, stopping at the first that fails
+pub fn run(commands: &[Elevated]) -> Result<(), DofiError> {
+    for command in commands {
+        let command_line = command_line(command);
+        info!("Running 'sudo {command_line}'");
+        let status = Command::new("sudo")
+            .args(command)
+            .status()
+            .map_err(|e| DofiError::ExternalCommandFailed("sudo".to_string(), e.to_string()))?;
+        if !status.success() {
+            return Err(DofiError::ExternalCommandFailed(
+                format!("sudo {command_line}"),
+                format!("exited with {status}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders `command` as it would be typed into a shell
+pub fn command_line(command: &Elevated) -> String {
+    command
+        .iter()
+        .map(|word| {
+            let word = word.to_string_lossy();
+            let plain = !word.is_empty()
+                && word
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=+,:@".contains(c));
+            if plain {
+                word.into_owned()
+            } else {
+                format!("'{}'", word.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn elevated(program: &str, args: &[&OsStr]) -> Elevated {
+    std::iter::once(OsString::from(program))
+        .chain(args.iter().map(|arg| arg.to_os_string()))
+        .collect()
+}
@@ -0,0 +1,504 @@
+//! Dotfiles stored encrypted in the dotfiles directory.
+//!
+//! An encrypted dotfile carries the extension of its backend in the repo, `.age` for age and
+//! `.gpg` for GPG: `<dotfiles>/.netrc.age` targets `<base>/.netrc`. Instead of a symlink,
+//! linking writes the decrypted contents to the target. The keys are set in the user
+//! configuration, `backend` selects what `add --encrypt` uses:
+//!
+//! ```toml
+//! [encryption]
+//! backend = "age"
+//! identity = "~/.config/age/key.txt"
+//! recipients = ["age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p"]
+//!
+//! [encryption.gpg]
+//! recipients = ["me@example.com"]
+//! ```
+//!
+//! Without recipients age encrypts to the identity itself and GPG to the default key.
+//!
+//! Dotfiles carrying a tag with [recipients](crate::manifest) in the manifest are encrypted to
+//! those instead, e.g. the keys of the work machines. An age identity can be a key pair of the
+//! machine or an SSH key, whose public key is read from the `.pub` file next to it. `link`
+//! skips the dotfiles encrypted to none of the identity's keys, GPG tries to decrypt them.
+//!
+//! An identity protected with a passphrase, `age -p` encrypted, asks for it whenever a dotfile
+//! is decrypted. With `keyring = true` `dofi key unlock` asks once and leaves the identity in
+//! the OS [keyring](crate::keyring) instead, where decrypting finds it until `dofi key lock`
+//! takes it out again. GPG passphrases are cached by `gpg-agent` already.
+//!
+//! `dofi key import` goes further and moves the identity into the keyring, leaving only its
+//! public key next to where the identity file was. Without the file the identity is always
+//! looked up in the keyring, and `dofi key export` writes the file again.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Arc, OnceLock},
+};
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::{config::expand_path, fs::ScratchDirectory, keyring, DofiError, Fs, Manifest};
+
+/// The extensions of encrypted dotfiles, one per backend
+pub const EXTENSIONS: [&str; 2] = [Age::EXTENSION, Gpg::EXTENSION];
+
+/// Encrypts and decrypts the contents of dotfiles
+pub trait Encryption {
+    /// The extension of dotfiles encrypted by this backend
+    fn extension(&self) -> &'static str;
+    /// Encrypts `plaintext` to `recipients`, to the configured recipients if there are none
+    fn encrypt(&self, plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>, DofiError>;
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DofiError>;
+
+    /// Whether this machine holds the key of one of `recipients`, true if it cannot tell
+    fn is_recipient(&self, _recipients: &[String]) -> bool {
+        true
+    }
+}
+
+/// The `[encryption]` section of the user configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// The backend used to encrypt newly added dotfiles
+    #[serde(default)]
+    pub backend: Backend,
+    /// The age identity file used for decryption
+    pub identity: Option<PathBuf>,
+    /// Decrypt with the identity `dofi key unlock` left in the OS keyring, if it is there
+    #[serde(default)]
+    pub keyring: bool,
+    /// The age recipients files are encrypted to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    #[serde(default)]
+    pub gpg: GpgConfig,
+}
+
+/// The `[encryption.gpg]` section of the user configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GpgConfig {
+    /// Key IDs or user IDs files are encrypted to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Age,
+    Gpg,
+}
+
+impl EncryptionConfig {
+    /// The backend selected by `backend`
+    pub fn backend(&self) -> Box<dyn Encryption> {
+        match self.backend {
+            Backend::Age => Box::new(Age::new(self)),
+            Backend::Gpg => Box::new(Gpg::new(self)),
+        }
+    }
+
+    /// Every backend, to decrypt dotfiles whatever they were encrypted with
+    pub fn backends(&self) -> Vec<Box<dyn Encryption>> {
+        vec![Box::new(Age::new(self)), Box::new(Gpg::new(self))]
+    }
+}
+
+/// Whether `path` is an encrypted dotfile
+pub fn is_encrypted(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| EXTENSIONS.iter().any(|known| extension == *known))
+}
+
+/// Whether `source` in `dotfiles_directory` is encrypted to the recipients of its tags and
+/// none of them is a key `backends` hold, so it cannot be decrypted on this machine
+pub fn is_encrypted_for_others(
+    fs: &dyn Fs,
+    backends: &[Box<dyn Encryption>],
+    source: &Path,
+    dotfiles_directory: &Path,
+) -> Result<bool, DofiError> {
+    let Some(relative_source) = source
+        .strip_prefix(dotfiles_directory)
+        .ok()
+        .filter(|_| is_encrypted(source))
+    else {
+        return Ok(false);
+    };
+    let recipients = Manifest::load(fs, dotfiles_directory)?.recipients_of(relative_source);
+    Ok(!recipients.is_empty()
+        && backends
+            .iter()
+            .find(|backend| source.extension().is_some_and(|e| e == backend.extension()))
+            .is_some_and(|backend| !backend.is_recipient(&recipients)))
+}
+
+/// The location of `path` encrypted with the backend using `extension`
+pub fn encrypted_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".");
+    name.push(extension);
+    name.into()
+}
+
+/// Encryption using the `age` command line tool
+#[derive(Debug, Clone)]
+pub struct Age {
+    identity: Option<PathBuf>,
+    recipients: Vec<String>,
+    keyring: bool,
+    /// The public keys of `identity`, looked up once they are needed
+    public_keys: OnceLock<Option<Vec<String>>>,
+    /// The unlocked identity from the keyring, written to a private scratch directory once
+    /// it is needed
+    unlocked: OnceLock<Option<Arc<ScratchDirectory>>>,
+}
+
+impl Age {
+    pub const EXTENSION: &'static str = "age";
+
+    pub fn new(config: &EncryptionConfig) -> Self {
+        Self {
+            identity: config.identity.as_deref().map(expand_path),
+            recipients: config.recipients.clone(),
+            keyring: config.keyring,
+            public_keys: OnceLock::new(),
+            unlocked: OnceLock::new(),
+        }
+    }
+
+    /// The file holding the identity unlocked in the keyring, `None` if it is not there. The
+    /// keyring is only asked with `keyring = true` or once the identity file was
+    /// [imported](import_identity).
+    fn unlocked_identity(&self) -> Option<PathBuf> {
+        const NAME: &str = "identity";
+        self.unlocked
+            .get_or_init(|| {
+                let identity = self
+                    .identity
+                    .as_ref()
+                    .filter(|identity| self.keyring || !identity.exists())?;
+                let secret = match keyring::lookup(&identity.to_string_lossy()) {
+                    Ok(secret) => secret?,
+                    Err(e) => {
+                        warn!("Could not look up the identity in the keyring: {e}");
+                        return None;
+                    }
+                };
+                let scratch = private_scratch_directory()
+                    .and_then(|scratch| {
+                        write_private(&scratch.path().join(NAME), &secret)?;
+                        Ok(scratch)
+                    })
+                    .inspect_err(|e| warn!("Could not use the identity from the keyring: {e}"))
+                    .ok()?;
+                Some(Arc::new(scratch))
+            })
+            .as_ref()
+            .map(|scratch| scratch.path().join(NAME))
+    }
+
+    /// The public keys of the identity, `None` if they cannot be found
+    fn public_keys(&self) -> Option<&[String]> {
+        self.public_keys
+            .get_or_init(|| {
+                let Some(identity) = &self.identity else {
+                    return Some(Vec::new());
+                };
+                let mut public = identity.as_os_str().to_os_string();
+                public.push(".pub");
+                let keys = match std::fs::read_to_string(public) {
+                    Ok(keys) => keys,
+                    // Prints the recipient of every age key in the file
+                    Err(_) => {
+                        let mut command = Command::new("age-keygen");
+                        command.arg("-y").arg(identity);
+                        String::from_utf8(pipe("age-keygen", command, &[]).ok()?).ok()?
+                    }
+                };
+                Some(keys.lines().filter_map(public_key).collect())
+            })
+            .as_deref()
+    }
+}
+
+/// The age recipient of a line like `age1...` or `ssh-ed25519 AAAA... comment`, without the
+/// comment
+fn public_key(line: &str) -> Option<String> {
+    let mut fields = line.split_whitespace();
+    let key = fields.next()?;
+    if key.starts_with('#') {
+        return None;
+    }
+    Some(match fields.next() {
+        Some(data) if key.starts_with("ssh-") => format!("{key} {data}"),
+        _ => key.to_string(),
+    })
+}
+
+impl Encryption for Age {
+    fn extension(&self) -> &'static str {
+        Self::EXTENSION
+    }
+
+    fn encrypt(&self, plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>, DofiError> {
+        let recipients = if recipients.is_empty() {
+            &self.recipients
+        } else {
+            recipients
+        };
+        let mut command = Command::new("age");
+        command.arg("--encrypt");
+        if !recipients.is_empty() {
+            for recipient in recipients {
+                command.arg("--recipient").arg(recipient);
+            }
+        } else if let Some(identity) = self.unlocked_identity().or(self.identity.clone()) {
+            command.arg("--identity").arg(identity);
+        } else {
+            return Err(DofiError::NoEncryptionKey);
+        }
+
+        pipe("age", command, plaintext)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DofiError> {
+        let identity = match self.unlocked_identity() {
+            Some(unlocked) => unlocked,
+            None => self.identity.clone().ok_or(DofiError::NoEncryptionKey)?,
+        };
+        let mut command = Command::new("age");
+        command.arg("--decrypt").arg("--identity").arg(identity);
+
+        pipe("age", command, ciphertext)
+    }
+
+    fn is_recipient(&self, recipients: &[String]) -> bool {
+        self.public_keys().is_none_or(|keys| {
+            recipients
+                .iter()
+                .filter_map(|recipient| public_key(recipient))
+                .any(|recipient| keys.contains(&recipient))
+        })
+    }
+}
+
+/// Encryption using the `gpg` command line tool
+#[derive(Debug, Clone)]
+pub struct Gpg {
+    recipients: Vec<String>,
+}
+
+impl Gpg {
+    pub const EXTENSION: &'static str = "gpg";
+
+    pub fn new(config: &EncryptionConfig) -> Self {
+        Self {
+            recipients: config.gpg.recipients.clone(),
+        }
+    }
+}
+
+impl Encryption for Gpg {
+    fn extension(&self) -> &'static str {
+        Self::EXTENSION
+    }
+
+    fn encrypt(&self, plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>, DofiError> {
+        let recipients = if recipients.is_empty() {
+            &self.recipients
+        } else {
+            recipients
+        };
+        let mut command = Command::new("gpg");
+        command.arg("--batch").arg("--yes").arg("--encrypt");
+        if recipients.is_empty() {
+            command.arg("--default-recipient-self");
+        }
+        for recipient in recipients {
+            command.arg("--recipient").arg(recipient);
+        }
+
+        pipe("gpg", command, plaintext)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, DofiError> {
+        let mut command = Command::new("gpg");
+        command.arg("--batch").arg("--quiet").arg("--decrypt");
+
+        pipe("gpg", command, ciphertext)
+    }
+}
+
+/// Decrypts the age identity of `config` if it is protected with a passphrase, asking for it
+/// on the terminal, and stores it in the OS keyring. Returns the identity file.
+pub fn unlock_identity(config: &EncryptionConfig) -> Result<PathBuf, DofiError> {
+    let (identity, secret) = decrypted_identity(config)?;
+    keyring::store(&identity.to_string_lossy(), &secret)?;
+
+    Ok(identity)
+}
+
+/// Moves the age identity of `config` into the OS keyring like [`unlock_identity`], then
+/// writes its public key to the `.pub` file next to it if there is none and removes the
+/// identity file. Returns the identity file.
+pub fn import_identity(config: &EncryptionConfig) -> Result<PathBuf, DofiError> {
+    let (identity, secret) = decrypted_identity(config)?;
+    let account = identity.to_string_lossy();
+    keyring::store(&account, &secret)?;
+    // The only copy is about to be the one in the keyring
+    if keyring::lookup(&account)?.as_deref() != Some(secret.as_slice()) {
+        return Err(DofiError::ExternalCommandFailed(
+            "keyring".to_string(),
+            "the stored identity does not read back the same".to_string(),
+        ));
+    }
+
+    let mut public = identity.as_os_str().to_os_string();
+    public.push(".pub");
+    let public = PathBuf::from(public);
+    if !public.exists() {
+        let mut command = Command::new("age-keygen");
+        command.arg("-y");
+        match pipe("age-keygen", command, &secret) {
+            Ok(keys) => std::fs::write(&public, keys)?,
+            Err(e) => warn!("Could not write the public key of the identity: {e}"),
+        }
+    }
+    std::fs::remove_file(&identity)?;
+
+    Ok(identity)
+}
+
+/// Writes the age identity of `config` from the OS keyring back to its file, which must not
+/// exist. Returns the identity file.
+pub fn export_identity(config: &EncryptionConfig) -> Result<PathBuf, DofiError> {
+    let identity = identity_path(config)?;
+    if identity.exists() {
+        return Err(DofiError::FileExists(identity));
+    }
+    let secret =
+        keyring::lookup(&identity.to_string_lossy())?.ok_or(DofiError::NoKeyringIdentity)?;
+    if let Some(parent) = identity.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    write_private(&identity, &secret)?;
+
+    Ok(identity)
+}
+
+/// Whether the age identity of `config` was [imported](import_identity), the keyring holding
+/// the only copy of it
+pub fn is_imported(config: &EncryptionConfig) -> bool {
+    identity_path(config).is_ok_and(|identity| {
+        !identity.exists()
+            && keyring::lookup(&identity.to_string_lossy()).is_ok_and(|s| s.is_some())
+    })
+}
+
+/// The identity file of `config`
+fn identity_path(config: &EncryptionConfig) -> Result<PathBuf, DofiError> {
+    config
+        .identity
+        .as_deref()
+        .map(expand_path)
+        .ok_or(DofiError::NoEncryptionKey)
+}
+
+/// The identity file of `config` and the identity it holds, asking for its passphrase if it
+/// is protected with one
+fn decrypted_identity(config: &EncryptionConfig) -> Result<(PathBuf, Vec<u8>), DofiError> {
+    let identity = identity_path(config)?;
+    let contents = std::fs::read(&identity)?;
+    let encrypted = [ENCRYPTED_HEADER, ARMORED_HEADER]
+        .iter()
+        .any(|header| contents.starts_with(header.as_bytes()));
+    let secret = if encrypted {
+        let output = Command::new("age")
+            .arg("--decrypt")
+            .arg(&identity)
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| DofiError::ExternalCommandFailed("age".to_string(), e.to_string()))?;
+        if !output.status.success() {
+            return Err(DofiError::ExternalCommandFailed(
+                "age".to_string(),
+                format!("exited with {}", output.status),
+            ));
+        }
+        output.stdout
+    } else {
+        contents
+    };
+
+    Ok((identity, secret))
+}
+
+/// Removes the age identity of `config` from the OS keyring, returning whether it was there
+pub fn lock_identity(config: &EncryptionConfig) -> Result<bool, DofiError> {
+    keyring::delete(&identity_path(config)?.to_string_lossy())
+}
+
+/// How files encrypted by age begin, in binary and armored form
+const ENCRYPTED_HEADER: &str = "age-encryption.org/v1";
+const ARMORED_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// A scratch directory only the user can enter, in the runtime directory if there is one
+fn private_scratch_directory() -> std::io::Result<ScratchDirectory> {
+    let parent = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let scratch = ScratchDirectory::new(&parent, "dofi-identity")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(scratch.path(), std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(scratch)
+}
+
+/// Writes `contents` to the new file `path`, readable only by the user
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Runs `command` with `input` on stdin and returns its stdout
+fn pipe(name: &str, mut command: Command, input: &[u8]) -> Result<Vec<u8>, DofiError> {
+    let failed = |reason: String| DofiError::ExternalCommandFailed(name.to_string(), reason);
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input)
+        .map_err(|e| failed(e.to_string()))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| failed(e.to_string()))?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(failed(format!("exited with {}", output.status)))
+    }
+}
@@ -0,0 +1,481 @@
+use std::path::{Path, PathBuf};
+
+use miette::{Diagnostic, NamedSource, SourceSpan};
+use thiserror::Error;
+
+use crate::paths;
+
+/// Errors produced by dofi operations
+#[derive(Error, Diagnostic, Debug)]
+pub enum DofiError {
+    #[error(transparent)]
+    #[diagnostic(code(dofi::io_error))]
+    GenericIoError(#[from] std::io::Error),
+
+    #[error("Failed to watch the dotfiles for changes")]
+    #[diagnostic(code(dofi::watch_failed))]
+    WatchFailed(#[from] notify::Error),
+
+    #[error("Base '{}' is not a prefix of target '{}'", paths::display(.0), paths::display(.1))]
+    #[diagnostic(code(dofi::prefix_error))]
+    BaseIsNotPrefixOfFile(PathBuf, PathBuf),
+
+    #[error("Not adding '{}', it is {1}", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::unwieldy_file),
+        help("pass --allow-large to add it anyway, `add.max_size` in the configuration sets the size limit")
+    )]
+    UnwieldyFile(PathBuf, String),
+
+    #[error("Target '{}' is not a regular file", paths::display(.0))]
+    #[diagnostic(code(dofi::not_regular_file_error))]
+    FileIsNotRegular(PathBuf),
+
+    #[error("'{}' is not a directory", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::not_a_directory),
+        help("`--as-dir` adds a directory as a whole, leave it out to add a file")
+    )]
+    NotADirectory(PathBuf),
+
+    #[error("'{}' is not text, it cannot become a template", paths::display(.0))]
+    #[diagnostic(code(dofi::not_text_error))]
+    FileIsNotText(PathBuf),
+
+    #[error("Invalid base directory '{}': {0}", paths::display(.1))]
+    #[diagnostic(code(dofi::base_dir_error))]
+    InvalidBaseDirectory(std::io::Error, PathBuf),
+
+    #[error("No base directory given and no home directory found")]
+    #[diagnostic(
+        code(dofi::no_base_dir_error),
+        help("pass -b or set HOME (USERPROFILE on Windows)")
+    )]
+    NoBaseDirectory,
+
+    #[error("No dotfiles directory given")]
+    #[diagnostic(
+        code(dofi::no_dotfiles_dir_error),
+        help("pass -d, set DOFI_DIR, select a workspace with -R, set default_workspace or create the default directory with `dofi init`")
+    )]
+    NoDotfilesDirectory,
+
+    #[error("Invalid dotfiles directory '{}': {0}", paths::display(.1))]
+    #[diagnostic(code(dofi::dotfiles_dir_error))]
+    InvalidDotfilesDirectory(std::io::Error, PathBuf),
+
+    #[error(transparent)]
+    #[diagnostic(code(dofi::ignore_error))]
+    ListDirectoryFailed(#[from] ignore::Error),
+
+    #[error("'{}' already exists", paths::display(.0))]
+    #[diagnostic(code(dofi::file_exists))]
+    FileExists(PathBuf),
+
+    #[error("'{}' is not a symlink into the dotfiles, `--force` does not replace it", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::file_not_owned),
+        help("pass --force-all to replace it anyway, `dofi impact` lists what would be replaced")
+    )]
+    FileNotOwned(PathBuf),
+
+    #[error("'{}' already links to a dotfile", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::already_a_dotfile),
+        help("it is managed already, see `dofi status`")
+    )]
+    FileIsADotfile(PathBuf),
+
+    #[error("'{}' is already managed by dofi", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::already_managed),
+        help("did you mean `dofi link`? `dofi add --relink` recreates its symlink")
+    )]
+    AlreadyManaged(PathBuf),
+
+    #[error("File '{}' is not a dotfile", paths::display(.0))]
+    #[diagnostic(code(dofi::file_is_not_a_dotfile))]
+    FileIsNotADotfile(PathBuf),
+
+    #[error("Both '{}' and '{}' link to '{}'", paths::display(.1), paths::display(.2), paths::display(.0))]
+    #[diagnostic(
+        code(dofi::duplicate_target),
+        help("rename or remove one of them, or make them conditional, `dofi check` lists every duplicate")
+    )]
+    DuplicateTarget(PathBuf, PathBuf, PathBuf),
+
+    #[error("Could not read or write the operation journal: {0}")]
+    #[diagnostic(code(dofi::journal_error))]
+    InvalidJournal(serde_json::Error),
+
+    #[error("Could not read or write the snapshots: {0}")]
+    #[diagnostic(code(dofi::snapshots_error))]
+    InvalidSnapshot(serde_json::Error),
+
+    #[error("Could not read or write which scripts ran: {0}")]
+    #[diagnostic(code(dofi::script_state_error))]
+    InvalidScriptState(serde_json::Error),
+
+    #[error("Could not read or write the files queued for review: {0}")]
+    #[diagnostic(code(dofi::track_state_error))]
+    InvalidTrackState(serde_json::Error),
+
+    #[error("There are no directories to track")]
+    #[diagnostic(
+        code(dofi::nothing_to_track),
+        help("watch a directory with `--watch`, or list directories in the `track` section of the configuration")
+    )]
+    NothingToTrack,
+
+    #[error("No file to {0} was given")]
+    #[diagnostic(
+        code(dofi::no_file_given),
+        help("pass a file, or run it in a terminal to pick one")
+    )]
+    NoFileGiven(&'static str),
+
+    #[error("Script '{}' is ordered against '{}', which is neither a script nor a step", paths::display(.0), paths::display(.1))]
+    #[diagnostic(
+        code(dofi::unknown_script),
+        help("`after` and `before` list repo-relative paths of scripts, like 'scripts/brew.sh', or the steps 'pre-link', 'link' and 'post-link'")
+    )]
+    UnknownScript(PathBuf, PathBuf),
+
+    #[error("Scripts depend on each other in a cycle, {0}")]
+    #[diagnostic(code(dofi::script_cycle))]
+    ScriptCycle(String),
+
+    #[error("There is no snapshot {0}")]
+    #[diagnostic(
+        code(dofi::unknown_snapshot),
+        help("`dofi snapshots list` shows the available snapshots")
+    )]
+    UnknownSnapshot(u64),
+
+    #[error("Could not read or write the target checksums: {0}")]
+    #[diagnostic(code(dofi::checksums_error))]
+    InvalidChecksums(serde_json::Error),
+
+    #[error("'{}' was changed since it was written, overwriting it would lose the changes", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::target_modified),
+        help("copy the changes into the dotfile or remove the target, `dofi verify` lists all changed targets")
+    )]
+    TargetModified(PathBuf),
+
+    #[error("'{0}' has nothing to merge, it is {1}")]
+    #[diagnostic(
+        code(dofi::nothing_to_merge),
+        help("only copied targets edited locally whose dotfile changed since can be merged, see `dofi status`")
+    )]
+    NothingToMerge(PathBuf, String),
+
+    #[error("'{}' is rendered from its dotfile, it cannot be adopted", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::cannot_adopt_rendered),
+        help("change the dotfile with `dofi edit`, or merge the local edits with `dofi merge`")
+    )]
+    CannotAdoptRendered(PathBuf),
+
+    #[error("Cannot migrate '{}', {1}", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::cannot_migrate),
+        help("rename it by hand and run `dofi migrate` again")
+    )]
+    CannotMigrate(PathBuf, String),
+
+    #[error("'{}' has no variants for single hosts to turn into a template", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::no_host_variants),
+        help("variants for single hosts are named like `.gitconfig.hostname-laptop`")
+    )]
+    NoHostVariants(PathBuf),
+
+    #[error("Cannot templatify '{}', {1}", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::cannot_templatify),
+        help("turn the variants that are templates or encrypted back into plain files first")
+    )]
+    NotTemplatable(PathBuf, String),
+
+    #[error("What was last written to '{0}' is unknown")]
+    #[diagnostic(
+        code(dofi::missing_merge_base),
+        help("it was written by an older version of dofi, compare the sides with `dofi diff` instead")
+    )]
+    MissingMergeBase(PathBuf),
+
+    #[error("{0} {} out of date", if *.0 == 1 { "target is" } else { "targets are" })]
+    #[diagnostic(
+        code(dofi::out_of_date),
+        help("see `dofi status` and `dofi verify` for the targets")
+    )]
+    OutOfDate(usize),
+
+    #[error("{0} {} found in the dotfiles", if *.0 == 1 { "problem" } else { "problems" })]
+    #[diagnostic(code(dofi::check_failed))]
+    CheckFailed(usize),
+
+    #[error("Invalid query term '{0}': {1}")]
+    #[diagnostic(
+        code(dofi::invalid_query),
+        help("terms look like 'state:conflict', 'path:nvim' or 'changed:<7d'")
+    )]
+    InvalidQuery(String, String),
+
+    #[error("Invalid prompt format '{0}': {1}")]
+    #[diagnostic(
+        code(dofi::invalid_prompt_format),
+        help("placeholders are {{drift}}, {{dirty}}, {{unlinked}}, {{conflicts}}, {{broken}}, {{uncommitted}}, {{ahead}} and {{behind}}, write {{{{ for a literal brace")
+    )]
+    InvalidPromptFormat(String, String),
+
+    #[error("Invalid search pattern '{0}': {1}")]
+    #[diagnostic(
+        code(dofi::invalid_search_pattern),
+        help("the pattern is a regular expression, escape characters like '.' or '(' to match them literally")
+    )]
+    InvalidSearchPattern(String, String),
+
+    #[error("Invalid glob '{0}': {1}")]
+    #[diagnostic(
+        code(dofi::invalid_glob),
+        help("the path is a glob, escape '*', '?', '[' and '{{' as '[*]', '[?]', '[[]' and '[{{]' to match them literally")
+    )]
+    InvalidGlob(String, String),
+
+    #[error("Unknown command '{0}'")]
+    #[diagnostic(
+        code(dofi::unknown_command),
+        help("it is neither one of dofi's commands, see `dofi --help`, nor a `dofi-{0}` executable on the PATH")
+    )]
+    UnknownCommand(String),
+
+    #[error("Cannot tell which shell to install completions for")]
+    #[diagnostic(
+        code(dofi::unknown_shell),
+        help("name it, e.g. `dofi completions install zsh`")
+    )]
+    UnknownShell,
+
+    #[error("{0} has no conventional directory for completions")]
+    #[diagnostic(
+        code(dofi::unsupported_shell),
+        help("write `dofi completions {0}` to where it loads completions from instead")
+    )]
+    UnsupportedShell(String),
+
+    #[error("Invalid release: {0}")]
+    #[diagnostic(
+        code(dofi::invalid_release),
+        help("see https://github.com/jsfr/dofi/releases for the binaries that were published")
+    )]
+    InvalidRelease(String),
+
+    #[error("The download of '{0}' is corrupt, it does not match the checksum published with it")]
+    #[diagnostic(
+        code(dofi::checksum_mismatch),
+        help("the binary was left untouched, try again later")
+    )]
+    ChecksumMismatch(String),
+
+    #[error("The dotfiles from '{0}' are read-only")]
+    #[diagnostic(
+        code(dofi::read_only_source),
+        help("change them where they are kept, `--source` only reads them")
+    )]
+    ReadOnlySource(String),
+
+    #[error("The dotfiles were given both with `-d` and with `--source`")]
+    #[diagnostic(
+        code(dofi::conflicting_sources),
+        help("pass only one of them, `--source` also takes a directory of dotfiles")
+    )]
+    ConflictingSources,
+
+    #[error("Running '{0}' failed: {1}")]
+    #[diagnostic(code(dofi::external_command_error))]
+    ExternalCommandFailed(String, String),
+
+    #[error("No dotfile matches '{pattern}'")]
+    #[diagnostic(code(dofi::no_matching_dotfile))]
+    NoMatchingDotfile {
+        pattern: String,
+        #[help]
+        help: String,
+    },
+
+    #[error("Invalid pattern '{0}': {1}")]
+    #[diagnostic(code(dofi::invalid_pattern))]
+    InvalidPattern(String, String),
+
+    #[error("Invalid mode {1:#o} for '{0}' in the manifest")]
+    #[diagnostic(
+        code(dofi::invalid_mode),
+        help("modes are permission bits written in octal, like 0o600")
+    )]
+    InvalidMode(String, u32),
+
+    #[error("The variable '{0}' in '{1}' is not set")]
+    #[diagnostic(
+        code(dofi::unset_variable),
+        help("set the variable, or write `$$` for a literal `$`")
+    )]
+    UnsetVariable(String, String),
+
+    #[error("Invalid configuration: {message}")]
+    #[diagnostic(code(dofi::config_error))]
+    InvalidConfig {
+        message: String,
+        #[label("{message}")]
+        span: Option<SourceSpan>,
+        #[source_code]
+        source_code: NamedSource<String>,
+    },
+
+    #[error("Invalid configuration key '{0}': {1}")]
+    #[diagnostic(
+        code(dofi::invalid_config_key),
+        help("keys are dotted paths like `git.auto_push`, see them with `dofi config list`")
+    )]
+    InvalidConfigKey(String, String),
+
+    #[error("Could not determine the location of the configuration file")]
+    #[diagnostic(
+        code(dofi::no_config_file),
+        help("pass --config or set XDG_CONFIG_HOME")
+    )]
+    NoConfigFile,
+
+    #[error("Unknown workspace '{0}'")]
+    #[diagnostic(
+        code(dofi::unknown_workspace),
+        help("see the registered workspaces with `dofi workspaces list`")
+    )]
+    UnknownWorkspace(String),
+
+    #[error("Repo path '{}' is not a relative path inside the dotfiles directory", paths::display(.0))]
+    #[diagnostic(code(dofi::invalid_repo_path))]
+    InvalidRepoPath(PathBuf),
+
+    #[error("Target '{}' of '{}' in the manifest is neither an absolute path nor a relative path inside the base directory", paths::display(.1), paths::display(.0))]
+    #[diagnostic(code(dofi::invalid_manifest_target))]
+    InvalidManifestTarget(PathBuf, PathBuf),
+
+    #[error("Generated target '{}' in the manifest is not a relative path inside the base directory", paths::display(.0))]
+    #[diagnostic(code(dofi::invalid_generated_target))]
+    InvalidGeneratedTarget(PathBuf),
+
+    #[error("Root '{1}' of '{}' in the manifest cannot be resolved", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::invalid_manifest_root),
+        help("roots are paths, optionally starting with `~` or a `$VARIABLE` that is set")
+    )]
+    InvalidManifestRoot(PathBuf, String),
+
+    #[error("Linking {1} system targets requires root")]
+    #[diagnostic(
+        code(dofi::elevation_required),
+        help("pass --sudo to have dofi run the commands, or run them yourself:\n{0}")
+    )]
+    ElevationRequired(String, usize),
+
+    #[error("The dotfiles repository '{}' has {1} uncommitted changes", paths::display(.0))]
+    #[diagnostic(
+        code(dofi::dirty_repository),
+        help("commit or stash the changes first, or pass --allow-dirty")
+    )]
+    DirtyRepository(PathBuf, usize),
+
+    #[error("No key configured for encrypted dotfiles")]
+    #[diagnostic(
+        code(dofi::no_encryption_key),
+        help("set identity or recipients in the [encryption] section of the configuration")
+    )]
+    NoEncryptionKey,
+
+    #[error("There is no OS keyring dofi can use on this system")]
+    #[diagnostic(
+        code(dofi::no_keyring),
+        help(
+            "dofi uses `secret-tool` for the Secret Service on Linux, `security` on macOS and \
+             the Credential Manager on Windows"
+        )
+    )]
+    NoKeyring,
+
+    #[error("The identity is not in the keyring")]
+    #[diagnostic(
+        code(dofi::no_keyring_identity),
+        help("`dofi key import` moves the identity file into the keyring")
+    )]
+    NoKeyringIdentity,
+
+    #[error("The keyring holds the only copy of the identity")]
+    #[diagnostic(
+        code(dofi::imported_identity),
+        help("`dofi key export` writes the identity file again, lock it after that")
+    )]
+    ImportedIdentity,
+
+    #[error("Invalid template: {message}")]
+    #[diagnostic(code(dofi::template_error))]
+    InvalidTemplate {
+        message: String,
+        #[label("{message}")]
+        span: SourceSpan,
+        #[source_code]
+        source_code: NamedSource<String>,
+    },
+
+    #[error("No secret provider configured")]
+    #[diagnostic(
+        code(dofi::no_secret_provider),
+        help("set provider, and command for the command provider, in the [secrets] section of the configuration")
+    )]
+    NoSecretProvider,
+
+    #[error("Another dofi run holds the lock '{}'", paths::display(.0))]
+    #[diagnostic(code(dofi::locked), help("pass --wait to wait for it to finish"))]
+    Locked(PathBuf),
+
+    #[error("There is nothing to undo")]
+    #[diagnostic(code(dofi::nothing_to_undo))]
+    NothingToUndo,
+}
+
+impl DofiError {
+    /// The paths the error is about, for tools presenting it
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            DofiError::BaseIsNotPrefixOfFile(a, b)
+            | DofiError::UnknownScript(a, b)
+            | DofiError::InvalidManifestTarget(a, b) => vec![a, b],
+            DofiError::DuplicateTarget(a, b, c) => vec![a, b, c],
+            DofiError::FileIsNotRegular(path)
+            | DofiError::NotADirectory(path)
+            | DofiError::FileIsNotText(path)
+            | DofiError::InvalidBaseDirectory(_, path)
+            | DofiError::InvalidDotfilesDirectory(_, path)
+            | DofiError::FileExists(path)
+            | DofiError::FileNotOwned(path)
+            | DofiError::FileIsADotfile(path)
+            | DofiError::FileIsNotADotfile(path)
+            | DofiError::AlreadyManaged(path)
+            | DofiError::TargetModified(path)
+            | DofiError::NothingToMerge(path, _)
+            | DofiError::CannotAdoptRendered(path)
+            | DofiError::UnwieldyFile(path, _)
+            | DofiError::CannotMigrate(path, _)
+            | DofiError::NoHostVariants(path)
+            | DofiError::NotTemplatable(path, _)
+            | DofiError::MissingMergeBase(path)
+            | DofiError::InvalidRepoPath(path)
+            | DofiError::InvalidGeneratedTarget(path)
+            | DofiError::InvalidManifestRoot(path, _)
+            | DofiError::DirtyRepository(path, _)
+            | DofiError::Locked(path) => vec![path],
+            _ => Vec::new(),
+        }
+    }
+}
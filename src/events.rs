@@ -0,0 +1,63 @@
+//! A stream of newline-delimited JSON events describing a run, for wrappers showing live
+//! progress.
+//!
+//! Events are only written once [`init`] was called, every line is one [`Event`] tagged by
+//! its `event` field, e.g. `{"event":"started","command":"link"}`. The [`report`] of
+//! `--report` is gathered from the same events.
+
+use std::{io::Write, path::Path, sync::Mutex};
+
+use serde::Serialize;
+
+use crate::{journal::Action, report};
+
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A mutating command started
+    Started { command: &'a str },
+    /// A dotfile is about to be linked to `target`
+    Planned {
+        #[serde(serialize_with = "crate::paths::serde::serialize")]
+        source: &'a Path,
+        #[serde(serialize_with = "crate::paths::serde::serialize")]
+        target: &'a Path,
+    },
+    /// A filesystem change was performed
+    Action { action: &'a Action },
+    /// `target` already exists, `resolution` says how it is handled
+    Conflict {
+        #[serde(serialize_with = "crate::paths::serde::serialize")]
+        target: &'a Path,
+        resolution: &'a str,
+    },
+    /// The dotfile of `target` is left alone for `reason`
+    Skipped {
+        #[serde(serialize_with = "crate::paths::serde::serialize")]
+        target: &'a Path,
+        reason: &'a str,
+    },
+    /// A mutating command finished after performing `actions` changes
+    Summary { command: &'a str, actions: usize },
+    /// The run failed
+    Failed { message: &'a str },
+}
+
+/// Sends all following events to `writer`
+pub fn init(writer: Box<dyn Write + Send>) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+}
+
+/// Writes `event` to the sink, if there is one, and adds it to the [`report`]. Failing to
+/// write never fails the run.
+pub fn emit(event: &Event) {
+    report::observe(event);
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(writer) = sink.as_mut() {
+        if let Ok(line) = serde_json::to_string(event) {
+            let _ = writeln!(writer, "{line}").and_then(|()| writer.flush());
+        }
+    }
+}
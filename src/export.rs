@@ -0,0 +1,260 @@
+//! Portable archives of the dotfiles, for machines without dofi or git.
+//!
+//! `dofi export --output dotfiles.tar.gz` packs the dotfiles as linking leaves them in the base
+//! directory: the layers and conditions are resolved for this machine, templates are rendered
+//! and every target is a regular file instead of a symlink into the repo. Encrypted dotfiles
+//! are only decrypted into the archive with `--decrypt`, system targets outside the base
+//! directory are always left out. The system `tar` writes the archive, compressed according
+//! to its extension, with paths relative to the base directory, so
+//! `tar -xzf dotfiles.tar.gz -C ~` unpacks it on the other machine.
+//!
+//! `dofi export --format home-manager` instead writes a Nix module for [home-manager] declaring
+//! every target as `xdg.configFile` below `~/.config` and as `home.file` elsewhere, sourced
+//! from the dotfiles directories so a migration can keep the tree as it is. With
+//! `--out-of-store` the targets link to the dotfiles through
+//! `config.lib.file.mkOutOfStoreSymlink`, so edits show up without switching again, as they do
+//! with dofi. Templates are rendered into `text`, while encrypted and private dotfiles are left
+//! out as the Nix store is readable by everyone.
+//!
+//! [home-manager]: https://github.com/nix-community/home-manager
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::warn;
+
+use crate::{
+    encryption,
+    fs::{FileType, ScratchDirectory},
+    layered_dotfiles, platform, private, target_contents, DofiError, Fs, LinkOptions, Strategy,
+};
+
+/// A file of the exported tree
+#[derive(Debug, PartialEq, Eq)]
+pub struct File {
+    /// The path relative to the base directory
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+    pub mode: u32,
+}
+
+/// The files linking the layered `dotfiles_directories` with `options` leaves in
+/// `base_directory`, ordered by path. Encrypted dotfiles are decrypted if `decrypt` is set
+/// and left out otherwise.
+pub fn tree(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+    decrypt: bool,
+) -> Result<Vec<File>, DofiError> {
+    let mut files = Vec::new();
+
+    for dotfile in layered_dotfiles(fs, base_directory, dotfiles_directories)? {
+        let Ok(path) = dotfile.target.strip_prefix(base_directory) else {
+            warn!(
+                "Not exporting '{}', it is a system file",
+                dotfile.target.display()
+            );
+            continue;
+        };
+        let encrypted = encryption::is_encrypted(&dotfile.source);
+        if encrypted && !decrypt {
+            warn!(
+                "Not exporting '{}', it is encrypted",
+                dotfile.target.display()
+            );
+            continue;
+        }
+        if encryption::is_encrypted_for_others(
+            fs,
+            &options.encryption,
+            &dotfile.source,
+            &dotfile.layer,
+        )? {
+            warn!(
+                "Not exporting '{}', it is not encrypted for this machine",
+                dotfile.target.display()
+            );
+            continue;
+        }
+
+        // Nested repositories linked as a whole are directories
+        let metadata = fs.symlink_metadata(&dotfile.source)?;
+        if metadata.file_type == FileType::Directory {
+            for source in fs.walk(&dotfile.source)? {
+                let relative = source.strip_prefix(&dotfile.source).unwrap_or(&source);
+                files.push(File {
+                    path: path.join(relative),
+                    contents: fs.read(&source)?,
+                    mode: fs.symlink_metadata(&source)?.mode,
+                });
+            }
+            continue;
+        }
+
+        let mode = if dotfile.private {
+            private::PRIVATE_MODE
+        } else if encrypted {
+            0o600
+        } else if let Some(mode) = dotfile.mode {
+            mode
+        } else if metadata.file_type == FileType::Symlink {
+            // A symlink kept in the repo becomes a copy of what it points to
+            0o644
+        } else {
+            metadata.mode
+        };
+        files.push(File {
+            path: path.to_path_buf(),
+            contents: target_contents(fs, &dotfile, options)?,
+            mode,
+        });
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Packs `files` into the archive `output` with the system `tar`, staging them in
+/// `state_directory` as they may hold decrypted secrets
+pub fn write_archive(
+    files: &[File],
+    output: &Path,
+    state_directory: &Path,
+) -> Result<(), DofiError> {
+    let staging = ScratchDirectory::new(state_directory, "export")?;
+    for file in files {
+        let path = staging.path().join(&file.path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, &file.contents)?;
+        platform::set_mode(&path, file.mode)?;
+    }
+
+    // Packing `.` would carry the permissions of the staging directory over to the base
+    // directory on extraction
+    let entries = std::fs::read_dir(staging.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let command_line = format!("tar -caf {}", output.display());
+    let status = Command::new("tar")
+        .arg("-caf")
+        .arg(output)
+        .arg("-C")
+        .arg(staging.path())
+        .args(entries)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            command_line,
+            format!("exited with {status}"),
+        ))
+    }
+}
+
+/// A home-manager module declaring the targets linking the layered `dotfiles_directories` with
+/// `options` leaves in `base_directory`, linking them out of the store if `out_of_store` is set
+pub fn home_manager(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+    out_of_store: bool,
+) -> Result<String, DofiError> {
+    let mut module =
+        String::from("# Generated by `dofi export --format home-manager`\n{ config, ... }:\n{\n");
+    let config_directory = base_directory.join(".config");
+
+    for dotfile in layered_dotfiles(fs, base_directory, dotfiles_directories)? {
+        let target = &dotfile.target;
+        let Ok(path) = target.strip_prefix(base_directory) else {
+            warn!("Not exporting '{}', it is a system file", target.display());
+            continue;
+        };
+        if encryption::is_encrypted(&dotfile.source) {
+            warn!("Not exporting '{}', it is encrypted", target.display());
+            continue;
+        }
+        if dotfile.private {
+            warn!("Not exporting '{}', it is private", target.display());
+            continue;
+        }
+
+        let (option, name) = match target.strip_prefix(&config_directory) {
+            Ok(name) => ("xdg.configFile", name),
+            Err(_) => ("home.file", path),
+        };
+        let value = if dotfile.strategy == Strategy::Template {
+            let contents = target_contents(fs, &dotfile, options)?;
+            let Ok(text) = String::from_utf8(contents) else {
+                warn!("Not exporting '{}', it is not text", target.display());
+                continue;
+            };
+            format!("text = {}", nix_string(&text))
+        } else if out_of_store {
+            format!(
+                "source = config.lib.file.mkOutOfStoreSymlink {}",
+                nix_string(&dotfile.source.to_string_lossy())
+            )
+        } else {
+            format!("source = {}", nix_path(&dotfile.source))
+        };
+        let executable = dotfile.mode.map_or_else(
+            || {
+                fs.symlink_metadata(&dotfile.source).is_ok_and(|metadata| {
+                    metadata.file_type == FileType::File && metadata.mode & 0o111 != 0
+                })
+            },
+            |mode| mode & 0o111 != 0,
+        );
+        let attribute = format!(
+            "{option}.{}",
+            nix_string(&name.to_string_lossy().replace('\\', "/"))
+        );
+        module.push_str(&format!("  {attribute}.{value};\n"));
+        if executable {
+            module.push_str(&format!("  {attribute}.executable = true;\n"));
+        }
+    }
+
+    module.push_str("}\n");
+    Ok(module)
+}
+
+/// `text` as a Nix string
+fn nix_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The absolute `path` as a Nix path, a plain literal if its characters allow one
+fn nix_path(path: &Path) -> String {
+    let text = path.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "/._-+".contains(c);
+    if text.chars().all(plain) && !text.ends_with('/') {
+        text.into_owned()
+    } else {
+        format!("/. + {}", nix_string(&text))
+    }
+}
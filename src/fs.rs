@@ -0,0 +1,797 @@
+//! Filesystem abstraction used by all dofi operations.
+//!
+//! [`OsFs`] talks to the real filesystem, while [`MemoryFs`] keeps everything in memory so
+//! operations can be exercised without touching the disk.
+//!
+//! Network home directories on NFS or SSHFS occasionally fail a single call with a stale
+//! file handle or a timeout. [`OsFs`] retries those, doubling the wait each time, and walks
+//! and symlinks on as many threads as there are cores, only [`Fs::walk_each`] reads one
+//! directory after the other. The `filesystem` section of the user configuration tunes both,
+//! `--max-concurrency` overrides the number of threads:
+//!
+//! ```toml
+//! [filesystem]
+//! retries = 5
+//! backoff_ms = 100
+//! max_concurrency = 2
+//! ```
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    io::{self, ErrorKind},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use ignore::{overrides::OverrideBuilder, WalkBuilder, WalkState};
+use log::{debug, info};
+use serde::Deserialize;
+
+use crate::{platform, timings, DofiError};
+
+/// Version control metadata directories, never walked into
+pub const VCS_DIRECTORIES: [&str; 3] = [".git", ".hg", ".svn"];
+
+/// Whether `name` is one of the [`VCS_DIRECTORIES`]
+pub fn is_vcs_directory(name: &std::ffi::OsStr) -> bool {
+    VCS_DIRECTORIES.iter().any(|directory| name == *directory)
+}
+
+/// Moves the file `from` to `to`. Across filesystems, where renaming fails, it is copied
+/// together with its metadata and flushed to disk before `from` is removed. Directories are
+/// only ever renamed. Returns the metadata that could not be preserved.
+pub fn move_preserving(fs: &dyn Fs, from: &Path, to: &Path) -> io::Result<Vec<String>> {
+    match fs.rename(from, to) {
+        Ok(()) => return Ok(Vec::new()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices && !is_directory(fs, from) => {}
+        Err(e) => return Err(e),
+    }
+    info!(
+        "'{}' and '{}' are on different filesystems, copying instead",
+        from.display(),
+        to.display()
+    );
+    fs.copy(from, to)?;
+    let lost = fs.copy_metadata(from, to)?;
+    fs.sync(to)?;
+    fs.remove_file(from)?;
+    Ok(lost)
+}
+
+fn is_directory(fs: &dyn Fs, path: &Path) -> bool {
+    fs.symlink_metadata(path)
+        .is_ok_and(|metadata| metadata.file_type == FileType::Directory)
+}
+
+/// The kind of a filesystem entry, symlinks are never followed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Metadata of a filesystem entry as returned by [`Fs::symlink_metadata`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub len: u64,
+    /// Unix permission bits, always `0o777` for symlinks
+    pub mode: u32,
+    /// Last modification time, if the filesystem tracks it
+    pub modified: Option<SystemTime>,
+}
+
+/// A directory on the real filesystem for files handed to external programs, removed again
+/// with everything in it when dropped
+#[derive(Debug)]
+pub struct ScratchDirectory(PathBuf);
+
+impl ScratchDirectory {
+    /// Creates the scratch directory `<parent>/<name>-<pid>`
+    pub fn new(parent: &Path, name: &str) -> io::Result<ScratchDirectory> {
+        let path = parent.join(format!("{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&path)?;
+        Ok(ScratchDirectory(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The filesystem operations dofi needs
+pub trait Fs {
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn symlink(&self, original: &Path, link: &Path) -> io::Result<()>;
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+    /// Whether `a` and `b` are the same file, like hard links to each other. Symlinks are not
+    /// followed.
+    fn same_file(&self, a: &Path, b: &Path) -> bool;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Sets the Unix permission bits of `path`
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    /// Copies the permission bits, the access and modification times and the extended
+    /// attributes of the file `from` to the file `to`. Returns a description of each piece of
+    /// metadata that could not be copied, failing only if the permissions could not.
+    fn copy_metadata(&self, from: &Path, to: &Path) -> io::Result<Vec<String>>;
+    /// Flushes the contents and metadata of the file `path` to disk
+    fn sync(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists all regular files and symlinks below `root`, skipping `.git` directories. Symlinks
+    /// are never followed.
+    fn walk(&self, root: &Path) -> Result<Vec<PathBuf>, DofiError>;
+
+    /// Calls `visit` with the files [`Fs::walk`] lists below `root`, in the same order but one
+    /// at a time, without holding all of them
+    fn walk_each(
+        &self,
+        root: &Path,
+        visit: &mut dyn FnMut(PathBuf) -> Result<(), DofiError>,
+    ) -> Result<(), DofiError> {
+        self.walk(root)?.into_iter().try_for_each(visit)
+    }
+
+    /// Creates the symlinks of the `(original, link)` pairs, possibly in parallel, and returns
+    /// the outcome of each in the same order
+    fn symlink_all(&self, links: &[(PathBuf, PathBuf)]) -> Vec<io::Result<()>> {
+        links
+            .iter()
+            .map(|(original, link)| self.symlink(original, link))
+            .collect()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.symlink_metadata(path).is_ok()
+    }
+}
+
+/// How often a failing operation of the real filesystem is retried unless configured
+pub const DEFAULT_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry unless configured, in milliseconds
+pub const DEFAULT_BACKOFF_MS: u64 = 25;
+
+static RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_RETRIES);
+static BACKOFF_MS: AtomicU64 = AtomicU64::new(DEFAULT_BACKOFF_MS);
+/// The most threads walking or symlinking, 0 for as many as there are cores
+static MAX_CONCURRENCY: AtomicUsize = AtomicUsize::new(0);
+
+/// The `filesystem` section of the user configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FsConfig {
+    /// How often an operation failing with a transient error is retried
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// How long to wait before the first retry in milliseconds, doubled for every further one
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+    /// The most threads walking the dotfiles or symlinking them, as many as there are cores
+    /// unless set
+    pub max_concurrency: Option<NonZeroUsize>,
+}
+
+impl Default for FsConfig {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_RETRIES,
+            backoff_ms: DEFAULT_BACKOFF_MS,
+            max_concurrency: None,
+        }
+    }
+}
+
+fn default_retries() -> u32 {
+    DEFAULT_RETRIES
+}
+
+fn default_backoff_ms() -> u64 {
+    DEFAULT_BACKOFF_MS
+}
+
+/// Makes [`OsFs`] retry and spread its work like `config` says from now on
+pub fn configure(config: &FsConfig) {
+    RETRIES.store(config.retries, Ordering::Relaxed);
+    BACKOFF_MS.store(config.backoff_ms, Ordering::Relaxed);
+    MAX_CONCURRENCY.store(
+        config.max_concurrency.map_or(0, NonZeroUsize::get),
+        Ordering::Relaxed,
+    );
+}
+
+/// Runs `operation` until it succeeds, fails for good or ran out of retries
+fn retry<T>(mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let retries = RETRIES.load(Ordering::Relaxed);
+    let mut backoff = Duration::from_millis(BACKOFF_MS.load(Ordering::Relaxed));
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                debug!("Retrying in {}ms after: {e}", backoff.as_millis());
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether the operation failing with `error` may well succeed when tried again
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+            | ErrorKind::StaleNetworkFileHandle
+    )
+}
+
+/// The number of threads to spread work over
+fn threads() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    match MAX_CONCURRENCY.load(Ordering::Relaxed) {
+        0 => cores,
+        limit => cores.min(limit),
+    }
+}
+
+/// The real filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFs;
+
+impl Fs for OsFs {
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::rename(from, to))
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::copy(from, to)).map(|_| ())
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| platform::symlink(original, link))
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::hard_link(original, link))
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        timings::count_operations(1);
+        platform::same_file(a, b)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        timings::count_operations(1);
+        retry(|| std::fs::read_link(path))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| {
+            if path.is_symlink() {
+                platform::remove_symlink(path)
+            } else {
+                std::fs::remove_file(path)
+            }
+        })
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::remove_dir(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::create_dir_all(path))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        timings::count_operations(1);
+        let metadata = retry(|| path.symlink_metadata())?;
+        let file_type = if metadata.is_symlink() {
+            FileType::Symlink
+        } else if metadata.is_dir() {
+            FileType::Directory
+        } else {
+            FileType::File
+        };
+
+        // The BSDs and macOS give symlinks permission bits of their own, which nothing reads
+        let mode = match file_type {
+            FileType::Symlink => 0o777,
+            _ => platform::mode(&metadata),
+        };
+        Ok(Metadata {
+            file_type,
+            len: metadata.len(),
+            mode,
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| platform::set_mode(path, mode))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        timings::count_operations(1);
+        retry(|| std::fs::read(path))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::write(path, contents))
+    }
+
+    fn copy_metadata(&self, from: &Path, to: &Path) -> io::Result<Vec<String>> {
+        timings::count_operations(1);
+        let metadata = std::fs::metadata(from)?;
+        let mut lost = platform::copy_xattrs(from, to)?
+            .into_iter()
+            .map(|name| format!("the extended attribute '{name}'"))
+            .collect::<Vec<_>>();
+
+        let times = metadata
+            .accessed()
+            .and_then(|accessed| Ok((accessed, metadata.modified()?)))
+            .map(|(accessed, modified)| {
+                std::fs::FileTimes::new()
+                    .set_accessed(accessed)
+                    .set_modified(modified)
+            });
+        let set = times.and_then(|times| {
+            std::fs::File::options()
+                .write(true)
+                .open(to)?
+                .set_times(times)
+        });
+        if set.is_err() {
+            lost.push("the timestamps".to_string());
+        }
+
+        // Last, a read-only file could not take anything else
+        std::fs::set_permissions(to, metadata.permissions())?;
+        Ok(lost)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        timings::count_operations(1);
+        retry(|| std::fs::File::open(path)?.sync_all())
+    }
+
+    fn symlink_all(&self, links: &[(PathBuf, PathBuf)]) -> Vec<io::Result<()>> {
+        timings::count_operations(links.len());
+        if links.len() < PARALLEL_THRESHOLD {
+            return links
+                .iter()
+                .map(|(original, link)| retry(|| platform::symlink(original, link)))
+                .collect();
+        }
+
+        let chunk_size = links.len().div_ceil(threads());
+        std::thread::scope(|scope| {
+            let chunks = links
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|(original, link)| retry(|| platform::symlink(original, link)))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            chunks
+                .into_iter()
+                .flat_map(|chunk| chunk.join().expect("symlinking thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Walks `root` in parallel on at most `max_concurrency` threads, the files are sorted to
+    /// keep the output deterministic
+    fn walk(&self, root: &Path) -> Result<Vec<PathBuf>, DofiError> {
+        let files = Mutex::new(Vec::new());
+        let error = Mutex::new(None);
+
+        build_walker(root)?
+            .threads(threads())
+            .build_parallel()
+            .run(|| {
+                Box::new(|entry| {
+                    timings::count_operations(1);
+                    match entry {
+                        Ok(entry) => {
+                            if entry.file_type().is_some_and(|file_type| {
+                                file_type.is_file() || file_type.is_symlink()
+                            }) {
+                                files.lock().unwrap().push(entry.into_path());
+                            }
+                            WalkState::Continue
+                        }
+                        Err(e) => {
+                            *error.lock().unwrap() = Some(e);
+                            WalkState::Quit
+                        }
+                    }
+                })
+            });
+
+        if let Some(e) = error.into_inner().unwrap() {
+            return Err(e.into());
+        }
+        let mut files = files.into_inner().unwrap();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Walks `root` on the calling thread, reading one directory at a time in sorted order so
+    /// only the directories above the current file are held
+    fn walk_each(
+        &self,
+        root: &Path,
+        visit: &mut dyn FnMut(PathBuf) -> Result<(), DofiError>,
+    ) -> Result<(), DofiError> {
+        for entry in build_walker(root)?
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build()
+        {
+            timings::count_operations(1);
+            let entry = entry?;
+            if entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file() || file_type.is_symlink())
+            {
+                visit(entry.into_path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Below this many symlinks spawning threads costs more than it saves
+const PARALLEL_THRESHOLD: usize = 256;
+
+fn build_walker(path: &Path) -> Result<WalkBuilder, DofiError> {
+    let mut overrides = OverrideBuilder::new(path);
+    for directory in VCS_DIRECTORIES {
+        overrides.add(&format!("!{directory}/"))?;
+    }
+    let overrides = overrides.build()?;
+
+    let mut builder = WalkBuilder::new(path);
+    // Ignore files only apply where asked for, see `Manifest::gitignore`
+    builder.standard_filters(false).overrides(overrides);
+    Ok(builder)
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    File(Vec<u8>, u32),
+    Directory(u32),
+    Symlink(PathBuf),
+}
+
+/// An in-memory filesystem, useful for testing operations without touching the disk.
+///
+/// Paths are taken literally: there is no notion of a current directory and symlinks are
+/// never followed, except by [`Fs::read`] and [`Fs::copy`] which resolve a single level.
+/// Hard links are files kept in sync, writes and mode changes go to all of them.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    nodes: RefCell<BTreeMap<PathBuf, Node>>,
+    /// The groups of paths hard linked to each other
+    hard_links: RefCell<Vec<BTreeSet<PathBuf>>>,
+    /// The directories standing in for separate filesystems
+    mount_points: RefCell<BTreeSet<PathBuf>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `path` a separate filesystem, renames into or out of it fail like they would
+    /// across devices
+    pub fn mount(&self, path: &Path) {
+        self.mount_points.borrow_mut().insert(path.to_path_buf());
+    }
+
+    /// The innermost mount point holding `path`, if any
+    fn device(&self, path: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .find(|ancestor| self.mount_points.borrow().contains(*ancestor))
+            .map(Path::to_path_buf)
+    }
+
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if parent.parent().is_some() => match self.nodes.borrow().get(parent) {
+                Some(Node::Directory(_)) => Ok(()),
+                Some(_) => Err(ErrorKind::NotADirectory.into()),
+                None => Err(ErrorKind::NotFound.into()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn check_vacant(&self, path: &Path) -> io::Result<()> {
+        self.check_parent(path)?;
+        if self.nodes.borrow().contains_key(path) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        Ok(())
+    }
+
+    /// The other paths hard linked to `path`
+    fn hard_links_of(&self, path: &Path) -> Vec<PathBuf> {
+        self.hard_links
+            .borrow()
+            .iter()
+            .find(|group| group.contains(path))
+            .map(|group| {
+                group
+                    .iter()
+                    .filter(|other| *other != path)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forgets that `path` is hard linked to other paths
+    fn unlink(&self, path: &Path) {
+        let mut groups = self.hard_links.borrow_mut();
+        for group in groups.iter_mut() {
+            group.remove(path);
+        }
+        groups.retain(|group| group.len() > 1);
+    }
+
+    /// Returns the contents and mode of the file at `path`
+    fn resolve(&self, path: &Path) -> io::Result<(Vec<u8>, u32)> {
+        match self.nodes.borrow().get(path) {
+            Some(Node::File(contents, mode)) => Ok((contents.clone(), *mode)),
+            Some(Node::Symlink(original)) => match self.nodes.borrow().get(original) {
+                Some(Node::File(contents, mode)) => Ok((contents.clone(), *mode)),
+                _ => Err(ErrorKind::NotFound.into()),
+            },
+            Some(Node::Directory(_)) => Err(ErrorKind::IsADirectory.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+}
+
+impl Fs for MemoryFs {
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if !self.nodes.borrow().contains_key(from) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        self.check_parent(to)?;
+        if self.device(from) != self.device(to) {
+            return Err(ErrorKind::CrossesDevices.into());
+        }
+
+        let mut nodes = self.nodes.borrow_mut();
+        let moved = nodes
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in moved {
+            self.unlink(&path);
+            let node = nodes.remove(&path).expect("path was just listed");
+            let relative = path.strip_prefix(from).expect("path starts with from");
+            nodes.insert(to.join(relative), node);
+        }
+
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (contents, mode) = self.resolve(from)?;
+        self.check_parent(to)?;
+        self.nodes
+            .borrow_mut()
+            .insert(to.to_path_buf(), Node::File(contents, mode));
+        Ok(())
+    }
+
+    fn symlink(&self, original: &Path, link: &Path) -> io::Result<()> {
+        self.check_vacant(link)?;
+        self.nodes
+            .borrow_mut()
+            .insert(link.to_path_buf(), Node::Symlink(original.to_path_buf()));
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let Some(node @ Node::File(..)) = self.nodes.borrow().get(original).cloned() else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        self.check_vacant(link)?;
+        self.nodes.borrow_mut().insert(link.to_path_buf(), node);
+
+        let mut groups = self.hard_links.borrow_mut();
+        match groups.iter_mut().find(|group| group.contains(original)) {
+            Some(group) => {
+                group.insert(link.to_path_buf());
+            }
+            None => groups.push([original.to_path_buf(), link.to_path_buf()].into()),
+        }
+        Ok(())
+    }
+
+    fn same_file(&self, a: &Path, b: &Path) -> bool {
+        (a == b && self.exists(a)) || self.hard_links_of(a).iter().any(|other| other == b)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.nodes.borrow().get(path) {
+            Some(Node::Symlink(original)) => Ok(original.clone()),
+            Some(_) => Err(ErrorKind::InvalidInput.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        match nodes.get(path) {
+            Some(Node::Directory(_)) => Err(ErrorKind::IsADirectory.into()),
+            Some(_) => {
+                nodes.remove(path);
+                self.unlink(path);
+                Ok(())
+            }
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        match nodes.get(path) {
+            Some(Node::Directory(_)) => {
+                if nodes
+                    .keys()
+                    .any(|other| other != path && other.starts_with(path))
+                {
+                    return Err(ErrorKind::DirectoryNotEmpty.into());
+                }
+                nodes.remove(path);
+                Ok(())
+            }
+            Some(_) => Err(ErrorKind::NotADirectory.into()),
+            None => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        for ancestor in path.ancestors().filter(|a| a.parent().is_some()) {
+            match nodes.get(ancestor) {
+                Some(Node::Directory(_)) => {}
+                Some(_) => return Err(ErrorKind::NotADirectory.into()),
+                None => {
+                    nodes.insert(ancestor.to_path_buf(), Node::Directory(0o755));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = match self.nodes.borrow().get(path) {
+            Some(Node::File(contents, mode)) => Metadata {
+                file_type: FileType::File,
+                len: contents.len() as u64,
+                mode: *mode,
+                modified: None,
+            },
+            Some(Node::Directory(mode)) => Metadata {
+                file_type: FileType::Directory,
+                len: 0,
+                mode: *mode,
+                modified: None,
+            },
+            Some(Node::Symlink(original)) => Metadata {
+                file_type: FileType::Symlink,
+                len: original.as_os_str().len() as u64,
+                mode: 0o777,
+                modified: None,
+            },
+            None => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(metadata)
+    }
+
+    fn copy_metadata(&self, from: &Path, to: &Path) -> io::Result<Vec<String>> {
+        let (_, mode) = self.resolve(from)?;
+        self.set_mode(to, mode)?;
+        Ok(Vec::new())
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        self.resolve(path).map(|_| ())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut nodes = self.nodes.borrow_mut();
+        match nodes.get_mut(path) {
+            Some(Node::File(_, current) | Node::Directory(current)) => *current = mode,
+            Some(Node::Symlink(_)) => return Err(ErrorKind::InvalidInput.into()),
+            None => return Err(ErrorKind::NotFound.into()),
+        }
+        for other in self.hard_links_of(path) {
+            if let Some(Node::File(_, current)) = nodes.get_mut(&other) {
+                *current = mode;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.resolve(path).map(|(contents, _)| contents)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.check_parent(path)?;
+        let mode = match self.nodes.borrow().get(path) {
+            Some(Node::Directory(_)) => return Err(ErrorKind::IsADirectory.into()),
+            Some(Node::File(_, mode)) => *mode,
+            _ => 0o644,
+        };
+        let mut nodes = self.nodes.borrow_mut();
+        for path in std::iter::once(path.to_path_buf()).chain(self.hard_links_of(path)) {
+            nodes.insert(path, Node::File(contents.to_vec(), mode));
+        }
+        Ok(())
+    }
+
+    fn walk(&self, root: &Path) -> Result<Vec<PathBuf>, DofiError> {
+        Ok(self
+            .nodes
+            .borrow()
+            .iter()
+            .filter(|(path, node)| {
+                matches!(node, Node::File(..) | Node::Symlink(_)) && path.starts_with(root)
+            })
+            .filter(|(path, _)| {
+                !path
+                    .strip_prefix(root)
+                    .expect("path starts with root")
+                    .components()
+                    .any(|component| is_vcs_directory(component.as_os_str()))
+            })
+            .map(|(path, _)| path.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,198 @@
+//! Targets whose contents are the output of a command, like shell completions or the
+//! environment a package manager wants sourced.
+//!
+//! The `generated` section of the repo [`Manifest`] maps base-relative targets to the command
+//! producing them, with an optional time to live for its output, a day by default:
+//!
+//! ```toml
+//! [generated]
+//! ".config/brew/shellenv.sh" = "brew shellenv"
+//! ".zsh/completions/_kubectl" = { command = "kubectl completion zsh", ttl = "1w" }
+//! ```
+//!
+//! `apply` runs the commands through the shell in the dotfiles directory and writes their
+//! output to the targets. The output is cached in the state directory, so until its time to
+//! live has passed `apply` uses it without running the command again. A command that fails
+//! leaves its target with the cached output if there is one. Generated targets are recorded
+//! like rendered ones: they are only replaced while nobody changed them.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{checksum, query, DofiError, Fs, Journal, Manifest};
+
+/// The directory in the state directory caching the output of the commands
+pub const CACHE_DIRECTORY: &str = "generated";
+
+/// How long the output of a command is used when the manifest does not say
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A command generating a target
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Declaration")]
+pub struct Generator {
+    pub command: String,
+    /// How long its output is used before it runs again
+    pub ttl: Duration,
+}
+
+/// A generator as written in the manifest, the command alone or a table
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Declaration {
+    Command(String),
+    Table {
+        command: String,
+        ttl: Option<String>,
+    },
+}
+
+impl TryFrom<Declaration> for Generator {
+    type Error = String;
+
+    fn try_from(declaration: Declaration) -> Result<Self, Self::Error> {
+        let (command, ttl) = match declaration {
+            Declaration::Command(command) => (command, None),
+            Declaration::Table { command, ttl } => (command, ttl),
+        };
+        let ttl = match ttl {
+            Some(ttl) => query::parse_duration(&ttl)
+                .ok_or_else(|| format!("invalid ttl '{ttl}', expected one like '30m' or '1d'"))?,
+            None => DEFAULT_TTL,
+        };
+        Ok(Generator { command, ttl })
+    }
+}
+
+/// Writes the output of the generators of the layered `dotfiles_directories` to their
+/// targets in `base_directory`, the generator of the last layer declaring a target wins.
+/// Existing targets dofi did not write, or that changed since, are only replaced with `force`.
+/// Returns the number of written targets.
+pub fn generate(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    force: bool,
+    journal: &mut Journal,
+) -> Result<usize, DofiError> {
+    let mut generators = BTreeMap::new();
+    for layer in dotfiles_directories {
+        for (target, generator) in Manifest::load(fs, layer)?.generated {
+            generators.insert(base_directory.join(target), (generator, layer));
+        }
+    }
+
+    let state_directory = journal.directory().to_path_buf();
+    let checksums = checksum::load(fs, &state_directory)?;
+    let mut generated = 0;
+    for (target, (generator, layer)) in generators {
+        let output = output(fs, &generator, layer, &state_directory)?;
+        if fs.read(&target).is_ok_and(|contents| contents == output) {
+            continue;
+        }
+        if !force
+            && fs.symlink_metadata(&target).is_ok()
+            && checksum::modified(fs, &checksums, &target) != Some(false)
+        {
+            return Err(DofiError::FileExists(target));
+        }
+
+        if let Some(parent) = target.parent() {
+            journal.create_dir_all(fs, parent)?;
+        }
+        info!(
+            "Writing the output of '{}' to '{}'",
+            generator.command,
+            target.display()
+        );
+        journal.write_file(fs, &target, &output)?;
+        checksum::record(fs, &target, &output, generator.command.as_bytes(), journal)?;
+        generated += 1;
+    }
+
+    Ok(generated)
+}
+
+/// The output of `generator`, from the cache while it is fresh. The cache file holds the
+/// unix time the command ran on its first line, followed by the output.
+fn output(
+    fs: &dyn Fs,
+    generator: &Generator,
+    dotfiles_directory: &Path,
+    state_directory: &Path,
+) -> Result<Vec<u8>, DofiError> {
+    let key = format!("{}\0{}", dotfiles_directory.display(), generator.command);
+    let cache = state_directory
+        .join(CACHE_DIRECTORY)
+        .join(checksum::digest(key.as_bytes()));
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let cached = fs.read(&cache).ok().and_then(|contents| {
+        let newline = contents.iter().position(|&byte| byte == b'\n')?;
+        let ran = std::str::from_utf8(&contents[..newline])
+            .ok()?
+            .parse::<u64>()
+            .ok()?;
+        Some((ran, contents[newline + 1..].to_vec()))
+    });
+    match &cached {
+        Some((ran, output)) if now.saturating_sub(*ran) < generator.ttl.as_secs() => {
+            return Ok(output.clone());
+        }
+        _ => {}
+    }
+
+    match run(&generator.command, dotfiles_directory) {
+        Ok(output) => {
+            fs.create_dir_all(&state_directory.join(CACHE_DIRECTORY))?;
+            fs.write(&cache, &[format!("{now}\n").as_bytes(), &output].concat())?;
+            Ok(output)
+        }
+        Err(e) => match cached {
+            Some((_, output)) => {
+                warn!("{e}, using the output of an earlier run");
+                Ok(output)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Runs `command` through the shell in `directory`, returning its stdout
+fn run(command: &str, directory: &Path) -> Result<Vec<u8>, DofiError> {
+    info!("Running '{command}'");
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .current_dir(directory)
+        .output()
+        .map_err(|e| DofiError::ExternalCommandFailed(command.to_string(), e.to_string()))?;
+
+    if output.status.success() {
+        return Ok(output.stdout);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    let reason = if stderr.is_empty() {
+        format!("exited with {}", output.status)
+    } else {
+        stderr
+    };
+    Err(DofiError::ExternalCommandFailed(
+        command.to_string(),
+        reason,
+    ))
+}
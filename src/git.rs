@@ -0,0 +1,357 @@
+//! Inspecting the git repository the dotfiles live in.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum, paths, DofiError};
+
+/// Marks a push that failed and is retried with the next one, in the state directory. Each
+/// repository has its own marker, named after a digest of its path and holding the path.
+const PENDING_PUSH_FILE: &str = "pending-push";
+/// The commits the layers were at when everything was last linked, in the state directory
+const LINKED_FILE: &str = "linked.json";
+
+/// Lists the uncommitted changes in the git repository containing `directory`, as reported
+/// by `git status --porcelain`. Returns `None` if `directory` is not in a git repository or
+/// git is not installed.
+pub fn uncommitted_changes(directory: &Path) -> Option<Vec<String>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .arg("status")
+        .arg("--porcelain")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// How the git repository of the dotfiles stands against its upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncState {
+    /// The upstream branch together with the commits ahead of and behind it, if there is one
+    pub upstream: Option<(String, usize, usize)>,
+    /// The number of uncommitted changes
+    pub uncommitted: usize,
+}
+
+/// The [`SyncState`] of the git repository containing `directory`, `None` if it is not in one
+pub fn sync_state(directory: &Path) -> Option<SyncState> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(["status", "--porcelain=v2", "--branch"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let mut upstream = None;
+    let mut counts = None;
+    let mut uncommitted = 0;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(name) = line.strip_prefix("# branch.upstream ") {
+            upstream = Some(name.to_string());
+        } else if let Some(ab) = line.strip_prefix("# branch.ab ") {
+            counts = ab.split_once(' ').and_then(|(ahead, behind)| {
+                Some((
+                    ahead.trim_start_matches('+').parse().ok()?,
+                    behind.trim_start_matches('-').parse().ok()?,
+                ))
+            });
+        } else if !line.starts_with('#') {
+            uncommitted += 1;
+        }
+    }
+
+    Some(SyncState {
+        upstream: upstream.map(|name| {
+            let (ahead, behind) = counts.unwrap_or_default();
+            (name, ahead, behind)
+        }),
+        uncommitted,
+    })
+}
+
+/// Fails if the git repository containing `directory` has uncommitted changes, so a
+/// destructive operation cannot get them mixed up with the files it moves
+pub fn ensure_clean(directory: &Path) -> Result<(), DofiError> {
+    match uncommitted_changes(directory) {
+        Some(changes) if !changes.is_empty() => {
+            for change in &changes {
+                warn!("Uncommitted change: {change}");
+            }
+            Err(DofiError::DirtyRepository(
+                directory.to_path_buf(),
+                changes.len(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Turns `directory` into a git repository, unless it already is inside one
+pub fn init(directory: &Path) -> Result<(), DofiError> {
+    if uncommitted_changes(directory).is_some() {
+        info!("'{}' is already in a git repository", directory.display());
+        return Ok(());
+    }
+
+    let status = Command::new("git")
+        .arg("init")
+        .arg("--quiet")
+        .arg(directory)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed("git init".to_string(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            "git init".to_string(),
+            format!("exited with {status}"),
+        ))
+    }
+}
+
+/// Runs git with `args` inside `directory`, sharing dofi's stdio, and returns how it exited
+pub fn run(directory: &Path, args: &[OsString]) -> Result<ExitStatus, DofiError> {
+    Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed("git".to_string(), e.to_string()))
+}
+
+/// Commits the changes to `paths` in the git repository at `directory` with `message`.
+/// Returns whether there was anything to commit.
+pub fn commit(directory: &Path, paths: &[PathBuf], message: &str) -> Result<bool, DofiError> {
+    // Paths that neither exist nor are tracked, e.g. a manifest that was never written, would
+    // make git reject the whole pathspec
+    let paths = paths
+        .iter()
+        .filter(|path| path.exists() || is_tracked(directory, path))
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Ok(false);
+    }
+
+    git(directory, ["add", "--all", "--"], &paths)?;
+    let staged = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(["diff", "--cached", "--quiet", "--"])
+        .args(&paths)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed("git diff".to_string(), e.to_string()))?;
+    if staged.success() {
+        return Ok(false);
+    }
+
+    info!("Committing '{message}'");
+    git(
+        directory,
+        ["commit", "--quiet", "--message", message, "--"],
+        &paths,
+    )?;
+
+    Ok(true)
+}
+
+/// Pushes the current branch of the git repository at `directory` to its upstream
+pub fn push(directory: &Path) -> Result<(), DofiError> {
+    info!("Pushing '{}'", directory.display());
+    git(directory, ["push", "--quiet"], &[] as &[&Path])
+}
+
+/// Pushes like [`push`], but if pushing fails, e.g. while offline, only warns and remembers in
+/// `state_directory` to retry with the next push of `directory` or [`push_pending`]
+pub fn push_or_queue(directory: &Path, state_directory: &Path) -> Result<(), DofiError> {
+    let marker = pending_push_marker(directory, state_directory);
+    match push(directory) {
+        Ok(()) => match std::fs::remove_file(&marker) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        },
+        Err(e) => {
+            warn!("{e}, pushing again next time");
+            std::fs::create_dir_all(state_directory)?;
+            std::fs::write(&marker, paths::bytes(directory))?;
+            Ok(())
+        }
+    }
+}
+
+/// Whether an earlier [`push_or_queue`] of `directory` failed and still needs to be retried
+pub fn is_push_pending(directory: &Path, state_directory: &Path) -> bool {
+    pending_push_marker(directory, state_directory).exists()
+}
+
+/// Retries every push [`push_or_queue`] queued in `state_directory`, of whichever repository
+/// it was for. Pushes failing again stay queued. Returns how many pushes went through.
+pub fn push_pending(state_directory: &Path) -> Result<usize, DofiError> {
+    let entries = match std::fs::read_dir(state_directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    // Collected first, retrying writes the markers of the pushes failing again
+    let mut markers = Vec::new();
+    for entry in entries {
+        let marker = entry?.path();
+        if marker
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(PENDING_PUSH_FILE))
+        {
+            markers.push(marker);
+        }
+    }
+    let mut pushed = 0;
+    for marker in markers {
+        let directory = paths::from_bytes(std::fs::read(&marker)?);
+        if !directory.is_dir() {
+            warn!(
+                "Dropping the pending push of '{}', it no longer exists",
+                directory.display()
+            );
+            std::fs::remove_file(&marker)?;
+            continue;
+        }
+        // A marker written before they were kept per repository goes under its new name
+        if marker != pending_push_marker(&directory, state_directory) {
+            std::fs::remove_file(&marker)?;
+        }
+        push_or_queue(&directory, state_directory)?;
+        if !is_push_pending(&directory, state_directory) {
+            pushed += 1;
+        }
+    }
+    Ok(pushed)
+}
+
+/// The marker [`push_or_queue`] leaves in `state_directory` when pushing `directory` failed
+fn pending_push_marker(directory: &Path, state_directory: &Path) -> PathBuf {
+    let digest = checksum::digest(&paths::bytes(directory));
+    state_directory.join(format!("{PENDING_PUSH_FILE}-{}", &digest[..16]))
+}
+
+/// The commit each layer was at when it was last linked, as it is stored
+#[derive(Serialize, Deserialize)]
+struct Linked(#[serde(with = "crate::paths::serde::map")] BTreeMap<PathBuf, String>);
+
+/// Records in `state_directory` the commit each of the `layers` is at, after all of them were
+/// linked. Layers outside of git repositories are left out.
+pub fn record_linked(state_directory: &Path, layers: &[PathBuf]) -> Result<(), DofiError> {
+    let commits = layers
+        .iter()
+        .filter_map(|layer| Some((layer.clone(), output(layer, &["rev-parse", "HEAD"])?)))
+        .map(|(layer, head)| (layer, String::from_utf8_lossy(&head).trim().to_string()))
+        .collect::<BTreeMap<_, _>>();
+    let contents = serde_json::to_vec_pretty(&Linked(commits)).map_err(std::io::Error::from)?;
+    std::fs::create_dir_all(state_directory)?;
+    std::fs::write(state_directory.join(LINKED_FILE), contents)?;
+    Ok(())
+}
+
+/// The files added to each of the `layers` since [`record_linked`], committed or not, relative
+/// to their layer. `None` if a layer was not recorded, e.g. because it is not in a git
+/// repository, or its commit is gone.
+pub fn added_since_linked(state_directory: &Path, layers: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    let contents = std::fs::read(state_directory.join(LINKED_FILE)).ok()?;
+    let Linked(commits) = serde_json::from_slice(&contents).ok()?;
+    let mut added = Vec::new();
+    for layer in layers {
+        let commit = commits.get(layer)?;
+        // Renamed files are new at their new path
+        let committed = output(
+            layer,
+            &[
+                "diff",
+                "--name-only",
+                "--relative",
+                "--no-renames",
+                "--diff-filter=A",
+                "-z",
+                commit,
+                "HEAD",
+            ],
+        )?;
+        let untracked = output(layer, &["ls-files", "--others", "--exclude-standard", "-z"])?;
+        added.extend(
+            [committed, untracked]
+                .iter()
+                .flat_map(|paths| paths.split(|&byte| byte == 0))
+                .filter(|path| !path.is_empty())
+                .map(|path| PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+                .filter(|path| layer.join(path).exists()),
+        );
+    }
+    added.sort();
+    added.dedup();
+    Some(added)
+}
+
+/// The output of git with `args` in `directory`, `None` if it fails
+fn output(directory: &Path, args: &[&str]) -> Option<Vec<u8>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .output()
+        .ok()?;
+    output.status.success().then_some(output.stdout)
+}
+
+fn is_tracked(directory: &Path, path: &Path) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(["ls-files", "--error-unmatch", "--"])
+        .arg(path)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Runs git with `args` followed by `paths` in `directory`, failing with its error output
+fn git<const N: usize>(
+    directory: &Path,
+    args: [&str; N],
+    paths: &[impl AsRef<Path>],
+) -> Result<(), DofiError> {
+    let subcommand = format!("git {}", args[0]);
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .args(paths.iter().map(AsRef::as_ref))
+        .output()
+        .map_err(|e| DofiError::ExternalCommandFailed(subcommand.clone(), e.to_string()))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            subcommand,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
@@ -0,0 +1,74 @@
+//! Searching the contents of the managed dotfiles.
+//!
+//! Only the dotfiles that would be linked are searched, so ignored and excluded files and
+//! those of other machines are left out. Binary files, recognised by a NUL byte near their
+//! start, and encrypted dotfiles are skipped.
+
+use std::path::{Path, PathBuf};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{encryption, layered_dotfiles, DofiError, Fs};
+
+/// How far into a file to look for a NUL byte when telling whether it is binary
+const BINARY_PROBE: usize = 8192;
+
+/// A line of a dotfile matching the pattern
+#[derive(Debug, PartialEq, Eq)]
+pub struct Match {
+    /// The dotfile, relative to its dotfiles directory
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// The number of the line, starting at 1
+    pub line_number: usize,
+    pub line: String,
+}
+
+/// Compiles the regular expression `pattern`, matching case insensitively if `ignore_case`
+pub fn pattern(pattern: &str, ignore_case: bool) -> Result<Regex, DofiError> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| DofiError::InvalidSearchPattern(pattern.to_string(), e.to_string()))
+}
+
+/// Searches the dotfiles of the layered `dotfiles_directories` for lines matching `pattern`
+pub fn grep(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    pattern: &Regex,
+) -> Result<Vec<Match>, DofiError> {
+    let mut matches = Vec::new();
+
+    for dotfile in layered_dotfiles(fs, base_directory, dotfiles_directories)? {
+        if encryption::is_encrypted(&dotfile.source) {
+            continue;
+        }
+        let contents = fs.read(&dotfile.source)?;
+        if is_binary(&contents) {
+            continue;
+        }
+
+        let source = dotfile
+            .source
+            .strip_prefix(&dotfile.layer)
+            .unwrap_or(&dotfile.source);
+        for (index, line) in String::from_utf8_lossy(&contents).lines().enumerate() {
+            if pattern.is_match(line) {
+                matches.push(Match {
+                    source: source.to_path_buf(),
+                    target: dotfile.target.clone(),
+                    line_number: index + 1,
+                    line: line.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+fn is_binary(contents: &[u8]) -> bool {
+    contents[..contents.len().min(BINARY_PROBE)].contains(&0)
+}
@@ -0,0 +1,138 @@
+//! Keeping files that would bloat the repo out of it when they are added.
+//!
+//! `add` refuses files above `add.max_size` in the user configuration, 1 MiB unless set, and
+//! binary files, which git can neither diff nor store compactly. It also refuses history and
+//! cache files like `.zsh_history` or `.lesshst`, which change with every use of their program:
+//!
+//! ```toml
+//! [add]
+//! max_size = 5_000_000
+//! ```
+//!
+//! `--allow-large` adds them anyway. History and cache files inside a directory added as a
+//! whole are only warned about, listing them in the repo's `.gitignore` keeps them out.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::{fs::FileType, stats::format_size, DofiError, Fs};
+
+/// The size above which files are refused unless `add.max_size` says otherwise
+pub const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// How much of a file is looked at to tell whether it is binary, like git does
+const BINARY_PROBE: usize = 8000;
+
+/// The names of files programs keep their history or caches in
+const VOLATILE_NAMES: &[&str] = &[
+    ".bash_history",
+    ".zsh_history",
+    ".histfile",
+    ".lesshst",
+    ".python_history",
+    ".node_repl_history",
+    ".psql_history",
+    ".mysql_history",
+    ".sqlite_history",
+    ".viminfo",
+    ".wget-hsts",
+    ".recently-used",
+    ".xsession-errors",
+    "fish_history",
+];
+
+/// The `add` section of the user configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AddConfig {
+    /// The size in bytes above which files are refused
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+}
+
+impl Default for AddConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+fn default_max_size() -> u64 {
+    DEFAULT_MAX_SIZE
+}
+
+/// Why a file does not belong in the repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Concern {
+    /// It holds this many bytes, more than allowed
+    Large(u64),
+    /// It is not text
+    Binary,
+    /// It is a history or cache file
+    Volatile,
+}
+
+impl Concern {
+    /// The concern as the end of "it is ..."
+    pub fn describe(&self, max_size: u64) -> String {
+        match self {
+            Concern::Large(size) => format!(
+                "{}, above the limit of {}",
+                format_size(*size),
+                format_size(max_size)
+            ),
+            Concern::Binary => "a binary file".to_string(),
+            Concern::Volatile => "a history or cache file changing with every use".to_string(),
+        }
+    }
+}
+
+/// The files at or below `path` that should not be added, with why
+pub fn inspect(
+    fs: &dyn Fs,
+    path: &Path,
+    max_size: u64,
+) -> Result<Vec<(PathBuf, Concern)>, DofiError> {
+    let files = match fs.symlink_metadata(path)?.file_type {
+        FileType::Directory => fs.walk(path)?,
+        _ => vec![path.to_path_buf()],
+    };
+    let mut concerns = Vec::new();
+    for file in files {
+        let metadata = fs.symlink_metadata(&file)?;
+        if metadata.file_type != FileType::File {
+            continue;
+        }
+        let concern = if is_volatile(&file) {
+            Some(Concern::Volatile)
+        } else if metadata.len > max_size {
+            Some(Concern::Large(metadata.len))
+        } else if is_binary(&fs.read(&file)?) {
+            Some(Concern::Binary)
+        } else {
+            None
+        };
+        concerns.extend(concern.map(|concern| (file, concern)));
+    }
+    Ok(concerns)
+}
+
+/// Whether `path` is named like a history or cache file
+pub fn is_volatile(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    VOLATILE_NAMES.contains(&name)
+        || name.starts_with(".zcompdump")
+        || name.ends_with(".log")
+        || path
+            .components()
+            .any(|component| component.as_os_str() == ".cache")
+}
+
+/// Whether `contents` hold a NUL byte near their start
+fn is_binary(contents: &[u8]) -> bool {
+    contents[..contents.len().min(BINARY_PROBE)].contains(&0)
+}
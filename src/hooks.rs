@@ -0,0 +1,221 @@
+//! Commands run before and after dofi changes the filesystem.
+//!
+//! Hooks are declared in the repo [`Manifest`], either for an event or for a single dotfile,
+//! which runs whenever that dotfile was linked:
+//!
+//! ```toml
+//! [hooks]
+//! post-link = "fc-cache -f"
+//!
+//! [hooks.files]
+//! ".ssh/config" = "chmod 600 ~/.ssh/config"
+//! ```
+//!
+//! Built-in hooks install the plugins of a plugin manager after the dotfiles of a package, a
+//! top-level directory of the dotfiles, were linked, so a new machine gets the plugins its
+//! configuration lists right away. The `packages` section selects them for each package:
+//!
+//! ```toml
+//! [hooks.packages]
+//! fish = ["fisher"]
+//! nvim = ["lazy"]
+//! ```
+//!
+//! - `fisher` runs `fisher update`
+//! - `zinit` compiles the plugins with `zinit compile --all`
+//! - `zplug` installs the missing plugins with `zplug install`
+//! - `tpm` installs the tmux plugins with the `install_plugins` script of tpm
+//! - `lazy` syncs the Neovim plugins with `nvim --headless "+Lazy! sync" +qa`
+//!
+//! Commands run through the shell (`sh -c`, `cmd /C` on Windows) in the dotfiles directory
+//! with `DOFI_EVENT`, `DOFI_BASE`, `DOFI_DOTFILES` and `DOFI_CHANGED`, the changed paths
+//! separated by newlines, set, together with one `DOFI_VAR_<NAME>` per [variable](crate::vars).
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::{package_dotfiles, timings, vars, DofiError, Fs, Manifest};
+
+/// The points at which hooks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    PreLink,
+    PostLink,
+    PostAdd,
+    PostRemove,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Event::PreLink => "pre-link",
+            Event::PostLink => "post-link",
+            Event::PostAdd => "post-add",
+            Event::PostRemove => "post-remove",
+        })
+    }
+}
+
+/// The hooks section of the manifest
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Hooks {
+    pub pre_link: Option<String>,
+    pub post_link: Option<String>,
+    pub post_add: Option<String>,
+    pub post_remove: Option<String>,
+    /// Repo-relative dotfiles mapped to a command run after the dotfile was linked
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, String>,
+    /// Packages mapped to the plugin managers whose built-in hooks run after they were linked
+    #[serde(default)]
+    pub packages: BTreeMap<String, Vec<PluginManager>>,
+}
+
+impl Hooks {
+    fn command(&self, event: Event) -> Option<&str> {
+        match event {
+            Event::PreLink => self.pre_link.as_deref(),
+            Event::PostLink => self.post_link.as_deref(),
+            Event::PostAdd => self.post_add.as_deref(),
+            Event::PostRemove => self.post_remove.as_deref(),
+        }
+    }
+}
+
+/// A plugin manager with a built-in hook, see the [module documentation](self)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PluginManager {
+    Fisher,
+    Zinit,
+    Zplug,
+    Tpm,
+    Lazy,
+}
+
+impl PluginManager {
+    /// The command installing the plugins
+    pub fn command(self) -> &'static str {
+        match self {
+            PluginManager::Fisher => "fish -c \"fisher update\"",
+            PluginManager::Zinit => "zsh -ic \"zinit compile --all\"",
+            PluginManager::Zplug => "zsh -ic \"zplug check || zplug install\"",
+            PluginManager::Tpm => "\"$HOME/.tmux/plugins/tpm/bin/install_plugins\"",
+            PluginManager::Lazy => "nvim --headless \"+Lazy! sync\" +qa",
+        }
+    }
+}
+
+/// Runs the hook of `event` followed by the hooks of every dotfile and every package linked to
+/// one of the `changed` targets
+pub fn run(
+    fs: &dyn Fs,
+    manifest: &Manifest,
+    event: Event,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    changed: &[PathBuf],
+    variables: &BTreeMap<String, String>,
+) -> Result<(), DofiError> {
+    let _hooks = timings::phase("hooks");
+    let environment = Environment {
+        event,
+        base_directory,
+        dotfiles_directory,
+        changed,
+        variables,
+    };
+
+    if let Some(command) = manifest.hooks.command(event) {
+        environment.run(command)?;
+    }
+
+    if matches!(event, Event::PostLink | Event::PostAdd) {
+        for (file, command) in &manifest.hooks.files {
+            let target = manifest.target_path(
+                &dotfiles_directory.join(file),
+                base_directory,
+                dotfiles_directory,
+            )?;
+            if changed.contains(&target) {
+                environment.run(command)?;
+            }
+        }
+    }
+
+    if event == Event::PostLink {
+        for (package, managers) in &manifest.hooks.packages {
+            let mut linked = false;
+            for dotfile in package_dotfiles(fs, dotfiles_directory, package)? {
+                let target = manifest.target_path(&dotfile, base_directory, dotfiles_directory)?;
+                linked |= changed.contains(&target);
+            }
+            if linked {
+                for manager in managers {
+                    environment.run(manager.command())?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct Environment<'a> {
+    event: Event,
+    base_directory: &'a Path,
+    dotfiles_directory: &'a Path,
+    changed: &'a [PathBuf],
+    variables: &'a BTreeMap<String, String>,
+}
+
+impl Environment<'_> {
+    fn run(&self, command: &str) -> Result<(), DofiError> {
+        info!("Running {} hook '{command}'", self.event);
+
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let changed = self
+            .changed
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let status = Command::new(shell)
+            .arg(flag)
+            .arg(command)
+            .current_dir(self.dotfiles_directory)
+            .env("DOFI_EVENT", self.event.to_string())
+            .env("DOFI_BASE", self.base_directory)
+            .env("DOFI_DOTFILES", self.dotfiles_directory)
+            .env("DOFI_CHANGED", changed)
+            .envs(
+                self.variables
+                    .iter()
+                    .map(|(name, value)| (vars::environment_name(name), value)),
+            )
+            .status()
+            .map_err(|e| DofiError::ExternalCommandFailed(command.to_string(), e.to_string()))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(DofiError::ExternalCommandFailed(
+                command.to_string(),
+                format!("exited with {status}"),
+            ))
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! What `link --force-all` would change, as a review before running it on a long-lived machine.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    conflict::{ConflictPolicies, ConflictPolicy},
+    hooks::Hooks,
+    status::{self, State},
+    DofiError, Fs, Manifest,
+};
+
+/// A process holding a target open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    pub target: PathBuf,
+    pub pid: u32,
+    pub command: String,
+}
+
+/// The changes `link --force-all` would make
+#[derive(Debug, Default)]
+pub struct Impact {
+    /// Targets that would be linked for the first time
+    pub linked: Vec<PathBuf>,
+    /// Existing targets that would be replaced
+    pub replaced: Vec<PathBuf>,
+    /// Existing targets kept because of a never-force policy
+    pub kept: Vec<PathBuf>,
+    /// Processes holding a replaced target open, `None` if `lsof` is not available
+    pub open: Option<Vec<OpenFile>>,
+    /// The hook commands that would run afterwards
+    pub hooks: Vec<String>,
+}
+
+/// Works out what `link --force-all` would change in `base_directory`. Hooks come from the
+/// manifest of the first of `dotfiles_directories`.
+pub fn assess(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    policies: &ConflictPolicies,
+) -> Result<Impact, DofiError> {
+    let manifest = match dotfiles_directories.first() {
+        Some(dotfiles_directory) => Manifest::load(fs, dotfiles_directory)?,
+        None => Manifest::default(),
+    };
+    let mut impact = Impact::default();
+
+    for entry in status::entries(fs, base_directory, dotfiles_directories)? {
+        match entry.state {
+            State::Linked => continue,
+            State::Unlinked => impact.linked.push(entry.target.clone()),
+            State::Conflict | State::Broken
+                if policies.policy(&entry.target) == Some(ConflictPolicy::NeverForce) =>
+            {
+                impact.kept.push(entry.target);
+                continue;
+            }
+            State::Conflict | State::Broken => impact.replaced.push(entry.target.clone()),
+        }
+
+        if let Some(command) = manifest.hooks.files.get(entry.relative_source()) {
+            impact.hooks.push(command.clone());
+        }
+    }
+
+    let Hooks {
+        pre_link,
+        post_link,
+        ..
+    } = &manifest.hooks;
+    impact.hooks.splice(0..0, pre_link.iter().cloned());
+    impact.hooks.extend(post_link.iter().cloned());
+    impact.open = open_files(&impact.replaced);
+
+    Ok(impact)
+}
+
+/// Asks `lsof` which processes have any of `targets` open
+fn open_files(targets: &[PathBuf]) -> Option<Vec<OpenFile>> {
+    if targets.is_empty() {
+        return Some(Vec::new());
+    }
+
+    // lsof exits with 1 both when nothing is open and on errors, so only a failure to run it
+    // counts as lsof being unavailable
+    let output = Command::new("lsof")
+        .arg("-F")
+        .arg("pcn")
+        .arg("--")
+        .args(targets)
+        .output()
+        .ok()?;
+
+    let mut open = Vec::new();
+    let (mut pid, mut command) = (0, String::new());
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let (field, value) = line.split_at(line.len().min(1));
+        match field {
+            "p" => pid = value.parse().unwrap_or_default(),
+            "c" => command = value.to_string(),
+            "n" => open.push(OpenFile {
+                target: PathBuf::from(value),
+                pid,
+                command: command.clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    Some(open)
+}
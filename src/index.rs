@@ -0,0 +1,107 @@
+//! A cache of the link states `list` and `status` show, for repos too large to look at every
+//! target each time.
+//!
+//! `index.json` in the state directory keeps the entries of the last full scan together with a
+//! fingerprint of what they were computed from: the path, size and modification time of every
+//! file in the dotfiles directories, which of their conditions hold on this machine, the base
+//! directory and the journal, which every command changing targets writes to. While the
+//! fingerprint matches, the entries come from the index and no target is looked at. Targets
+//! changed by anything but dofi therefore go unnoticed until the repo or the journal changes,
+//! `--no-cache` scans them all again.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum, condition, journal, status, DofiError, Fs};
+
+/// The file in the state directory holding the index
+pub const INDEX_FILE: &str = "index.json";
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    fingerprint: String,
+    entries: Vec<status::Entry>,
+}
+
+/// The [entries](status::entries) of the layered `dotfiles_directories`, from the index in
+/// `state_directory` if `cached` is set and it is up to date. A full scan updates the index.
+pub fn entries(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+    cached: bool,
+) -> Result<Vec<status::Entry>, DofiError> {
+    let fingerprint = fingerprint(fs, base_directory, dotfiles_directories, state_directory)?;
+    let path = state_directory.join(INDEX_FILE);
+    if cached {
+        let index = fs
+            .read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<Index>(&contents).ok());
+        if let Some(index) = index.filter(|index| index.fingerprint == fingerprint) {
+            return Ok(index.entries);
+        }
+    }
+
+    let entries = status::entries(fs, base_directory, dotfiles_directories)?;
+    let index = Index {
+        fingerprint,
+        entries,
+    };
+    // The index only saves time, failing to write it must not fail the command
+    if let Err(e) = save(fs, &path, &index) {
+        warn!("Failed to write the index '{}': {e}", path.display());
+    }
+    Ok(index.entries)
+}
+
+fn fingerprint(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+) -> Result<String, DofiError> {
+    let mut fingerprint = format!("{}\n", base_directory.display());
+    let mut add = |path: &Path| {
+        let metadata = fs.symlink_metadata(path).ok();
+        let modified = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.modified)
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |modified| modified.as_nanos());
+        let holds = condition::condition(path).is_none_or(|condition| condition.holds());
+        let _ = writeln!(
+            fingerprint,
+            "{}\t{}\t{modified}\t{holds}",
+            path.display(),
+            metadata.map_or(0, |metadata| metadata.len)
+        );
+    };
+
+    add(&journal::journal_file(state_directory));
+    for layer in dotfiles_directories {
+        add(layer);
+        for path in fs.walk(layer)? {
+            add(&path);
+        }
+    }
+    Ok(checksum::digest(fingerprint.as_bytes()))
+}
+
+fn save(fs: &dyn Fs, path: &Path, index: &Index) -> Result<(), DofiError> {
+    let contents = serde_json::to_vec(index).map_err(std::io::Error::from)?;
+    let partial = path.with_extension("json.partial");
+    if let Some(parent) = path.parent() {
+        fs.create_dir_all(parent)?;
+    }
+    fs.write(&partial, &contents)?;
+    fs.rename(&partial, path)?;
+    Ok(())
+}
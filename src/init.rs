@@ -0,0 +1,70 @@
+//! Scaffolding a new dotfiles directory.
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::{manifest::MANIFEST_FILE, DofiError, Fs, Journal};
+
+/// The ignore file written next to the manifest
+pub const GITIGNORE_FILE: &str = ".gitignore";
+
+const STARTER_MANIFEST: &str = r#"# The dofi manifest, see `dofi --help` for the commands using it.
+
+# Files in the repo that are not dotfiles, besides the README and other documentation at the
+# root
+exclude = [".gitignore", "**/.DS_Store"]
+
+# Never link the files git ignores, like caches or `*.local` files
+gitignore = true
+
+# Dotfiles whose target does not mirror their location in the repo
+[targets]
+# "karabiner.json" = ".config/karabiner/karabiner.json"
+
+# Labels for dotfiles, `dofi link --tag gui` only links the tagged ones
+[tags]
+# gui = [".config/alacritty"]
+
+# Permission bits targets must have, `dofi link` applies them
+[modes]
+# ".ssh/config" = 0o600
+
+# Commands run whenever dofi changed the filesystem
+[hooks]
+# post-link = "fc-cache -f"
+"#;
+
+const STARTER_GITIGNORE: &str = "\
+.DS_Store
+*.swp
+*~
+";
+
+/// Creates `dotfiles_directory` with a starter manifest and `.gitignore`. Files that already
+/// exist are kept as they are. Returns the files that were written.
+pub fn scaffold(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<Vec<PathBuf>, DofiError> {
+    journal.create_dir_all(fs, dotfiles_directory)?;
+
+    let mut written = Vec::new();
+    for (name, contents) in [
+        (MANIFEST_FILE, STARTER_MANIFEST),
+        (GITIGNORE_FILE, STARTER_GITIGNORE),
+    ] {
+        let path = dotfiles_directory.join(name);
+        if fs.symlink_metadata(&path).is_ok() {
+            info!("Keeping the existing '{}'", path.display());
+            continue;
+        }
+
+        info!("Writing '{}'", path.display());
+        journal.write_file(fs, &path, contents.as_bytes())?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
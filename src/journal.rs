@@ -0,0 +1,644 @@
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    elevate,
+    events::{self, Event},
+    fs::{self, FileType},
+    permissions::{self, DirectoryModes},
+    DofiError, Fs,
+};
+
+/// A single reversible filesystem change performed by dofi
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Action {
+    /// A file was moved from `from` to `to`
+    Moved {
+        #[serde(with = "crate::paths::serde")]
+        from: PathBuf,
+        #[serde(with = "crate::paths::serde")]
+        to: PathBuf,
+    },
+    /// A symlink was created at `link` pointing to `target`
+    Symlinked {
+        #[serde(with = "crate::paths::serde")]
+        link: PathBuf,
+        #[serde(with = "crate::paths::serde")]
+        target: PathBuf,
+    },
+    /// A file at `path` was removed, its content is kept at `backup`
+    Removed {
+        #[serde(with = "crate::paths::serde")]
+        path: PathBuf,
+        #[serde(with = "crate::paths::serde")]
+        backup: PathBuf,
+    },
+    /// A directory was created at `path`
+    CreatedDirectory {
+        #[serde(with = "crate::paths::serde")]
+        path: PathBuf,
+    },
+    /// A new file was written at `path`
+    CreatedFile {
+        #[serde(with = "crate::paths::serde")]
+        path: PathBuf,
+    },
+    /// An empty directory was removed at `path`
+    RemovedDirectory {
+        #[serde(with = "crate::paths::serde")]
+        path: PathBuf,
+    },
+    /// A symlink was created as root at `link` pointing to `target`, replacing the file now
+    /// kept at `backup`
+    ElevatedSymlinked {
+        #[serde(with = "crate::paths::serde")]
+        link: PathBuf,
+        #[serde(with = "crate::paths::serde")]
+        target: PathBuf,
+        #[serde(with = "crate::paths::serde::option")]
+        backup: Option<PathBuf>,
+    },
+    /// The permission bits of `path` were changed, `mode` holds the previous ones
+    ChangedMode {
+        #[serde(with = "crate::paths::serde")]
+        path: PathBuf,
+        mode: u32,
+    },
+}
+
+/// All actions performed by a single invocation of a mutating command
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Operation {
+    pub command: String,
+    pub timestamp: u64,
+    pub actions: Vec<Action>,
+}
+
+/// How many operations the journal keeps for `undo`
+pub const HISTORY_LIMIT: usize = 100;
+
+/// The command of the operation the journal folds its expired operations into
+const EXPIRED_COMMAND: &str = "expired";
+
+/// Records mutating operations so they can be reverted with `undo`.
+///
+/// The journal lives in `$XDG_STATE_HOME/dofi` (or `~/.local/state/dofi`) and
+/// holds one JSON encoded [`Operation`] per line, oldest first. Removed files
+/// are moved into a backup directory next to it instead of being deleted.
+///
+/// Only the last [`HISTORY_LIMIT`] operations can be undone. Older ones expire: their backups
+/// are deleted and only the symlinks and directories they created that are still there are
+/// remembered, so dangling links and empty directories can still be pruned.
+pub struct Journal {
+    directory: PathBuf,
+    operation: Operation,
+    directory_modes: DirectoryModes,
+}
+
+impl Journal {
+    pub fn new(directory: &Path, command: &str) -> Self {
+        events::emit(&Event::Started { command });
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            directory: directory.to_path_buf(),
+            operation: Operation {
+                command: command.to_string(),
+                timestamp,
+                actions: Vec::new(),
+            },
+            directory_modes: permissions::default_modes(),
+        }
+    }
+
+    /// Gives the directories the journal creates the permission bits of `modes` instead of
+    /// those [`permissions::init`] set
+    pub fn with_directory_modes(mut self, modes: DirectoryModes) -> Self {
+        self.directory_modes = modes;
+        self
+    }
+
+    pub fn record(&mut self, action: Action) {
+        events::emit(&Event::Action { action: &action });
+        self.operation.actions.push(action);
+    }
+
+    /// The paths symlinked or removed by the recorded actions
+    pub fn changed_paths(&self) -> Vec<PathBuf> {
+        self.operation
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Symlinked { link, .. } | Action::ElevatedSymlinked { link, .. } => {
+                    Some(link.clone())
+                }
+                Action::Removed { path, .. } => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every path the recorded actions created, changed or removed, leaving out backups
+    pub fn touched_paths(&self) -> BTreeSet<PathBuf> {
+        let mut paths = BTreeSet::new();
+        for action in &self.operation.actions {
+            match action {
+                Action::Moved { from, to } => {
+                    paths.insert(from.clone());
+                    paths.insert(to.clone());
+                }
+                Action::Symlinked { link, .. } | Action::ElevatedSymlinked { link, .. } => {
+                    paths.insert(link.clone());
+                }
+                Action::Removed { path, .. }
+                | Action::CreatedDirectory { path }
+                | Action::CreatedFile { path }
+                | Action::RemovedDirectory { path }
+                | Action::ChangedMode { path, .. } => {
+                    paths.insert(path.clone());
+                }
+            }
+        }
+        paths
+    }
+
+    /// Creates `path` and any missing parents with their [mode](permissions), recording each
+    /// created directory
+    pub fn create_dir_all(&mut self, fs: &dyn Fs, path: &Path) -> Result<(), DofiError> {
+        let missing = path
+            .ancestors()
+            .take_while(|ancestor| !fs.exists(ancestor))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
+        fs.create_dir_all(path)?;
+
+        for directory in missing.into_iter().rev() {
+            if let Some(mode) = self.directory_modes.mode(&directory) {
+                fs.set_mode(&directory, mode)?;
+            }
+            self.record(Action::CreatedDirectory { path: directory });
+        }
+
+        Ok(())
+    }
+
+    /// Removes the file at `path` by moving it into the journal's backup directory
+    pub fn remove_file(&mut self, fs: &dyn Fs, path: &Path) -> Result<(), DofiError> {
+        let backup = self.new_backup(fs)?;
+        if fs.symlink_metadata(path)?.file_type == FileType::Symlink {
+            let target = fs.read_link(path)?;
+            fs.symlink(&target, &backup)?;
+            fs.remove_file(path)?;
+        } else {
+            fs::move_preserving(fs, path, &backup)?;
+        }
+
+        self.record(Action::Removed {
+            path: path.to_path_buf(),
+            backup,
+        });
+
+        Ok(())
+    }
+
+    /// Writes `contents` to `path`, keeping a backup of any file it replaces
+    pub fn write_file(
+        &mut self,
+        fs: &dyn Fs,
+        path: &Path,
+        contents: &[u8],
+    ) -> Result<(), DofiError> {
+        if fs.exists(path) {
+            self.remove_file(fs, path)?;
+        }
+        fs.write(path, contents)?;
+        self.record(Action::CreatedFile {
+            path: path.to_path_buf(),
+        });
+
+        Ok(())
+    }
+
+    /// Writes `contents` to `path` with the permission bits `mode`, keeping a backup of any file
+    /// it replaces. The contents are written to a temporary file next to `path` that is then
+    /// renamed over it, so an interrupted write never leaves `path` half written or readable
+    /// with other permissions.
+    pub fn replace_file(
+        &mut self,
+        fs: &dyn Fs,
+        path: &Path,
+        contents: &[u8],
+        mode: u32,
+    ) -> Result<(), DofiError> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temporary = path.with_file_name(format!(".{name}.dofi-{}", std::process::id()));
+        let written = fs
+            .write(&temporary, contents)
+            .and_then(|()| fs.set_mode(&temporary, mode))
+            .and_then(|()| fs.sync(&temporary));
+        if let Err(e) = written {
+            let _ = fs.remove_file(&temporary);
+            return Err(e.into());
+        }
+
+        match fs.symlink_metadata(path).map(|metadata| metadata.file_type) {
+            Ok(FileType::Directory) => self.remove_file(fs, path)?,
+            // Copied rather than moved, so `path` exists throughout
+            Ok(file_type) => {
+                let backup = self.new_backup(fs)?;
+                if file_type == FileType::Symlink {
+                    fs.symlink(&fs.read_link(path)?, &backup)?;
+                } else {
+                    fs.copy(path, &backup)?;
+                    fs.copy_metadata(path, &backup)?;
+                }
+                self.record(Action::Removed {
+                    path: path.to_path_buf(),
+                    backup,
+                });
+            }
+            Err(_) => {}
+        }
+        if let Err(e) = fs.rename(&temporary, path) {
+            let _ = fs.remove_file(&temporary);
+            return Err(e.into());
+        }
+        self.record(Action::CreatedFile {
+            path: path.to_path_buf(),
+        });
+
+        Ok(())
+    }
+
+    /// Hard links `link` to `original`, keeping a backup of any file it replaces
+    pub fn hard_link(
+        &mut self,
+        fs: &dyn Fs,
+        original: &Path,
+        link: &Path,
+    ) -> Result<(), DofiError> {
+        if fs.exists(link) {
+            self.remove_file(fs, link)?;
+        }
+        fs.hard_link(original, link)?;
+        self.record(Action::CreatedFile {
+            path: link.to_path_buf(),
+        });
+
+        Ok(())
+    }
+
+    /// A path in the journal's backup directory that is not taken yet
+    fn new_backup(&self, fs: &dyn Fs) -> Result<PathBuf, DofiError> {
+        let backups = self.directory.join("backups").join(format!(
+            "{}-{}",
+            self.operation.timestamp,
+            std::process::id()
+        ));
+        fs.create_dir_all(&backups)?;
+
+        // Journals of the same process and second share the directory
+        Ok((self.operation.actions.len()..)
+            .map(|n| backups.join(n.to_string()))
+            .find(|backup| fs.symlink_metadata(backup).is_err())
+            .expect("the backup names are unbounded"))
+    }
+
+    /// Sets the permission bits of `path` to `mode`, recording the previous ones if they differ
+    pub fn set_mode(&mut self, fs: &dyn Fs, path: &Path, mode: u32) -> Result<(), DofiError> {
+        let previous = fs.symlink_metadata(path)?.mode;
+        if previous == mode {
+            return Ok(());
+        }
+
+        info!("Setting the mode of '{}' to {mode:o}", path.display());
+        fs.set_mode(path, mode)?;
+        self.record(Action::ChangedMode {
+            path: path.to_path_buf(),
+            mode: previous,
+        });
+
+        Ok(())
+    }
+
+    /// Removes the empty directory `directory` and then its parents while they are empty, up to
+    /// but excluding `boundary`. Only directories `removable` allows are removed, the walk stops
+    /// at the first one it does not.
+    pub fn remove_empty_directories(
+        &mut self,
+        fs: &dyn Fs,
+        directory: &Path,
+        boundary: &Path,
+        removable: impl Fn(&Path) -> bool,
+    ) -> Result<(), DofiError> {
+        for directory in directory.ancestors() {
+            if directory == boundary || !directory.starts_with(boundary) || !removable(directory) {
+                break;
+            }
+            if fs.remove_dir(directory).is_err() {
+                break;
+            }
+
+            info!("Removed empty directory '{}'", directory.display());
+            self.record(Action::RemovedDirectory {
+                path: directory.to_path_buf(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Marks the current end of the recorded actions, to [roll back](Journal::rollback) to
+    pub fn savepoint(&self) -> usize {
+        self.operation.actions.len()
+    }
+
+    /// Reverts the actions recorded after `savepoint`, latest first, and forgets them
+    pub fn rollback(&mut self, fs: &dyn Fs, savepoint: usize) -> Result<(), DofiError> {
+        while self.operation.actions.len() > savepoint {
+            let action = self
+                .operation
+                .actions
+                .pop()
+                .expect("there are actions after the savepoint");
+            revert(fs, &action)?;
+        }
+
+        Ok(())
+    }
+
+    /// The command the journal records the actions of
+    pub fn command(&self) -> &str {
+        &self.operation.command
+    }
+
+    /// The state directory the journal lives in
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Appends the recorded operation to the journal, does nothing if no actions were recorded
+    pub fn commit(self, fs: &dyn Fs) -> Result<(), DofiError> {
+        events::emit(&Event::Summary {
+            command: &self.operation.command,
+            actions: self.operation.actions.len(),
+        });
+        if self.operation.actions.is_empty() {
+            return Ok(());
+        }
+
+        let mut operations = read_operations(fs, &self.directory)?;
+        operations.push(self.operation);
+        expire_operations(fs, &mut operations);
+        write_operations(fs, &self.directory, &operations)
+    }
+}
+
+/// Reverts the most recent operation in the journal at `directory` and drops it from the journal
+pub fn undo(fs: &dyn Fs, directory: &Path) -> Result<Operation, DofiError> {
+    let mut operations = read_operations(fs, directory)?;
+    let operation = operations
+        .pop_if(|operation| operation.command != EXPIRED_COMMAND)
+        .ok_or(DofiError::NothingToUndo)?;
+    info!("Undoing '{}'", operation.command);
+
+    for action in operation.actions.iter().rev() {
+        revert(fs, action)?;
+    }
+
+    write_operations(fs, directory, &operations)?;
+
+    Ok(operation)
+}
+
+fn read_operations(fs: &dyn Fs, directory: &Path) -> Result<Vec<Operation>, DofiError> {
+    let contents = match fs.read(&journal_file(directory)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    String::from_utf8_lossy(&contents)
+        .lines()
+        .map(|line| serde_json::from_str(line).map_err(DofiError::InvalidJournal))
+        .collect()
+}
+
+fn write_operations(
+    fs: &dyn Fs,
+    directory: &Path,
+    operations: &[Operation],
+) -> Result<(), DofiError> {
+    let mut contents = String::new();
+    for operation in operations {
+        let line = serde_json::to_string(operation).map_err(DofiError::InvalidJournal)?;
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+
+    // Written aside and renamed over the journal, so a crash never leaves it half written
+    fs.create_dir_all(directory)?;
+    let journal = journal_file(directory);
+    let temporary = journal.with_extension(format!("jsonl.{}", std::process::id()));
+    let written = fs
+        .write(&temporary, contents.as_bytes())
+        .and_then(|()| fs.sync(&temporary))
+        .and_then(|()| fs.rename(&temporary, &journal));
+    if let Err(e) = written {
+        let _ = fs.remove_file(&temporary);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Folds the operations beyond the last [`HISTORY_LIMIT`] into a single one that cannot be
+/// undone, deleting their backups and keeping the symlinks and directories they created that
+/// are still there
+fn expire_operations(fs: &dyn Fs, operations: &mut Vec<Operation>) {
+    let undoable = operations
+        .iter()
+        .filter(|operation| operation.command != EXPIRED_COMMAND)
+        .count();
+    if undoable <= HISTORY_LIMIT {
+        return;
+    }
+
+    let expired = operations
+        .drain(..operations.len() - HISTORY_LIMIT)
+        .collect::<Vec<_>>();
+    let mut kept = Operation {
+        command: EXPIRED_COMMAND.to_string(),
+        timestamp: expired[0].timestamp,
+        actions: Vec::new(),
+    };
+    for action in expired.into_iter().flat_map(|operation| operation.actions) {
+        match action {
+            Action::Symlinked {
+                ref link,
+                ref target,
+            } if fs.read_link(link).is_ok_and(|current| &current == target) => {
+                kept.actions.push(action);
+            }
+            Action::CreatedDirectory { ref path } if fs.exists(path) => kept.actions.push(action),
+            // Their backups belong to root
+            Action::ElevatedSymlinked { .. } => kept.actions.push(action),
+            Action::Removed { backup, .. } => remove_backup(fs, &backup),
+            _ => {}
+        }
+    }
+    operations.insert(0, kept);
+}
+
+/// Deletes the `backup` of a removed file or directory, and its backup directory once empty
+fn remove_backup(fs: &dyn Fs, backup: &Path) {
+    let is_directory = fs
+        .symlink_metadata(backup)
+        .is_ok_and(|metadata| metadata.file_type == FileType::Directory);
+    if is_directory {
+        for file in fs.walk(backup).unwrap_or_default() {
+            let _ = fs.remove_file(&file);
+            for directory in file.ancestors().skip(1) {
+                if directory == backup || fs.remove_dir(directory).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = fs.remove_dir(backup);
+    } else {
+        let _ = fs.remove_file(backup);
+    }
+    if let Some(parent) = backup.parent() {
+        let _ = fs.remove_dir(parent);
+    }
+}
+
+fn revert(fs: &dyn Fs, action: &Action) -> Result<(), DofiError> {
+    match action {
+        Action::Moved { from, to } => {
+            info!("Moving '{}' back to '{}'", to.display(), from.display());
+            if fs.read_link(from).is_ok() {
+                fs.remove_file(from)?;
+            }
+            let lost = fs::move_preserving(fs, to, from)?;
+            if !lost.is_empty() {
+                warn!(
+                    "Could not preserve {} of '{}'",
+                    lost.join(", "),
+                    from.display()
+                );
+            }
+        }
+        Action::Symlinked { link, target } => {
+            if fs.read_link(link).is_ok_and(|current| &current == target) {
+                info!("Removing symlink '{}'", link.display());
+                fs.remove_file(link)?;
+            } else {
+                warn!(
+                    "Not removing '{}', it no longer points to '{}'",
+                    link.display(),
+                    target.display()
+                );
+            }
+        }
+        Action::ElevatedSymlinked {
+            link,
+            target,
+            backup,
+        } => {
+            if fs.read_link(link).is_ok_and(|current| &current == target) {
+                info!("Removing symlink '{}' as root", link.display());
+                elevate::unlink(link, backup.as_ref())?;
+            } else {
+                warn!(
+                    "Not removing '{}', it no longer points to '{}'",
+                    link.display(),
+                    target.display()
+                );
+            }
+        }
+        Action::Removed { path, backup } => {
+            info!("Restoring '{}'", path.display());
+            if let Some(parent) = path.parent() {
+                fs.create_dir_all(parent)?;
+            }
+            if fs.exists(path) {
+                fs.remove_file(path)?;
+            }
+            fs.rename(backup, path)?;
+        }
+        Action::CreatedFile { path } => {
+            info!("Removing created file '{}'", path.display());
+            fs.remove_file(path)?;
+        }
+        Action::ChangedMode { path, mode } => {
+            info!("Restoring the mode of '{}'", path.display());
+            fs.set_mode(path, *mode)?;
+        }
+        Action::RemovedDirectory { path } => {
+            info!("Restoring directory '{}'", path.display());
+            fs.create_dir_all(path)?;
+        }
+        Action::CreatedDirectory { path } => {
+            if fs.remove_dir(path).is_ok() {
+                info!("Removed directory '{}'", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The directories created by the operations in the journal at `directory`
+pub fn created_directories(fs: &dyn Fs, directory: &Path) -> Result<BTreeSet<PathBuf>, DofiError> {
+    Ok(read_operations(fs, directory)?
+        .into_iter()
+        .flat_map(|operation| operation.actions)
+        .filter_map(|action| match action {
+            Action::CreatedDirectory { path } => Some(path),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The symlinks created by the operations in the journal at `directory`, each with the path it
+/// pointed to
+pub fn created_symlinks(
+    fs: &dyn Fs,
+    directory: &Path,
+) -> Result<BTreeSet<(PathBuf, PathBuf)>, DofiError> {
+    Ok(read_operations(fs, directory)?
+        .into_iter()
+        .flat_map(|operation| operation.actions)
+        .filter_map(|action| match action {
+            Action::Symlinked { link, target } => Some((link, target)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// The directory holding dofi's journal and backups
+pub fn state_directory(base_directory: &Path) -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| base_directory.join(".local/state"))
+        .join("dofi")
+}
+
+/// The journal in the state `directory`
+pub(crate) fn journal_file(directory: &Path) -> PathBuf {
+    directory.join("journal.jsonl")
+}
@@ -0,0 +1,248 @@
+//! The OS keyring, where `dofi key unlock` leaves the age identity so decrypting does not ask
+//! for its passphrase every time, and where `dofi key import` keeps it instead of a file.
+//!
+//! dofi talks to the keyring through the tools that come with it: `secret-tool` for the Secret
+//! Service of GNOME Keyring or KWallet, and `security` for the login keychain on macOS.
+//! Secrets are stored hex encoded under the service `dofi`, one account per identity file. On
+//! Windows they are generic credentials of the Credential Manager named `dofi:<account>`.
+//!
+//! This is deliberately not the `keyring` crate. On Linux and the BSDs its Secret Service
+//! backend talks D-Bus itself, through libdbus, a C library the static musl build would have to
+//! vendor, or through zbus and an async runtime, a large dependency for the few calls a command
+//! makes. The tools come with the keyring they talk to, so they are there wherever it is, and
+//! the Credential Manager is reached through `windows-sys`, which dofi depends on already.
+
+#[cfg(not(windows))]
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::DofiError;
+
+/// The service the secrets of dofi are stored under
+const SERVICE: &str = "dofi";
+
+/// Stores `secret` for `account`, replacing what was stored before
+#[cfg(windows)]
+pub fn store(account: &str, secret: &[u8]) -> Result<(), DofiError> {
+    Ok(credentials::write(account, secret)?)
+}
+
+/// Stores `secret` for `account`, replacing what was stored before
+#[cfg(not(windows))]
+pub fn store(account: &str, secret: &[u8]) -> Result<(), DofiError> {
+    let secret = hex(secret);
+    if cfg!(target_os = "macos") {
+        // Through stdin, the arguments of a process are visible to everyone
+        let command = format!(
+            "add-generic-password -U -s {SERVICE} -a \"{}\" -w {secret}\n",
+            account.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        let output = run("security", &["-i"], command.as_bytes())?;
+        succeeded("security", output)?;
+    } else if cfg!(unix) {
+        let label = format!("dofi: {account}");
+        let output = run(
+            "secret-tool",
+            &[
+                "store", "--label", &label, "service", SERVICE, "account", account,
+            ],
+            secret.as_bytes(),
+        )?;
+        succeeded("secret-tool", output)?;
+    } else {
+        return Err(DofiError::NoKeyring);
+    }
+    Ok(())
+}
+
+/// The secret stored for `account`, if there is one
+#[cfg(windows)]
+pub fn lookup(account: &str) -> Result<Option<Vec<u8>>, DofiError> {
+    Ok(credentials::read(account)?)
+}
+
+/// The secret stored for `account`, if there is one
+#[cfg(not(windows))]
+pub fn lookup(account: &str) -> Result<Option<Vec<u8>>, DofiError> {
+    let output = if cfg!(target_os = "macos") {
+        run(
+            "security",
+            &["find-generic-password", "-s", SERVICE, "-a", account, "-w"],
+            &[],
+        )?
+    } else if cfg!(unix) {
+        run(
+            "secret-tool",
+            &["lookup", "service", SERVICE, "account", account],
+            &[],
+        )?
+    } else {
+        return Err(DofiError::NoKeyring);
+    };
+    Ok(output.and_then(|output| unhex(String::from_utf8_lossy(&output).trim())))
+}
+
+/// Removes the secret stored for `account`, returning whether there was one
+#[cfg(windows)]
+pub fn delete(account: &str) -> Result<bool, DofiError> {
+    Ok(credentials::delete(account)?)
+}
+
+/// Removes the secret stored for `account`, returning whether there was one
+#[cfg(not(windows))]
+pub fn delete(account: &str) -> Result<bool, DofiError> {
+    if lookup(account)?.is_none() {
+        return Ok(false);
+    }
+    let (program, arguments) = if cfg!(target_os = "macos") {
+        (
+            "security",
+            ["delete-generic-password", "-s", SERVICE, "-a", account],
+        )
+    } else {
+        (
+            "secret-tool",
+            ["clear", "service", SERVICE, "account", account],
+        )
+    };
+    succeeded(program, run(program, &arguments, &[])?)?;
+    Ok(true)
+}
+
+#[cfg(not(windows))]
+fn succeeded(program: &str, output: Option<Vec<u8>>) -> Result<(), DofiError> {
+    match output {
+        Some(_) => Ok(()),
+        None => Err(DofiError::ExternalCommandFailed(
+            program.to_string(),
+            "the keyring refused the change".to_string(),
+        )),
+    }
+}
+
+/// Runs `program` with `input` on stdin, returning its stdout or `None` if it failed, which is
+/// how both tools report a missing secret. Fails only if it cannot be run at all.
+#[cfg(not(windows))]
+fn run(program: &str, arguments: &[&str], input: &[u8]) -> Result<Option<Vec<u8>>, DofiError> {
+    let failed =
+        |e: std::io::Error| DofiError::ExternalCommandFailed(program.to_string(), e.to_string());
+    let mut child = Command::new(program)
+        .args(arguments)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(failed)?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input)
+        .map_err(failed)?;
+    let output = child.wait_with_output().map_err(failed)?;
+    Ok(output.status.success().then_some(output.stdout))
+}
+
+#[cfg(not(windows))]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(not(windows))]
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The generic credentials of the Windows Credential Manager, which hold the secret as it is
+#[cfg(windows)]
+mod credentials {
+    use std::{io, ptr};
+
+    use windows_sys::Win32::{
+        Foundation::ERROR_NOT_FOUND,
+        Security::Credentials::{
+            CredDeleteW, CredFree, CredReadW, CredWriteW, CREDENTIALW, CRED_PERSIST_LOCAL_MACHINE,
+            CRED_TYPE_GENERIC,
+        },
+    };
+
+    use super::SERVICE;
+
+    /// The NUL terminated name of the credential of `account`
+    fn target_name(account: &str) -> Vec<u16> {
+        format!("{SERVICE}:{account}")
+            .encode_utf16()
+            .chain([0])
+            .collect()
+    }
+
+    /// The last error, `None` if it is that there is no such credential
+    fn last_error() -> Option<io::Error> {
+        let error = io::Error::last_os_error();
+        (error.raw_os_error() != Some(ERROR_NOT_FOUND as i32)).then_some(error)
+    }
+
+    pub fn write(account: &str, secret: &[u8]) -> io::Result<()> {
+        let mut target_name = target_name(account);
+        let credential = CREDENTIALW {
+            Type: CRED_TYPE_GENERIC,
+            TargetName: target_name.as_mut_ptr(),
+            CredentialBlobSize: secret.len() as u32,
+            // Only read by CredWriteW
+            CredentialBlob: secret.as_ptr().cast_mut(),
+            Persist: CRED_PERSIST_LOCAL_MACHINE,
+            ..Default::default()
+        };
+        // SAFETY: `credential` and the name and secret it points to outlive the call, which
+        // copies them and does not write through the pointers
+        if unsafe { CredWriteW(&credential, 0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn read(account: &str) -> io::Result<Option<Vec<u8>>> {
+        let target_name = target_name(account);
+        let mut credential: *mut CREDENTIALW = ptr::null_mut();
+        // SAFETY: the name is NUL terminated and `credential` is a valid place for the pointer
+        // to the credential the call allocates
+        if unsafe { CredReadW(target_name.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) } == 0 {
+            return last_error().map_or(Ok(None), Err);
+        }
+        // SAFETY: the call succeeded, so `credential` points to a credential whose blob holds
+        // `CredentialBlobSize` bytes until it is freed below. The blob of an empty secret may
+        // be null, which a slice cannot start at.
+        let secret = unsafe {
+            let credential = &*credential;
+            if credential.CredentialBlobSize == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(
+                    credential.CredentialBlob,
+                    credential.CredentialBlobSize as usize,
+                )
+                .to_vec()
+            }
+        };
+        // SAFETY: `credential` was allocated by `CredReadW` and is not used after this
+        unsafe { CredFree(credential.cast()) };
+        Ok(Some(secret))
+    }
+
+    pub fn delete(account: &str) -> io::Result<bool> {
+        let target_name = target_name(account);
+        // SAFETY: the name is NUL terminated and outlives the call
+        if unsafe { CredDeleteW(target_name.as_ptr(), CRED_TYPE_GENERIC, 0) } == 0 {
+            return last_error().map_or(Ok(false), Err);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,2146 @@
+//! Core operations of dofi, a simple dotfile manager inspired by stow.
+//!
+//! A dotfiles directory mirrors the layout of a base directory (usually `$HOME`): the dotfile
+//! `<dotfiles>/.config/foo/rc` is linked to `<base>/.config/foo/rc`. The functions in this crate
+//! move files between the two and maintain the symlinks, recording every change in a
+//! [`Journal`] so it can be reverted. Dotfiles with a different target are recorded in the
+//! repo's [`Manifest`].
+//!
+//! Several dotfiles directories can be layered on top of each other, a target provided by
+//! more than one layer is linked to the dotfile of the last of them.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path, PathBuf},
+};
+
+use batch::Batches;
+use conflict::{ConflictPolicies, ConflictPolicy};
+use encryption::Encryption;
+use events::Event;
+use globset::{Glob, GlobMatcher, GlobSet};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use init::GITIGNORE_FILE;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+pub use error::DofiError;
+pub use fs::{Fs, MemoryFs, OsFs};
+use journal::Action;
+pub use journal::Journal;
+use manifest::NestedRepos;
+pub use manifest::{Manifest, Strategy};
+
+pub mod adopt;
+pub mod batch;
+pub mod check;
+pub mod checksum;
+pub mod color;
+pub mod condition;
+pub mod config;
+pub mod confirm;
+pub mod conflict;
+pub mod diff;
+pub mod doctor;
+pub mod editor;
+pub mod elevate;
+pub mod encryption;
+mod error;
+pub mod events;
+pub mod export;
+pub mod fs;
+pub mod generate;
+pub mod git;
+pub mod grep;
+pub mod guard;
+pub mod hooks;
+pub mod impact;
+pub mod index;
+pub mod init;
+pub mod journal;
+pub mod keyring;
+pub mod lock;
+pub mod manifest;
+pub mod merge;
+pub mod migrate;
+pub mod nuon;
+pub mod paths;
+pub mod permissions;
+pub mod picker;
+pub mod platform;
+pub mod plugin;
+pub mod private;
+pub mod progress;
+pub mod query;
+pub mod remote;
+pub mod report;
+pub mod scripts;
+pub mod secrets;
+pub mod service;
+pub mod snapshot;
+pub mod source;
+pub mod stats;
+pub mod status;
+pub mod template;
+pub mod templatify;
+pub mod timings;
+pub mod track;
+pub mod trash;
+pub mod tui;
+pub mod update;
+pub mod vars;
+pub mod watch;
+
+/// How [`add_files`] stores the files in the dotfiles
+#[derive(Clone, Copy)]
+pub enum AddKind<'a> {
+    /// Moved as they are, see [`add_file`]
+    File,
+    /// Moved as whole directories, see [`add_directory`]
+    Directory,
+    /// Encrypted with the backend, see [`add_encrypted_file`]
+    Encrypted(&'a dyn Encryption),
+    /// Turned into templates substituting the variables, see [`add_template_file`]
+    Template(Option<&'a BTreeMap<String, String>>),
+}
+
+/// How [`add_files`] adds the files and what it records about them in the manifest
+#[derive(Clone, Copy)]
+pub struct AddOptions<'a> {
+    pub kind: AddKind<'a>,
+    /// The repo-relative location to store the files at instead of their own
+    pub repo_path: Option<&'a Path>,
+    /// The foreign symlinks among the files, which are added at their own location
+    pub adopted: &'a [PathBuf],
+    /// The permission bits the targets are required to have
+    pub mode: Option<u32>,
+    /// The tags the new dotfiles get
+    pub tags: &'a [String],
+}
+
+/// How [`remove_file`] treats the target and the directories left behind
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RemoveOptions {
+    /// Keep the target working, replacing a symlink to the dotfile with a copy of it
+    pub keep_target: bool,
+    /// Leave the dotfile in the dotfiles directory, only removing the symlink to it
+    pub keep_source: bool,
+    /// Remove the directories left empty, in the dotfiles directory and those dofi created in
+    /// the base directory
+    pub prune_empty: bool,
+    /// Put a copy of the dotfile into the [trash](trash) before removing it, so it can be
+    /// recovered once the journal no longer has it
+    pub trash: bool,
+}
+
+/// The symlinks dofi created, according to the journal in `state_directory`, that point to
+/// dotfiles since removed from the layered `dotfiles_directories`
+pub fn dangling_links(
+    fs: &dyn Fs,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+) -> Result<Vec<PathBuf>, DofiError> {
+    Ok(journal::created_symlinks(fs, state_directory)?
+        .into_iter()
+        .filter(|(link, target)| {
+            dotfiles_directories
+                .iter()
+                .any(|layer| target.starts_with(layer))
+                && !fs.exists(target)
+                && fs.read_link(link).is_ok_and(|current| &current == target)
+        })
+        .map(|(link, _)| link)
+        .collect())
+}
+
+/// Removes the [dangling links](dangling_links) of the layered `dotfiles_directories`, and the
+/// directories they leave empty that dofi created if `prune_empty` is set. Returns the number
+/// of removed symlinks.
+pub fn prune_dangling_links(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    prune_empty: bool,
+    journal: &mut Journal,
+) -> Result<usize, DofiError> {
+    let mut pruned = 0;
+    let created = if prune_empty {
+        journal::created_directories(fs, journal.directory())?
+    } else {
+        BTreeSet::new()
+    };
+    for link in dangling_links(fs, dotfiles_directories, journal.directory())? {
+        info!("Pruning '{}'", link.display());
+        journal.remove_file(fs, &link)?;
+        pruned += 1;
+
+        if let Some(parent) = link.parent().filter(|_| prune_empty) {
+            journal.remove_empty_directories(fs, parent, base_directory, |directory| {
+                created.contains(directory)
+            })?;
+        }
+    }
+
+    Ok(pruned)
+}
+
+/// Removes the dotfile `file` from `dotfiles_directory` together with its symlink in
+/// `base_directory`, if there is one. With `keep_source` only the symlink is removed.
+///
+/// The removed file is kept as a backup by the `journal` so the removal can be undone, and
+/// goes to the trash as well if `trash` is set.
+pub fn remove_file(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    options: RemoveOptions,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if !file.starts_with(dotfiles_directory) {
+        return Err(DofiError::FileIsNotADotfile(file.to_path_buf()));
+    }
+
+    let manifest = Manifest::load(fs, dotfiles_directory)?;
+    let symlink = manifest.target_path(file, base_directory, dotfiles_directory)?;
+    // A directory linked as a whole, see `Manifest::directories`
+    let directory = fs
+        .symlink_metadata(file)
+        .is_ok_and(|metadata| metadata.file_type == fs::FileType::Directory);
+    let linked = fs
+        .read_link(&symlink)
+        .is_ok_and(|original| original == file);
+
+    let mut moved = false;
+    if options.keep_target && linked && directory {
+        info!(
+            "Moving '{}' back in place of the symlink '{}'",
+            file.display(),
+            symlink.display()
+        );
+        journal.remove_file(fs, &symlink)?;
+        fs::move_preserving(fs, file, &symlink)?;
+        journal.record(Action::Moved {
+            from: file.to_path_buf(),
+            to: symlink.clone(),
+        });
+        moved = true;
+    } else if options.keep_target && linked {
+        info!("Replacing symlink '{}' with a copy", symlink.display());
+        let contents = fs.read(file)?;
+        journal.remove_file(fs, &symlink)?;
+        journal.write_file(fs, &symlink, &contents)?;
+        fs.set_mode(&symlink, fs.symlink_metadata(file)?.mode)?;
+    }
+
+    if options.keep_source {
+        if linked {
+            info!("Removing symlink '{}'", symlink.display());
+            journal.remove_file(fs, &symlink)?;
+            if let Some(parent) = symlink.parent().filter(|_| options.prune_empty) {
+                let created = journal::created_directories(fs, journal.directory())?;
+                journal.remove_empty_directories(fs, parent, base_directory, |directory| {
+                    created.contains(directory)
+                })?;
+            }
+        }
+        return Ok(());
+    }
+
+    if options.trash && directory && !moved {
+        warn!(
+            "Not moving the directory '{}' to the trash, `dofi undo` brings it back",
+            file.display()
+        );
+    } else if options.trash && !moved {
+        trash::trash(fs, file, base_directory)?;
+    }
+    if !moved {
+        info!("Removing file '{}'", file.display());
+        journal.remove_file(fs, file)?;
+    }
+
+    let mut removed_symlink = false;
+    if !options.keep_target && fs.exists(&symlink) {
+        info!("Removing symlink '{}'", symlink.display());
+        removed_symlink = journal.remove_file(fs, &symlink).is_ok();
+    }
+
+    if options.prune_empty {
+        if let Some(parent) = file.parent() {
+            journal.remove_empty_directories(fs, parent, dotfiles_directory, |_| true)?;
+        }
+        if let Some(parent) = symlink.parent().filter(|_| removed_symlink) {
+            let created = journal::created_directories(fs, journal.directory())?;
+            journal.remove_empty_directories(fs, parent, base_directory, |directory| {
+                created.contains(directory)
+            })?;
+        }
+    }
+
+    if let Ok(relative_file) = file.strip_prefix(dotfiles_directory) {
+        manifest::set_target(fs, dotfiles_directory, relative_file, None, journal)?;
+        if directory {
+            manifest::set_directory(fs, dotfiles_directory, relative_file, false, journal)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves `file` from `base_directory` to the same relative location in `dotfiles_directory`
+/// and replaces it with a symlink to its new location.
+///
+/// With `repo_path` the file is moved to that repo-relative location instead and the mapping
+/// is recorded in the [`Manifest`]. Where the file cannot simply be renamed it is copied
+/// together with its permissions, timestamps and extended attributes, with a warning about
+/// any of them that could not be kept.
+pub fn add_file(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let new_file = repo_location(
+        fs,
+        file,
+        base_directory,
+        dotfiles_directory,
+        repo_path,
+        None,
+        journal,
+    )?;
+    move_and_link(fs, file, &new_file, journal)
+}
+
+/// Moves the `directory` from `base_directory` into `dotfiles_directory` like [`add_file`]
+/// and replaces it with a single symlink, listing it in the `directories` of the
+/// [`Manifest`] so it is linked as a whole from then on. Unlike a file the directory is not
+/// copied across filesystems, it has to be on the same one as the dotfiles.
+pub fn add_directory(
+    fs: &dyn Fs,
+    directory: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    match fs.symlink_metadata(directory) {
+        Ok(metadata) if metadata.file_type == fs::FileType::Directory => {}
+        _ => return Err(DofiError::NotADirectory(directory.to_path_buf())),
+    }
+    let new_directory = repo_location(
+        fs,
+        directory,
+        base_directory,
+        dotfiles_directory,
+        repo_path,
+        None,
+        journal,
+    )?;
+    if fs.exists(&new_directory) {
+        return Err(DofiError::FileExists(new_directory));
+    }
+
+    move_and_link(fs, directory, &new_directory, journal)?;
+    if let Ok(relative_directory) = new_directory.strip_prefix(dotfiles_directory) {
+        manifest::set_directory(fs, dotfiles_directory, relative_directory, true, journal)?;
+    }
+
+    Ok(())
+}
+
+/// Moves `file` to `new_file`, creating its parent directories, and symlinks it back
+fn move_and_link(
+    fs: &dyn Fs,
+    file: &Path,
+    new_file: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if let Some(parent) = new_file.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+
+    info!("Moving '{}' to '{}'", file.display(), new_file.display());
+    let lost = fs::move_preserving(fs, file, new_file)?;
+    if !lost.is_empty() {
+        warn!(
+            "Could not preserve {} of '{}'",
+            lost.join(", "),
+            file.display()
+        );
+    }
+    journal.record(Action::Moved {
+        from: file.to_path_buf(),
+        to: new_file.to_path_buf(),
+    });
+    info!(
+        "Symlinking '{}' at '{}'",
+        new_file.display(),
+        file.display()
+    );
+    fs.symlink(new_file, file)?;
+    journal.record(Action::Symlinked {
+        link: file.to_path_buf(),
+        target: new_file.to_path_buf(),
+    });
+
+    Ok(())
+}
+
+/// Creates a dotfile holding `contents` for the `target` in `base_directory`, which must not
+/// exist yet, and symlinks it there. The dotfile goes where [`add_file`] would put the target,
+/// or to `repo_path`. Returns the new dotfile.
+pub fn new_file(
+    fs: &dyn Fs,
+    target: &Path,
+    contents: &[u8],
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    journal: &mut Journal,
+) -> Result<PathBuf, DofiError> {
+    if fs.exists(target) {
+        return Err(DofiError::FileExists(target.to_path_buf()));
+    }
+    let source = repo_location(
+        fs,
+        target,
+        base_directory,
+        dotfiles_directory,
+        repo_path,
+        None,
+        journal,
+    )?;
+    if fs.exists(&source) {
+        return Err(DofiError::FileExists(source));
+    }
+
+    for path in [&source, target] {
+        if let Some(parent) = path.parent() {
+            journal.create_dir_all(fs, parent)?;
+        }
+    }
+    info!("Creating '{}'", source.display());
+    journal.write_file(fs, &source, contents)?;
+    info!(
+        "Symlinking '{}' at '{}'",
+        source.display(),
+        target.display()
+    );
+    fs.symlink(&source, target)?;
+    journal.record(Action::Symlinked {
+        link: target.to_path_buf(),
+        target: source.clone(),
+    });
+
+    Ok(source)
+}
+
+/// Replaces the symlink `link` with the regular file it resolves to, moving the file into its
+/// place, so a link left behind by another tool can be added like any other file. Fails if
+/// the file already is a dotfile in `dotfiles_directory`.
+pub fn materialize_symlink(
+    fs: &dyn Fs,
+    link: &Path,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let original = resolve_links(fs, link);
+    match fs.symlink_metadata(&original) {
+        Ok(metadata) if metadata.file_type == fs::FileType::File => {}
+        _ => return Err(DofiError::FileIsNotRegular(link.to_path_buf())),
+    }
+    if original.starts_with(dotfiles_directory) {
+        return Err(DofiError::FileIsADotfile(link.to_path_buf()));
+    }
+
+    info!(
+        "Replacing the symlink '{}' with '{}'",
+        link.display(),
+        original.display()
+    );
+    journal.remove_file(fs, link)?;
+    fs.rename(&original, link)?;
+    journal.record(Action::Moved {
+        from: original,
+        to: link.to_path_buf(),
+    });
+
+    Ok(())
+}
+
+/// The dotfile `file` already is, if it lies in one of the layered `dotfiles_directories`, or
+/// is linked to, if it is a symlink into them. `None` for files outside of them, which can be
+/// added. Fails for the files in them that are no dotfiles, like the manifest.
+pub fn managed_dotfile(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+) -> Result<Option<Dotfile>, DofiError> {
+    let original = resolve_links(fs, file);
+    if !dotfiles_directories
+        .iter()
+        .any(|layer| original.starts_with(layer))
+    {
+        return Ok(None);
+    }
+    dotfiles_linked_to(fs, base_directory, dotfiles_directories, &original)?
+        .pop()
+        .map(Some)
+        .ok_or_else(|| DofiError::FileIsNotADotfile(file.to_path_buf()))
+}
+
+/// The file the symlink `link` ends at, following chains of links and giving up on cycles like
+/// the OS does. `link` itself if it is no symlink.
+fn resolve_links(fs: &dyn Fs, link: &Path) -> PathBuf {
+    let mut original = link.to_path_buf();
+    for _ in 0..40 {
+        let Ok(next) = fs.read_link(&original) else {
+            break;
+        };
+        original = original.parent().unwrap_or(&original).join(next);
+    }
+    original
+}
+
+/// Stores an encrypted copy of `file` in `dotfiles_directory`, next to where [`add_file`]
+/// would move it. The file itself stays in place as the target of the encrypted dotfile.
+pub fn add_encrypted_file(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    encryption: &dyn Encryption,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let new_file = repo_location(
+        fs,
+        file,
+        base_directory,
+        dotfiles_directory,
+        repo_path,
+        Some(encryption.extension()),
+        journal,
+    )?;
+    if fs.exists(&new_file) {
+        return Err(DofiError::FileExists(new_file));
+    }
+
+    if let Some(parent) = new_file.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    info!(
+        "Encrypting '{}' to '{}'",
+        file.display(),
+        new_file.display()
+    );
+    let recipients = match new_file.strip_prefix(dotfiles_directory) {
+        Ok(relative_file) => Manifest::load(fs, dotfiles_directory)?.recipients_of(relative_file),
+        Err(_) => Vec::new(),
+    };
+    let ciphertext = encryption.encrypt(&fs.read(file)?, &recipients)?;
+    journal.write_file(fs, &new_file, &ciphertext)
+}
+
+/// Stores `file` as a template in `dotfiles_directory`, next to where [`add_file`] would move
+/// it. With `variables` their values in the file are replaced by expressions inserting them,
+/// see [`template::templatize`]. The file stays in place as the target of the template, which
+/// renders to it again.
+pub fn add_template_file(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    variables: Option<&BTreeMap<String, String>>,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let contents = String::from_utf8(fs.read(file)?)
+        .map_err(|_| DofiError::FileIsNotText(file.to_path_buf()))?;
+    let new_file = repo_location(
+        fs,
+        file,
+        base_directory,
+        dotfiles_directory,
+        repo_path,
+        Some(template::TEMPLATE_EXTENSION),
+        journal,
+    )?;
+    if fs.exists(&new_file) {
+        return Err(DofiError::FileExists(new_file));
+    }
+
+    if let Some(parent) = new_file.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    info!(
+        "Storing '{}' as the template '{}'",
+        file.display(),
+        new_file.display()
+    );
+    let template = template::templatize(&contents, variables.unwrap_or(&BTreeMap::new()));
+    journal.write_file(fs, &new_file, template.as_bytes())?;
+    journal.set_mode(fs, &new_file, fs.symlink_metadata(file)?.mode)?;
+    // Like a rendered target, so linking rewrites it until it is changed
+    checksum::record(fs, file, contents.as_bytes(), template.as_bytes(), journal)
+}
+
+/// Adds each of `files` in `base_directory` to `dotfiles_directory` like `options` says and
+/// records their mode and tags in the manifest. The `options.adopted` symlinks among them are
+/// first replaced by a copy of the file they link to, see [`materialize_symlink`].
+pub fn add_files(
+    fs: &dyn Fs,
+    files: &[PathBuf],
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    options: &AddOptions,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let repo_path = options.repo_path;
+    for file in files {
+        if options.adopted.contains(file) {
+            materialize_symlink(fs, file, dotfiles_directory, journal)?;
+        }
+        match options.kind {
+            AddKind::File => add_file(
+                fs,
+                file,
+                base_directory,
+                dotfiles_directory,
+                repo_path,
+                journal,
+            ),
+            AddKind::Directory => add_directory(
+                fs,
+                file,
+                base_directory,
+                dotfiles_directory,
+                repo_path,
+                journal,
+            ),
+            AddKind::Encrypted(encryption) => add_encrypted_file(
+                fs,
+                file,
+                base_directory,
+                dotfiles_directory,
+                repo_path,
+                encryption,
+                journal,
+            ),
+            AddKind::Template(variables) => add_template_file(
+                fs,
+                file,
+                base_directory,
+                dotfiles_directory,
+                repo_path,
+                variables,
+                journal,
+            ),
+        }?;
+        if let Some(mode) = options.mode {
+            let target = file.strip_prefix(base_directory).unwrap_or(file);
+            let pattern = globset::escape(&target.to_string_lossy().replace('\\', "/"));
+            manifest::set_mode(fs, dotfiles_directory, &pattern, mode, journal)?;
+        }
+        for tag in options.tags {
+            tag_path(
+                fs,
+                file,
+                tag,
+                true,
+                base_directory,
+                dotfiles_directory,
+                journal,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Links each of the managed `dotfiles` again, replacing whatever is at its target
+pub fn relink_dotfiles(
+    fs: &dyn Fs,
+    dotfiles: &[Dotfile],
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    for dotfile in dotfiles {
+        info!("Relinking '{}'", dotfile.target.display());
+        link_entry(fs, dotfile, true, options, journal)?;
+    }
+    Ok(())
+}
+
+/// Where `file` is stored in `dotfiles_directory`, with `extension` appended if given, that of
+/// an encryption backend or of templates, recording `repo_path` in the manifest if it differs
+/// from the default location
+fn repo_location(
+    fs: &dyn Fs,
+    file: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    repo_path: Option<&Path>,
+    extension: Option<&str>,
+    journal: &mut Journal,
+) -> Result<PathBuf, DofiError> {
+    let mirrored_path =
+        Manifest::load(fs, dotfiles_directory)?.mirrored_path(file, base_directory)?;
+    let default_path = match extension {
+        Some(extension) => encryption::encrypted_path(&mirrored_path, extension),
+        None => mirrored_path,
+    };
+
+    let Some(repo_path) = repo_path else {
+        return Ok(dotfiles_directory.join(default_path));
+    };
+    manifest::validate_repo_path(repo_path)?;
+    let repo_path = match extension {
+        Some(extension) => encryption::encrypted_path(repo_path, extension),
+        None => repo_path.to_path_buf(),
+    };
+    if repo_path != default_path {
+        let relative_file = file.strip_prefix(base_directory).map_err(|_| {
+            DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), file.to_path_buf())
+        })?;
+        manifest::set_target(
+            fs,
+            dotfiles_directory,
+            &repo_path,
+            Some(relative_file),
+            journal,
+        )?;
+    }
+
+    Ok(dotfiles_directory.join(repo_path))
+}
+
+/// How [`link_files`] treats existing targets and encrypted dotfiles
+#[derive(Default)]
+pub struct LinkOptions {
+    /// Replace existing targets that are symlinks into the dotfiles directories
+    pub force: bool,
+    /// Replace any existing target, including regular files and foreign symlinks
+    pub force_all: bool,
+    /// Per target overrides of `force`
+    pub policies: ConflictPolicies,
+    /// The backends decrypting encrypted dotfiles, matched by extension
+    pub encryption: Vec<Box<dyn Encryption>>,
+    /// What template dotfiles are rendered with
+    pub templates: template::Context,
+    /// Only link dotfiles carrying one of these tags, all dotfiles if empty
+    pub tags: Vec<String>,
+    /// Only link the dotfiles at or below these repo-relative paths, all dotfiles if empty
+    pub paths: Vec<PathBuf>,
+    /// Link targets outside the base directory with `sudo` instead of failing
+    pub elevate: bool,
+    /// Remove the directories dofi created that symlinks pruned by [`watch::sync`] leave empty
+    pub prune_empty: bool,
+}
+
+impl LinkOptions {
+    /// Whether a dotfile with `tags` is selected by the tags of the options
+    pub fn selects(&self, tags: &[String]) -> bool {
+        self.tags.is_empty() || tags.iter().any(|tag| self.tags.contains(tag))
+    }
+}
+
+/// What [`link_files`] plans to do for a single dotfile
+enum Step {
+    /// Nothing, the target already is a symlink to the dotfile
+    Linked,
+    /// Symlink the plain dotfile at its missing target, together with all others
+    Symlink,
+    /// Link the dotfile with [`link_entry`], first copying an existing target aside if
+    /// `backup` is set
+    Entry { force: bool, backup: bool },
+    /// Write the rendered or decrypted dotfile again over its target, which is unchanged since
+    /// it was last written but no longer holds what the dotfile renders to
+    Rewrite,
+    /// Run the commands linking the system target as root
+    Elevated(Vec<elevate::Elevated>),
+    /// Nothing, the dotfile is left out of this link
+    Skipped,
+}
+
+/// How many dotfiles [`link_files`] linked, found already linked and left out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkSummary {
+    pub linked: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+}
+
+/// Symlinks every dotfile of the layered `dotfiles_directories` to the same relative location
+/// in `base_directory`, creating parent directories as needed. With `paths` only the
+/// dotfiles at or below them are looked at, the rest of the repo is not even walked.
+///
+/// Existing symlinks into the dotfiles directories at the target locations are replaced if
+/// `force` is set, any other existing file only with `force_all`, otherwise linking fails on
+/// the first existing target. A matching [`ConflictPolicy`] takes precedence over both.
+/// Encrypted dotfiles are decrypted to their targets instead, others are linked with their
+/// [`Strategy`]. Targets copied, rendered or decrypted before are only written again when the
+/// dotfile gives something else, unless they were changed since.
+/// System targets outside `base_directory` are linked as root if `elevate` is set, otherwise
+/// linking fails listing the commands to run.
+///
+/// The dotfiles are linked in batches of a few thousand targets. Every target of a
+/// batch is checked before the batch changes anything, the system targets go first so the
+/// commands linking them are reported together, and if linking still fails halfway the changes
+/// of all batches so far are rolled back. The existing targets that get replaced are saved in
+/// a [`snapshot`] first. The walk is streamed and larger trees are spread over a scratch
+/// directory by target, so memory stays flat however many dotfiles the repo holds. Only the
+/// journal grows with every change, `undo` needs all of them.
+pub fn link_files(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<LinkSummary, DofiError> {
+    let checksums = checksum::load(fs, journal.directory())?;
+    let layers = Layer::load_all(fs, dotfiles_directories)?;
+    check_paths(fs, dotfiles_directories, &options.paths)?;
+
+    let walk = timings::phase("walk");
+    let mut privileged = Vec::new();
+    let mut batches = Batches::new(LINK_BATCH);
+    for (index, layer) in layers.iter().enumerate() {
+        each_file_in(fs, layer.directory, &options.paths, &mut |source| {
+            if !layer.links(&source) {
+                return Ok(());
+            }
+            let target = layer
+                .manifest
+                .target_path(&source, base_directory, layer.directory)?;
+            let listed = Listed {
+                layer: index,
+                source,
+            };
+            if elevate::is_privileged(&target, base_directory) {
+                privileged.push(listed);
+            } else {
+                batches.push(batch::key(&target), listed)?;
+            }
+            Ok(())
+        })?;
+    }
+    drop(walk);
+
+    let savepoint = journal.savepoint();
+    let mut summary = LinkSummary::default();
+    let mut replaced = None;
+    progress::start("Linking", privileged.len() + batches.len());
+    let mut link_batch = |listed: Vec<Listed>| -> Result<(), DofiError> {
+        let listed_count = listed.len();
+        let dotfiles = resolve_layers(&layers, base_directory, listed)?;
+        // The dotfiles overridden by other layers are done with as well
+        for _ in dotfiles.len()..listed_count {
+            progress::advance();
+        }
+        let steps = plan_links(
+            fs,
+            base_directory,
+            dotfiles_directories,
+            options,
+            &checksums,
+            &dotfiles,
+        )?;
+
+        let targets = dotfiles
+            .iter()
+            .zip(&steps)
+            .filter(|(_, step)| matches!(step, Step::Entry { .. }))
+            .map(|(dotfile, _)| dotfile.target.clone())
+            .collect::<Vec<_>>();
+        snapshot::extend(
+            fs,
+            journal.directory(),
+            &mut replaced,
+            journal.command(),
+            &targets,
+        )?;
+
+        let _apply = timings::phase("apply");
+        apply_links(fs, &dotfiles, &steps, options, journal)?;
+        for step in &steps {
+            match step {
+                Step::Linked => summary.unchanged += 1,
+                Step::Skipped => summary.skipped += 1,
+                _ => summary.linked += 1,
+            }
+        }
+        Ok(())
+    };
+    let result = link_batch(privileged).and_then(|()| batches.try_for_each(link_batch));
+    progress::finish();
+    if let Err(e) = result {
+        warn!("Linking failed, rolling back");
+        if let Err(e) = journal.rollback(fs, savepoint) {
+            warn!("Failed to roll back: {e}");
+        }
+        return Err(e);
+    }
+
+    Ok(summary)
+}
+
+/// How many dotfiles [`link_files`] plans and links at a time, enough for [`Fs::symlink_all`]
+/// to spread them over threads
+const LINK_BATCH: usize = 4096;
+
+/// A dotfile [`link_files`] found in a layer, before the layers are resolved
+#[derive(Serialize, Deserialize)]
+struct Listed {
+    layer: usize,
+    #[serde(with = "paths::serde")]
+    source: PathBuf,
+}
+
+/// Plans the steps linking each of `dotfiles`, failing on the first target that cannot be
+/// linked. The system targets that need elevation are reported together at the end.
+fn plan_links(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+    checksums: &BTreeMap<PathBuf, String>,
+    dotfiles: &[Dotfile],
+) -> Result<Vec<Step>, DofiError> {
+    let mut privileged = Vec::new();
+
+    let _plan = timings::phase("plan");
+    let mut steps = Vec::with_capacity(dotfiles.len());
+    for dotfile in dotfiles {
+        let Dotfile { source, target, .. } = dotfile;
+        if !options.selects(&dotfile.tags) {
+            info!("Skipping '{}', it is not tagged", source.display());
+            events::emit(&Event::Skipped {
+                target,
+                reason: "it is not tagged",
+            });
+            steps.push(Step::Skipped);
+            continue;
+        }
+        if encryption::is_encrypted_for_others(fs, &options.encryption, source, &dotfile.layer)? {
+            info!(
+                "Skipping '{}', it is not encrypted for this machine",
+                source.display()
+            );
+            events::emit(&Event::Skipped {
+                target,
+                reason: "it is not encrypted for this machine",
+            });
+            steps.push(Step::Skipped);
+            continue;
+        }
+
+        events::emit(&Event::Planned { source, target });
+
+        let symlinked = dotfile.strategy == Strategy::Symlink;
+        let copied = dotfile.strategy.is_copied();
+        if elevate::is_privileged(target, base_directory) {
+            if !symlinked {
+                warn!(
+                    "Not linking '{}', only symlinked dotfiles can target system files",
+                    target.display()
+                );
+                events::emit(&Event::Skipped {
+                    target,
+                    reason: "only symlinked dotfiles can target system files",
+                });
+                steps.push(Step::Skipped);
+                continue;
+            }
+            let force =
+                options.force_all || options.force && links_into(fs, target, dotfiles_directories);
+            let commands = elevate::plan(fs, source, target, force)?;
+            if commands.is_empty() {
+                steps.push(Step::Linked);
+                continue;
+            }
+            if options.elevate {
+                steps.push(Step::Elevated(commands));
+            } else {
+                privileged.push(commands);
+            }
+            continue;
+        }
+
+        if let Some(reason) = unlinkable_symlink(fs, source) {
+            warn!("Not linking '{}', {reason}", source.display());
+            events::emit(&Event::Skipped { target, reason });
+            steps.push(Step::Skipped);
+            continue;
+        }
+
+        let step = match fs.symlink_metadata(target) {
+            _ if symlinked
+                && fs
+                    .read_link(target)
+                    .is_ok_and(|original| original == link_original(fs, source)) =>
+            {
+                Step::Linked
+            }
+            Ok(_) if dotfile.strategy == Strategy::Hardlink && fs.same_file(source, target) => {
+                Step::Linked
+            }
+            Err(_) if symlinked => Step::Symlink,
+            Err(_) => Step::Entry {
+                force: options.force,
+                backup: false,
+            },
+            // Copied, rendered and decrypted targets nobody changed since are written again
+            // when what they should hold changed
+            Ok(metadata) if copied && checksum::modified(fs, checksums, target) == Some(false) => {
+                let is_file = metadata.file_type == fs::FileType::File;
+                if is_file && fs.read(target)? == target_contents(fs, dotfile, options)? {
+                    Step::Linked
+                } else {
+                    Step::Rewrite
+                }
+            }
+            Ok(metadata) => {
+                let (resolution, force, backup) = match options.policies.policy(target) {
+                    None if options.force_all => ("force", true, false),
+                    None if options.force && links_into(fs, target, dotfiles_directories) => {
+                        ("force", true, false)
+                    }
+                    None if options.force => {
+                        return Err(DofiError::FileNotOwned(target.clone()));
+                    }
+                    None => ("fail", false, false),
+                    Some(ConflictPolicy::Force) => ("force", true, false),
+                    Some(ConflictPolicy::NeverForce) => {
+                        events::emit(&Event::Conflict {
+                            target,
+                            resolution: "skip",
+                        });
+                        warn!(
+                            "Not linking '{}', its conflict policy is never-force",
+                            target.display()
+                        );
+                        events::emit(&Event::Skipped {
+                            target,
+                            reason: "its conflict policy is never-force",
+                        });
+                        steps.push(Step::Skipped);
+                        continue;
+                    }
+                    Some(ConflictPolicy::CopyBackup) => (
+                        "copy-backup",
+                        true,
+                        metadata.file_type == fs::FileType::File,
+                    ),
+                };
+                events::emit(&Event::Conflict { target, resolution });
+                if !force {
+                    return Err(DofiError::FileExists(target.clone()));
+                }
+                Step::Entry { force, backup }
+            }
+        };
+        steps.push(step);
+    }
+
+    if !privileged.is_empty() {
+        let commands = privileged
+            .iter()
+            .flatten()
+            .map(|command| format!("  sudo {}", elevate::command_line(command)))
+            .collect::<Vec<_>>();
+        return Err(DofiError::ElevationRequired(
+            commands.join("\n"),
+            privileged.len(),
+        ));
+    }
+
+    Ok(steps)
+}
+
+fn apply_links(
+    fs: &dyn Fs,
+    dotfiles: &[Dotfile],
+    steps: &[Step],
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    // Plain dotfiles without an existing target are symlinked together
+    let mut pending = Vec::new();
+
+    for (dotfile, step) in dotfiles.iter().zip(steps) {
+        let Dotfile {
+            source,
+            target,
+            mode,
+            strategy,
+            ..
+        } = dotfile;
+        match step {
+            Step::Skipped => {
+                progress::advance();
+                continue;
+            }
+            Step::Linked => {}
+            Step::Symlink => {
+                if let Some(parent) = target.parent() {
+                    journal.create_dir_all(fs, parent)?;
+                }
+                pending.push(dotfile);
+                continue;
+            }
+            Step::Entry { force, backup } => {
+                if *backup {
+                    let backup = conflict::backup_path(target);
+                    info!("Copying '{}' to '{}'", target.display(), backup.display());
+                    journal.write_file(fs, &backup, &fs.read(target)?)?;
+                }
+                link_entry(fs, dotfile, *force, options, journal)?;
+            }
+            Step::Rewrite => link_entry(fs, dotfile, true, options, journal)?,
+            Step::Elevated(commands) => {
+                elevate::link(source, target, commands, journal)?;
+                progress::advance();
+                continue;
+            }
+        }
+        if let Some(mode) = mode {
+            journal.set_mode(fs, mode_path(source, target, *strategy), *mode)?;
+        }
+        progress::advance();
+    }
+
+    symlink_pending(fs, &pending, journal)
+}
+
+/// Symlinks the `pending` plain dotfiles at their targets, all of them even if some fail.
+/// Fails with the first error.
+fn symlink_pending(
+    fs: &dyn Fs,
+    pending: &[&Dotfile],
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let links = pending
+        .iter()
+        .map(|dotfile| (link_original(fs, &dotfile.source), dotfile.target.clone()))
+        .collect::<Vec<_>>();
+    let mut result = Ok(());
+    for ((original, _), (dotfile, outcome)) in
+        links.iter().zip(pending.iter().zip(fs.symlink_all(&links)))
+    {
+        progress::advance();
+        match outcome {
+            Ok(()) => {
+                info!(
+                    "Symlinking '{}' at '{}'",
+                    dotfile.source.display(),
+                    dotfile.target.display()
+                );
+                journal.record(Action::Symlinked {
+                    link: dotfile.target.clone(),
+                    target: original.clone(),
+                });
+                if let Some(mode) = dotfile.mode {
+                    journal.set_mode(fs, &dotfile.source, mode)?;
+                }
+            }
+            Err(e) if result.is_ok() => result = Err(e.into()),
+            Err(_) => {}
+        }
+    }
+    result
+}
+
+/// A dotfile together with the target it is linked to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dotfile {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// The dotfiles directory providing the dotfile
+    pub layer: PathBuf,
+    /// The tags its layer's manifest gives it
+    pub tags: Vec<String>,
+    /// The permission bits its layer's manifest requires of it
+    pub mode: Option<u32>,
+    /// Whether it lies in the [private] subtree and is copied instead of symlinked
+    pub private: bool,
+    /// How it is linked to its target
+    pub strategy: Strategy,
+}
+
+/// Lists the dotfiles of the layered `dotfiles_directories` ordered by target, a target
+/// provided by several layers comes from the last of them. Conditional dotfiles that do
+/// not apply on this machine are left out. Fails if two dotfiles of a layer, both
+/// conditional or both not, link to the same target.
+pub fn layered_dotfiles(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+) -> Result<Vec<Dotfile>, DofiError> {
+    layered_dotfiles_in(fs, base_directory, dotfiles_directories, &[])
+}
+
+/// Lists the dotfiles of the layered `dotfiles_directories` like [`layered_dotfiles`], only
+/// walking the repo-relative `paths` if there are any. Fails if one of them exists in none of
+/// the layers.
+pub fn layered_dotfiles_in(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    paths: &[PathBuf],
+) -> Result<Vec<Dotfile>, DofiError> {
+    let _walk = timings::phase("walk");
+    let layers = Layer::load_all(fs, dotfiles_directories)?;
+    check_paths(fs, dotfiles_directories, paths)?;
+
+    let mut listed = Vec::new();
+    for (index, layer) in layers.iter().enumerate() {
+        for source in list_files_in(fs, layer.directory, paths)? {
+            if layer.links(&source) {
+                listed.push(Listed {
+                    layer: index,
+                    source,
+                });
+            }
+        }
+    }
+
+    resolve_layers(&layers, base_directory, listed)
+}
+
+/// Fails if one of the repo-relative `paths` exists in none of the `dotfiles_directories`
+fn check_paths(
+    fs: &dyn Fs,
+    dotfiles_directories: &[PathBuf],
+    paths: &[PathBuf],
+) -> Result<(), DofiError> {
+    match paths.iter().find(|path| {
+        !dotfiles_directories
+            .iter()
+            .any(|layer| fs.exists(&layer.join(path)))
+    }) {
+        Some(path) => Err(DofiError::NoMatchingDotfile {
+            pattern: path.display().to_string(),
+            help: "paths are relative to the dotfiles directory".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A dotfiles directory together with what its manifest says about linking its dotfiles
+struct Layer<'a> {
+    directory: &'a Path,
+    manifest: Manifest,
+    modes: Vec<(GlobMatcher, u32)>,
+    strategies: Vec<(GlobMatcher, Strategy)>,
+}
+
+impl<'a> Layer<'a> {
+    fn load_all(fs: &dyn Fs, directories: &'a [PathBuf]) -> Result<Vec<Self>, DofiError> {
+        directories
+            .iter()
+            .map(|directory| {
+                let manifest = Manifest::load(fs, directory)?;
+                Ok(Layer {
+                    directory,
+                    modes: manifest.mode_matchers()?,
+                    strategies: manifest.strategy_matchers()?,
+                    manifest,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the dotfile `source` of the layer is linked on this machine
+    fn links(&self, source: &Path) -> bool {
+        condition::condition(source).is_none_or(|c| c.holds())
+            && source
+                .strip_prefix(self.directory)
+                .is_ok_and(|relative| self.manifest.is_linked_on(relative, condition::hostname()))
+    }
+}
+
+/// The dotfiles of the `listed` ones ordered by target, a target listed by several layers
+/// comes from the last of them. Fails if two dotfiles of a layer, both conditional or both
+/// not, link to the same target.
+fn resolve_layers(
+    layers: &[Layer],
+    base_directory: &Path,
+    mut listed: Vec<Listed>,
+) -> Result<Vec<Dotfile>, DofiError> {
+    // Conditional dotfiles go after the others of their layer so they win over them
+    listed.sort_by_cached_key(|listed| {
+        (listed.layer, condition::condition(&listed.source).is_some())
+    });
+
+    let mut dotfiles = BTreeMap::new();
+    for Listed { layer, source } in listed {
+        let layer = &layers[layer];
+        let conditional = condition::condition(&source).is_some();
+        let dotfile = layer_dotfile(
+            &layer.manifest,
+            &layer.modes,
+            &layer.strategies,
+            source,
+            base_directory,
+            layer.directory,
+        )?;
+        // Looked up in what is listed so far rather than remembered separately, which
+        // would double the memory taken by huge batches
+        let duplicate = dotfiles.get(&dotfile.target).filter(|other: &&Dotfile| {
+            other.layer == layer.directory
+                && condition::condition(&other.source).is_some() == conditional
+        });
+        if let Some(other) = duplicate {
+            return Err(DofiError::DuplicateTarget(
+                dotfile.target,
+                other.source.clone(),
+                dotfile.source,
+            ));
+        }
+        dotfiles.insert(dotfile.target.clone(), dotfile);
+    }
+
+    Ok(dotfiles.into_values().collect())
+}
+
+/// The dotfile `source` of the dotfiles directory `layer`, as its manifest links it into
+/// `base_directory`
+pub fn dotfile(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    layer: &Path,
+    source: &Path,
+) -> Result<Dotfile, DofiError> {
+    let manifest = Manifest::load(fs, layer)?;
+    layer_dotfile(
+        &manifest,
+        &manifest.mode_matchers()?,
+        &manifest.strategy_matchers()?,
+        source.to_path_buf(),
+        base_directory,
+        layer,
+    )
+}
+
+fn layer_dotfile(
+    manifest: &Manifest,
+    modes: &[(GlobMatcher, u32)],
+    strategies: &[(GlobMatcher, Strategy)],
+    source: PathBuf,
+    base_directory: &Path,
+    layer: &Path,
+) -> Result<Dotfile, DofiError> {
+    let target = manifest.target_path(&source, base_directory, layer)?;
+    let tags = source
+        .strip_prefix(layer)
+        .map(|relative_source| manifest.tags_of(relative_source))
+        .unwrap_or_default();
+    let private = private::is_private(&source, &[layer.to_path_buf()]);
+    let relative_target = target.strip_prefix(base_directory).ok();
+    // Private targets always get the private mode when they are copied
+    let mode = matching(modes, relative_target).filter(|_| !private);
+    let strategy = Strategy::resolve(&source, private, matching(strategies, relative_target));
+
+    Ok(Dotfile {
+        source,
+        target,
+        layer: layer.to_path_buf(),
+        tags,
+        mode,
+        private,
+        strategy,
+    })
+}
+
+/// The value of the first of `matchers` matching the base-relative `target`
+pub(crate) fn matching<T: Copy>(matchers: &[(GlobMatcher, T)], target: Option<&Path>) -> Option<T> {
+    let target = target?;
+    matchers
+        .iter()
+        .find(|(matcher, _)| matcher.is_match(target))
+        .map(|(_, value)| *value)
+}
+
+/// What the symlink at the target of the dotfile `source` points to: `source` itself, or for a
+/// symlink kept in the repo the same relative path it points to, so it is recreated as is
+pub fn link_original(fs: &dyn Fs, source: &Path) -> PathBuf {
+    fs.read_link(source)
+        .ok()
+        .filter(|original| original.is_relative())
+        .unwrap_or_else(|| source.to_path_buf())
+}
+
+/// Whether `target` is a symlink pointing into one of `dotfiles_directories`, which `force`
+/// may replace as dofi plausibly created it
+fn links_into(fs: &dyn Fs, target: &Path, dotfiles_directories: &[PathBuf]) -> bool {
+    fs.read_link(target).is_ok_and(|original| {
+        let original = target.parent().unwrap_or(target).join(original);
+        dotfiles_directories
+            .iter()
+            .any(|directory| original.starts_with(directory))
+    })
+}
+
+/// Why the symlink `source` kept in the repo cannot be recreated at its target, if it is one
+fn unlinkable_symlink(fs: &dyn Fs, source: &Path) -> Option<&'static str> {
+    let original = fs.read_link(source).ok()?;
+    if original.is_absolute() {
+        Some("it is a symlink to an absolute path")
+    } else if !fs.exists(&source.parent().unwrap_or(source).join(&original)) {
+        Some("it is a broken symlink")
+    } else {
+        None
+    }
+}
+
+/// The file holding the permission bits of the dotfile `source` linked at `target` with
+/// `strategy`: the copy at `target` for copied, encrypted and template dotfiles, `source`
+/// itself for symlinked and hard linked ones
+pub fn mode_path<'a>(source: &'a Path, target: &'a Path, strategy: Strategy) -> &'a Path {
+    if strategy.is_copied() {
+        target
+    } else {
+        source
+    }
+}
+
+/// The actual permission bits of the dotfile `source` linked at `target` if they differ from
+/// the required `mode`. Windows has no permission bits to compare.
+pub fn mode_violation(
+    fs: &dyn Fs,
+    source: &Path,
+    target: &Path,
+    strategy: Strategy,
+    mode: Option<u32>,
+) -> Option<u32> {
+    let mode = mode.filter(|_| !cfg!(windows))?;
+    let actual = fs
+        .symlink_metadata(mode_path(source, target, strategy))
+        .ok()?
+        .mode;
+
+    (actual != mode).then_some(actual)
+}
+
+/// Links `dotfile` at its target with its [`Strategy`], decrypting it if it is encrypted.
+/// Private copies and renders are readable only by the user.
+pub fn link_entry(
+    fs: &dyn Fs,
+    dotfile: &Dotfile,
+    force: bool,
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let Dotfile {
+        source: file,
+        target,
+        private,
+        strategy,
+        ..
+    } = dotfile;
+    if encryption::is_encrypted(file) {
+        let encryption = options
+            .encryption
+            .iter()
+            .find(|backend| file.extension().is_some_and(|e| e == backend.extension()))
+            .ok_or(DofiError::NoEncryptionKey)?;
+        decrypt_file(fs, file, target, force, encryption.as_ref(), journal)
+    } else {
+        match strategy {
+            Strategy::Template => render_file(
+                fs,
+                file,
+                target,
+                *private,
+                force,
+                &options.templates,
+                journal,
+            ),
+            Strategy::Copy => copy_file(fs, file, target, *private, force, journal),
+            Strategy::Hardlink => hard_link_file(fs, file, target, force, journal),
+            Strategy::Symlink => link_file(fs, file, target, force, journal),
+        }
+    }
+}
+
+/// What linking `dotfile` writes to its target: its contents decrypted, rendered or as they
+/// are, depending on how it is [linked](Strategy)
+pub fn target_contents(
+    fs: &dyn Fs,
+    dotfile: &Dotfile,
+    options: &LinkOptions,
+) -> Result<Vec<u8>, DofiError> {
+    let file = &dotfile.source;
+    let contents = fs.read(file)?;
+    if encryption::is_encrypted(file) {
+        options
+            .encryption
+            .iter()
+            .find(|backend| file.extension().is_some_and(|e| e == backend.extension()))
+            .ok_or(DofiError::NoEncryptionKey)?
+            .decrypt(&contents)
+    } else if dotfile.strategy == Strategy::Template {
+        let rendered = template::render(
+            fs,
+            &String::from_utf8_lossy(&contents),
+            file,
+            &options.templates,
+        )?;
+        Ok(rendered.into_bytes())
+    } else {
+        Ok(contents)
+    }
+}
+
+/// Copies the dotfile `file` to `target` with the same permissions, or readable only by the
+/// user if it is `private`. An existing file at `target` is replaced if `force` is set, unless
+/// it was changed since it was last written.
+pub fn copy_file(
+    fs: &dyn Fs,
+    file: &Path,
+    target: &Path,
+    private: bool,
+    force: bool,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if !force && fs.symlink_metadata(target).is_ok() {
+        return Err(DofiError::FileExists(target.to_path_buf()));
+    }
+    checksum::ensure_unmodified(fs, target, journal)?;
+    if let Some(parent) = target.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+
+    info!("Copying '{}' to '{}'", file.display(), target.display());
+    let contents = fs.read(file)?;
+    let mode = if private {
+        private::PRIVATE_MODE
+    } else {
+        fs.symlink_metadata(file)?.mode
+    };
+    journal.replace_file(fs, target, &contents, mode)?;
+    checksum::record(fs, target, &contents, &contents, journal)?;
+
+    Ok(())
+}
+
+/// Hard links `target` to the dotfile `file`, creating parent directories as needed. An
+/// existing file at `target` is replaced if `force` is set.
+pub fn hard_link_file(
+    fs: &dyn Fs,
+    file: &Path,
+    target: &Path,
+    force: bool,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if !force && fs.symlink_metadata(target).is_ok() {
+        return Err(DofiError::FileExists(target.to_path_buf()));
+    }
+    if let Some(parent) = target.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+
+    info!(
+        "Hard linking '{}' at '{}'",
+        file.display(),
+        target.display()
+    );
+    journal.hard_link(fs, file, target)
+}
+
+/// Writes the rendered template dotfile `file` to `target`, with the same permissions as
+/// `file` or readable only by the user if it is `private`. An existing file at `target` is
+/// replaced if `force` is set, unless it was changed since it was last written.
+pub fn render_file(
+    fs: &dyn Fs,
+    file: &Path,
+    target: &Path,
+    private: bool,
+    force: bool,
+    context: &template::Context,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if !force && fs.symlink_metadata(target).is_ok() {
+        return Err(DofiError::FileExists(target.to_path_buf()));
+    }
+    checksum::ensure_unmodified(fs, target, journal)?;
+    let source = fs.read(file)?;
+    let rendered = template::render(fs, &String::from_utf8_lossy(&source), file, context)?;
+
+    if let Some(parent) = target.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    info!("Rendering '{}' to '{}'", file.display(), target.display());
+    let mode = if private {
+        private::PRIVATE_MODE
+    } else {
+        fs.symlink_metadata(file)?.mode
+    };
+    journal.replace_file(fs, target, rendered.as_bytes(), mode)?;
+    checksum::record(fs, target, rendered.as_bytes(), &source, journal)?;
+
+    Ok(())
+}
+
+/// Writes the decrypted contents of the encrypted dotfile `file` to `target`, readable only
+/// by the user. An existing file at `target` is replaced if `force` is set, unless it was
+/// changed since it was last written.
+pub fn decrypt_file(
+    fs: &dyn Fs,
+    file: &Path,
+    target: &Path,
+    force: bool,
+    encryption: &dyn Encryption,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if !force && fs.symlink_metadata(target).is_ok() {
+        return Err(DofiError::FileExists(target.to_path_buf()));
+    }
+    checksum::ensure_unmodified(fs, target, journal)?;
+    if let Some(parent) = target.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+
+    info!("Decrypting '{}' to '{}'", file.display(), target.display());
+    let source = fs.read(file)?;
+    let plaintext = encryption.decrypt(&source)?;
+    journal.replace_file(fs, target, &plaintext, 0o600)?;
+    checksum::record(fs, target, &plaintext, &source, journal)?;
+
+    Ok(())
+}
+
+/// Symlinks the dotfile `file` at `symlink`, creating parent directories as needed. An
+/// existing file at `symlink` is replaced if `force` is set.
+pub fn link_file(
+    fs: &dyn Fs,
+    file: &Path,
+    symlink: &Path,
+    force: bool,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if let Some(parent) = symlink.parent() {
+        info!("Create folder '{}'", parent.display());
+        journal.create_dir_all(fs, parent)?;
+    }
+
+    if force && fs.exists(symlink) {
+        info!("Removing existing file '{}'", symlink.display());
+        journal.remove_file(fs, symlink)?;
+    }
+
+    info!("Symlinking '{}' at '{}'", file.display(), symlink.display());
+    let original = link_original(fs, file);
+    fs.symlink(&original, symlink)?;
+    journal.record(Action::Symlinked {
+        link: symlink.to_path_buf(),
+        target: original,
+    });
+
+    Ok(())
+}
+
+/// Moves the dotfile linked at `old_target` so it is linked at `new_target` instead.
+///
+/// The dotfile is moved to the location in `dotfiles_directory` mirroring `new_target`, the
+/// old symlink is removed and a new one is created.
+pub fn move_file(
+    fs: &dyn Fs,
+    old_target: &Path,
+    new_target: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let source = find_dotfile(fs, old_target, base_directory, dotfiles_directory)?;
+    let manifest = Manifest::load(fs, dotfiles_directory)?;
+    let old_target = manifest.target_path(&source, base_directory, dotfiles_directory)?;
+
+    let relative_target = new_target.strip_prefix(base_directory).map_err(|_| {
+        DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), new_target.to_path_buf())
+    })?;
+    let new_source = dotfiles_directory.join(relative_target);
+    for path in [new_source.as_path(), new_target] {
+        if fs.symlink_metadata(path).is_ok() {
+            return Err(DofiError::FileExists(path.to_path_buf()));
+        }
+    }
+
+    if fs
+        .read_link(&old_target)
+        .is_ok_and(|original| original == source)
+    {
+        info!("Removing symlink '{}'", old_target.display());
+        journal.remove_file(fs, &old_target)?;
+    }
+
+    if let Some(parent) = new_source.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    info!(
+        "Moving '{}' to '{}'",
+        source.display(),
+        new_source.display()
+    );
+    fs.rename(&source, &new_source)?;
+    journal.record(Action::Moved {
+        from: source.clone(),
+        to: new_source.clone(),
+    });
+
+    if let Ok(relative_source) = source.strip_prefix(dotfiles_directory) {
+        manifest::set_target(fs, dotfiles_directory, relative_source, None, journal)?;
+    }
+
+    link_file(fs, &new_source, new_target, false, journal)
+}
+
+/// The dotfiles of `dotfiles_directory` inside `directory`, a directory in the dotfiles or
+/// one holding their targets in `base_directory`
+pub fn dotfiles_under(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    directory: &Path,
+) -> Result<Vec<PathBuf>, DofiError> {
+    Ok(
+        layered_dotfiles(fs, base_directory, &[dotfiles_directory.into()])?
+            .into_iter()
+            .filter(|dotfile| {
+                dotfile.source.starts_with(directory) || dotfile.target.starts_with(directory)
+            })
+            .map(|dotfile| dotfile.source)
+            .collect(),
+    )
+}
+
+/// Finds the dotfile backing `path`, which may be the dotfile itself, a symlink to it or its
+/// target location in `base_directory`
+pub fn find_dotfile(
+    fs: &dyn Fs,
+    path: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+) -> Result<PathBuf, DofiError> {
+    let mut source = match fs.read_link(path) {
+        Ok(original) if original.starts_with(dotfiles_directory) => original,
+        _ => Manifest::load(fs, dotfiles_directory)?.source_path(
+            path,
+            base_directory,
+            dotfiles_directory,
+        )?,
+    };
+    // Encrypted, template and conditional dotfiles carry a suffix their target does not have
+    if !fs.exists(&source) {
+        if let Some(dotfile) = layered_dotfiles(fs, base_directory, &[dotfiles_directory.into()])?
+            .into_iter()
+            .find(|dotfile| dotfile.target == path)
+        {
+            source = dotfile.source;
+        }
+    }
+
+    match fs.symlink_metadata(&source) {
+        Ok(metadata) if metadata.file_type == fs::FileType::File => Ok(source),
+        _ => Err(DofiError::FileIsNotADotfile(path.to_path_buf())),
+    }
+}
+
+/// The dotfiles of the layered `dotfiles_directories` linked to the target `path`, or to the
+/// target of the dotfile `path`, from the lowest layer to the one that wins
+pub fn dotfiles_linked_to(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    path: &Path,
+) -> Result<Vec<Dotfile>, DofiError> {
+    let mut dotfiles = Vec::new();
+    for layer in dotfiles_directories {
+        dotfiles.extend(layered_dotfiles(
+            fs,
+            base_directory,
+            std::slice::from_ref(layer),
+        )?);
+    }
+
+    let target = dotfiles
+        .iter()
+        .find(|dotfile| dotfile.source == path)
+        .map_or(path, |dotfile| &dotfile.target)
+        .to_path_buf();
+    dotfiles.retain(|dotfile| dotfile.target == target);
+    Ok(dotfiles)
+}
+
+/// The dotfiles of the package `package` of `dotfiles_directory`, the top-level directory of
+/// that name. Fails if there is no such directory or it holds no dotfiles.
+pub fn package_dotfiles(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    package: &str,
+) -> Result<Vec<PathBuf>, DofiError> {
+    let relative = Path::new(package);
+    let dotfiles = match relative.components().collect::<Vec<_>>().as_slice() {
+        [Component::Normal(_)]
+            if fs
+                .symlink_metadata(&dotfiles_directory.join(relative))
+                .is_ok_and(|metadata| metadata.file_type == fs::FileType::Directory) =>
+        {
+            list_files_in(fs, dotfiles_directory, &[relative.to_path_buf()])?
+        }
+        _ => Vec::new(),
+    };
+    if dotfiles.is_empty() {
+        return Err(DofiError::NoMatchingDotfile {
+            pattern: package.to_string(),
+            help: "packages are the top-level directories of the dotfiles, `dofi stats` lists them"
+                .to_string(),
+        });
+    }
+    Ok(dotfiles)
+}
+
+/// The names of the packages of `dotfiles_directory`, the top-level directories holding
+/// dotfiles, in order
+pub fn packages(fs: &dyn Fs, dotfiles_directory: &Path) -> Result<Vec<String>, DofiError> {
+    let mut packages = BTreeSet::new();
+    for source in list_files(fs, dotfiles_directory)? {
+        let relative = source
+            .strip_prefix(dotfiles_directory)
+            .expect("the dotfiles are listed inside their directory");
+        let mut components = relative.components();
+        if let (Some(package), Some(_)) = (components.next(), components.next()) {
+            packages.insert(package.as_os_str().to_string_lossy().into_owned());
+        }
+    }
+    Ok(packages.into_iter().collect())
+}
+
+/// The dotfiles of `dotfiles_directory` whose repo-relative path matches `pattern`, a plain
+/// path like `zsh/zshrc` or a glob like `nvim/**`, in path order. Fails listing the closest
+/// dotfiles if none matches.
+pub fn match_dotfiles(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    pattern: &str,
+) -> Result<Vec<PathBuf>, DofiError> {
+    let matcher = Glob::new(pattern)
+        .map_err(|e| DofiError::InvalidGlob(pattern.to_string(), e.to_string()))?
+        .compile_matcher();
+    let files = list_files(fs, dotfiles_directory)?;
+    let relative = |file: &PathBuf| {
+        file.strip_prefix(dotfiles_directory)
+            .unwrap_or(file)
+            .to_path_buf()
+    };
+
+    let matches = files
+        .iter()
+        .filter(|file| matcher.is_match(relative(file)))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !matches.is_empty() {
+        return Ok(matches);
+    }
+
+    let mut close = files
+        .iter()
+        .map(|file| relative(file).to_string_lossy().into_owned())
+        .map(|file| (edit_distance(pattern, &file), file))
+        .filter(|(distance, file)| *distance <= file.len().max(pattern.len()) / 3)
+        .collect::<Vec<_>>();
+    close.sort();
+    let help = if close.is_empty() {
+        "see `dofi list` for the dotfiles".to_string()
+    } else {
+        let close = close
+            .into_iter()
+            .take(3)
+            .map(|(_, file)| format!("'{file}'"))
+            .collect::<Vec<_>>();
+        format!("did you mean {}?", close.join(", "))
+    };
+    Err(DofiError::NoMatchingDotfile {
+        pattern: pattern.to_string(),
+        help,
+    })
+}
+
+/// The Levenshtein distance between `a` and `b`, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Adds `tag` to the dotfile or directory `path` in the [`Manifest`], or removes it with
+/// `tagged` unset. `path` may be given in `dotfiles_directory` or as its target.
+pub fn tag_path(
+    fs: &dyn Fs,
+    path: &Path,
+    tag: &str,
+    tagged: bool,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let source = match find_dotfile(fs, path, base_directory, dotfiles_directory) {
+        Ok(source) => source,
+        Err(_) => Manifest::load(fs, dotfiles_directory)?.source_path(
+            path,
+            base_directory,
+            dotfiles_directory,
+        )?,
+    };
+    let relative_source = match source.strip_prefix(dotfiles_directory) {
+        Ok(relative_source) if fs.exists(&source) => relative_source,
+        _ => return Err(DofiError::FileIsNotADotfile(path.to_path_buf())),
+    };
+
+    info!(
+        "{} '{}'",
+        if tagged { "Tagging" } else { "Untagging" },
+        relative_source.display()
+    );
+    manifest::set_tag(
+        fs,
+        dotfiles_directory,
+        tag,
+        relative_source,
+        tagged,
+        journal,
+    )
+}
+
+/// Lists all dotfiles in `dotfiles_directory`, leaving out the manifest, the
+/// [variables](vars), the [scripts], the [partials](template::PARTIALS_DIRECTORY), version
+/// control metadata and whatever the manifest excludes, including the files matched by
+/// `.gitignore` files if it says so
+pub fn list_files(fs: &dyn Fs, dotfiles_directory: &Path) -> Result<Vec<PathBuf>, DofiError> {
+    list_files_in(fs, dotfiles_directory, &[])
+}
+
+/// Lists the dotfiles in `dotfiles_directory` like [`list_files`], only walking the
+/// repo-relative `paths` if there are any. Paths missing from the directory are ignored.
+pub fn list_files_in(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    paths: &[PathBuf],
+) -> Result<Vec<PathBuf>, DofiError> {
+    let mut filter = FileFilter::new(fs, dotfiles_directory)?;
+    let mut files = Vec::new();
+    for root in walk_roots(fs, dotfiles_directory, paths) {
+        for file in fs.walk(&root)? {
+            filter.filter(fs, file, &mut |file| {
+                files.push(file);
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(files)
+}
+
+/// Calls `visit` with the dotfiles [`list_files_in`] lists, one at a time and without ever
+/// holding all of them
+fn each_file_in(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    paths: &[PathBuf],
+    visit: &mut dyn FnMut(PathBuf) -> Result<(), DofiError>,
+) -> Result<(), DofiError> {
+    let mut filter = FileFilter::new(fs, dotfiles_directory)?;
+    for root in walk_roots(fs, dotfiles_directory, paths) {
+        fs.walk_each(&root, &mut |file| filter.filter(fs, file, visit))?;
+    }
+
+    Ok(())
+}
+
+/// What to walk for the repo-relative `paths` of `dotfiles_directory`, in order, leaving out
+/// the paths missing from it and those inside others. The whole directory if there are none.
+fn walk_roots(fs: &dyn Fs, dotfiles_directory: &Path, paths: &[PathBuf]) -> Vec<PathBuf> {
+    if paths.is_empty() {
+        return vec![dotfiles_directory.to_path_buf()];
+    }
+    let mut roots = paths
+        .iter()
+        .map(|path| dotfiles_directory.join(path))
+        .filter(|path| fs.exists(path))
+        .collect::<Vec<_>>();
+    roots.sort();
+    roots.dedup_by(|root, outer| root.starts_with(outer));
+    roots
+}
+
+/// Narrows the files walked in a dotfiles directory, in order, down to its dotfiles. Only the
+/// directories above the current file are remembered, with their `.gitignore` rules.
+struct FileFilter<'a> {
+    dotfiles_directory: &'a Path,
+    manifest_file: PathBuf,
+    manifest: Manifest,
+    exclusions: GlobSet,
+    directories: Vec<WalkedDirectory>,
+    /// The nested repository given in place of its files last
+    repository: Option<PathBuf>,
+    /// The directories linked as a whole that were given in place of their files
+    collapsed: BTreeSet<PathBuf>,
+}
+
+/// A directory above the file [`FileFilter`] looks at
+struct WalkedDirectory {
+    path: PathBuf,
+    /// The rules of its `.gitignore`, if it has one the manifest asks to honor
+    gitignore: Option<Gitignore>,
+    /// Whether it is a git repository nested in the dotfiles directory
+    repository: bool,
+}
+
+impl<'a> FileFilter<'a> {
+    fn new(fs: &dyn Fs, dotfiles_directory: &'a Path) -> Result<Self, DofiError> {
+        let manifest = Manifest::load(fs, dotfiles_directory)?;
+        Ok(Self {
+            dotfiles_directory,
+            manifest_file: dotfiles_directory.join(manifest::MANIFEST_FILE),
+            exclusions: manifest.exclusions()?,
+            manifest,
+            directories: Vec::new(),
+            repository: None,
+            collapsed: BTreeSet::new(),
+        })
+    }
+
+    /// Gives `emit` the dotfile the walked `file` stands for, if any: the file itself, the
+    /// nested repository or the directory linked as a whole it lies in, or nothing if it is
+    /// ignored or excluded
+    fn filter(
+        &mut self,
+        fs: &dyn Fs,
+        file: PathBuf,
+        emit: &mut dyn FnMut(PathBuf) -> Result<(), DofiError>,
+    ) -> Result<(), DofiError> {
+        if let Some(parent) = file.parent() {
+            self.enter(fs, parent)?;
+        }
+        if self.is_gitignored(&file) || self.is_excluded(&file) {
+            return Ok(());
+        }
+
+        let mut file = file;
+        if let Some(nested_repos) = self.manifest.nested_repos {
+            // The outermost repository wins
+            if let Some(directory) = self.directories.iter().find(|d| d.repository) {
+                if nested_repos == NestedRepos::Skip
+                    || self.repository.as_ref() == Some(&directory.path)
+                {
+                    return Ok(());
+                }
+                self.repository = Some(directory.path.clone());
+                file = directory.path.clone();
+            }
+        }
+        // The outermost directory wins, like with nested repositories
+        let directory = self
+            .manifest
+            .directories
+            .iter()
+            .map(|directory| self.dotfiles_directory.join(directory))
+            .filter(|directory| file.starts_with(directory))
+            .min_by_key(|directory| directory.components().count());
+        match directory {
+            Some(directory) if !self.collapsed.insert(directory.clone()) => Ok(()),
+            Some(directory) => emit(directory),
+            None => emit(file),
+        }
+    }
+
+    /// Makes `directory` the one holding the current file, leaving the directories it is not
+    /// in and entering those above it that were not entered yet, outermost first
+    fn enter(&mut self, fs: &dyn Fs, directory: &Path) -> Result<(), DofiError> {
+        while self
+            .directories
+            .last()
+            .is_some_and(|walked| !directory.starts_with(&walked.path))
+        {
+            self.directories.pop();
+        }
+        let current = self.directories.last().map(|walked| walked.path.clone());
+        let mut entered = directory
+            .ancestors()
+            .take_while(|ancestor| match &current {
+                Some(current) => ancestor != current,
+                None => ancestor.starts_with(self.dotfiles_directory),
+            })
+            .collect::<Vec<_>>();
+        entered.reverse();
+
+        for path in entered {
+            let gitignore = path.join(GITIGNORE_FILE);
+            let gitignore = if self.manifest.gitignore
+                && fs
+                    .symlink_metadata(&gitignore)
+                    .is_ok_and(|metadata| metadata.file_type != fs::FileType::Directory)
+            {
+                let mut builder = GitignoreBuilder::new(path);
+                for line in String::from_utf8_lossy(&fs.read(&gitignore)?).lines() {
+                    builder.add_line(Some(gitignore.clone()), line)?;
+                }
+                Some(builder.build()?)
+            } else {
+                None
+            };
+            let repository = self.manifest.nested_repos.is_some()
+                && path != self.dotfiles_directory
+                && fs.exists(&path.join(".git"));
+            self.directories.push(WalkedDirectory {
+                path: path.to_path_buf(),
+                gitignore,
+                repository,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Whether `file` is ignored, the deepest `.gitignore` with a matching rule deciding like git
+    fn is_gitignored(&self, file: &Path) -> bool {
+        self.directories
+            .iter()
+            .rev()
+            .filter_map(|directory| directory.gitignore.as_ref())
+            .map(|gitignore| gitignore.matched_path_or_any_parents(file, false))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+
+    /// Whether `file` is the manifest, a [variables](vars) file, a [script](scripts), a
+    /// [partial](template::PARTIALS_DIRECTORY) or excluded by the manifest
+    fn is_excluded(&self, file: &Path) -> bool {
+        let excluded = file
+            .strip_prefix(self.dotfiles_directory)
+            .is_ok_and(|relative_file| {
+                vars::is_vars_path(relative_file)
+                    || scripts::is_script_path(relative_file)
+                    || template::is_partial_path(relative_file)
+                    || relative_file
+                        .ancestors()
+                        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                        .any(|ancestor| self.exclusions.is_match(ancestor))
+            });
+        *file == self.manifest_file || excluded
+    }
+}
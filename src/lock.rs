@@ -0,0 +1,57 @@
+//! The advisory lock keeping concurrent dofi runs from interleaving their changes.
+//!
+//! Every command changing the filesystem holds an exclusive lock on `dofi.lock` in
+//! `$XDG_RUNTIME_DIR`, or the state directory where that is not set, until it finishes.
+
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::DofiError;
+
+const LOCK_FILE: &str = "dofi.lock";
+
+/// A held lock, released when dropped
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// The lock file for the journal in `state_directory`
+pub fn lock_path(state_directory: &Path) -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| state_directory.to_path_buf())
+        .join(LOCK_FILE)
+}
+
+/// Takes the lock at `path`. If another run holds it, waits for it to finish if `wait` is set
+/// and fails otherwise.
+pub fn acquire(path: &Path, wait: bool) -> Result<Lock, DofiError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) if wait => {
+            info!("Waiting for another dofi run to finish");
+            file.lock()?;
+        }
+        Err(std::fs::TryLockError::WouldBlock) => {
+            return Err(DofiError::Locked(path.to_path_buf()))
+        }
+        Err(std::fs::TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    Ok(Lock { _file: file })
+}
@@ -1,256 +1,4044 @@
 use std::{
-    io,
-    iter::Filter,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use clap::{command, Command, CommandFactory, Parser, Subcommand};
-use clap_complete::{generate, Generator, Shell};
-use ignore::{overrides::OverrideBuilder, WalkBuilder};
-use log::info;
-use miette::{bail, Diagnostic, Result};
-use thiserror::Error;
+use clap::{Arg, ArgAction, Command, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::{generate, Shell};
+use dofi::{
+    add_files, adopt, check,
+    checksum::{self, Drift},
+    color::{self, Color},
+    config::{self, Config, GitConfig},
+    confirm,
+    conflict::ConflictPolicies,
+    dangling_links, diff, doctor, dotfile, dotfiles_linked_to, dotfiles_under, editor, encryption,
+    events, export, find_dotfile, generate, git, grep, guard,
+    hooks::{self, Event},
+    impact, index, init, journal, layered_dotfiles, layered_dotfiles_in, link_entry, link_files,
+    list_files, lock, managed_dotfile, manifest, match_dotfiles, merge, migrate, mode_violation,
+    move_file, nuon, package_dotfiles, packages, paths,
+    permissions::{self, DirectoryModes},
+    picker, platform, plugin, progress, prune_dangling_links,
+    query::Query,
+    relink_dotfiles, remote, remove_file, report,
+    scripts::{self, Script, Step},
+    service, snapshot, source, stats,
+    status::{self, Entry, State},
+    tag_path, target_contents, template, templatify, timings, track, tui, update, vars, watch,
+    AddKind, AddOptions, DofiError, Dotfile, Journal, LinkOptions, Manifest, OsFs, RemoveOptions,
+};
+use log::{error, info, warn};
+use miette::{bail, Result};
 
 /// A simple dotfile manager, inspired by stow
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
 
-    #[arg(short, env = "DOFI_DIR")]
-    dotfiles_directory: PathBuf,
+    /// Defaults to `$DOFI_DIR`, the dotfiles directory of the selected workspace or the first
+    /// existing `default_dotfiles` directory, `$XDG_DATA_HOME/dofi` or `~/.dotfiles` unless configured
+    #[arg(short)]
+    dotfiles_directory: Option<PathBuf>,
+
+    /// Read the dotfiles from a bare git repository or the URL of a tarball instead of the
+    /// dotfiles directory, e.g. `dofi apply --source https://example.com/dotfiles.tar.gz`
+    #[arg(long, global = true, value_name = "SOURCE")]
+    source: Option<String>,
+
+    /// A dotfiles directory layered on top of the others, can be repeated, later ones win
+    #[arg(short = 'l', long = "layer", value_name = "DIRECTORY")]
+    layers: Vec<PathBuf>,
+
+    /// Defaults to the base directory of the selected workspace, or the home directory
+    /// (`$HOME`, or `%USERPROFILE%` on Windows)
+    #[arg(short)]
+    base_directory: Option<PathBuf>,
+
+    /// Use a workspace registered in the configuration file
+    #[arg(short = 'R', long, env = "DOFI_WORKSPACE")]
+    workspace: Option<String>,
+
+    /// Do not run the hooks declared in the dotfiles' `dofi.toml`
+    #[arg(long, global = true)]
+    no_hooks: bool,
+
+    /// Wait for another running dofi to finish instead of failing
+    #[arg(long, global = true)]
+    wait: bool,
+
+    /// When to color the output, `auto` colors it on a terminal unless `NO_COLOR` is set
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// How to write log messages to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// How to write an error to stderr, `json` writes one object with its `code`, `message`,
+    /// `help`, `paths` and `exit_code` among others
+    #[arg(long, global = true, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+
+    /// Write newline-delimited JSON progress events to this file, `-` for stdout
+    #[arg(long, global = true, value_name = "PATH")]
+    events: Option<PathBuf>,
+
+    /// Write a JSON summary of the run to this file, every action taken, every dotfile skipped
+    /// with why, the error if it failed and the timings
+    #[arg(long, global = true, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// The most threads walking the dotfiles or symlinking them, overriding
+    /// `filesystem.max_concurrency` in the configuration
+    #[arg(long, global = true, value_name = "N")]
+    max_concurrency: Option<NonZeroUsize>,
 
-    #[arg(short, env = "HOME")]
-    base_directory: PathBuf,
+    /// Print how long each phase took and how many filesystem operations ran to stderr
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Run destructive commands without asking, also set by `assume_yes` in the configuration
+    #[arg(short, long, global = true)]
+    yes: bool,
+
+    /// Defaults to `$XDG_CONFIG_HOME/dofi/config.toml`
+    #[arg(long, env = "DOFI_CONFIG")]
+    config: Option<PathBuf>,
 
     #[command(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 }
 
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Failure or, with `check --quick`, a dotfile that is not linked
+  2  Invalid command line or query
+  3  Invalid configuration or manifest
+  4  Conflicting, changed or, with `status`, out of date targets";
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Adds a dotfile to the dotfiles and links it back to its original place
-    Add { file: PathBuf },
+    Add {
+        /// The file to add, `-` to read a list of files from stdin
+        #[arg(required_unless_present_any = ["interactive", "files_from"])]
+        file: Option<PathBuf>,
+        /// Read the files to add from this file, one per line or separated by NUL, `-` for stdin
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["file", "repo_path"])]
+        files_from: Option<PathBuf>,
+        /// Pick the files to add from those in the base directory with a fuzzy finder
+        #[arg(short, long, conflicts_with_all = ["file", "files_from", "repo_path"])]
+        interactive: bool,
+        /// How many directories deep to look for files to pick
+        #[arg(long, default_value_t = 3, requires = "interactive")]
+        max_depth: usize,
+        /// Where in the dotfiles to put the file, defaults to its path relative to the base
+        #[arg(long = "as", value_name = "REPO_PATH")]
+        repo_path: Option<PathBuf>,
+        /// Store the file encrypted, with the configured backend, and leave it in place instead of linking it
+        #[arg(long)]
+        encrypt: bool,
+        /// Store the file as a template, `.gitconfig.tmpl` for `.gitconfig`, and leave it in place
+        /// to be rendered to
+        #[arg(long, conflicts_with = "encrypt")]
+        template: bool,
+        /// Replace the values of template variables in the file, like the hostname or the home
+        /// directory, with expressions inserting them
+        #[arg(long, requires = "template")]
+        substitute: bool,
+        /// Add the file a symlink points to, e.g. one left over from stow, moving it in place of the symlink
+        #[arg(long, visible_alias = "follow")]
+        adopt: bool,
+        /// Commit the new dotfile and push it, as if `git.auto_commit` and `git.auto_push` were set
+        #[arg(long)]
+        push: bool,
+        /// Add files even if they are large, binary or history and cache files
+        #[arg(long)]
+        allow_large: bool,
+        /// Require these permission bits of the target in the manifest, e.g. `600`
+        #[arg(long, value_parser = parse_mode)]
+        mode: Option<u32>,
+        /// Tag the new dotfile in the manifest, can be repeated
+        #[arg(short, long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Add a directory as a whole, replacing it with a single symlink so whatever is
+        /// written into it lands in the dotfiles too
+        #[arg(long, conflicts_with_all = ["interactive", "encrypt", "template", "adopt"])]
+        as_dir: bool,
+        /// Recreate the targets of files dofi already manages instead of refusing them, like
+        /// `link --force` does for those files only
+        #[arg(long, conflicts_with_all = ["interactive", "repo_path", "encrypt", "template", "adopt", "as_dir"])]
+        relink: bool,
+    },
     /// Remove a dotfile and any potential symlink, can be pointed both at the symlink and the original
     #[command(alias = "rm")]
-    Remove { file: PathBuf },
-    /// Links or relinks all dotfiles
+    Remove {
+        /// The file to remove, a path relative to the dotfiles directory or a glob like
+        /// 'nvim/**' matching such paths, `-` to read a list of files from stdin. Without one
+        /// the dotfiles to remove are picked interactively
+        file: Option<PathBuf>,
+        /// Read the files to remove from this file, one per line or separated by NUL, `-` for
+        /// stdin
+        #[arg(long, value_name = "PATH", conflicts_with = "file")]
+        files_from: Option<PathBuf>,
+        /// Remove every dotfile of a package, a top-level directory of the dotfiles, and the
+        /// directories this leaves empty
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["file", "files_from"])]
+        package: Option<String>,
+        /// Stop managing the file but keep it at its target, replacing the symlink with a copy
+        #[arg(long)]
+        keep_target: bool,
+        /// Only remove the symlinks, leaving the dotfiles in the dotfiles directory
+        #[arg(long, conflicts_with = "keep_target")]
+        keep_sources: bool,
+        /// Remove every dotfile inside the given directory, in the dotfiles or holding targets
+        #[arg(short, long)]
+        recursive: bool,
+        /// Only print the dotfiles that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Remove the directories left empty, in the dotfiles and those dofi created at the target
+        #[arg(long)]
+        prune_empty: bool,
+        /// Delete the file for good instead of moving it to the trash
+        #[arg(long)]
+        permanent: bool,
+        /// Commit the removal and push it, as if `git.auto_commit` and `git.auto_push` were set
+        #[arg(long)]
+        push: bool,
+    },
+    /// Moves a dotfile to a new target, relocating it in the dotfiles to match
+    #[command(alias = "move")]
+    Mv {
+        /// The current target or the dotfile
+        old_target: PathBuf,
+        new_target: PathBuf,
+    },
+    /// Links or relinks all dotfiles, or only those at or below the given paths
     #[command(alias = "ln")]
     Link {
-        #[arg(short, long, default_value_t = false)]
-        force: bool,
+        #[command(flatten)]
+        link: LinkArgs,
+        /// Also replace symlinks to the wrong dotfile and prune the symlinks to removed
+        /// dotfiles, so the targets match the dotfiles exactly
+        #[arg(long, conflicts_with = "paths")]
+        prune: bool,
+        /// Only link the dotfiles added to the repo since everything was last linked, as told
+        /// by git, without looking at the other dotfiles
+        #[arg(long, conflicts_with_all = ["prune", "paths"])]
+        only_new: bool,
+        /// Repo-relative files or directories to link, e.g. `nvim/ zsh/zshrc`
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+    },
+    /// Brings every target up to date: links, renders and decrypts all dotfiles, prunes the
+    /// symlinks to removed dotfiles, writes the generated targets, runs the hooks and the
+    /// scripts that did not run yet, and retries the pushes that failed
+    Apply {
+        #[command(flatten)]
+        link: LinkArgs,
+        /// Remove the directories dofi created that pruned symlinks leave empty
+        #[arg(long)]
+        prune_empty: bool,
+        /// Only run the scripts that did not finish yet, e.g. after one failed, without
+        /// linking again
+        #[arg(long, conflicts_with_all = ["prune_empty", "target_dir"])]
+        resume: bool,
     },
     /// Lists all dotfiles
     #[command(alias = "ls")]
-    List,
+    List {
+        /// List the targets of the dotfiles instead of the dotfiles themselves
+        #[arg(long)]
+        targets: bool,
+        /// List each dotfile with its target, as `<source> -> <target>`. Kept apart from
+        /// `--targets`, whose one path per line the shell completions read.
+        #[arg(long, conflicts_with = "targets")]
+        mapping: bool,
+        /// Print the dotfiles relative to their dotfiles directory and the targets relative to
+        /// the base directory
+        #[arg(long)]
+        relative: bool,
+        /// Show the dotfiles as a tree with the state of each
+        #[arg(long, conflicts_with_all = ["targets", "mapping", "relative"])]
+        tree: bool,
+        /// Print `<state>\t<source>\t<target>` lines in a format that is stable across versions
+        #[arg(long, conflicts_with_all = ["targets", "mapping", "relative", "tree"])]
+        porcelain: bool,
+        /// Also list the dotfiles in the private subtree
+        #[arg(long)]
+        private: bool,
+        /// End each path with a NUL byte instead of a newline and write it unescaped, with
+        /// `--porcelain` every field
+        #[arg(short = 'z', long = "null", conflicts_with = "tree")]
+        null: bool,
+        /// Print the dotfiles as data for another program, `nuon` for a nushell table
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with_all = ["targets", "mapping", "relative", "tree", "porcelain", "null"])]
+        format: OutputFormat,
+        #[command(flatten)]
+        states: StateFilter,
+        /// Look at every target instead of using the index of the last scan while the dotfiles
+        /// and the journal are unchanged
+        #[arg(long)]
+        no_cache: bool,
+        /// Only list dotfiles matching the query, e.g. 'state:unlinked changed:<7d'
+        query: Vec<String>,
+    },
+    /// Shows the link state of all dotfiles, and how their git repository stands against its upstream
+    #[command(alias = "st")]
+    Status {
+        #[command(flatten)]
+        states: StateFilter,
+        /// Look at every target, like `--no-cache`, so the exit code, 4 if any of the shown
+        /// targets is not linked or drifted from its dotfile, is not taken from the last scan
+        #[arg(long)]
+        check: bool,
+        /// Print `<state>\t<source>\t<target>` lines in a format that is stable across versions
+        #[arg(long)]
+        porcelain: bool,
+        /// Print the targets as data for another program, `nuon` for a nushell table
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "porcelain")]
+        format: OutputFormat,
+        /// Look at every target instead of using the index of the last scan while the dotfiles
+        /// and the journal are unchanged
+        #[arg(long)]
+        no_cache: bool,
+        /// Keep the view up to date as the dotfiles and targets change, marking the targets
+        /// whose state changed, until interrupted
+        #[arg(long, conflicts_with_all = ["check", "porcelain", "format"])]
+        watch: bool,
+        /// Only show dotfiles matching the query, e.g. 'state:conflict path:nvim'
+        query: Vec<String>,
+    },
+    /// Shows how targets that are not linked, or copies that drifted, differ from their dotfiles
+    Diff {
+        /// A dotfile or target, defaults to all dotfiles
+        file: Option<PathBuf>,
+        /// Only print how many lines changed in each file
+        #[arg(long)]
+        stat: bool,
+    },
+    /// Merges the local edits of a copied target with the changes made to its dotfile since,
+    /// using diff3 or `$MERGE_TOOL`
+    Merge {
+        /// A dotfile or target
+        file: PathBuf,
+    },
+    /// Writes an archive of the dotfiles as they appear in the base directory, with templates
+    /// rendered, for machines without dofi or git
+    Export {
+        /// Defaults to `archive`
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// The archive to write, compressed according to its extension, or the Nix module,
+        /// which is printed if none is given
+        #[arg(
+            short,
+            long,
+            value_name = "FILE",
+            required_unless_present = "format",
+            required_if_eq("format", "archive")
+        )]
+        output: Option<PathBuf>,
+        /// Also decrypt the encrypted dotfiles into the archive, they are left out otherwise
+        #[arg(long)]
+        decrypt: bool,
+        /// Link the home-manager targets to the dotfiles directly instead of to copies in the
+        /// Nix store
+        #[arg(long)]
+        out_of_store: bool,
+    },
+    /// Applies the dotfiles to another machine over SSH, with its dofi if it has one and by
+    /// unpacking the rendered tree into its home directory otherwise
+    PushRemote {
+        /// The machine to push to, anything `ssh` accepts like `user@host`
+        destination: String,
+        /// Unpack the rendered tree even if the remote machine has dofi
+        #[arg(long)]
+        copy: bool,
+        /// Also decrypt the encrypted dotfiles into the unpacked tree, they are left out otherwise
+        #[arg(long)]
+        decrypt: bool,
+    },
+    /// Searches the contents of the dotfiles for a regular expression
+    Grep {
+        pattern: String,
+        /// Match regardless of case
+        #[arg(short, long)]
+        ignore_case: bool,
+    },
+    /// Shows the dotfile tree in an interactive dashboard to link, unlink, diff and edit
+    /// single dotfiles, resolve conflicts and apply everything
+    Tui {
+        /// Only show dotfiles matching the query, e.g. 'state:conflict package:nvim'
+        query: Vec<String>,
+    },
+    /// Creates a dotfile for a target that does not exist yet, links it and opens it in
+    /// `$VISUAL` or `$EDITOR`
+    New {
+        /// The target to create, e.g. `~/.config/foot/foot.ini`
+        target: PathBuf,
+        /// Where in the dotfiles to put the file, defaults to the target's path relative to the base
+        #[arg(long = "as", value_name = "REPO_PATH")]
+        repo_path: Option<PathBuf>,
+        /// Start from the contents of this file, a skeleton, or `-` for stdin, instead of an
+        /// empty file
+        #[arg(long, value_name = "PATH")]
+        from: Option<PathBuf>,
+        /// Do not open the new dotfile in the editor
+        #[arg(long)]
+        no_edit: bool,
+    },
+    /// Opens the dotfile behind a target in `$VISUAL` or `$EDITOR`
+    Edit {
+        /// A dotfile or target, picked interactively if not given
+        file: Option<PathBuf>,
+        /// Link the dotfile afterwards if it is not linked yet
+        #[arg(short, long)]
+        link: bool,
+    },
+    /// Takes the contents of a target edited in place of its dotfile into the repo and links
+    /// it, or with `--from` imports the dotfiles managed by another tool and relinks them
+    Adopt {
+        /// The tool currently managing the dotfiles
+        #[arg(long, value_enum)]
+        from: Option<AdoptFrom>,
+        /// The target to adopt, or with `--from` the directory of the other tool, e.g. the
+        /// stow directory
+        #[arg(value_name = "PATH")]
+        directory: PathBuf,
+        /// Translate stow's `--dotfiles` naming, e.g. `dot-zshrc` to `.zshrc`
+        #[arg(long, requires = "from")]
+        dot_prefix: bool,
+        /// Merge the target with its dotfile instead of replacing the dotfile with it
+        #[arg(long, conflicts_with = "from")]
+        merge: bool,
+        /// Proceed even if the dotfiles repository has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
+    },
+    /// Copies the source directory of another dotfile manager into the dotfiles
+    Import {
+        /// The dotfile manager the source directory belongs to
+        #[arg(long, value_enum)]
+        from: ImportFrom,
+        /// The source directory, e.g. `~/.local/share/chezmoi`
+        directory: PathBuf,
+        /// Proceed even if the dotfiles repository has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
+    },
+    /// Moves the dotfiles stored under the names of their targets to `dot_` names, turning on
+    /// `dot_prefix` in the manifest
+    Migrate {
+        /// Only print the dotfiles that would move
+        #[arg(long)]
+        dry_run: bool,
+        /// Proceed even if the dotfiles repository has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
+    },
+    /// Turns the variants of a dotfile for single hosts into a template, with the lines they
+    /// differ in as variables in `vars.d`
+    Templatify {
+        /// The dotfile, by its target, its path in the dotfiles or one of its variants
+        file: PathBuf,
+    },
+    /// Reverts the last add, remove or link
+    Undo,
+    /// Keeps the age identity in the OS keyring, unlocked for `keyring = true` in the
+    /// [encryption] section of the configuration or instead of the identity file
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Lists or restores the targets that link runs replaced
+    Snapshots {
+        #[command(subcommand)]
+        command: SnapshotsCommand,
+    },
+    /// Summarizes what `link --force-all` would change before running it
+    Impact,
+    /// Keeps the targets up to date while the dotfiles change, until interrupted
+    Watch {
+        /// Only link dotfiles with this tag, can be repeated
+        #[arg(short, long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Remove the directories dofi created that pruned symlinks leave empty
+        #[arg(long)]
+        prune_empty: bool,
+    },
+    /// Watches directories for new config files, queueing them for review in `status` or
+    /// adding them, until interrupted
+    Track {
+        /// A directory to watch besides those in the `track` section of the configuration, can
+        /// be repeated
+        #[arg(long = "watch", value_name = "DIRECTORY")]
+        watch: Vec<PathBuf>,
+        /// Add the new files below the directories given with `--watch` instead of queueing them
+        #[arg(long)]
+        add: bool,
+        /// Remove files from the review queue instead of watching
+        #[arg(long, value_name = "FILE", num_args = 1.., conflicts_with_all = ["watch", "add"])]
+        dismiss: Vec<PathBuf>,
+    },
+    /// Manages a background service applying the dotfiles periodically
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Manages the tags of dotfiles
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+    /// Creates a dotfiles directory with a starter dofi.toml and .gitignore
+    Init {
+        /// The directory to create, defaults to the dotfiles directory
+        directory: Option<PathBuf>,
+        /// Also make the directory a git repository
+        #[arg(long)]
+        git: bool,
+    },
+    /// Checks for dotfiles whose targets will not work as symlinks or whose symlinks broke
+    Doctor {
+        /// Repair the broken symlinks, recreating missing ones, pointing those into an old
+        /// location of the repo at the dotfiles and removing those to removed dotfiles
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Validates the dotfiles without touching their targets, e.g. in the CI of the dotfiles repo
+    Check {
+        /// Look up every secret as an empty string instead of asking the secret provider
+        #[arg(long)]
+        offline: bool,
+        /// Only tell from the index whether any dotfile is not linked, silently, exiting with 1
+        /// if one is, fast enough for a shell prompt
+        #[arg(long, conflicts_with = "offline")]
+        quick: bool,
+    },
+    /// Prints a compact summary of the dotfiles that are not linked for a shell prompt, like
+    /// `+2 !1` for two unlinked dotfiles and a conflict, and nothing if all are linked. `x`
+    /// counts broken links. Uses the index like `check --quick`.
+    Prompt {
+        /// What to print, e.g. '{dirty}{behind}': `{drift}` is the default summary, `{dirty}`
+        /// a `*` if anything is not linked, `{unlinked}`, `{conflicts}` and `{broken}` its
+        /// parts, `{uncommitted}`, `{ahead}` and `{behind}` the git state of the dotfiles
+        #[arg(long, default_value = "{drift}")]
+        format: String,
+        /// Escape the output for the prompt of this shell
+        #[arg(long, value_enum, default_value_t = PromptShell::Plain)]
+        shell: PromptShell,
+    },
+    /// Summarizes the dotfiles: how many are in each state, per top-level directory of the
+    /// repo and in size, and which are the largest and the most recently modified
+    Stats {
+        /// How many of the largest and most recently modified dotfiles to list
+        #[arg(long, default_value_t = 5)]
+        top: usize,
+        /// Look at every target instead of using the index of the last scan while the dotfiles
+        /// and the journal are unchanged
+        #[arg(long)]
+        no_cache: bool,
+    },
+    /// Lists rendered and decrypted targets that were changed since dofi wrote them
+    Verify,
+    /// Runs git inside the dotfiles directory, e.g. `dofi git push`, exiting like git does
+    Git {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "ARGS"
+        )]
+        args: Vec<OsString>,
+    },
+    /// Explains how a target or dotfile is managed: the dotfile behind it and its layer, how
+    /// it is linked and the state of the target
+    Which {
+        /// A target or dotfile
+        path: PathBuf,
+    },
+    /// Prints what linking writes to a target, its dotfile rendered or decrypted for this
+    /// host, without writing anything
+    Cat {
+        /// A target or dotfile
+        path: PathBuf,
+    },
+    /// Prints the dotfiles directory, e.g. for `cdot() { cd "$(dofi dir)"; }`
+    Dir {
+        /// Print the target this dotfile is linked to instead
+        #[arg(long, value_name = "DOTFILE")]
+        target: Option<PathBuf>,
+    },
+    /// Prints the directories and settings dofi resolved, as shell variables to `eval` or as
+    /// JSON, which also holds the permission bits the manifests require
+    Env {
+        #[arg(long, value_enum, default_value_t = EnvFormat::Shell)]
+        format: EnvFormat,
+    },
+    /// Manages the registered workspaces
+    Workspaces {
+        #[command(subcommand)]
+        command: WorkspacesCommand,
+    },
+    /// Reads and changes the settings in the configuration file
+    Config {
+        /// Use the repo manifest `dofi.toml` shared through the dotfiles instead, e.g.
+        /// `dofi config --repo set modes.'.ssh/config' 0o600`
+        #[arg(long)]
+        repo: bool,
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
     /// Generate shell completions
-    Completions { shell: Shell },
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Completions {
+        #[arg(required = true)]
+        shell: Option<Shell>,
+        #[command(subcommand)]
+        command: Option<CompletionsCommand>,
+    },
+    /// Replaces the dofi binary with the latest GitHub release if it is newer, checking it for corruption only
+    SelfUpdate {
+        /// Only tell whether a newer release exists
+        #[arg(long)]
+        check: bool,
+    },
+    /// Writes man pages for dofi and all its subcommands
+    Manpages {
+        /// The directory to write the pages to, it is created if missing
+        out_dir: PathBuf,
+    },
+    /// Prints the names the completion scripts offer for an option, one per line
+    #[command(hide = true)]
+    Complete { values: CompletionValues },
+    /// Runs `dofi-<name>` from the `PATH` with the remaining arguments
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
+}
+
+impl Commands {
+    /// Whether the command changes the filesystem and has to hold the lock
+    fn mutates(&self) -> bool {
+        match self {
+            Commands::List { .. }
+            | Commands::Status { .. }
+            | Commands::Diff { .. }
+            | Commands::Grep { .. }
+            | Commands::Export { .. }
+            | Commands::PushRemote { .. }
+            // The dashboard locks each of its operations on its own
+            | Commands::Tui { .. }
+            | Commands::Impact
+            | Commands::Verify
+            | Commands::Dir { .. }
+            | Commands::Env { .. }
+            | Commands::Which { .. }
+            | Commands::Cat { .. }
+            | Commands::Git { .. }
+            // Plugins take the lock themselves if they need it, through dofi
+            | Commands::External(_)
+            | Commands::Check { .. }
+            | Commands::Prompt { .. }
+            | Commands::Stats { .. }
+            | Commands::Completions { .. }
+            | Commands::Complete { .. }
+            | Commands::Manpages { .. }
+            | Commands::SelfUpdate { .. }
+            | Commands::Workspaces { .. }
+            | Commands::Key { .. }
+            | Commands::Init { .. } => false,
+            Commands::Config { repo, command } => {
+                *repo && matches!(command, ConfigCommand::Set { .. })
+            }
+            Commands::Tag { command } => !matches!(command, TagCommand::List),
+            Commands::Snapshots { command } => !matches!(command, SnapshotsCommand::List),
+            Commands::Edit { link, .. } => *link,
+            Commands::Doctor { fix } => *fix,
+            Commands::Migrate { dry_run, .. } => !*dry_run,
+            Commands::Add { .. }
+            | Commands::New { .. }
+            | Commands::Remove { .. }
+            | Commands::Mv { .. }
+            | Commands::Link { .. }
+            | Commands::Apply { .. }
+            | Commands::Adopt { .. }
+            | Commands::Import { .. }
+            | Commands::Undo
+            | Commands::Service { .. }
+            | Commands::Merge { .. }
+            | Commands::Track { .. }
+            | Commands::Templatify { .. }
+            | Commands::Watch { .. } => true,
+        }
+    }
+
+    /// Whether the command changes the dotfiles themselves, which a read-only `--source`
+    /// does not allow
+    fn writes_dotfiles(&self) -> bool {
+        match self {
+            Commands::Config { repo, command } => {
+                *repo && matches!(command, ConfigCommand::Set { .. })
+            }
+            Commands::Tag { command } => !matches!(command, TagCommand::List),
+            Commands::Migrate { dry_run, .. } => !*dry_run,
+            command => matches!(
+                command,
+                Commands::Add { .. }
+                    | Commands::New { .. }
+                    | Commands::Remove { .. }
+                    | Commands::Mv { .. }
+                    | Commands::Edit { .. }
+                    | Commands::Adopt { .. }
+                    | Commands::Import { .. }
+                    | Commands::Merge { .. }
+                    | Commands::Track { .. }
+                    | Commands::Templatify { .. }
+            ),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => color::auto(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ExportFormat {
+    /// A tarball of the rendered tree
+    Archive,
+    /// A home-manager module declaring the targets
+    HomeManager,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Lines for people to read
+    Text,
+    /// A list of records in Nushell's object notation
+    Nuon,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EnvFormat {
+    /// `export NAME='value'` lines
+    Shell,
+    /// A JSON object
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with `timestamp`, `level`, `target` and `message` fields
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    /// The rendered diagnostic
+    Text,
+    /// The diagnostic as a JSON object
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PromptShell {
+    /// The output as it is, for bash, fish and starship
+    Plain,
+    /// With `%` doubled, zsh expands it in prompts
+    Zsh,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AdoptFrom {
+    /// A GNU stow directory with one directory per package
+    Stow,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ImportFrom {
+    /// A chezmoi source directory
+    Chezmoi,
+}
+
+/// How `link` and `apply` link the dotfiles
+#[derive(clap::Args, Debug)]
+struct LinkArgs {
+    /// Replace symlinks into the dotfiles that already exist at the targets, e.g. stale ones
+    #[arg(short, long, default_value_t = false)]
+    force: bool,
+    /// Replace whatever already exists at the targets, including regular files and foreign
+    /// symlinks
+    #[arg(long)]
+    force_all: bool,
+    /// Replace whatever already exists at the targets matching this glob, like `--force-all`
+    /// for those targets only, can be repeated. Relative globs and those starting with `~` are
+    /// relative to the base directory.
+    #[arg(long = "force-path", value_name = "GLOB", conflicts_with = "force_all")]
+    force_paths: Vec<String>,
+    /// Only link dotfiles with this tag, can be repeated
+    #[arg(short, long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+    /// Proceed even if the dotfiles repository has uncommitted changes
+    #[arg(long)]
+    allow_dirty: bool,
+    /// Link system targets outside the base directory with sudo
+    #[arg(long)]
+    sudo: bool,
+    /// Link into this directory instead of the base directory, to preview a run in a scratch
+    /// directory. It keeps its own state and the hooks and scripts do not run.
+    #[arg(long, value_name = "DIRECTORY", conflicts_with = "sudo")]
+    target_dir: Option<PathBuf>,
+}
+
+/// Restricts the output to dotfiles in any of the selected states, all if none is selected
+#[derive(clap::Args, Debug)]
+struct StateFilter {
+    /// Only dotfiles whose target is a symlink to them
+    #[arg(long)]
+    linked: bool,
+    /// Only dotfiles whose target is missing
+    #[arg(long)]
+    unlinked: bool,
+    /// Only dotfiles whose target is taken by another file
+    #[arg(long)]
+    conflicting: bool,
+    /// Only dotfiles whose target is a dangling symlink
+    #[arg(long)]
+    broken: bool,
+}
+
+impl StateFilter {
+    fn states(&self) -> Vec<State> {
+        [
+            (self.linked, State::Linked),
+            (self.unlinked, State::Unlinked),
+            (self.conflicting, State::Conflict),
+            (self.broken, State::Broken),
+        ]
+        .into_iter()
+        .filter_map(|(selected, state)| selected.then_some(state))
+        .collect()
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum TagCommand {
+    /// Lists all tags and what carries them
+    #[command(alias = "ls")]
+    List,
+    /// Tags dotfiles or directories, given in the dotfiles or as their targets
+    Add {
+        tag: String,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Removes a tag from dotfiles or directories
+    #[command(alias = "rm")]
+    Remove {
+        tag: String,
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
 }
 
-#[derive(Error, Diagnostic, Debug)]
-enum DofiError {
-    #[error(transparent)]
-    #[diagnostic(code(dofi::io_error))]
-    GenericIoError(#[from] std::io::Error),
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Installs a service running `dofi apply` on a schedule, with the current directories
+    #[command(group(clap::ArgGroup::new("manager").required(true)))]
+    Install {
+        /// Install a systemd user service and timer
+        #[arg(long, group = "manager")]
+        systemd: bool,
+        /// Install a launchd LaunchAgent, on macOS
+        #[arg(long, group = "manager")]
+        launchd: bool,
+        /// How often to apply the dotfiles, e.g. '30m', '1h' or '1d'
+        #[arg(long, value_name = "INTERVAL", default_value = "1h", value_parser = service::parse_interval)]
+        every: Duration,
+        /// Remove the service instead
+        #[arg(long)]
+        uninstall: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Asks for the passphrase of the identity once and leaves it unlocked in the keyring
+    Unlock,
+    /// Removes the unlocked identity from the keyring again
+    Lock,
+    /// Moves the identity into the keyring, removing the identity file and leaving its public
+    /// key next to it
+    Import,
+    /// Writes the identity from the keyring back to the identity file
+    Export,
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotsCommand {
+    /// Lists the snapshots, oldest first
+    #[command(alias = "ls")]
+    List,
+    /// Puts the targets of a snapshot back as they were before the link run replaced them
+    Restore { id: u64 },
+}
+
+#[derive(Subcommand, Debug)]
+enum CompletionsCommand {
+    /// Writes the completions to the directory the shell loads them from and tells what to add
+    /// to its rc file if it does not yet
+    Install {
+        /// Defaults to the shell in `$SHELL`
+        shell: Option<Shell>,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CompletionValues {
+    /// The packages for `remove --package`
+    Packages,
+    /// The tags of the manifest for `--tag`
+    Tags,
+}
+
+#[derive(Subcommand, Debug)]
+enum WorkspacesCommand {
+    /// Lists all registered workspaces
+    #[command(alias = "ls")]
+    List,
+    /// Registers a dotfiles directory as a workspace
+    Add {
+        name: String,
+        dotfiles: PathBuf,
+        /// The base directory of the workspace, defaults to the home directory
+        #[arg(short, long)]
+        base: Option<PathBuf>,
+    },
+    /// Unregisters a workspace, leaving its files untouched
+    #[command(alias = "rm")]
+    Remove { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Lists every setting with its value
+    #[command(alias = "ls")]
+    List,
+    /// Prints the value of a setting
+    Get {
+        /// The dotted key of the setting, e.g. `git.auto_push`
+        key: String,
+    },
+    /// Changes a setting, keeping the rest of the file as it is
+    Set {
+        /// The dotted key of the setting, e.g. `git.auto_push`
+        key: String,
+        /// A TOML value like `true` or `["~/dotfiles"]`, anything else is a string
+        value: String,
+    },
+}
 
-    #[error("Base '{}' is not a prefix of target '{}'", .0.display(), .1.display())]
-    #[diagnostic(code(dofi::prefix_error))]
-    BaseIsNotPrefixOfFile(PathBuf, PathBuf),
+/// Exit codes, documented in the help of [`Args`]
+const FAILURE: u8 = 1;
+const USAGE: u8 = 2;
+const INVALID_CONFIGURATION: u8 = 3;
+const OUT_OF_DATE: u8 = 4;
 
-    #[error("Target '{}' is not a regular file", .0.display())]
-    #[diagnostic(code(dofi::not_regular_file_error))]
-    FileIsNotRegular(PathBuf),
+fn main() -> ExitCode {
+    let args = expand_aliases(Args::parse());
+    let (log_format, error_format) = (args.log_format, args.error_format);
+    let report_path = args.report.clone();
+    let result = try_main(args);
+    if let Some(path) = &report_path {
+        let error = result.as_ref().err().map(json_diagnostic);
+        if let Err(e) = report::write(path, error) {
+            warn!("Could not write the report to '{}': {e}", path.display());
+        }
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            match (error_format, log_format) {
+                (ErrorFormat::Json, _) => eprintln!("{}", json_diagnostic(&report)),
+                (ErrorFormat::Text, LogFormat::Text) => eprintln!("Error: {report:?}"),
+                (ErrorFormat::Text, LogFormat::Json) => error!("{report}"),
+            }
+            ExitCode::from(exit_code(&report))
+        }
+    }
+}
 
-    #[error("Invalid base directory '{}': {0}", .1.display())]
-    #[diagnostic(code(dofi::base_dir_error))]
-    InvalidBaseDirectory(std::io::Error, PathBuf),
+/// `args` with an alias from the configuration in place of the command replaced by the
+/// arguments it stands for, parsed again. Exits if an alias ends up using itself.
+fn expand_aliases(mut args: Args) -> Args {
+    let mut raw = std::env::args_os().collect::<Vec<_>>();
+    let mut expanded = BTreeSet::new();
+    while let Commands::External(external) = &args.command {
+        let Some(name) = external.first().and_then(|name| name.to_str()) else {
+            break;
+        };
+        let Some(alias) = args
+            .config
+            .clone()
+            .or_else(config::config_path)
+            .and_then(|path| Config::load(&path).ok())
+            .and_then(|config| config.alias.get(name).cloned())
+        else {
+            break;
+        };
+        if !expanded.insert(name.to_string()) {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::InvalidSubcommand,
+                    format!("the alias '{name}' uses itself"),
+                )
+                .exit();
+        }
+        let position = raw.len() - external.len();
+        raw.splice(position..=position, alias.into_iter().map(OsString::from));
+        args = Args::parse_from(&raw);
+    }
+    args
+}
 
-    #[error("Invalid dotfiles directory '{}': {0}", .1.display())]
-    #[diagnostic(code(dofi::dotfiles_dir_error))]
-    InvalidDotfilesDirectory(std::io::Error, PathBuf),
+/// `report` as a JSON object, the fields of miette's JSON report with the `paths` the error is
+/// about and the `exit_code` added
+fn json_diagnostic(report: &miette::Report) -> serde_json::Value {
+    #[derive(serde::Serialize)]
+    struct Lossless<'a>(#[serde(serialize_with = "paths::serde::serialize")] &'a Path);
 
-    #[error(transparent)]
-    #[diagnostic(code(dofi::ignore_error))]
-    ListDirectoryFailed(#[from] ignore::Error),
+    let mut rendered = String::new();
+    let mut diagnostic = miette::JSONReportHandler::new()
+        .render_report(&mut rendered, report.as_ref())
+        .ok()
+        .and_then(|()| serde_json::from_str(&rendered).ok())
+        .unwrap_or_else(|| serde_json::json!({ "message": report.to_string() }));
+    let paths = report
+        .downcast_ref::<DofiError>()
+        .map(DofiError::paths)
+        .unwrap_or_default()
+        .into_iter()
+        .map(Lossless)
+        .collect::<Vec<_>>();
+    if let Some(object) = diagnostic.as_object_mut() {
+        object.insert("paths".to_string(), serde_json::json!(paths));
+        object.insert("exit_code".to_string(), exit_code(report).into());
+    }
+    diagnostic
+}
 
-    #[error("File '{}' is not a dotfile", .0.display())]
-    #[diagnostic(code(dofi::file_is_not_a_dotfile))]
-    FileIsNotADotfile(PathBuf),
+/// The exit code reporting the failure `report`
+fn exit_code(report: &miette::Report) -> u8 {
+    match report.downcast_ref::<DofiError>() {
+        Some(
+            DofiError::InvalidConfig { .. }
+            | DofiError::NoConfigFile
+            | DofiError::UnknownWorkspace(_)
+            | DofiError::NoBaseDirectory
+            | DofiError::InvalidBaseDirectory(..)
+            | DofiError::NoDotfilesDirectory
+            | DofiError::InvalidDotfilesDirectory(..)
+            | DofiError::InvalidRepoPath(_)
+            | DofiError::InvalidManifestTarget(..)
+            | DofiError::InvalidGeneratedTarget(_)
+            | DofiError::InvalidManifestRoot(..)
+            | DofiError::DuplicateTarget(..)
+            | DofiError::InvalidMode(..)
+            | DofiError::UnknownScript(..)
+            | DofiError::ScriptCycle(_)
+            | DofiError::UnsetVariable(..)
+            | DofiError::InvalidPattern(..)
+            | DofiError::NoEncryptionKey
+            | DofiError::NoSecretProvider,
+        ) => INVALID_CONFIGURATION,
+        Some(
+            DofiError::InvalidQuery(..)
+            | DofiError::InvalidPromptFormat(..)
+            | DofiError::InvalidSearchPattern(..)
+            | DofiError::InvalidGlob(..)
+            | DofiError::NoMatchingDotfile { .. }
+            | DofiError::InvalidConfigKey(..)
+            | DofiError::UnknownCommand(_)
+            | DofiError::UnknownShell
+            | DofiError::UnsupportedShell(_)
+            | DofiError::NothingToTrack
+            | DofiError::NoFileGiven(_),
+        ) => USAGE,
+        Some(
+            DofiError::FileExists(_)
+            | DofiError::FileNotOwned(_)
+            | DofiError::TargetModified(_)
+            | DofiError::OutOfDate(_),
+        ) => OUT_OF_DATE,
+        _ => FAILURE,
+    }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+fn try_main(args: Args) -> Result<()> {
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(args.verbose.log_level_filter());
+    if args.log_format == LogFormat::Json {
+        logger.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str().to_lowercase(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    logger.init();
 
-    env_logger::Builder::new()
-        .filter_level(args.verbose.log_level_filter())
-        .init();
+    if io::stderr().is_terminal() && args.verbose.log_level_filter() < log::LevelFilter::Info {
+        progress::enable();
+    }
+
+    if let Some(path) = &args.events {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path).map_err(DofiError::GenericIoError)?)
+        };
+        events::init(writer);
+    }
+    if args.timings {
+        timings::enable();
+    }
+    if args.report.is_some() {
+        report::enable();
+    }
+    let print_timings = args.timings;
+
+    let result = run(args).inspect_err(|e| {
+        events::emit(&events::Event::Failed {
+            message: &e.to_string(),
+        })
+    });
+    if let Some(report) = timings::report().filter(|_| print_timings) {
+        eprint!("{report}");
+    }
+    result
+}
+
+fn run(args: Args) -> Result<()> {
+    let color = args.color.enabled();
+    let config_path = args.config.clone().or_else(config::config_path);
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let assume_yes = args.yes || config.assume_yes;
+    dofi::fs::configure(&dofi::fs::FsConfig {
+        max_concurrency: args.max_concurrency.or(config.filesystem.max_concurrency),
+        ..config.filesystem.clone()
+    });
+
+    let command = match args.command {
+        Commands::Completions { shell, command } => {
+            let mut cmd = with_plugins(Args::command());
+            match command {
+                Some(CompletionsCommand::Install { shell }) => {
+                    let shell = shell
+                        .or_else(Shell::from_env)
+                        .ok_or(DofiError::UnknownShell)?;
+                    install_completions(shell, &mut cmd)?;
+                }
+                None => print!(
+                    "{}",
+                    completion_script(shell.expect("the shell is required"), &mut cmd)
+                ),
+            }
+            return Ok(());
+        }
+        Commands::Manpages { out_dir } => {
+            std::fs::create_dir_all(&out_dir).map_err(DofiError::GenericIoError)?;
+            clap_mangen::generate_to(Args::command(), &out_dir)
+                .map_err(DofiError::GenericIoError)?;
+            return Ok(());
+        }
+        Commands::SelfUpdate { check } => {
+            let current = env!("CARGO_PKG_VERSION");
+            let release = update::latest_release()?;
+            if !update::is_newer(release.version(), current) {
+                println!("dofi {current} is up to date");
+            } else if check {
+                println!(
+                    "dofi {} is available, {current} is installed",
+                    release.version()
+                );
+            } else {
+                let asset = release.asset(&update::asset_name())?;
+                let executable = std::env::current_exe().map_err(DofiError::GenericIoError)?;
+                update::install(&release, asset, &executable)?;
+                println!("Updated dofi from {current} to {}", release.version());
+            }
+            return Ok(());
+        }
+        Commands::Workspaces { command } => {
+            return workspaces(command, &config, config_path.as_deref());
+        }
+        Commands::Key { command } => {
+            match command {
+                KeyCommand::Unlock => {
+                    let identity = encryption::unlock_identity(&config.encryption)?;
+                    println!("Unlocked '{}' in the keyring", identity.display());
+                    if !config.encryption.keyring {
+                        warn!("Decrypting only uses it with keyring = true in [encryption]");
+                    }
+                }
+                KeyCommand::Lock => {
+                    if encryption::is_imported(&config.encryption) {
+                        bail!(DofiError::ImportedIdentity);
+                    }
+                    if encryption::lock_identity(&config.encryption)? {
+                        println!("Removed the identity from the keyring");
+                    } else {
+                        println!("The identity was not unlocked");
+                    }
+                }
+                KeyCommand::Import => {
+                    let identity = encryption::import_identity(&config.encryption)?;
+                    println!("Moved '{}' into the keyring", identity.display());
+                }
+                KeyCommand::Export => {
+                    let identity = encryption::export_identity(&config.encryption)?;
+                    println!("Wrote '{}' from the keyring", identity.display());
+                }
+            }
+            return Ok(());
+        }
+        Commands::Config {
+            repo: false,
+            command,
+        } => {
+            let config_path = config_path.ok_or(DofiError::NoConfigFile)?;
+            match command {
+                ConfigCommand::List => {
+                    for (key, value) in config::settings(&config_path)? {
+                        println!("{key} = {value}");
+                    }
+                }
+                ConfigCommand::Get { key } => match config::setting(&config_path, &key)? {
+                    Some(value) => println!("{value}"),
+                    None => bail!(DofiError::InvalidConfigKey(
+                        key,
+                        "it is not set".to_string()
+                    )),
+                },
+                ConfigCommand::Set { key, value } => {
+                    config::set_setting(&config_path, &key, &value)?;
+                    info!("Set '{key}'");
+                }
+            }
+            return Ok(());
+        }
+        command => command,
+    };
+
+    let workspace_name = match &args.workspace {
+        Some(name) => Some(name.clone()),
+        None if args.dotfiles_directory.is_some() || std::env::var_os("DOFI_DIR").is_some() => None,
+        None => config.default_workspace.clone(),
+    };
+    let workspace = workspace_name
+        .as_deref()
+        .map(|name| config.workspace(name))
+        .transpose()?;
 
     let base_directory = args
         .base_directory
+        .or_else(|| workspace.and_then(|w| w.base.as_deref().map(config::expand_path)))
+        .or_else(platform::home_directory)
+        .ok_or(DofiError::NoBaseDirectory)?;
+    let base_directory = base_directory
         .canonicalize()
-        .map_err(|e| DofiError::InvalidBaseDirectory(e, args.base_directory))?;
-    let dotfiles_directory = args
-        .dotfiles_directory
+        .map_err(|e| DofiError::InvalidBaseDirectory(e, base_directory))?;
+    let directory_modes = DirectoryModes::new(&config.directories, &base_directory)?;
+    permissions::init(directory_modes.clone());
+    if let Commands::Init { directory, git } = &command {
+        let dotfiles_directory = directory
+            .clone()
+            .or(args.dotfiles_directory)
+            .or_else(|| workspace.map(|w| config::expand_path(&w.dotfiles)))
+            .or_else(|| std::env::var_os("DOFI_DIR").map(PathBuf::from))
+            .or_else(|| config.default_dotfiles_directory(&base_directory, true))
+            .ok_or(DofiError::NoDotfilesDirectory)?;
+        let dotfiles_directory =
+            std::path::absolute(&dotfiles_directory).map_err(DofiError::GenericIoError)?;
+
+        let mut journal = Journal::new(&journal::state_directory(&base_directory), "init");
+        let result = init::scaffold(&OsFs, &dotfiles_directory, &mut journal);
+        journal.commit(&OsFs)?;
+        let written = result?;
+        for file in &written {
+            println!("Created '{}'", file.display());
+        }
+        if written.is_empty() {
+            println!("'{}' is already set up", dotfiles_directory.display());
+        }
+        if *git {
+            git::init(&dotfiles_directory)?;
+        }
+        return Ok(());
+    }
+
+    let state_directory = journal::state_directory(&base_directory);
+    let dotfiles_directory = match args.source.as_deref().map(source::parse) {
+        Some(_) if args.dotfiles_directory.is_some() => bail!(DofiError::ConflictingSources),
+        Some(source) => {
+            if !source.is_writable() && command.writes_dotfiles() {
+                bail!(DofiError::ReadOnlySource(source.name()));
+            }
+            source::open(source.as_ref(), &state_directory)?
+        }
+        None => args
+            .dotfiles_directory
+            .or_else(|| workspace.map(|w| config::expand_path(&w.dotfiles)))
+            .or_else(|| std::env::var_os("DOFI_DIR").map(PathBuf::from))
+            .or_else(|| config.default_dotfiles_directory(&base_directory, false))
+            .ok_or(DofiError::NoDotfilesDirectory)?,
+    };
+    let dotfiles_directory = dotfiles_directory
         .canonicalize()
-        .map_err(|e| DofiError::InvalidDotfilesDirectory(e, args.dotfiles_directory))?;
+        .map_err(|e| DofiError::InvalidDotfilesDirectory(e, dotfiles_directory))?;
+    let layers = std::iter::once(Ok(dotfiles_directory.clone()))
+        .chain(
+            workspace
+                .into_iter()
+                .flat_map(|w| w.layers.iter().map(|layer| config::expand_path(layer)))
+                .chain(args.layers)
+                .map(|layer| {
+                    layer
+                        .canonicalize()
+                        .map_err(|e| DofiError::InvalidDotfilesDirectory(e, layer))
+                }),
+        )
+        .collect::<Result<Vec<_>, _>>()?;
 
-    match args.command {
-        Commands::Add { file } => {
-            if file.is_symlink() || !file.is_file() {
-                bail!(DofiError::FileIsNotRegular(file.to_path_buf()))
+    log_environment(&base_directory, &layers, &state_directory);
+    let hooks = !args.no_hooks;
+    // Watch and track lock each sync on their own
+    let watching = matches!(command, Commands::Watch { .. })
+        || matches!(&command, Commands::Track { dismiss, .. } if dismiss.is_empty());
+    let _lock = if command.mutates() && !watching {
+        Some(lock::acquire(
+            &lock::lock_path(&state_directory),
+            args.wait,
+        )?)
+    } else {
+        None
+    };
+
+    match command {
+        Commands::Add {
+            file,
+            files_from,
+            interactive: _,
+            max_depth,
+            repo_path,
+            encrypt,
+            template,
+            substitute,
+            adopt,
+            push,
+            allow_large,
+            mode,
+            tags,
+            as_dir,
+            relink,
+        } => {
+            let files = match (file, files_from) {
+                (Some(file), None) if file == Path::new("-") => read_file_list(&file)?,
+                (Some(file), None) => vec![file],
+                (None, Some(list)) => read_file_list(&list)?,
+                _ => {
+                    // Rendered and decrypted targets are regular files dofi already manages
+                    let managed = layered_dotfiles(&OsFs, &base_directory, &layers)?
+                        .into_iter()
+                        .map(|dotfile| dotfile.target)
+                        .collect::<BTreeSet<_>>();
+                    let mut candidates = picker::candidates(&base_directory, &layers, max_depth);
+                    candidates.retain(|candidate| {
+                        !managed.contains(candidate) && !candidate.starts_with(&state_directory)
+                    });
+                    let files = picker::pick(
+                        &candidates,
+                        &base_directory,
+                        io::stdin().lock(),
+                        io::stderr(),
+                    )?;
+                    if files.is_empty() {
+                        info!("Nothing selected");
+                        return Ok(());
+                    }
+                    files
+                }
+            };
+            let mut canonical = Vec::new();
+            let mut symlinks = Vec::new();
+            let mut relinks = Vec::new();
+            for file in &files {
+                // The file itself at its canonical location, not what it links to
+                let parent = file
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or(Path::new("."));
+                let located = parent
+                    .canonicalize()
+                    .map(|parent| parent.join(file.file_name().unwrap_or_default()));
+                if let Ok(located) = &located {
+                    if let Some(dotfile) =
+                        managed_dotfile(&OsFs, located, &base_directory, &layers)?
+                    {
+                        if !relink {
+                            bail!(DofiError::AlreadyManaged(file.to_path_buf()));
+                        }
+                        relinks.push(dotfile);
+                        continue;
+                    }
+                }
+                if file.is_symlink() && adopt && file.is_file() {
+                    // The link itself is added, at its own location
+                    let link = located.map_err(DofiError::GenericIoError)?;
+                    symlinks.push(link.clone());
+                    canonical.push(link);
+                    continue;
+                }
+                if as_dir && (file.is_symlink() || !file.is_dir()) {
+                    bail!(DofiError::NotADirectory(file.to_path_buf()))
+                }
+                if !as_dir && (file.is_symlink() || !file.is_file()) {
+                    bail!(DofiError::FileIsNotRegular(file.to_path_buf()))
+                }
+                canonical.push(file.canonicalize().map_err(DofiError::GenericIoError)?);
             }
-            let file = file.canonicalize().map_err(DofiError::GenericIoError)?;
-            add_file(&file, &base_directory, &dotfiles_directory)?;
+            for file in &canonical {
+                for (path, concern) in guard::inspect(&OsFs, file, config.add.max_size)? {
+                    let described = concern.describe(config.add.max_size);
+                    if path != *file && concern == guard::Concern::Volatile {
+                        warn!(
+                            "'{}' is {described}, the `.gitignore` of the dotfiles can leave it out",
+                            path.display()
+                        );
+                    } else if !allow_large {
+                        bail!(DofiError::UnwieldyFile(path, described));
+                    }
+                }
+            }
+
+            let variables = if substitute {
+                Some(
+                    link_options(&config, &base_directory, &layers, false)?
+                        .templates
+                        .variables,
+                )
+            } else {
+                None
+            };
+            let relink_options = if relinks.is_empty() {
+                None
+            } else {
+                Some(link_options(&config, &base_directory, &layers, true)?)
+            };
+            let backend = config.encryption.backend();
+            let kind = if encrypt {
+                AddKind::Encrypted(backend.as_ref())
+            } else if template {
+                AddKind::Template(variables.as_ref())
+            } else if as_dir {
+                AddKind::Directory
+            } else {
+                AddKind::File
+            };
+            let options = AddOptions {
+                kind,
+                repo_path: repo_path.as_deref(),
+                adopted: &symlinks,
+                mode,
+                tags: &tags,
+            };
+            let mut journal = Journal::new(&state_directory, "add");
+            let result = match &relink_options {
+                Some(relink_options) => {
+                    relink_dotfiles(&OsFs, &relinks, relink_options, &mut journal)
+                }
+                None => Ok(()),
+            }
+            .and_then(|()| {
+                add_files(
+                    &OsFs,
+                    &canonical,
+                    &base_directory,
+                    &dotfiles_directory,
+                    &options,
+                    &mut journal,
+                )
+            });
+            let changed = journal.changed_paths();
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            result?;
+            if canonical.is_empty() {
+                return Ok(());
+            }
+            let message = match canonical.as_slice() {
+                [file] => format!("Add {}", relative(file, &base_directory).display()),
+                files => format!("Add {} files", files.len()),
+            };
+            commit_changes(
+                &config.git,
+                push,
+                &dotfiles_directory,
+                &state_directory,
+                &touched,
+                &message,
+            )?;
+            run_hooks(
+                hooks,
+                Event::PostAdd,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &changed,
+            )?;
         }
-        Commands::Link { force } => {
-            link_files(&base_directory, &dotfiles_directory, force)?;
+        Commands::New {
+            target,
+            repo_path,
+            from,
+            no_edit,
+        } => {
+            let target = std::path::absolute(target).map_err(DofiError::GenericIoError)?;
+            let contents = match from {
+                Some(path) if path == Path::new("-") => {
+                    let mut contents = Vec::new();
+                    io::stdin()
+                        .read_to_end(&mut contents)
+                        .map_err(DofiError::GenericIoError)?;
+                    contents
+                }
+                Some(path) => std::fs::read(&path).map_err(DofiError::GenericIoError)?,
+                None => Vec::new(),
+            };
+            let mut journal = Journal::new(&state_directory, "new");
+            let result = dofi::new_file(
+                &OsFs,
+                &target,
+                &contents,
+                &base_directory,
+                &dotfiles_directory,
+                repo_path.as_deref(),
+                &mut journal,
+            );
+            let changed = journal.changed_paths();
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            let source = result?;
+            if !no_edit {
+                editor::edit(&source)?;
+            }
+            commit_changes(
+                &config.git,
+                false,
+                &dotfiles_directory,
+                &state_directory,
+                &touched,
+                &format!("Add {}", relative(&target, &base_directory).display()),
+            )?;
+            run_hooks(
+                hooks,
+                Event::PostAdd,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &changed,
+            )?;
         }
-        Commands::List => {
-            list_files(&dotfiles_directory)?;
+        Commands::Mv {
+            old_target,
+            new_target,
+        } => {
+            let old_target = std::path::absolute(old_target).map_err(DofiError::GenericIoError)?;
+            let new_target = std::path::absolute(new_target).map_err(DofiError::GenericIoError)?;
+            let mut journal = Journal::new(&state_directory, "mv");
+            let result = move_file(
+                &OsFs,
+                &old_target,
+                &new_target,
+                &base_directory,
+                &dotfiles_directory,
+                &mut journal,
+            );
+            let changed = journal.changed_paths();
+            journal.commit(&OsFs)?;
+            result?;
+            run_hooks(
+                hooks,
+                Event::PostLink,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &changed,
+            )?;
         }
-        Commands::Remove { file } => {
-            if !file.is_file() {
-                bail!(DofiError::FileIsNotRegular(file.to_path_buf()))
+        command @ (Commands::Link { .. } | Commands::Apply { .. }) => {
+            // Whether dangling links are pruned, and if so the directories left empty as well
+            let (name, link, prune_empty, only_new, resume, mut paths) = match command {
+                Commands::Apply {
+                    link,
+                    prune_empty,
+                    resume,
+                } => ("apply", link, Some(prune_empty), false, resume, Vec::new()),
+                Commands::Link {
+                    link,
+                    prune,
+                    only_new,
+                    paths,
+                } => ("link", link, prune.then_some(false), only_new, false, paths),
+                _ => unreachable!("only link and apply are matched"),
+            };
+            let apply = name == "apply";
+            let LinkArgs {
+                force,
+                force_all,
+                force_paths,
+                tags,
+                allow_dirty,
+                sudo,
+                target_dir,
+            } = link;
+            // A sandboxed run leaves the real base directory and its state alone
+            let sandboxed = target_dir.is_some();
+            let (base_directory, state_directory) = match target_dir {
+                Some(target_dir) => {
+                    std::fs::create_dir_all(&target_dir).map_err(DofiError::from)?;
+                    let target_dir = target_dir
+                        .canonicalize()
+                        .map_err(|e| DofiError::InvalidBaseDirectory(e, target_dir))?;
+                    let state_directory = target_dir.join(".local/state/dofi");
+                    info!("Linking into '{}'", target_dir.display());
+                    (target_dir, state_directory)
+                }
+                None => (base_directory, state_directory),
+            };
+            let hooks = hooks && !sandboxed;
+            if resume {
+                let pending = scripts::pending(&OsFs, &layers, &state_directory)?;
+                let mut ran = 0;
+                for step in Step::ALL.map(Some).into_iter().chain([None]) {
+                    ran += run_scripts(&base_directory, &layers, &state_directory, &pending, step)?;
+                }
+                if ran == 0 {
+                    info!("There are no scripts left to run");
+                }
+                return Ok(());
+            }
+            // Symlinks into the dotfiles are dofi's own, converging replaces the stale ones
+            let converge = name == "link" && prune_empty.is_some();
+            let force = force || force_all;
+            for path in &paths {
+                manifest::validate_repo_path(path)?;
+            }
+            // Only a run linking everything is a starting point for `--only-new`
+            let record = paths.is_empty() && tags.is_empty();
+            if only_new {
+                match git::added_since_linked(&state_directory, &layers) {
+                    Some(added) if added.is_empty() => {
+                        info!("Nothing was added since the last link");
+                        return Ok(());
+                    }
+                    Some(added) => paths = added,
+                    None => info!("Linking everything, there is no earlier link to start from"),
+                }
+            }
+            if (force || !force_paths.is_empty()) && !allow_dirty {
+                git::ensure_clean(&dotfiles_directory)?;
+            }
+            if force_all || !force_paths.is_empty() {
+                let mut policies = ConflictPolicies::new(&config.conflicts, &base_directory)?;
+                policies.force(&force_paths, &base_directory)?;
+                let mut replaced =
+                    impact::assess(&OsFs, &base_directory, &layers, &policies)?.replaced;
+                if !force_all {
+                    // Only the targets with a policy are replaced without `--force-all`
+                    replaced.retain(|target| policies.policy(target).is_some());
+                }
+                if !paths.is_empty() {
+                    let selected = layered_dotfiles_in(&OsFs, &base_directory, &layers, &paths)?
+                        .into_iter()
+                        .map(|dotfile| dotfile.target)
+                        .collect::<BTreeSet<_>>();
+                    replaced.retain(|target| selected.contains(target));
+                }
+                if !confirmed("Replace these targets?", &replaced, assume_yes)? {
+                    info!("Nothing changed");
+                    return Ok(());
+                }
             }
-            let file = file.canonicalize().map_err(DofiError::GenericIoError)?;
-            remove_file(&file, &base_directory, &dotfiles_directory)?;
+            if prune_empty.is_some() {
+                let dangling = dangling_links(&OsFs, &layers, &state_directory)?;
+                if !confirmed("Prune these symlinks?", &dangling, assume_yes)? {
+                    info!("Nothing changed");
+                    return Ok(());
+                }
+            }
+            // Ordered up front, so a cycle fails before any of them runs
+            let pending = if apply && !sandboxed {
+                scripts::pending(&OsFs, &layers, &state_directory)?
+            } else {
+                Vec::new()
+            };
+            let run_scripts_before =
+                |step| run_scripts(&base_directory, &layers, &state_directory, &pending, step);
+            run_scripts_before(Some(Step::PreLink))?;
+            run_hooks(
+                hooks,
+                Event::PreLink,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &[],
+            )?;
+            run_scripts_before(Some(Step::Link))?;
+            let mut journal = Journal::new(&state_directory, name);
+            let mut options = LinkOptions {
+                force_all,
+                tags,
+                paths,
+                elevate: sudo,
+                ..link_options(&config, &base_directory, &layers, force || converge)?
+            };
+            options.policies.force(&force_paths, &base_directory)?;
+            let result = link_files(&OsFs, &base_directory, &layers, &options, &mut journal)
+                .and_then(|summary| match prune_empty {
+                    Some(prune_empty) => prune_dangling_links(
+                        &OsFs,
+                        &base_directory,
+                        &layers,
+                        prune_empty,
+                        &mut journal,
+                    )
+                    .and_then(|pruned| {
+                        let generated = if apply {
+                            Some(generate::generate(
+                                &OsFs,
+                                &base_directory,
+                                &layers,
+                                force,
+                                &mut journal,
+                            )?)
+                        } else {
+                            None
+                        };
+                        Ok((summary, Some((pruned, generated))))
+                    }),
+                    None => Ok((summary, None)),
+                });
+            let changed = journal.changed_paths();
+            journal.commit(&OsFs)?;
+            let (summary, pruned) = result?;
+            // Skipped new dotfiles are picked up by the next `--only-new`
+            if record && (!only_new || summary.skipped == 0) {
+                if let Err(e) = git::record_linked(&state_directory, &layers) {
+                    warn!("Failed to record the linked commits: {e}");
+                }
+            }
+            let mut line = format!(
+                "{}, {} unchanged, {}",
+                color::paint(&format!("{} linked", summary.linked), Color::Green, color),
+                summary.unchanged,
+                color::paint(
+                    &format!("{} skipped", summary.skipped),
+                    Color::Yellow,
+                    color
+                ),
+            );
+            if let Some((pruned, generated)) = pruned {
+                line.push_str(&format!(", {pruned} pruned"));
+                if let Some(generated) = generated {
+                    line.push_str(&format!(", {generated} generated"));
+                }
+            }
+            println!("{line}");
+            run_scripts_before(Some(Step::PostLink))?;
+            run_hooks(
+                hooks,
+                Event::PostLink,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &changed,
+            )?;
+
+            if apply && sandboxed {
+                info!("Not running the scripts, the run is sandboxed");
+            } else if apply {
+                run_scripts_before(None)?;
+                let pushed = git::push_pending(&state_directory)?;
+                if pushed > 0 {
+                    let noun = if pushed == 1 { "repo" } else { "repos" };
+                    info!("Pushed {pushed} {noun} that failed to push before");
+                }
+            }
+        }
+        Commands::List {
+            format: OutputFormat::Nuon,
+            private,
+            states,
+            no_cache,
+            query,
+            ..
+        } => {
+            let entries = filtered_entries(
+                &base_directory,
+                &layers,
+                &state_directory,
+                !no_cache,
+                &states,
+                &query,
+            )?
+            .iter()
+            .filter(|entry| private || !entry.private)
+            .map(|entry| nuon::Value::Record(nuon::entry(entry)))
+            .collect();
+            println!("{}", nuon::Value::List(entries).write());
         }
-        Commands::Completions { shell } => {
-            let mut cmd = Args::command();
-            print_completions(shell, &mut cmd);
+        Commands::List {
+            porcelain: true,
+            private,
+            null,
+            states,
+            no_cache,
+            query,
+            ..
+        } => {
+            let cached = !no_cache;
+            let mut stdout = std::io::stdout().lock();
+            for entry in filtered_entries(
+                &base_directory,
+                &layers,
+                &state_directory,
+                cached,
+                &states,
+                &query,
+            )?
+            .into_iter()
+            .filter(|entry| private || !entry.private)
+            {
+                if null {
+                    for field in [
+                        entry.state.as_str().as_bytes(),
+                        &paths::bytes(&entry.source),
+                        &paths::bytes(&entry.target),
+                    ] {
+                        stdout.write_all(field).map_err(DofiError::from)?;
+                        stdout.write_all(b"\0").map_err(DofiError::from)?;
+                    }
+                } else {
+                    writeln!(stdout, "{}", entry.porcelain()).map_err(DofiError::from)?;
+                }
+            }
+        }
+        Commands::List {
+            tree: true,
+            private,
+            states,
+            no_cache,
+            query,
+            ..
+        } => {
+            let cached = !no_cache;
+            let mut entries = filtered_entries(
+                &base_directory,
+                &layers,
+                &state_directory,
+                cached,
+                &states,
+                &query,
+            )?;
+            entries.retain(|entry| private || !entry.private);
+            print!("{}", status::tree(&entries, color));
+        }
+        Commands::List {
+            targets,
+            mapping,
+            relative,
+            private,
+            null,
+            states,
+            query,
+            ..
+        } if query.is_empty() && states.states().is_empty() => {
+            let listing = status::Listing {
+                targets,
+                mapping,
+                relative,
+            };
+            let mut stdout = std::io::stdout().lock();
+            for dotfile in layered_dotfiles(&OsFs, &base_directory, &layers)?
+                .into_iter()
+                .filter(|dotfile| private || !dotfile.private)
+            {
+                let paths = listing.paths(
+                    &dotfile.source,
+                    &dotfile.target,
+                    &dotfile.layer,
+                    &base_directory,
+                );
+                if null {
+                    for path in paths {
+                        print_path(&mut stdout, path, true)?;
+                    }
+                } else {
+                    writeln!(stdout, "{}", status::Listing::line(&paths))
+                        .map_err(DofiError::from)?;
+                }
+            }
+        }
+        Commands::List {
+            targets,
+            mapping,
+            relative,
+            private,
+            null,
+            states,
+            no_cache,
+            query,
+            ..
+        } => {
+            let listing = status::Listing {
+                targets,
+                mapping,
+                relative,
+            };
+            let cached = !no_cache;
+            let mut stdout = std::io::stdout().lock();
+            for entry in filtered_entries(
+                &base_directory,
+                &layers,
+                &state_directory,
+                cached,
+                &states,
+                &query,
+            )?
+            .into_iter()
+            .filter(|entry| private || !entry.private)
+            {
+                let paths =
+                    listing.paths(&entry.source, &entry.target, &entry.layer, &base_directory);
+                if null {
+                    for path in paths {
+                        print_path(&mut stdout, path, true)?;
+                    }
+                } else {
+                    writeln!(
+                        stdout,
+                        "{}",
+                        color::paint(
+                            &status::Listing::line(&paths),
+                            color::of_state(entry.state),
+                            color
+                        )
+                    )
+                    .map_err(DofiError::from)?;
+                }
+            }
+        }
+        Commands::Status {
+            states,
+            watch: true,
+            query,
+            ..
+        } => {
+            // What each target was at the last refresh, to mark those that changed since
+            let mut previous = BTreeMap::<PathBuf, (State, Option<Drift>)>::new();
+            let mut refresh = || {
+                let checksums = checksum::load(&OsFs, &state_directory)?;
+                let sources = checksum::load_sources(&OsFs, &state_directory)?;
+                let mut lines = Vec::new();
+                let mut current = BTreeMap::new();
+                for entry in filtered_entries(
+                    &base_directory,
+                    &layers,
+                    &state_directory,
+                    false,
+                    &states,
+                    &query,
+                )? {
+                    let drift =
+                        checksum::drift(&OsFs, &checksums, &sources, &entry.source, &entry.target)
+                            .filter(|_| entry.state == State::Linked && entry.strategy.is_copied());
+                    let violation = mode_violation(
+                        &OsFs,
+                        &entry.source,
+                        &entry.target,
+                        entry.strategy,
+                        entry.mode,
+                    );
+                    let mut line = status_line(&entry, drift, violation, layers.len() > 1, color);
+                    let now = (entry.state, drift);
+                    if let Some(before) =
+                        previous.get(&entry.target).filter(|&&before| before != now)
+                    {
+                        let label = |(state, drift): (State, Option<Drift>)| {
+                            drift.map_or_else(|| state.to_string(), |drift| drift.to_string())
+                        };
+                        let transition = format!("({} → {})", label(*before), label(now));
+                        line.push_str(&format!(
+                            "  {}",
+                            color::emphasize(&transition, Color::Yellow, color)
+                        ));
+                    }
+                    current.insert(entry.target, now);
+                    lines.push(line);
+                }
+                previous = current;
+
+                let mut stdout = io::stdout().lock();
+                // Clears the screen and moves to its top left corner
+                write!(stdout, "\x1b[2J\x1b[H").map_err(DofiError::from)?;
+                for line in &lines {
+                    writeln!(stdout, "{line}").map_err(DofiError::from)?;
+                }
+                writeln!(stdout, "\nWatching for changes, press Ctrl-C to stop")
+                    .map_err(DofiError::from)?;
+                stdout.flush().map_err(DofiError::from)?;
+                Ok::<_, DofiError>(())
+            };
+            refresh()?;
+
+            // The targets may be anywhere, only the directories holding them are watched
+            let mut directories = layered_dotfiles(&OsFs, &base_directory, &layers)?
+                .into_iter()
+                .filter_map(|dotfile| {
+                    dotfile
+                        .target
+                        .ancestors()
+                        .skip(1)
+                        .find(|directory| directory.is_dir())
+                        .map(Path::to_path_buf)
+                })
+                .collect::<Vec<_>>();
+            directories.sort();
+            directories.dedup();
+            watch::observe(&layers, &directories, &state_directory, &mut refresh)?;
+        }
+        Commands::Status {
+            states,
+            check,
+            porcelain,
+            format,
+            no_cache,
+            query,
+            watch: false,
+        } => {
+            let cached = !no_cache && !check;
+            let checksums = checksum::load(&OsFs, &state_directory)?;
+            let sources = checksum::load_sources(&OsFs, &state_directory)?;
+            let mut out_of_date = 0;
+            let text = !porcelain && format == OutputFormat::Text;
+            let mut records = Vec::new();
+            for entry in filtered_entries(
+                &base_directory,
+                &layers,
+                &state_directory,
+                cached,
+                &states,
+                &query,
+            )? {
+                let drift =
+                    checksum::drift(&OsFs, &checksums, &sources, &entry.source, &entry.target)
+                        .filter(|_| entry.state == State::Linked && entry.strategy.is_copied());
+                if entry.state != State::Linked || drift.is_some() {
+                    out_of_date += 1;
+                }
+                if porcelain {
+                    println!("{}", entry.porcelain());
+                    continue;
+                }
+                let violation = mode_violation(
+                    &OsFs,
+                    &entry.source,
+                    &entry.target,
+                    entry.strategy,
+                    entry.mode,
+                );
+                if format == OutputFormat::Nuon {
+                    let mut fields = nuon::entry(&entry);
+                    fields.push(("drift", drift.map(|drift| drift.to_string()).into()));
+                    fields.push((
+                        "actual_mode",
+                        violation.map(|actual| format!("{actual:o}")).into(),
+                    ));
+                    records.push(nuon::Value::Record(fields));
+                    continue;
+                }
+                println!(
+                    "{}",
+                    status_line(&entry, drift, violation, layers.len() > 1, color)
+                );
+            }
+            if format == OutputFormat::Nuon {
+                println!("{}", nuon::Value::List(records).write());
+            }
+            if text && query.is_empty() && states.states().is_empty() {
+                for file in track::pending(&OsFs, &state_directory, &base_directory, &layers)? {
+                    println!(
+                        "{}  {}  (`dofi add` it or `dofi track --dismiss` it)",
+                        color::paint("new     ", Color::Cyan, color),
+                        file.display()
+                    );
+                }
+            }
+            if let Some(state) = git::sync_state(&dotfiles_directory).filter(|_| text) {
+                let pending = git::is_push_pending(&dotfiles_directory, &state_directory);
+                let summary = sync_summary(&state, pending);
+                let synced = state.uncommitted == 0
+                    && !pending
+                    && state
+                        .upstream
+                        .as_ref()
+                        .is_some_and(|(_, ahead, behind)| ahead + behind == 0);
+                let color_of = if synced { Color::Green } else { Color::Yellow };
+                println!("{}  {summary}", color::paint("git     ", color_of, color));
+            }
+            if out_of_date > 0 {
+                bail!(DofiError::OutOfDate(out_of_date));
+            }
+        }
+        Commands::Remove {
+            file,
+            files_from,
+            package,
+            keep_target,
+            keep_sources,
+            recursive,
+            dry_run,
+            prune_empty,
+            permanent,
+            push,
+        } => {
+            let files = match (file, files_from, &package) {
+                (Some(file), _, _) if file != Path::new("-") => vec![file],
+                (Some(list), _, _) | (None, Some(list), _) => read_file_list(&list)?,
+                (None, None, Some(package)) => {
+                    package_dotfiles(&OsFs, &dotfiles_directory, package)?
+                }
+                (None, None, None) => {
+                    if !io::stdin().is_terminal() {
+                        bail!(DofiError::NoFileGiven("remove"));
+                    }
+                    let managed = managed_targets(&base_directory, &layers)?;
+                    let targets = managed.keys().cloned().collect::<Vec<_>>();
+                    let picked =
+                        picker::pick(&targets, &base_directory, io::stdin().lock(), io::stderr())?;
+                    if picked.is_empty() {
+                        info!("Nothing selected");
+                        return Ok(());
+                    }
+                    picked
+                        .iter()
+                        .map(|target| managed[target].clone())
+                        .collect()
+                }
+            };
+            let prune_empty = prune_empty || package.is_some();
+            let mut canonical = Vec::new();
+            for file in &files {
+                // Anything but an existing file is looked up relative to the dotfiles
+                let matches = if recursive && file.is_dir() {
+                    let directory = file.canonicalize().map_err(DofiError::GenericIoError)?;
+                    let dotfiles =
+                        dotfiles_under(&OsFs, &base_directory, &dotfiles_directory, &directory)?;
+                    if dotfiles.is_empty() {
+                        bail!(DofiError::NoMatchingDotfile {
+                            pattern: file.display().to_string(),
+                            help: "the directory holds no dotfiles or targets".to_string(),
+                        });
+                    }
+                    dotfiles
+                } else if file.is_file() {
+                    vec![file.clone()]
+                } else if file.is_dir() && !file.is_relative() {
+                    // Only a directory linked as a whole is a dotfile itself
+                    let directory = file.canonicalize().map_err(DofiError::GenericIoError)?;
+                    if !list_files(&OsFs, &dotfiles_directory)?.contains(&directory) {
+                        bail!(DofiError::FileIsNotRegular(file.to_path_buf()))
+                    }
+                    vec![directory]
+                } else if file.is_relative() {
+                    match_dotfiles(&OsFs, &dotfiles_directory, &file.to_string_lossy())?
+                } else {
+                    bail!(DofiError::FileIsNotRegular(file.to_path_buf()))
+                };
+                for file in matches {
+                    let file = file.canonicalize().map_err(DofiError::GenericIoError)?;
+                    if !canonical.contains(&file) {
+                        canonical.push(file);
+                    }
+                }
+            }
+
+            let verb = if keep_sources { "unlink" } else { "remove" };
+            if let Some(package) = &package {
+                let linked = canonical
+                    .iter()
+                    .filter(|file| {
+                        dotfile(&OsFs, &base_directory, &dotfiles_directory, file).is_ok_and(
+                            |dotfile| {
+                                dotfile
+                                    .target
+                                    .read_link()
+                                    .is_ok_and(|original| &original == *file)
+                            },
+                        )
+                    })
+                    .count();
+                let noun = if canonical.len() == 1 {
+                    "dotfile"
+                } else {
+                    "dotfiles"
+                };
+                println!(
+                    "Package '{package}' has {} {noun}, {linked} of them linked",
+                    canonical.len()
+                );
+            }
+            if dry_run {
+                for file in &canonical {
+                    println!("Would {verb} '{}'", file.display());
+                }
+                return Ok(());
+            }
+            let question = match &package {
+                Some(package) if keep_sources => format!("Unlink the dotfiles of '{package}'?"),
+                Some(package) if permanent => {
+                    format!("Permanently delete the dotfiles of '{package}'?")
+                }
+                Some(package) => format!("Remove the dotfiles of '{package}'?"),
+                None if keep_sources => "Unlink these dotfiles?".to_string(),
+                None if permanent => "Permanently delete these dotfiles?".to_string(),
+                None => "Remove these dotfiles?".to_string(),
+            };
+            if !confirmed(&question, &canonical, assume_yes)? {
+                info!("Nothing changed");
+                return Ok(());
+            }
+
+            let mut journal = Journal::new(&state_directory, "remove");
+            let result = canonical.iter().try_for_each(|file| {
+                remove_file(
+                    &OsFs,
+                    file,
+                    &base_directory,
+                    &dotfiles_directory,
+                    RemoveOptions {
+                        keep_target,
+                        keep_source: keep_sources,
+                        prune_empty,
+                        trash: !permanent,
+                    },
+                    &mut journal,
+                )
+            });
+            let changed = journal.changed_paths();
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            result?;
+            let message = match (&package, canonical.as_slice()) {
+                (Some(package), _) => format!("Remove {package}"),
+                (None, [file]) => format!(
+                    "Remove {}",
+                    relative(relative(file, &dotfiles_directory), &base_directory).display()
+                ),
+                (None, files) => format!("Remove {} files", files.len()),
+            };
+            commit_changes(
+                &config.git,
+                push,
+                &dotfiles_directory,
+                &state_directory,
+                &touched,
+                &message,
+            )?;
+            run_hooks(
+                hooks,
+                Event::PostRemove,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &changed,
+            )?;
+        }
+        Commands::Adopt {
+            from: None,
+            directory: target,
+            merge,
+            allow_dirty,
+            ..
+        } => {
+            if !allow_dirty {
+                git::ensure_clean(&dotfiles_directory)?;
+            }
+            let target = std::path::absolute(target).map_err(DofiError::GenericIoError)?;
+            let mut journal = Journal::new(&state_directory, "adopt");
+            let options = link_options(&config, &base_directory, &layers, false)?;
+            let result = adopt::adopt_target(
+                &OsFs,
+                &target,
+                &base_directory,
+                &layers,
+                merge,
+                &options,
+                &mut journal,
+            );
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            match result? {
+                adopt::Adopted::Linked(source) => {
+                    info!("Adopted '{}' into '{}'", target.display(), source.display());
+                    commit_changes(
+                        &config.git,
+                        false,
+                        &dotfiles_directory,
+                        &state_directory,
+                        &touched,
+                        &format!("Adopt {}", relative(&target, &base_directory).display()),
+                    )?;
+                    run_hooks(
+                        hooks,
+                        Event::PostLink,
+                        &base_directory,
+                        &dotfiles_directory,
+                        &layers,
+                        &[target],
+                    )?;
+                }
+                adopt::Adopted::Conflicts => warn!(
+                    "Resolve the conflicts in '{}' and run `dofi adopt` again",
+                    target.display()
+                ),
+            }
+        }
+        Commands::Adopt {
+            from: Some(AdoptFrom::Stow),
+            directory,
+            dot_prefix,
+            allow_dirty,
+            ..
+        } => {
+            if !allow_dirty {
+                git::ensure_clean(&dotfiles_directory)?;
+            }
+            let directory = directory
+                .canonicalize()
+                .map_err(DofiError::GenericIoError)?;
+            let mut journal = Journal::new(&state_directory, "adopt");
+            let result = adopt::adopt_stow(
+                &OsFs,
+                &directory,
+                &base_directory,
+                &dotfiles_directory,
+                dot_prefix,
+                &mut journal,
+            );
+            journal.commit(&OsFs)?;
+            info!("Adopted {}", counted(result?, "file"));
+        }
+        Commands::Import {
+            from: ImportFrom::Chezmoi,
+            directory,
+            allow_dirty,
+        } => {
+            if !allow_dirty {
+                git::ensure_clean(&dotfiles_directory)?;
+            }
+            let directory = directory
+                .canonicalize()
+                .map_err(DofiError::GenericIoError)?;
+            let mut journal = Journal::new(&state_directory, "import");
+            let result =
+                adopt::import_chezmoi(&OsFs, &directory, &dotfiles_directory, &mut journal);
+            journal.commit(&OsFs)?;
+            info!(
+                "Imported {}, run `dofi link` to link them",
+                counted(result?, "file")
+            );
+        }
+        Commands::Migrate {
+            dry_run,
+            allow_dirty,
+        } => {
+            let migration = migrate::plan(&OsFs, &base_directory, &dotfiles_directory)?;
+            if migration.is_empty() {
+                println!("The dotfiles already use `dot_prefix`");
+                return Ok(());
+            }
+            if dry_run {
+                for (from, to) in &migration.moves {
+                    println!("{} -> {}", from.display(), to.display());
+                }
+                println!(
+                    "{} dotfiles would move, {} symlinks would be pointed at them",
+                    migration.moves.len(),
+                    migration.relinks.len()
+                );
+                return Ok(());
+            }
+            if !allow_dirty {
+                git::ensure_clean(&dotfiles_directory)?;
+            }
+            let mut journal = Journal::new(&state_directory, "migrate");
+            let result = migrate::apply(&OsFs, &migration, &dotfiles_directory, &mut journal);
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            result?;
+            info!(
+                "Moved {} dotfiles and relinked {} symlinks, `dofi undo` moves them back",
+                migration.moves.len(),
+                migration.relinks.len()
+            );
+            commit_changes(
+                &config.git,
+                false,
+                &dotfiles_directory,
+                &state_directory,
+                &touched,
+                "Migrate to dot_ names",
+            )?;
+        }
+        Commands::Templatify { file } => {
+            let file = match file.symlink_metadata() {
+                Ok(_) => std::path::absolute(&file).map_err(DofiError::GenericIoError)?,
+                Err(_) => file,
+            };
+            let mut journal = Journal::new(&state_directory, "templatify");
+            let result = templatify::templatify(
+                &OsFs,
+                &file,
+                &base_directory,
+                &dotfiles_directory,
+                &mut journal,
+            );
+            let touched = journal.touched_paths();
+            journal.commit(&OsFs)?;
+            let templatified = result?;
+            info!(
+                "Turned {} variants into '{}' with the variables {}, run `dofi apply` to link it",
+                templatified.variants.len(),
+                templatified.template.display(),
+                templatified.variables.join(", ")
+            );
+            commit_changes(
+                &config.git,
+                false,
+                &dotfiles_directory,
+                &state_directory,
+                &touched,
+                "Templatify per-host variants",
+            )?;
+        }
+        Commands::Watch { tags, prune_empty } => {
+            let options = LinkOptions {
+                tags,
+                prune_empty,
+                ..link_options(&config, &base_directory, &layers, false)?
+            };
+            watch::run(&base_directory, &layers, &options, &state_directory)?;
+        }
+        Commands::Track {
+            dismiss,
+            watch: _,
+            add: _,
+        } if !dismiss.is_empty() => {
+            let files = dismiss
+                .iter()
+                .map(std::path::absolute)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(DofiError::GenericIoError)?;
+            let dismissed = track::dismiss(&OsFs, &state_directory, &files)?;
+            println!("Dismissed {dismissed} of {}", counted(files.len(), "file"));
+        }
+        Commands::Track { watch, add, .. } => {
+            let watched = watch
+                .iter()
+                .map(std::path::absolute)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(DofiError::GenericIoError)?;
+            let tracking = if add {
+                track::Tracking::Add
+            } else {
+                track::Tracking::Review
+            };
+            let rules = track::Rules::new(&config.track, &watched, tracking, &base_directory)?;
+            if rules.directories().next().is_none() {
+                bail!(DofiError::NothingToTrack);
+            }
+            let added = |touched: &BTreeSet<PathBuf>, files: &[PathBuf]| {
+                let message = match files {
+                    [file] => format!("Add {}", relative(file, &base_directory).display()),
+                    files => format!("Add {} files", files.len()),
+                };
+                if let Err(e) = commit_changes(
+                    &config.git,
+                    false,
+                    &dotfiles_directory,
+                    &state_directory,
+                    touched,
+                    &message,
+                ) {
+                    warn!("Failed to commit the added files: {e}");
+                }
+            };
+            track::run(
+                &rules,
+                &base_directory,
+                &dotfiles_directory,
+                &layers,
+                &state_directory,
+                &added,
+            )?;
+        }
+        Commands::Config {
+            repo: true,
+            command,
+        } => {
+            let manifest_path = dotfiles_directory.join(manifest::MANIFEST_FILE);
+            match command {
+                ConfigCommand::List => {
+                    for (key, value) in config::settings(&manifest_path)? {
+                        println!("{key} = {value}");
+                    }
+                }
+                ConfigCommand::Get { key } => match config::setting(&manifest_path, &key)? {
+                    Some(value) => println!("{value}"),
+                    None => bail!(DofiError::InvalidConfigKey(
+                        key,
+                        "it is not set".to_string()
+                    )),
+                },
+                ConfigCommand::Set { key, value } => {
+                    let mut journal = Journal::new(&state_directory, "config");
+                    let result = manifest::set_setting(
+                        &OsFs,
+                        &dotfiles_directory,
+                        &key,
+                        &value,
+                        &mut journal,
+                    );
+                    journal.commit(&OsFs)?;
+                    result?;
+                    info!("Set '{key}'");
+                }
+            }
+        }
+        Commands::Complete { values } => {
+            let names = match values {
+                CompletionValues::Packages => packages(&OsFs, &dotfiles_directory)?,
+                CompletionValues::Tags => Manifest::load(&OsFs, &dotfiles_directory)?
+                    .tags
+                    .into_keys()
+                    .collect(),
+            };
+            for name in names {
+                println!("{name}");
+            }
+        }
+        Commands::Tag {
+            command: TagCommand::List,
+        } => {
+            let manifest = Manifest::load(&OsFs, &dotfiles_directory)?;
+            for (tag, paths) in &manifest.tags {
+                for path in paths {
+                    println!("{tag}  {}", path.display());
+                }
+            }
+        }
+        Commands::Tag { command } => {
+            let (tag, files, tagged) = match command {
+                TagCommand::Add { tag, files } => (tag, files, true),
+                TagCommand::Remove { tag, files } => (tag, files, false),
+                TagCommand::List => unreachable!("handled above"),
+            };
+            let mut journal = Journal::new(&state_directory, "tag");
+            let result = files.iter().try_for_each(|file| {
+                let file = std::path::absolute(file).map_err(DofiError::GenericIoError)?;
+                tag_path(
+                    &OsFs,
+                    &file,
+                    &tag,
+                    tagged,
+                    &base_directory,
+                    &dotfiles_directory,
+                    &mut journal,
+                )
+            });
+            journal.commit(&OsFs)?;
+            result?;
+        }
+        Commands::Snapshots {
+            command: SnapshotsCommand::List,
+        } => {
+            for snapshot in snapshot::list(&OsFs, &state_directory)? {
+                println!(
+                    "{}  {}  {}  {}",
+                    snapshot.id,
+                    age(snapshot.timestamp),
+                    snapshot.command,
+                    counted(snapshot.files.len(), "file")
+                );
+            }
+        }
+        Commands::Snapshots {
+            command: SnapshotsCommand::Restore { id },
+        } => {
+            let mut journal = Journal::new(&state_directory, "restore");
+            let result = snapshot::restore(&OsFs, id, &mut journal);
+            journal.commit(&OsFs)?;
+            let snapshot = result?;
+            info!(
+                "Restored {} targets from snapshot {id}",
+                snapshot.files.len()
+            );
+        }
+        Commands::Service {
+            command:
+                ServiceCommand::Install {
+                    launchd,
+                    uninstall: true,
+                    ..
+                },
+        } => {
+            let mut journal = Journal::new(&state_directory, "service");
+            if launchd {
+                let path = service::launchd_path(&base_directory);
+                // Fails if the agent is not loaded, it is removed regardless
+                if let Err(e) = service::launchctl(&["unload".as_ref(), path.as_os_str()]) {
+                    warn!("{e}");
+                }
+                let result = service::remove_launchd_agent(&OsFs, &path, &mut journal);
+                journal.commit(&OsFs)?;
+                if result? {
+                    println!("Removed '{}'", path.display());
+                }
+            } else {
+                // Fails if the timer is not installed, the units are removed regardless
+                if let Err(e) = service::systemctl(&["disable", "--now", "dofi.timer"]) {
+                    warn!("{e}");
+                }
+                let result = service::remove_systemd_units(
+                    &OsFs,
+                    &service::systemd_directory(&base_directory),
+                    &mut journal,
+                );
+                journal.commit(&OsFs)?;
+                for file in result? {
+                    println!("Removed '{}'", file.display());
+                }
+                service::systemctl(&["daemon-reload"])?;
+            }
+        }
+        Commands::Service {
+            command: ServiceCommand::Install { launchd, every, .. },
+        } => {
+            let executable = std::env::current_exe().map_err(DofiError::GenericIoError)?;
+            let mut arguments = vec![
+                OsString::from("--wait"),
+                "-d".into(),
+                dotfiles_directory.clone().into(),
+                "-b".into(),
+                base_directory.clone().into(),
+            ];
+            for layer in &layers[1..] {
+                arguments.extend(["-l".into(), layer.clone().into()]);
+            }
+            if let Some(path) = &config_path {
+                arguments.extend(["--config".into(), path.clone().into()]);
+            }
+            arguments.push("apply".into());
+            let service = service::Service::new(&executable, &arguments, every);
+
+            let mut journal = Journal::new(&state_directory, "service");
+            if launchd {
+                let path = service::launchd_path(&base_directory);
+                let log = state_directory.join(service::LOG_FILE);
+                // launchd does not create the directory of the log file
+                std::fs::create_dir_all(&state_directory).map_err(DofiError::GenericIoError)?;
+                let result =
+                    service::write_launchd_agent(&OsFs, &path, &service, &log, &mut journal);
+                journal.commit(&OsFs)?;
+                result?;
+                println!("Wrote '{}'", path.display());
+                // An earlier version of the agent has to be unloaded before loading this one
+                let _ = service::launchctl(&["unload".as_ref(), path.as_os_str()]);
+                service::launchctl(&["load".as_ref(), "-w".as_ref(), path.as_os_str()])?;
+            } else {
+                let result = service::write_systemd_units(
+                    &OsFs,
+                    &service::systemd_directory(&base_directory),
+                    &service,
+                    &mut journal,
+                );
+                journal.commit(&OsFs)?;
+                for file in result? {
+                    println!("Wrote '{}'", file.display());
+                }
+                service::systemctl(&["daemon-reload"])?;
+                service::systemctl(&["enable", "--now", "dofi.timer"])?;
+            }
+        }
+        Commands::Undo => {
+            let operation = journal::undo(&OsFs, &state_directory)?;
+            info!("Reverted '{}'", operation.command);
+        }
+        Commands::Tui { query } => {
+            let mut input = io::stdin().lock();
+            let mut query = Query::parse(&query.join(" "))?.resolve(&OsFs, &layers)?;
+            loop {
+                let now = SystemTime::now();
+                let entries = status::entries(&OsFs, &base_directory, &layers)?
+                    .into_iter()
+                    .filter(|entry| query.matches(entry, now))
+                    .collect::<Vec<_>>();
+                print!("{}", tui::render(&entries, color));
+                print!("dofi> ");
+                io::stdout().flush().map_err(DofiError::GenericIoError)?;
+
+                let mut line = String::new();
+                if input
+                    .read_line(&mut line)
+                    .map_err(DofiError::GenericIoError)?
+                    == 0
+                {
+                    break;
+                }
+                let Some(action) = tui::Action::parse(&line) else {
+                    println!("Unknown command '{}', see 'help'", line.trim());
+                    continue;
+                };
+                let entry = match action.entry().map(|index| entries.get(index)) {
+                    Some(Some(entry)) => Some(entry),
+                    Some(None) => {
+                        println!("There are only {} dotfiles", entries.len());
+                        continue;
+                    }
+                    None => None,
+                };
+
+                let result = match (&action, entry) {
+                    (tui::Action::Quit, _) => break,
+                    (tui::Action::Help, _) => {
+                        println!("{}", tui::HELP);
+                        Ok(())
+                    }
+                    (tui::Action::Link(_), Some(entry)) if entry.state != State::Unlinked => {
+                        println!(
+                            "'{}' is {}, use 'force' to replace it",
+                            entry.target.display(),
+                            entry.state
+                        );
+                        Ok(())
+                    }
+                    (tui::Action::Link(_) | tui::Action::Force(_), Some(entry)) => {
+                        let force = matches!(action, tui::Action::Force(_));
+                        link_options(&config, &base_directory, &layers, force).and_then(|options| {
+                            tui_link(
+                                entry,
+                                &options,
+                                hooks,
+                                args.wait,
+                                &base_directory,
+                                &layers,
+                                &state_directory,
+                            )
+                        })
+                    }
+                    (tui::Action::Unlink(_), Some(entry)) if entry.state != State::Linked => {
+                        println!("'{}' is {}", entry.target.display(), entry.state);
+                        Ok(())
+                    }
+                    (tui::Action::Unlink(_), Some(entry)) => {
+                        lock::acquire(&lock::lock_path(&state_directory), args.wait)
+                            .map_err(Into::into)
+                            .and_then(|_lock| {
+                                let mut journal = Journal::new(&state_directory, "unlink");
+                                let result = tui::unlink(&OsFs, entry, &mut journal);
+                                journal.commit(&OsFs)?;
+                                Ok(result?)
+                            })
+                    }
+                    (tui::Action::Diff(_), Some(entry)) => {
+                        if entry.state == State::Conflict && entry.target.is_file() {
+                            diff::print_diff(
+                                &OsFs,
+                                &entry.source,
+                                &entry.target,
+                                entry.relative_source(),
+                                color,
+                            )
+                            .map(|_| ())
+                            .map_err(Into::into)
+                        } else {
+                            println!("'{}' is {}", entry.target.display(), entry.state);
+                            Ok(())
+                        }
+                    }
+                    (tui::Action::Edit(_), Some(entry)) => {
+                        editor::edit(&entry.source)
+                            .map_err(Into::into)
+                            .and_then(|()| {
+                                // Rendered templates and private copies are stale after an edit
+                                if entry.state == State::Linked && entry.strategy.is_copied() {
+                                    let options =
+                                        link_options(&config, &base_directory, &layers, true)?;
+                                    tui_link(
+                                        entry,
+                                        &options,
+                                        hooks,
+                                        args.wait,
+                                        &base_directory,
+                                        &layers,
+                                        &state_directory,
+                                    )
+                                } else {
+                                    Ok(())
+                                }
+                            })
+                    }
+                    (tui::Action::Apply, _) => rerun("apply"),
+                    (tui::Action::Filter(text), _) => Query::parse(text)
+                        .and_then(|parsed| parsed.resolve(&OsFs, &layers))
+                        .map(|parsed| query = parsed)
+                        .map_err(Into::into),
+                    (tui::Action::Undo, _) => {
+                        lock::acquire(&lock::lock_path(&state_directory), args.wait)
+                            .and_then(|_lock| journal::undo(&OsFs, &state_directory))
+                            .map(|operation| println!("Reverted '{}'", operation.command))
+                            .map_err(Into::into)
+                    }
+                    (_, None) => unreachable!("actions on entries have one"),
+                };
+                if let Err(e) = result {
+                    eprintln!("{e:?}");
+                }
+            }
+        }
+        Commands::Edit { file, link } => {
+            let file = match file {
+                Some(file) => file,
+                None if io::stdin().is_terminal() => {
+                    let managed = managed_targets(&base_directory, &layers)?;
+                    let targets = managed.keys().cloned().collect::<Vec<_>>();
+                    let picked = picker::pick_one(
+                        &targets,
+                        &base_directory,
+                        io::stdin().lock(),
+                        io::stderr(),
+                    )?;
+                    let Some(target) = picked else {
+                        info!("Nothing selected");
+                        return Ok(());
+                    };
+                    managed[&target].clone()
+                }
+                None => bail!(DofiError::NoFileGiven("edit")),
+            };
+            let file = std::path::absolute(file).map_err(DofiError::GenericIoError)?;
+            let source = find_dotfile(&OsFs, &file, &base_directory, &dotfiles_directory)?;
+            editor::edit(&source)?;
+
+            if link {
+                let dotfile = dotfile(&OsFs, &base_directory, &dotfiles_directory, &source)?;
+                let target = dotfile.target.clone();
+                let force = match status::state(&OsFs, &source, &target, dotfile.strategy) {
+                    State::Unlinked => false,
+                    // Copies and rendered templates are stale after an edit
+                    State::Linked if dotfile.strategy.is_copied() => true,
+                    State::Linked => return Ok(()),
+                    State::Conflict | State::Broken => {
+                        warn!(
+                            "Not linking '{}', a file already exists there",
+                            target.display()
+                        );
+                        return Ok(());
+                    }
+                };
+
+                let mut journal = Journal::new(&state_directory, "link");
+                let options = link_options(&config, &base_directory, &layers, force)?;
+                let result = link_entry(&OsFs, &dotfile, force, &options, &mut journal);
+                journal.commit(&OsFs)?;
+                result?;
+                run_hooks(
+                    hooks,
+                    Event::PostLink,
+                    &base_directory,
+                    &dotfiles_directory,
+                    &layers,
+                    &[target],
+                )?;
+            }
+        }
+        Commands::Grep {
+            pattern,
+            ignore_case,
+        } => {
+            let pattern = grep::pattern(&pattern, ignore_case)?;
+            let matches = grep::grep(&OsFs, &base_directory, &layers, &pattern)?;
+            for (index, found) in matches.iter().enumerate() {
+                if index == 0 || matches[index - 1].target != found.target {
+                    println!("{} -> {}", found.source.display(), found.target.display());
+                }
+                println!("{:>5}: {}", found.line_number, found.line);
+            }
+        }
+        Commands::Diff { file, stat } => {
+            let entries = match file {
+                Some(file) => {
+                    let file = std::path::absolute(file).map_err(DofiError::GenericIoError)?;
+                    let source = find_dotfile(&OsFs, &file, &base_directory, &dotfiles_directory)?;
+                    let dotfile = dotfile(&OsFs, &base_directory, &dotfiles_directory, &source)?;
+                    let state = status::state(&OsFs, &source, &dotfile.target, dotfile.strategy);
+                    vec![(state, dotfile)]
+                }
+                None => status::entries(&OsFs, &base_directory, &layers)?
+                    .into_iter()
+                    .map(|entry| (entry.state, entry.dotfile()))
+                    .collect(),
+            };
+
+            let checksums = checksum::load(&OsFs, &state_directory)?;
+            let sources = checksum::load_sources(&OsFs, &state_directory)?;
+            let options = link_options(&config, &base_directory, &layers, false)?;
+            let mut stats = Vec::new();
+            for (state, dotfile) in entries {
+                let Dotfile { source, target, .. } = &dotfile;
+                let label = layers
+                    .iter()
+                    .find_map(|layer| source.strip_prefix(layer).ok())
+                    .unwrap_or(source);
+                // Copies are linked as long as they exist, what changed since is drift
+                let drift = checksum::drift(&OsFs, &checksums, &sources, source, target)
+                    .filter(|_| state == State::Linked && dotfile.strategy.is_copied());
+                let contents = if drift.is_some() {
+                    target_contents(&OsFs, &dotfile, &options)?
+                } else if state == State::Conflict && target.is_file() {
+                    std::fs::read(source).map_err(DofiError::GenericIoError)?
+                } else {
+                    info!("Skipping '{}', it is {state}", target.display());
+                    continue;
+                };
+                if stat {
+                    let current = std::fs::read(target).map_err(DofiError::GenericIoError)?;
+                    if contents != current {
+                        stats.push((label.display().to_string(), diff::stat(&contents, &current)));
+                    }
+                    continue;
+                }
+                if let Some(drift) = drift {
+                    println!("'{}' was {drift}", target.display());
+                }
+                diff::print_contents_diff(&OsFs, &contents, target, label, color)?;
+            }
+            if !stats.is_empty() {
+                print!("{}", diff::format_stats(&stats, color));
+            }
+        }
+        Commands::Merge { file } => {
+            let file = std::path::absolute(file).map_err(DofiError::GenericIoError)?;
+            let source = find_dotfile(&OsFs, &file, &base_directory, &dotfiles_directory)?;
+            let dotfile = dotfile(&OsFs, &base_directory, &dotfiles_directory, &source)?;
+            let target = dotfile.target.clone();
+
+            let checksums = checksum::load(&OsFs, &state_directory)?;
+            let sources = checksum::load_sources(&OsFs, &state_directory)?;
+            let drift = checksum::drift(&OsFs, &checksums, &sources, &source, &target);
+            match drift {
+                _ if !dotfile.strategy.is_copied() => {
+                    bail!(DofiError::NothingToMerge(target, "not a copy".to_string()))
+                }
+                Some(Drift::Both) => {}
+                Some(drift) => bail!(DofiError::NothingToMerge(target, drift.to_string())),
+                None => bail!(DofiError::NothingToMerge(target, "unchanged".to_string())),
+            }
+            let base = checksum::base(&OsFs, &state_directory, &checksums, &target)
+                .ok_or_else(|| DofiError::MissingMergeBase(target.clone()))?;
+            let options = link_options(&config, &base_directory, &layers, false)?;
+            let new = target_contents(&OsFs, &dotfile, &options)?;
+            let contents = std::fs::read(&source).map_err(DofiError::GenericIoError)?;
+            let label = dotfile
+                .source
+                .strip_prefix(&dotfile.layer)
+                .unwrap_or(&source);
+
+            let mut journal = Journal::new(&state_directory, "merge");
+            let sides = merge::Sides {
+                base: &base,
+                new: &new,
+                source: &contents,
+            };
+            let result = merge::merge(&OsFs, &target, sides, label, &mut journal);
+            journal.commit(&OsFs)?;
+            match result? {
+                merge::Outcome::Clean => {
+                    println!("Merged '{}' into '{}'", label.display(), target.display())
+                }
+                merge::Outcome::Conflicts => println!(
+                    "Merged '{}' into '{}' with conflicts, resolve the conflict markers",
+                    label.display(),
+                    target.display()
+                ),
+            }
+        }
+        Commands::Export {
+            format,
+            output,
+            decrypt,
+            out_of_store,
+        } => {
+            let output = output
+                .map(std::path::absolute)
+                .transpose()
+                .map_err(DofiError::GenericIoError)?;
+            let options = link_options(&config, &base_directory, &layers, false)?;
+            match (format.unwrap_or(ExportFormat::Archive), output) {
+                (ExportFormat::Archive, Some(output)) => {
+                    if out_of_store {
+                        warn!("Ignoring --out-of-store, it only applies to home-manager");
+                    }
+                    let files = export::tree(&OsFs, &base_directory, &layers, &options, decrypt)?;
+                    export::write_archive(&files, &output, &state_directory)?;
+                    println!(
+                        "Exported {} to '{}'",
+                        counted(files.len(), "file"),
+                        output.display()
+                    );
+                }
+                (ExportFormat::Archive, None) => unreachable!("clap requires an archive"),
+                (ExportFormat::HomeManager, output) => {
+                    if decrypt {
+                        warn!("Ignoring --decrypt, the Nix store is readable by everyone");
+                    }
+                    let module = export::home_manager(
+                        &OsFs,
+                        &base_directory,
+                        &layers,
+                        &options,
+                        out_of_store,
+                    )?;
+                    match output {
+                        Some(output) => {
+                            std::fs::write(&output, module).map_err(DofiError::from)?;
+                            println!("Exported the module to '{}'", output.display());
+                        }
+                        None => print!("{module}"),
+                    }
+                }
+            }
+        }
+        Commands::PushRemote {
+            destination,
+            copy,
+            decrypt,
+        } => {
+            if !copy && remote::has_dofi(&destination)? {
+                remote::push_dotfiles(&destination, &layers)?;
+                remote::ssh(
+                    &destination,
+                    &remote::apply_command(layers.len()),
+                    std::process::Stdio::inherit(),
+                )?;
+                println!("Applied the dotfiles on {destination}");
+            } else {
+                let options = link_options(&config, &base_directory, &layers, false)?;
+                let files = export::tree(&OsFs, &base_directory, &layers, &options, decrypt)?;
+                remote::push_tree(&destination, &files, &state_directory)?;
+                println!("Unpacked {} on {destination}", counted(files.len(), "file"));
+            }
+        }
+        Commands::Impact => {
+            let policies = ConflictPolicies::new(&config.conflicts, &base_directory)?;
+            let impact = impact::assess(&OsFs, &base_directory, &layers, &policies)?;
+
+            println!("{} targets would be linked", impact.linked.len());
+            println!(
+                "{} existing targets would be replaced",
+                impact.replaced.len()
+            );
+            for target in &impact.replaced {
+                println!("  {}", target.display());
+            }
+            if !impact.kept.is_empty() {
+                println!(
+                    "{} targets are kept by a never-force policy",
+                    impact.kept.len()
+                );
+            }
+            match &impact.open {
+                Some(open) if open.is_empty() => {}
+                Some(open) => {
+                    println!("Open in running processes:");
+                    for file in open {
+                        println!(
+                            "  {} ({} {})",
+                            file.target.display(),
+                            file.command,
+                            file.pid
+                        );
+                    }
+                }
+                None => warn!("lsof is not available, cannot check for open files"),
+            }
+            if !impact.hooks.is_empty() {
+                println!("Hooks that would run:");
+                for hook in &impact.hooks {
+                    println!("  {hook}");
+                }
+            }
+        }
+        Commands::Doctor { fix } => {
+            let findings = doctor::diagnose(
+                &OsFs,
+                &base_directory,
+                &dotfiles_directory,
+                &state_directory,
+                &directory_modes,
+            )?;
+            let mut journal = Journal::new(&state_directory, "doctor");
+            let mut result = Ok(());
+            for finding in &findings {
+                println!("'{}': {}", finding.target.display(), finding.problem);
+                if !fix {
+                    println!("  help: {}", finding.problem.help());
+                    continue;
+                }
+                match doctor::fix(&OsFs, finding, &mut journal) {
+                    Ok(true) => println!("  fixed"),
+                    Ok(false) => println!("  not fixed, {}", finding.problem.help()),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            if fix {
+                journal.commit(&OsFs)?;
+            }
+            result?;
+            if findings.is_empty() {
+                println!("No problems found");
+            }
+        }
+        Commands::Check { quick: true, .. } => {
+            let entries = index::entries(&OsFs, &base_directory, &layers, &state_directory, true)?;
+            if entries.iter().any(|entry| entry.state != State::Linked) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Prompt { format, shell } => {
+            let entries = index::entries(&OsFs, &base_directory, &layers, &state_directory, true)?;
+            // Asking git takes longer than the index, only prompts showing its state wait for it
+            let sync = status::GIT_PLACEHOLDERS
+                .iter()
+                .any(|placeholder| format.contains(placeholder))
+                .then(|| git::sync_state(&dotfiles_directory))
+                .flatten();
+            let prompt = status::prompt_format(&format, &entries, sync.as_ref())?;
+            let prompt = match shell {
+                // Zsh expands `%` sequences in the output of commands in its prompt
+                PromptShell::Zsh => prompt.replace('%', "%%"),
+                PromptShell::Plain => prompt,
+            };
+            if !prompt.is_empty() {
+                println!("{prompt}");
+            }
+        }
+        Commands::Stats { top, no_cache } => {
+            let entries =
+                index::entries(&OsFs, &base_directory, &layers, &state_directory, !no_cache)?;
+            let summary = stats::summarize(&OsFs, &entries, top);
+            let states = summary
+                .states
+                .iter()
+                .map(|(state, count)| {
+                    let line = format!("{count} {state}");
+                    if *count == 0 {
+                        line
+                    } else {
+                        color::paint(&line, color::of_state(*state), color)
+                    }
+                })
+                .collect::<Vec<_>>();
+            println!("{} dotfiles: {}", entries.len(), states.join(", "));
+            println!("{} in total", stats::format_size(summary.size));
+
+            println!("\nPackages");
+            let width = summary
+                .packages
+                .keys()
+                .map(|package| package.as_os_str().len())
+                .max()
+                .unwrap_or_default();
+            for (package, (files, size)) in &summary.packages {
+                println!(
+                    "  {:width$}  {files:>5} {:5}  {:>10}",
+                    paths::display(package).to_string(),
+                    if *files == 1 { "file" } else { "files" },
+                    stats::format_size(*size)
+                );
+            }
+            if !summary.largest.is_empty() {
+                println!("\nLargest");
+                for (source, size) in &summary.largest {
+                    println!(
+                        "  {:>10}  {}",
+                        stats::format_size(*size),
+                        paths::display(source)
+                    );
+                }
+            }
+            if !summary.recent.is_empty() {
+                println!("\nRecently modified");
+                for (source, modified) in &summary.recent {
+                    let timestamp = modified
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |since| since.as_secs());
+                    println!("  {:>8}  {}", age(timestamp), paths::display(source));
+                }
+            }
+        }
+        Commands::Check { offline, .. } => {
+            let mut options = link_options(&config, &base_directory, &layers, false)?;
+            if offline {
+                options.templates.secrets = Some(Box::new(check::OfflineSecrets));
+            }
+            let findings = check::check(&OsFs, &base_directory, &layers, &options)?;
+            let count = findings.len();
+            for finding in findings {
+                match finding.problem {
+                    // Shown with the offending expression, like the errors of dofi itself
+                    check::Problem::Lint(error) => eprintln!("{:?}", miette::Report::new(error)),
+                    problem => println!("'{}': {problem}", finding.path.display()),
+                }
+            }
+            if count == 0 {
+                println!("No problems found");
+            } else {
+                bail!(DofiError::CheckFailed(count));
+            }
+        }
+        Commands::Git { args } => {
+            let status = git::run(&dotfiles_directory, &args)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Commands::External(args) => {
+            let (name, args) = args
+                .split_first()
+                .expect("clap passes the name of external subcommands");
+            let executable = plugin::find(name)
+                .ok_or_else(|| DofiError::UnknownCommand(name.to_string_lossy().into_owned()))?;
+            let context = plugin::Context {
+                base_directory: &base_directory,
+                dotfiles_directories: &layers,
+                state_directory: &state_directory,
+            };
+            let status = plugin::run(&executable, args, &context)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Commands::Env { format } => match format {
+            EnvFormat::Shell => {
+                let layers = std::env::join_paths(&layers)
+                    .map_err(|e| DofiError::GenericIoError(io::Error::other(e)))?;
+                let mut variables = vec![
+                    ("DOFI_BASE", base_directory.into_os_string()),
+                    ("DOFI_DOTFILES", dotfiles_directory.clone().into_os_string()),
+                    ("DOFI_DIR", dotfiles_directory.into_os_string()),
+                    ("DOFI_LAYERS", layers),
+                    ("DOFI_STATE", state_directory.into_os_string()),
+                ];
+                if let Some(config_path) = config_path {
+                    variables.push(("DOFI_CONFIG", config_path.into_os_string()));
+                }
+                if let Some(workspace) = workspace_name {
+                    variables.push(("DOFI_WORKSPACE", workspace.into()));
+                }
+                for (name, value) in variables {
+                    let value = value.to_string_lossy().replace('\'', "'\\''");
+                    println!("export {name}='{value}'");
+                }
+            }
+            EnvFormat::Json => {
+                // Later layers win, like with the dotfiles
+                let mut modes = BTreeMap::new();
+                for layer in &layers {
+                    for (pattern, mode) in Manifest::load(&OsFs, layer)?.modes {
+                        modes.insert(pattern, format!("{mode:o}"));
+                    }
+                }
+                let environment = serde_json::json!({
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "platform": {
+                        "os": std::env::consts::OS,
+                        "family": std::env::consts::FAMILY,
+                        "arch": std::env::consts::ARCH,
+                    },
+                    "base": base_directory,
+                    "dotfiles": dotfiles_directory,
+                    "layers": layers,
+                    "state": state_directory,
+                    "config": config_path,
+                    "workspace": workspace_name,
+                    "modes": modes,
+                });
+                println!("{environment:#}");
+            }
+        },
+        Commands::Dir { target: None } => {
+            println!("{}", dotfiles_directory.display());
+        }
+        Commands::Dir { target: Some(file) } => {
+            let absolute = std::path::absolute(&file).map_err(DofiError::GenericIoError)?;
+            let dotfile = layered_dotfiles(&OsFs, &base_directory, &layers)?
+                .into_iter()
+                .find(|dotfile| {
+                    dotfile.source == absolute || dotfile.source == dotfile.layer.join(&file)
+                })
+                .ok_or(DofiError::FileIsNotADotfile(file))?;
+            println!("{}", dotfile.target.display());
+        }
+        Commands::Cat { path } => {
+            let absolute = std::path::absolute(&path).map_err(DofiError::GenericIoError)?;
+            let mut dotfiles = dotfiles_linked_to(&OsFs, &base_directory, &layers, &absolute)?;
+            let Some(dotfile) = dotfiles.pop() else {
+                bail!(DofiError::FileIsNotADotfile(path));
+            };
+            let options = link_options(&config, &base_directory, &layers, false)?;
+            let contents = target_contents(&OsFs, &dotfile, &options)?;
+            io::stdout()
+                .write_all(&contents)
+                .map_err(DofiError::GenericIoError)?;
+        }
+        Commands::Which { path } => {
+            let absolute = std::path::absolute(&path).map_err(DofiError::GenericIoError)?;
+            let mut dotfiles = dotfiles_linked_to(&OsFs, &base_directory, &layers, &absolute)?;
+            let Some(dotfile) = dotfiles.pop() else {
+                bail!(DofiError::FileIsNotADotfile(path));
+            };
+
+            let state = status::state(&OsFs, &dotfile.source, &dotfile.target, dotfile.strategy);
+            let strategy = if encryption::is_encrypted(&dotfile.source) {
+                format!("{}, decrypted", dotfile.strategy)
+            } else {
+                dotfile.strategy.to_string()
+            };
+            println!("{}", dotfile.target.display());
+            println!("  source     {}", dotfile.source.display());
+            println!("  layer      {}", dotfile.layer.display());
+            println!("  strategy   {strategy}");
+            if !dotfile.tags.is_empty() {
+                println!("  tags       {}", dotfile.tags.join(", "));
+            }
+            if let Some(mode) = dotfile.mode {
+                println!("  mode       {mode:o}");
+            }
+            if dotfile.private {
+                println!("  private    yes");
+            }
+            println!(
+                "  state      {}",
+                color::paint(state.as_str(), color::of_state(state), color)
+            );
+            if state == State::Linked && dotfile.strategy.is_copied() {
+                let checksums = checksum::load(&OsFs, &state_directory)?;
+                let sources = checksum::load_sources(&OsFs, &state_directory)?;
+                if let Some(drift) = checksum::drift(
+                    &OsFs,
+                    &checksums,
+                    &sources,
+                    &dotfile.source,
+                    &dotfile.target,
+                ) {
+                    println!("  drift      {drift}");
+                }
+            }
+            // The same target in lower layers, shadowed by this one
+            for shadowed in dotfiles.iter().rev() {
+                println!("  overrides  {}", shadowed.source.display());
+            }
+        }
+        Commands::Verify => {
+            let checksums = checksum::load(&OsFs, &state_directory)?;
+            let mut modified = 0;
+            for entry in status::entries(&OsFs, &base_directory, &layers)? {
+                if checksum::modified(&OsFs, &checksums, &entry.target) == Some(true) {
+                    println!("modified  {}", entry.target.display());
+                    modified += 1;
+                }
+            }
+            if modified == 0 {
+                println!("No changed targets");
+            } else {
+                bail!(DofiError::OutOfDate(modified));
+            }
+        }
+        Commands::Completions { .. }
+        | Commands::Manpages { .. }
+        | Commands::SelfUpdate { .. }
+        | Commands::Workspaces { .. }
+        | Commands::Key { .. }
+        | Commands::Config { repo: false, .. }
+        | Commands::Init { .. } => {
+            unreachable!("handled before resolving directories")
         }
     };
 
     Ok(())
 }
 
-fn print_completions<G: Generator>(gen: G, cmd: &mut Command) {
-    generate(gen, cmd, cmd.get_name().to_string(), &mut io::stdout());
+fn workspaces(
+    command: WorkspacesCommand,
+    config: &Config,
+    config_path: Option<&Path>,
+) -> Result<()> {
+    match command {
+        WorkspacesCommand::List => {
+            for (name, workspace) in &config.workspaces {
+                let marker = if config.default_workspace.as_ref() == Some(name) {
+                    "*"
+                } else {
+                    " "
+                };
+                let base = workspace
+                    .base
+                    .as_deref()
+                    .map(|base| base.display().to_string())
+                    .unwrap_or_else(|| "~".to_string());
+                println!(
+                    "{marker} {name}  {} -> {base}",
+                    workspace.dotfiles.display()
+                );
+            }
+        }
+        WorkspacesCommand::Add {
+            name,
+            dotfiles,
+            base,
+        } => {
+            let config_path = config_path.ok_or(DofiError::NoConfigFile)?;
+            let dotfiles = std::path::absolute(&dotfiles).map_err(DofiError::GenericIoError)?;
+            let base = base
+                .map(std::path::absolute)
+                .transpose()
+                .map_err(DofiError::GenericIoError)?;
+            config::add_workspace(config_path, &name, &dotfiles, base.as_deref())?;
+            info!("Registered workspace '{name}'");
+        }
+        WorkspacesCommand::Remove { name } => {
+            let config_path = config_path.ok_or(DofiError::NoConfigFile)?;
+            config::remove_workspace(config_path, &name)?;
+            info!("Unregistered workspace '{name}'");
+        }
+    }
+
+    Ok(())
 }
 
-fn remove_file(
-    file: &Path,
+fn filtered_entries(
     base_directory: &Path,
-    dotfiles_directory: &Path,
-) -> Result<(), DofiError> {
-    if !file.starts_with(dotfiles_directory) {
-        return Err(DofiError::FileIsNotADotfile(file.to_path_buf()));
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+    cached: bool,
+    states: &StateFilter,
+    query: &[String],
+) -> Result<Vec<Entry>, DofiError> {
+    let query = Query::parse(&query.join(" "))?.resolve(&OsFs, dotfiles_directories)?;
+    let states = states.states();
+    let now = SystemTime::now();
+
+    Ok(index::entries(
+        &OsFs,
+        base_directory,
+        dotfiles_directories,
+        state_directory,
+        cached,
+    )?
+    .into_iter()
+    .filter(|entry| states.is_empty() || states.contains(&entry.state))
+    .filter(|entry| query.matches(entry, now))
+    .collect())
+}
+
+fn log_environment(
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+) {
+    info!("dofi {}", env!("CARGO_PKG_VERSION"));
+    info!(
+        "Platform: {} ({}, {})",
+        std::env::consts::OS,
+        std::env::consts::FAMILY,
+        std::env::consts::ARCH
+    );
+    info!("Base directory: '{}'", base_directory.display());
+    for dotfiles_directory in dotfiles_directories {
+        info!("Dotfiles directory: '{}'", dotfiles_directory.display());
     }
+    info!("State directory: '{}'", state_directory.display());
+}
 
-    info!("Removing file '{}'", file.display());
-    std::fs::remove_file(file)?;
+/// The link options for `force`, the user configuration and the variables of `layers`
+fn link_options(
+    config: &Config,
+    base_directory: &Path,
+    layers: &[PathBuf],
+    force: bool,
+) -> Result<LinkOptions> {
+    Ok(LinkOptions {
+        force,
+        policies: ConflictPolicies::new(&config.conflicts, base_directory)?,
+        encryption: config.encryption.backends(),
+        templates: template::Context {
+            variables: template::builtin_variables()
+                .into_iter()
+                .chain(vars::load(&OsFs, layers)?)
+                .collect(),
+            secrets: Some(Box::new(config.secrets.clone())),
+            dotfiles_directories: layers.to_vec(),
+        },
+        ..Default::default()
+    })
+}
 
-    let symlink = file
-        .strip_prefix(dotfiles_directory)
-        .map(|relative_file| base_directory.join(relative_file))
-        .map_err(|_| {
-            DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), file.to_path_buf())
-        })?;
+/// Runs the `pending` scripts of `layers` that run before `step` of `apply`, or after all of
+/// its steps for `None`, stopping at the first one that fails. Returns how many ran.
+fn run_scripts(
+    base_directory: &Path,
+    layers: &[PathBuf],
+    state_directory: &Path,
+    pending: &[Script],
+    step: Option<Step>,
+) -> Result<usize> {
+    if !pending.iter().any(|script| script.before == step) {
+        return Ok(0);
+    }
+    let variables = vars::load(&OsFs, layers)?;
+    let mut ran = 0;
+    for (index, script) in pending.iter().enumerate() {
+        if script.before != step {
+            continue;
+        }
+        if let Err(e) = scripts::run(script, base_directory, &variables) {
+            warn!(
+                "{} scripts did not run, `dofi apply --resume` runs them once '{}' is fixed",
+                pending.len() - index,
+                script.relative_path().display()
+            );
+            return Err(e.into());
+        }
+        scripts::record(&OsFs, state_directory, script)?;
+        println!("Ran '{}'", script.relative_path().display());
+        ran += 1;
+    }
+    Ok(ran)
+}
 
-    if symlink.symlink_metadata().is_ok() {
-        info!("Removing symlink '{}'", symlink.display());
-        let _ = std::fs::remove_file(symlink);
+/// Commits the `touched` paths in the dotfiles with `message` if auto-committing, and pushes
+/// them, together with any push still pending, if auto-pushing or `push` is set
+fn commit_changes(
+    git_config: &GitConfig,
+    push: bool,
+    dotfiles_directory: &Path,
+    state_directory: &Path,
+    touched: &BTreeSet<PathBuf>,
+    message: &str,
+) -> Result<()> {
+    if !git_config.auto_commit && !push {
+        return Ok(());
+    }
+    if git::uncommitted_changes(dotfiles_directory).is_none() {
+        warn!(
+            "Not committing, '{}' is not a git repository",
+            dotfiles_directory.display()
+        );
+        return Ok(());
+    }
+
+    let paths = touched
+        .iter()
+        .filter(|path| path.starts_with(dotfiles_directory))
+        .cloned()
+        .collect::<Vec<_>>();
+    let committed = git::commit(dotfiles_directory, &paths, message)?;
+    if (git_config.auto_push || push)
+        && (committed || git::is_push_pending(dotfiles_directory, state_directory))
+    {
+        git::push_or_queue(dotfiles_directory, state_directory)?;
     }
 
     Ok(())
 }
 
-fn add_file(
-    file: &Path,
+/// The line of `status` for `entry`, with its `drift` and the permission bits that `violate`
+/// its mode. The layer is shown if the dotfiles are `layered`.
+fn status_line(
+    entry: &Entry,
+    drift: Option<Drift>,
+    violation: Option<u32>,
+    layered: bool,
+    color: bool,
+) -> String {
+    let state = format!("{:<8}", entry.state);
+    let mut line = format!(
+        "{}  {}",
+        color::paint(&state, color::of_state(entry.state), color),
+        entry.target.display()
+    );
+    if layered {
+        line.push_str(&format!("  ({})", entry.layer.display()));
+    }
+    if let Some(actual) = violation {
+        line.push_str(&format!(
+            "  [mode {actual:o}, requires {:o}]",
+            entry.mode.unwrap_or_default()
+        ));
+    }
+    if let Some(drift) = drift {
+        line.push_str(&format!("  [{drift}]"));
+    }
+    line
+}
+
+/// Describes how the dotfiles repository in `state` stands against its upstream, and whether a
+/// failed push is `pending`
+fn sync_summary(state: &git::SyncState, pending: bool) -> String {
+    let mut parts = vec![match &state.upstream {
+        None => "no upstream".to_string(),
+        Some((upstream, 0, 0)) => format!("up to date with '{upstream}'"),
+        Some((upstream, ahead, behind)) => {
+            format!("{ahead} ahead, {behind} behind '{upstream}'")
+        }
+    }];
+    if state.uncommitted > 0 {
+        parts.push(format!(
+            "{} uncommitted",
+            counted(state.uncommitted, "change")
+        ));
+    }
+    if pending {
+        parts.push("push pending".to_string());
+    }
+    parts.join(", ")
+}
+
+/// `count` with `noun`, plural unless there is exactly one
+fn counted(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+/// Whether to go ahead with a command deleting or replacing `affected`. Only a terminal is asked
+/// `question`, and only unless `assume_yes` is set.
+fn confirmed(question: &str, affected: &[PathBuf], assume_yes: bool) -> Result<bool> {
+    if affected.is_empty() || assume_yes || !io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    Ok(confirm::confirm(
+        question,
+        affected,
+        io::stdin().lock(),
+        io::stderr(),
+    )?)
+}
+
+/// The targets of the layered dotfiles with their sources, to pick from
+fn managed_targets(
     base_directory: &Path,
-    dotfiles_directory: &Path,
-) -> Result<(), DofiError> {
-    let new_file = file
-        .strip_prefix(base_directory)
-        .map(|relative_file| dotfiles_directory.join(relative_file))
-        .map_err(|_| {
-            DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), file.to_path_buf())
-        })?;
+    dotfiles_directories: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, PathBuf>> {
+    Ok(
+        layered_dotfiles(&OsFs, base_directory, dotfiles_directories)?
+            .into_iter()
+            .map(|dotfile| (dotfile.target, dotfile.source))
+            .collect(),
+    )
+}
+
+/// Reads the paths listed in the file at `path`, or stdin for `-`. The paths are separated by
+/// NUL if there is one, as written by `find -print0`, and by newlines otherwise.
+fn read_file_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let contents = if path == Path::new("-") {
+        let mut contents = Vec::new();
+        io::stdin()
+            .read_to_end(&mut contents)
+            .map_err(DofiError::GenericIoError)?;
+        contents
+    } else {
+        std::fs::read(path).map_err(DofiError::GenericIoError)?
+    };
+    let contents = String::from_utf8_lossy(&contents);
+
+    let separator = if contents.contains('\0') { '\0' } else { '\n' };
+    Ok(contents
+        .split(separator)
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
 
-    if let Some(parent) = new_file.parent() {
-        std::fs::create_dir_all(parent)?;
+/// How long ago the unix `timestamp` was, e.g. `3h ago`
+fn age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let seconds = now.saturating_sub(timestamp);
+    match seconds {
+        0..60 => format!("{seconds}s ago"),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
     }
+}
 
-    info!("Moving '{}' to '{}'", file.display(), new_file.display());
-    std::fs::rename(file, &new_file)?;
-    info!(
-        "Symlinking '{}' at '{}'",
-        new_file.display(),
-        file.display()
+/// `path` relative to `base`, or `path` itself if it lies outside
+fn relative<'a>(path: &'a Path, base: &Path) -> &'a Path {
+    path.strip_prefix(base).unwrap_or(path)
+}
+
+/// Parses permission bits given in octal, like `600` or `0o600`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid mode '{mode}', expected octal permission bits like 600"))
+}
+
+/// Writes `path` on a line of its own, or unescaped and followed by a NUL byte with `null`
+fn print_path(output: &mut impl Write, path: &Path, null: bool) -> Result<(), DofiError> {
+    if null {
+        output.write_all(&paths::bytes(path))?;
+        output.write_all(b"\0")?;
+    } else {
+        writeln!(output, "{}", paths::display(path))?;
+    }
+    Ok(())
+}
+
+/// Links the dotfile of `entry` from the dashboard, replacing its target if `options.force`
+/// is set
+fn tui_link(
+    entry: &Entry,
+    options: &LinkOptions,
+    hooks: bool,
+    wait: bool,
+    base_directory: &Path,
+    layers: &[PathBuf],
+    state_directory: &Path,
+) -> Result<()> {
+    let _lock = lock::acquire(&lock::lock_path(state_directory), wait)?;
+    let mut journal = Journal::new(state_directory, "link");
+    let result = link_entry(
+        &OsFs,
+        &entry.dotfile(),
+        options.force,
+        options,
+        &mut journal,
     );
-    std::os::unix::fs::symlink(&new_file, file)?;
+    journal.commit(&OsFs)?;
+    result?;
+    run_hooks(
+        hooks,
+        Event::PostLink,
+        base_directory,
+        &layers[0],
+        layers,
+        std::slice::from_ref(&entry.target),
+    )
+}
 
+/// Runs dofi again with `command` in place of the `tui` subcommand, keeping the other
+/// arguments
+fn rerun(command: &str) -> Result<()> {
+    let mut arguments = std::env::args_os().skip(1).collect::<Vec<_>>();
+    if let Some(position) = arguments.iter().rposition(|argument| argument == "tui") {
+        arguments[position] = command.into();
+    }
+    let executable = std::env::current_exe().map_err(DofiError::GenericIoError)?;
+    let status = std::process::Command::new(executable)
+        .args(arguments)
+        .status()
+        .map_err(DofiError::GenericIoError)?;
+    if !status.success() {
+        println!("'dofi {command}' failed");
+    }
     Ok(())
 }
 
-fn link_files(
+/// Runs the hooks of `event` from the dotfiles' manifest unless hooks are disabled
+fn run_hooks(
+    enabled: bool,
+    event: Event,
     base_directory: &Path,
     dotfiles_directory: &Path,
-    force: bool,
-) -> Result<(), DofiError> {
-    let walker = build_walker(dotfiles_directory)?;
-
-    for entry in walker {
-        let file = entry?;
-
-        let symlink = file
-            .path()
-            .strip_prefix(dotfiles_directory)
-            .map(|relative_file| base_directory.join(relative_file))
-            .map_err(|_| {
-                DofiError::BaseIsNotPrefixOfFile(
-                    base_directory.to_path_buf(),
-                    file.path().to_path_buf(),
-                )
-            })?;
+    layers: &[PathBuf],
+    changed: &[PathBuf],
+) -> Result<()> {
+    if enabled {
+        let manifest = Manifest::load(&OsFs, dotfiles_directory)?;
+        hooks::run(
+            &OsFs,
+            &manifest,
+            event,
+            base_directory,
+            dotfiles_directory,
+            changed,
+            &vars::load(&OsFs, layers)?,
+        )?;
+    }
 
-        if let Some(parent) = symlink.parent() {
-            info!("Create folder '{}'", parent.display());
-            std::fs::create_dir_all(parent)?;
-        }
+    Ok(())
+}
 
-        if force && symlink.symlink_metadata().is_ok() {
-            info!("Removing existing file '{}'", symlink.display());
-            std::fs::remove_file(&symlink)?;
-        }
+/// Wraps the generated bash completion to complete managed dotfiles, packages and tags, see
+/// [`completion_script`]
+const BASH_DOTFILE_COMPLETION: &str = r#"
+_dofi() {
+    local i dotfile values cur="${COMP_WORDS[COMP_CWORD]}"
+    case "${COMP_WORDS[COMP_CWORD-1]}" in
+        -t|--tag) values=tags ;;
+        --package) values=packages ;;
+    esac
+    for (( i = 1; i < COMP_CWORD; i++ )); do
+        case "${COMP_WORDS[i]}" in
+            tag|workspaces) break ;;
+            add|link|ln|apply|watch|remove|rm)
+                if [[ -n "$values" ]]; then
+                    COMPREPLY=($(compgen -W "$("${COMP_WORDS[0]}" "${COMP_WORDS[@]:1:i-1}" complete "$values" 2>/dev/null)" -- "$cur"))
+                    return 0
+                fi ;;
+        esac
+        case "${COMP_WORDS[i]}" in
+            remove|rm|edit|diff|mv|move)
+                [[ "$cur" == -* ]] && break
+                COMPREPLY=()
+                while IFS= read -r dotfile; do
+                    dotfile="${dotfile/#$HOME\//\~/}"
+                    [[ "$dotfile" == "$cur"* ]] && COMPREPLY+=("$dotfile")
+                done < <("${COMP_WORDS[0]}" "${COMP_WORDS[@]:1:i-1}" list --targets 2>/dev/null)
+                return 0 ;;
+        esac
+    done
+    _dofi_generated "$@"
+}
+"#;
 
-        info!(
-            "Symlinking '{}' at '{}'",
-            file.path().display(),
-            symlink.display()
-        );
-        std::os::unix::fs::symlink(file.path(), &symlink)?
+/// Wraps the generated zsh completion to complete managed dotfiles, packages and tags, see
+/// [`completion_script`]
+const ZSH_DOTFILE_COMPLETION: &str = r#"
+_dofi() {
+    local i values
+    local -a dotfiles names
+    case ${words[CURRENT-1]} in
+        -t|--tag) values=tags ;;
+        --package) values=packages ;;
+    esac
+    for (( i = 2; i < CURRENT; i++ )); do
+        case ${words[i]} in
+            tag|workspaces) break ;;
+            add|link|ln|apply|watch|remove|rm)
+                if [[ -n $values ]]; then
+                    names=(${(f)"$(${words[1]} ${words[2,i-1]} complete $values 2>/dev/null)"})
+                    compadd -a names
+                    return
+                fi ;;
+        esac
+        case ${words[i]} in
+            remove|rm|edit|diff|mv|move)
+                [[ ${words[CURRENT]} == -* ]] && break
+                dotfiles=(${(f)"$(${words[1]} ${words[2,i-1]} list --targets 2>/dev/null)"})
+                dotfiles=(${dotfiles/#$HOME\//\~/})
+                compadd -Q -a dotfiles
+                return ;;
+        esac
+    done
+    _dofi_generated "$@"
+}
+"#;
+
+/// Adds managed dotfiles, packages and tags to the generated fish completion, see
+/// [`completion_script`]
+const FISH_DOTFILE_COMPLETION: &str = r#"
+function __dofi_managed_dotfiles
+    set -l tokens (commandline -opc)
+    for i in (seq 2 (count $tokens))
+        switch $tokens[$i]
+            case tag workspaces
+                return
+            case remove rm edit diff mv move
+                set -l globals $tokens[2..$i]
+                set -e globals[-1]
+                $tokens[1] $globals list --targets 2>/dev/null | string replace -r "^$HOME/" "~/"
+                return
+        end
+    end
+end
+complete -c dofi -n "__fish_seen_subcommand_from remove rm edit diff mv move" -a "(__dofi_managed_dotfiles)"
+function __dofi_values
+    set -l tokens (commandline -opc)
+    for i in (seq 2 (count $tokens))
+        switch $tokens[$i]
+            case add link ln apply watch remove rm
+                set -l globals $tokens[2..$i]
+                set -e globals[-1]
+                $tokens[1] $globals complete $argv[1] 2>/dev/null
+                return
+        end
+    end
+end
+complete -c dofi -n "__fish_seen_subcommand_from add link ln apply watch" -s t -l tag -x -a "(__dofi_values tags)"
+complete -c dofi -n "__fish_seen_subcommand_from remove rm" -l package -x -a "(__dofi_values packages)"
+"#;
+
+/// `cmd` with a subcommand for each plugin on the `PATH` that is not shadowed by one of dofi's
+/// own, taking the subcommands and options the plugin advertises
+fn with_plugins(mut cmd: Command) -> Command {
+    for (name, completions) in plugin::installed() {
+        if cmd.find_subcommand(&name).is_some() {
+            continue;
+        }
+        let mut subcommand = Command::new(name).about(completions.about);
+        for (word, help) in completions.words {
+            // Words clap cannot take, or has already, are left out rather than failing
+            subcommand = match word.strip_prefix("--") {
+                Some(option) => {
+                    let (option, action) = match option.strip_suffix('=') {
+                        Some(option) => (option, ArgAction::Set),
+                        None => (option, ArgAction::SetTrue),
+                    };
+                    // dofi's global options are propagated to the plugin's as well
+                    let taken = [&cmd, &subcommand].iter().any(|command| {
+                        command
+                            .get_arguments()
+                            .any(|arg| arg.get_long() == Some(option))
+                    });
+                    if option.is_empty() || ["help", "version"].contains(&option) || taken {
+                        continue;
+                    }
+                    let arg = Arg::new(option.to_string()).long(option.to_string());
+                    subcommand.arg(arg.help(help).action(action))
+                }
+                None if word.starts_with('-') || subcommand.find_subcommand(&word).is_some() => {
+                    continue
+                }
+                None => subcommand.subcommand(Command::new(word).about(help)),
+            };
+        }
+        cmd = cmd.subcommand(subcommand);
     }
+    cmd
+}
 
-    Ok(())
+/// The completion script for `shell`. The bash, zsh and fish scripts also complete the
+/// targets of the dotfiles for the commands taking one, from `dofi list --targets`, and the
+/// packages and tags of the manifest for `--package` and `--tag`, from `dofi complete`.
+fn completion_script(shell: Shell, cmd: &mut Command) -> String {
+    let mut script = Vec::new();
+    generate(shell, cmd, cmd.get_name().to_string(), &mut script);
+    let script = String::from_utf8_lossy(&script);
+
+    match shell {
+        Shell::Bash => {
+            script.replacen("_dofi() {", "_dofi_generated() {", 1) + BASH_DOTFILE_COMPLETION
+        }
+        // The wrapper has to be defined before the script calls `_dofi` when autoloaded
+        Shell::Zsh => script
+            .replacen("_dofi() {", "_dofi_generated() {", 1)
+            .replacen(
+                "\nif [ \"$funcstack[1]\" = \"_dofi\" ]",
+                &format!("{ZSH_DOTFILE_COMPLETION}\nif [ \"$funcstack[1]\" = \"_dofi\" ]"),
+                1,
+            ),
+        Shell::Fish => script.into_owned() + FISH_DOTFILE_COMPLETION,
+        _ => script.into_owned(),
+    }
 }
 
-fn list_files(dotfiles_directory: &Path) -> Result<(), DofiError> {
-    let walker = build_walker(dotfiles_directory)?;
-    for entry in walker {
-        println!("{}", entry?.path().display());
+/// Writes the completion script for `shell` where it loads completions from: the user
+/// directory of bash-completion, `~/.zsh/completions` or fish's `completions` directory
+fn install_completions(shell: Shell, cmd: &mut Command) -> Result<()> {
+    let home = platform::home_directory().ok_or(DofiError::NoBaseDirectory)?;
+    let path = match shell {
+        Shell::Bash => env_directory(
+            "BASH_COMPLETION_USER_DIR",
+            env_directory("XDG_DATA_HOME", home.join(".local/share")).join("bash-completion"),
+        )
+        .join("completions/dofi"),
+        Shell::Zsh => home.join(".zsh/completions/_dofi"),
+        Shell::Fish => env_directory("XDG_CONFIG_HOME", home.join(".config"))
+            .join("fish/completions/dofi.fish"),
+        shell => bail!(DofiError::UnsupportedShell(shell.to_string())),
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(DofiError::GenericIoError)?;
     }
+    std::fs::write(&path, completion_script(shell, cmd)).map_err(DofiError::GenericIoError)?;
+    println!("Wrote the {shell} completions to '{}'", path.display());
 
+    // bash-completion and fish find the script on their own, zsh only searches its `fpath`
+    if shell == Shell::Zsh {
+        let zshrc = std::env::var_os("ZDOTDIR")
+            .map(PathBuf::from)
+            .unwrap_or(home)
+            .join(".zshrc");
+        let configured = std::fs::read_to_string(&zshrc)
+            .is_ok_and(|contents| contents.contains(".zsh/completions"));
+        if !configured {
+            println!(
+                "Add this to '{}' before `compinit` runs:\n  fpath=(~/.zsh/completions $fpath)",
+                zshrc.display()
+            );
+        }
+    }
     Ok(())
 }
 
-fn build_walker(
-    path: &Path,
-) -> Result<
-    Filter<ignore::Walk, impl FnMut(&Result<ignore::DirEntry, ignore::Error>) -> bool>,
-    DofiError,
-> {
-    let mut overrides = OverrideBuilder::new(path);
-    overrides.add("!.git/")?;
-    let overrides = overrides.build()?;
-
-    Ok(WalkBuilder::new(path)
-        .hidden(false)
-        .overrides(overrides)
-        .build()
-        .filter(|entry| match entry {
-            Ok(entry) if entry.file_type().is_some() => entry.file_type().unwrap().is_file(),
-            _ => true,
-        }))
+/// The absolute directory in the environment `variable`, `default` if it is unset
+fn env_directory(variable: &str, default: PathBuf) -> PathBuf {
+    std::env::var_os(variable)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or(default)
 }
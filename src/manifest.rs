@@ -0,0 +1,967 @@
+//! The repo manifest, `dofi.toml` at the root of the dotfiles directory.
+//!
+//! The manifest records dotfiles whose target does not mirror their location in the repo,
+//! keyed by the repo-relative path and mapping to the base-relative target:
+//!
+//! ```toml
+//! [targets]
+//! "karabiner.json" = ".config/karabiner/karabiner.json"
+//! ```
+//!
+//! Entries can be added with `dofi add --as` or written by hand, both sides must be relative
+//! paths that stay inside their directory. `link`, `status` and `remove` all honor the mapping.
+//! A target can also be an absolute path to a [system file](crate::elevate), which is linked
+//! as root. Environment variables in targets, `"$XDG_CONFIG_HOME/git/config"`, are expanded
+//! when the manifest is loaded, a target expanding to a path inside the base directory is
+//! linked as usual.
+//!
+//! Roots map repo-relative directories to the directories their contents are linked into,
+//! instead of the base directory. A root is a path relative to the base directory, where a
+//! leading `~` stands for the base directory itself, an absolute path to
+//! [system files](crate::elevate) or a `$VARIABLE` with an optional path after it. The XDG
+//! base directory variables fall back to their defaults when unset:
+//!
+//! ```toml
+//! [roots]
+//! home = "~"
+//! etc = "/etc"
+//! config = "$XDG_CONFIG_HOME"
+//! ```
+//!
+//! Files matching one of the `exclude` glob patterns, or inside a matching directory, are
+//! not dotfiles. Version control metadata (`.git`, `.hg`, `.svn`) is always left out, and so
+//! is the documentation at the root of the repo, `README*`, `LICENSE*`, `CHANGELOG*` and
+//! `*.md`, unless `link_docs = true`:
+//!
+//! ```toml
+//! exclude = ["**/node_modules"]
+//! link_docs = true
+//! ```
+//!
+//! With `gitignore = true` the files matched by the `.gitignore` files in the repo are left
+//! out as well. Git repositories nested in the repo, like vendored plugins, are linked file by
+//! file unless `nested_repos` says otherwise: `"link"` symlinks each of them as a whole and
+//! `"skip"` leaves them out.
+//!
+//! ```toml
+//! gitignore = true
+//! nested_repos = "link"
+//! ```
+//!
+//! Directories listed in `directories` are linked with a single symlink as well, so whatever
+//! programs write into them, like the plugins a package manager installs into
+//! `.config/nvim`, lands in the repo. `add --as-dir` adds a directory this way:
+//!
+//! ```toml
+//! directories = [".config/nvim"]
+//! ```
+//!
+//! With `dot_prefix = true` hidden files and directories can be stored without their dot,
+//! which keeps them visible in file managers and on code hosts: `dot_config/nvim/init.lua` is
+//! linked to `.config/nvim/init.lua`. `add` stores new dotfiles this way, those already
+//! stored with their dot keep working.
+//!
+//! Tags label repo-relative files or directories, a directory tags everything inside it.
+//! `link --tag` only links dotfiles carrying one of the given tags:
+//!
+//! ```toml
+//! [tags]
+//! gui = [".config/alacritty", ".hammerspoon"]
+//! ```
+//!
+//! Files can be limited to some machines by their short hostname, without a
+//! [condition](crate::condition) suffix in their name. An entry for a repo-relative file or
+//! directory lists the `hosts` it is linked on or the `exclude_hosts` it is not, a directory
+//! applying to everything inside it and the deepest entry deciding:
+//!
+//! ```toml
+//! [files.".config/work-vpn"]
+//! hosts = ["work-laptop"]
+//!
+//! [files.".config/syncthing"]
+//! exclude_hosts = ["server", "nas"]
+//! ```
+//!
+//! Recipients scope [encrypted](crate::encryption) dotfiles to the machines holding one of
+//! their keys. `add --encrypt` encrypts a dotfile carrying a tag listed here to the keys of
+//! its tags instead of the configured recipients, and `link` skips it on machines whose
+//! identity is none of them, so one repo can carry secrets only the work machines can read:
+//!
+//! ```toml
+//! [tags]
+//! work = ["work"]
+//!
+//! [recipients]
+//! work = ["age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p", "ssh-ed25519 AAAA..."]
+//! ```
+//!
+//! Modes declare the permission bits targets must have, keyed by glob patterns on
+//! base-relative targets. `link` applies them, to the dotfile itself for symlinked targets, and
+//! `status` and `doctor` report targets that lost them. The longest matching pattern wins:
+//!
+//! ```toml
+//! [modes]
+//! ".ssh/config" = 0o600
+//! "bin/*" = 0o755
+//! ```
+//!
+//! Strategies override how dotfiles are linked, keyed by glob patterns on base-relative
+//! targets like modes. `symlink` is the default, `copy` writes a copy of the dotfile,
+//! `hardlink` hard links it, which needs the target on the same filesystem as the repo, and
+//! `template` renders it like a `.tmpl` dotfile. Private dotfiles are always copied or
+//! rendered and encrypted ones decrypted, whatever their strategy. The longest matching
+//! pattern wins:
+//!
+//! ```toml
+//! [strategies]
+//! ".gnupg/gpg-agent.conf" = "copy"
+//! ".config/*/config" = "template"
+//! ```
+//!
+//! Generated targets are written by `apply` from the output of a command, see
+//! [`generate`](crate::generate):
+//!
+//! ```toml
+//! [generated]
+//! ".zsh/completions/_kubectl" = "kubectl completion zsh"
+//! ```
+//!
+//! Scripts can declare the [scripts](crate::scripts) and steps of `apply` they run after and
+//! before, and the command rolling them back when they fail:
+//!
+//! ```toml
+//! [scripts."scripts/brew-bundle.sh"]
+//! after = ["scripts/install-homebrew.sh"]
+//! before = ["link"]
+//! rollback = "brew bundle cleanup --force"
+//! ```
+//!
+//! It also declares the [`hooks`](crate::hooks) to run.
+//!
+//! Commands keep the manifest up to date rather than asking for manual edits: `add --as`,
+//! `add --mode` and `add --tag` record the new dotfile's target, mode and tags, `tag` changes
+//! the tags and `config --repo set` any other setting. They edit the file in place, keeping its
+//! comments, and `config --repo` only writes it if it stays valid.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use miette::{NamedSource, SourceSpan};
+use serde::{Deserialize, Serialize};
+use toml_edit::{value, Array, DocumentMut, Item, Key, Table};
+
+use crate::{
+    condition,
+    config::{self, expand_variables_in_path, invalid_config},
+    encryption,
+    generate::Generator,
+    hooks::Hooks,
+    private,
+    scripts::ScriptOptions,
+    template, vars, DofiError, Fs, Journal,
+};
+
+/// The name of the manifest file in the dotfiles directory, it is never linked itself
+pub const MANIFEST_FILE: &str = "dofi.toml";
+
+/// The documentation files at the root of the repo that are not dotfiles unless `link_docs`
+/// is set
+pub const DEFAULT_EXCLUDE: &[&str] = &["README*", "LICENSE*", "CHANGELOG*", "*.md"];
+
+/// The prefix standing for a leading dot with `dot_prefix`
+const DOT_PREFIX: &str = "dot_";
+
+/// What happens to git repositories nested in the dotfiles directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NestedRepos {
+    /// Symlink the repository directory as a single dotfile
+    Link,
+    /// Leave the repository out
+    Skip,
+}
+
+/// How a dotfile is linked to its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Symlink the target to the dotfile
+    Symlink,
+    /// Write a copy of the dotfile to the target
+    Copy,
+    /// Hard link the target to the dotfile
+    Hardlink,
+    /// Write the rendered [template](crate::template) to the target
+    Template,
+}
+
+impl Strategy {
+    /// How the dotfile `source` is linked when the manifest `chosen` a strategy for it, if
+    /// any. Encrypted dotfiles are decrypted copies, private ones are copied unless they are
+    /// rendered and `.tmpl` dotfiles are rendered unless something else is chosen.
+    pub fn resolve(source: &Path, private: bool, chosen: Option<Strategy>) -> Strategy {
+        let rendered = template::is_template(source);
+        match chosen {
+            _ if encryption::is_encrypted(source) => Strategy::Copy,
+            Some(Strategy::Template) => Strategy::Template,
+            None if rendered => Strategy::Template,
+            _ if private => Strategy::Copy,
+            Some(strategy) => strategy,
+            None => Strategy::Symlink,
+        }
+    }
+
+    /// Whether the target is a file of its own, written again whenever the dotfile changes
+    pub fn is_copied(self) -> bool {
+        matches!(self, Strategy::Copy | Strategy::Template)
+    }
+
+    /// The name of the strategy in the manifest
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Strategy::Symlink => "symlink",
+            Strategy::Copy => "copy",
+            Strategy::Hardlink => "hardlink",
+            Strategy::Template => "template",
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The machines a file is linked on, from the `files` section of the manifest
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileOptions {
+    /// The hostnames of the only machines the file is linked on, any machine if empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// The hostnames of machines the file is never linked on
+    #[serde(default)]
+    pub exclude_hosts: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Repo-relative dotfiles mapped to base-relative targets
+    #[serde(default)]
+    pub targets: BTreeMap<PathBuf, PathBuf>,
+
+    /// Repo-relative directories mapped to the roots their contents are linked into
+    #[serde(default)]
+    pub roots: BTreeMap<PathBuf, String>,
+
+    /// Glob patterns on repo-relative paths that are not dotfiles, in addition to the
+    /// version control metadata that is always left out
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Link the documentation at the root of the repo, [`DEFAULT_EXCLUDE`], like any other
+    /// file instead of leaving it out
+    #[serde(default)]
+    pub link_docs: bool,
+
+    /// Also leave out the files matched by the `.gitignore` files in the repo
+    #[serde(default)]
+    pub gitignore: bool,
+
+    /// How directories containing a `.git` are linked, file by file if unset
+    pub nested_repos: Option<NestedRepos>,
+
+    /// Repo-relative directories linked with a single symlink instead of file by file
+    #[serde(default)]
+    pub directories: Vec<PathBuf>,
+
+    /// Store hidden files and directories as `dot_name` instead of `.name`
+    #[serde(default)]
+    pub dot_prefix: bool,
+
+    /// Tags mapped to the repo-relative files and directories carrying them
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<PathBuf>>,
+
+    /// Repo-relative files and directories mapped to the machines they are linked on
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, FileOptions>,
+
+    /// Tags mapped to the keys encrypted dotfiles carrying them are encrypted to
+    #[serde(default)]
+    pub recipients: BTreeMap<String, Vec<String>>,
+
+    /// Glob patterns on base-relative targets mapped to the permission bits they must have
+    #[serde(default)]
+    pub modes: BTreeMap<String, u32>,
+
+    /// Glob patterns on base-relative targets mapped to how they are linked
+    #[serde(default)]
+    pub strategies: BTreeMap<String, Strategy>,
+
+    /// Base-relative targets mapped to the commands generating their contents
+    #[serde(default)]
+    pub generated: BTreeMap<PathBuf, Generator>,
+
+    /// Repo-relative scripts mapped to how they are ordered
+    #[serde(default)]
+    pub scripts: BTreeMap<PathBuf, ScriptOptions>,
+
+    #[serde(default)]
+    pub hooks: Hooks,
+}
+
+impl Manifest {
+    /// Loads the manifest of `dotfiles_directory`, a missing file is an empty manifest
+    pub fn load(fs: &dyn Fs, dotfiles_directory: &Path) -> Result<Self, DofiError> {
+        let path = dotfiles_directory.join(MANIFEST_FILE);
+        let Ok(contents) = fs.read(&path) else {
+            return Ok(Self::default());
+        };
+        Self::parse(&path, String::from_utf8_lossy(&contents).into_owned())
+    }
+
+    /// Parses and validates the `contents` of the manifest at `path`
+    fn parse(path: &Path, contents: String) -> Result<Self, DofiError> {
+        let mut manifest: Self =
+            toml::from_str(&contents).map_err(|e| invalid_config(path, contents, e))?;
+        for target in manifest.targets.values_mut() {
+            *target = expand_variables_in_path(target)?;
+        }
+        for (source, target) in &manifest.targets {
+            validate_repo_path(source)?;
+            if !is_inner_path(target) && !is_system_path(target) {
+                return Err(DofiError::InvalidManifestTarget(
+                    source.clone(),
+                    target.clone(),
+                ));
+            }
+        }
+
+        for (directory, root) in &manifest.roots {
+            validate_repo_path(directory)?;
+            if root.is_empty() {
+                return Err(DofiError::InvalidManifestRoot(
+                    directory.clone(),
+                    root.clone(),
+                ));
+            }
+        }
+
+        for path in manifest
+            .tags
+            .values()
+            .flatten()
+            .chain(&manifest.directories)
+            .chain(manifest.files.keys())
+        {
+            validate_repo_path(path)?;
+        }
+        for path in manifest.scripts.keys().chain(
+            manifest
+                .scripts
+                .values()
+                .flat_map(|options| options.after.iter().chain(&options.before)),
+        ) {
+            validate_repo_path(path)?;
+        }
+        for target in manifest.generated.keys() {
+            if !is_inner_path(target) {
+                return Err(DofiError::InvalidGeneratedTarget(target.clone()));
+            }
+        }
+        manifest.exclusions()?;
+        for (pattern, mode) in &manifest.modes {
+            if *mode > 0o7777 {
+                return Err(DofiError::InvalidMode(pattern.clone(), *mode));
+            }
+        }
+        manifest.mode_matchers()?;
+
+        Ok(manifest)
+    }
+
+    /// The compiled `exclude` patterns
+    pub fn exclusions(&self) -> Result<GlobSet, DofiError> {
+        let mut exclusions = GlobSetBuilder::new();
+        if !self.link_docs {
+            for pattern in DEFAULT_EXCLUDE {
+                // Only at the root, `*` does not cross directories
+                exclusions.add(
+                    GlobBuilder::new(pattern)
+                        .literal_separator(true)
+                        .build()
+                        .expect("the default patterns are valid"),
+                );
+            }
+        }
+        for pattern in &self.exclude {
+            exclusions.add(
+                Glob::new(pattern)
+                    .map_err(|e| DofiError::InvalidPattern(pattern.clone(), e.to_string()))?,
+            );
+        }
+        exclusions
+            .build()
+            .map_err(|e| DofiError::InvalidPattern(self.exclude.join(", "), e.to_string()))
+    }
+
+    /// The compiled `modes` patterns with their modes, the longest pattern first
+    pub fn mode_matchers(&self) -> Result<Vec<(GlobMatcher, u32)>, DofiError> {
+        target_matchers(&self.modes)
+    }
+
+    /// The compiled `strategies` patterns with their strategies, the longest pattern first
+    pub fn strategy_matchers(&self) -> Result<Vec<(GlobMatcher, Strategy)>, DofiError> {
+        target_matchers(&self.strategies)
+    }
+
+    /// The tags of the repo-relative dotfile `relative_file`, including those of the
+    /// directories it is in
+    pub fn tags_of(&self, relative_file: &Path) -> Vec<String> {
+        self.tags
+            .iter()
+            .filter(|(_, paths)| paths.iter().any(|path| relative_file.starts_with(path)))
+            .map(|(tag, _)| tag.clone())
+            .collect()
+    }
+
+    /// Whether the repo-relative dotfile `relative_file` is linked on the machine named
+    /// `hostname`, by the deepest entry in `files` it is at or below. A file limited to some
+    /// hosts is not linked where the hostname is unknown.
+    pub fn is_linked_on(&self, relative_file: &Path, hostname: Option<&str>) -> bool {
+        let Some(options) = self
+            .files
+            .iter()
+            .filter(|(path, _)| relative_file.starts_with(path))
+            .max_by_key(|(path, _)| path.components().count())
+            .map(|(_, options)| options)
+        else {
+            return true;
+        };
+        let named = |hosts: &[String]| {
+            hostname.is_some_and(|hostname| {
+                hosts.iter().any(|host| host.eq_ignore_ascii_case(hostname))
+            })
+        };
+        (options.hosts.is_empty() || named(&options.hosts)) && !named(&options.exclude_hosts)
+    }
+
+    /// The keys the repo-relative dotfile `relative_file` is encrypted to, those of all its
+    /// tags, empty if it is encrypted to the configured recipients
+    pub fn recipients_of(&self, relative_file: &Path) -> Vec<String> {
+        let mut recipients = Vec::new();
+        for tag in self.tags_of(relative_file) {
+            for recipient in self.recipients.get(&tag).into_iter().flatten() {
+                if !recipients.contains(recipient) {
+                    recipients.push(recipient.clone());
+                }
+            }
+        }
+        recipients
+    }
+
+    /// Maps the dotfile `file` to the location in `base_directory` it is linked to
+    pub fn target_path(
+        &self,
+        file: &Path,
+        base_directory: &Path,
+        dotfiles_directory: &Path,
+    ) -> Result<PathBuf, DofiError> {
+        let relative_file = file.strip_prefix(dotfiles_directory).map_err(|_| {
+            DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), file.to_path_buf())
+        })?;
+
+        if let Some(target) = self.targets.get(relative_file) {
+            return Ok(base_directory.join(target));
+        }
+        let relative_file =
+            if encryption::is_encrypted(relative_file) || template::is_template(relative_file) {
+                relative_file.with_extension("")
+            } else {
+                relative_file.to_path_buf()
+            };
+
+        let relative_file = condition::strip_suffix(private::strip_prefix(&relative_file));
+
+        for (directory, root) in self.resolved_roots(base_directory)? {
+            if let Ok(rest) = relative_file.strip_prefix(directory) {
+                return Ok(root.join(self.decode_dot_prefix(rest)));
+            }
+        }
+        Ok(base_directory.join(self.decode_dot_prefix(&relative_file)))
+    }
+
+    /// `path` with every `dot_` prefix standing for a leading dot, if the manifest asks
+    fn decode_dot_prefix(&self, path: &Path) -> PathBuf {
+        if !self.dot_prefix {
+            return path.to_path_buf();
+        }
+        path.components()
+            .map(|component| match component.as_os_str().to_str() {
+                Some(name) if name.len() > DOT_PREFIX.len() && name.starts_with(DOT_PREFIX) => {
+                    format!(".{}", &name[DOT_PREFIX.len()..]).into()
+                }
+                _ => component.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+
+    /// `path` with every leading dot written as `dot_`, if the manifest asks
+    fn encode_dot_prefix(&self, path: &Path) -> PathBuf {
+        if !self.dot_prefix {
+            return path.to_path_buf();
+        }
+        encode_dots(path)
+    }
+
+    /// The repo-relative `path` as it is stored with `dot_prefix`, where below the root
+    /// containing it every leading dot is written as `dot_`
+    pub fn prefixed_path(&self, path: &Path) -> PathBuf {
+        let (root, rest) = self.split_root(path);
+        root.join(encode_dots(rest))
+    }
+
+    /// Whether a name in the repo-relative `path` below the root containing it starts with
+    /// `dot_`, which `dot_prefix` turns into a leading dot
+    pub fn has_dot_prefix(&self, path: &Path) -> bool {
+        self.split_root(path)
+            .1
+            .components()
+            .any(|component| match component.as_os_str().to_str() {
+                Some(name) => name.len() > DOT_PREFIX.len() && name.starts_with(DOT_PREFIX),
+                None => false,
+            })
+    }
+
+    /// The repo-relative `path` split into the most nested root directory containing it, empty
+    /// if there is none, and the rest
+    fn split_root<'a, 'b>(&'a self, path: &'b Path) -> (&'a Path, &'b Path) {
+        self.roots
+            .keys()
+            .filter(|directory| path.starts_with(directory))
+            .max_by_key(|directory| directory.components().count())
+            .and_then(|directory| Some((directory.as_path(), path.strip_prefix(directory).ok()?)))
+            .unwrap_or((Path::new(""), path))
+    }
+
+    /// The roots with their resolved directories, the most nested repo directory first
+    fn resolved_roots<'a>(
+        &'a self,
+        base_directory: &Path,
+    ) -> Result<Vec<(&'a Path, PathBuf)>, DofiError> {
+        let mut roots = self
+            .roots
+            .iter()
+            .map(|(directory, root)| {
+                resolve_root(root, base_directory)
+                    .map(|resolved| (directory.as_path(), resolved))
+                    .ok_or_else(|| DofiError::InvalidManifestRoot(directory.clone(), root.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        roots.sort_by_key(|(directory, _)| std::cmp::Reverse(directory.components().count()));
+        Ok(roots)
+    }
+
+    /// The repo-relative path mirroring `target`, under the root containing it or relative to
+    /// `base_directory`
+    pub fn mirrored_path(
+        &self,
+        target: &Path,
+        base_directory: &Path,
+    ) -> Result<PathBuf, DofiError> {
+        let mut roots = self.resolved_roots(base_directory)?;
+        // The most nested root wins, like `~/.config` over `~`
+        roots.sort_by_key(|(_, root)| std::cmp::Reverse(root.components().count()));
+        for (directory, root) in roots {
+            if let Ok(rest) = target.strip_prefix(&root) {
+                return Ok(directory.join(self.encode_dot_prefix(rest)));
+            }
+        }
+
+        target
+            .strip_prefix(base_directory)
+            .map(|relative| self.encode_dot_prefix(relative))
+            .map_err(|_| {
+                DofiError::BaseIsNotPrefixOfFile(base_directory.to_path_buf(), target.to_path_buf())
+            })
+    }
+
+    /// Maps `path` to the dotfile backing it, `path` may be either the dotfile itself or its
+    /// location in `base_directory`
+    pub fn source_path(
+        &self,
+        path: &Path,
+        base_directory: &Path,
+        dotfiles_directory: &Path,
+    ) -> Result<PathBuf, DofiError> {
+        if path.starts_with(dotfiles_directory) {
+            return Ok(path.to_path_buf());
+        }
+
+        if let Some((source, _)) = self
+            .targets
+            .iter()
+            .find(|(_, target)| base_directory.join(target) == path)
+        {
+            return Ok(dotfiles_directory.join(source));
+        }
+
+        Ok(dotfiles_directory.join(self.mirrored_path(path, base_directory)?))
+    }
+}
+
+/// Checks that `path` is relative and cannot escape the dotfiles directory
+pub fn validate_repo_path(path: &Path) -> Result<(), DofiError> {
+    if is_inner_path(path) && path != Path::new(MANIFEST_FILE) && !vars::is_vars_path(path) {
+        Ok(())
+    } else {
+        Err(DofiError::InvalidRepoPath(path.to_path_buf()))
+    }
+}
+
+/// Whether `path` is a non-empty relative path without any `..` or `.` components
+fn is_inner_path(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Resolves the root `root` of a repo directory, `None` if it names an unset variable
+fn resolve_root(root: &str, base_directory: &Path) -> Option<PathBuf> {
+    let Some(variable) = root.strip_prefix('$') else {
+        let root = Path::new(root);
+        return Some(base_directory.join(root.strip_prefix("~").unwrap_or(root)));
+    };
+
+    let (name, rest) = variable.split_once('/').unwrap_or((variable, ""));
+    let value = std::env::var_os(name)
+        .map(PathBuf::from)
+        .filter(|value| value.is_absolute())
+        .or_else(|| {
+            let default = match name {
+                "XDG_CONFIG_HOME" => ".config",
+                "XDG_DATA_HOME" => ".local/share",
+                "XDG_STATE_HOME" => ".local/state",
+                "XDG_CACHE_HOME" => ".cache",
+                _ => return None,
+            };
+            Some(base_directory.join(default))
+        })?;
+    Some(value.join(rest))
+}
+
+/// Whether `path` is an absolute path without any `..` or `.` components
+fn is_system_path(path: &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|component| {
+            matches!(
+                component,
+                Component::Prefix(_) | Component::RootDir | Component::Normal(_)
+            )
+        })
+}
+
+/// Compiles glob `patterns` on base-relative targets, the longest pattern first
+pub(crate) fn target_matchers<T: Copy>(
+    patterns: &BTreeMap<String, T>,
+) -> Result<Vec<(GlobMatcher, T)>, DofiError> {
+    let mut matchers = patterns
+        .iter()
+        .map(|(pattern, value)| {
+            Glob::new(pattern)
+                .map(|glob| (glob.compile_matcher(), *value))
+                .map_err(|e| DofiError::InvalidPattern(pattern.clone(), e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    matchers.sort_by_key(|(matcher, _)| std::cmp::Reverse(matcher.glob().glob().len()));
+    Ok(matchers)
+}
+
+/// Records in the manifest that the repo-relative `source` is linked to the base-relative
+/// `target`, or removes its entry if `target` is `None`. The manifest is edited in place,
+/// preserving its formatting and comments.
+pub fn set_target(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    source: &Path,
+    target: Option<&Path>,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let (path, mut document) = load_document(fs, dotfiles_directory)?;
+
+    let key = toml_path(source);
+    match target {
+        Some(target) => {
+            if !document.get("targets").is_some_and(Item::is_table_like) {
+                document.insert("targets", Item::Table(Table::new()));
+            }
+            let targets = document["targets"]
+                .as_table_like_mut()
+                .expect("targets was just made a table");
+            targets.insert(&key, value(toml_path(target)));
+        }
+        None => {
+            let Some(targets) = document
+                .get_mut("targets")
+                .and_then(Item::as_table_like_mut)
+            else {
+                return Ok(());
+            };
+            if targets.remove(&key).is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    journal.write_file(fs, &path, document.to_string().as_bytes())
+}
+
+/// Lists the repo-relative `directory` in `directories`, so it is linked as a whole, or takes
+/// it off with `unit` unset. The manifest is edited in place like in [`set_target`].
+pub fn set_directory(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    directory: &Path,
+    unit: bool,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let (manifest_path, mut document) = load_document(fs, dotfiles_directory)?;
+    let entry = toml_path(directory);
+
+    if document
+        .get("directories")
+        .and_then(Item::as_array)
+        .is_none()
+    {
+        if !unit {
+            return Ok(());
+        }
+        document.insert("directories", value(Array::new()));
+    }
+    let directories = document["directories"]
+        .as_array_mut()
+        .expect("directories was just made an array");
+    let position = directories
+        .iter()
+        .position(|existing| existing.as_str() == Some(entry.as_str()));
+
+    match (position, unit) {
+        (None, true) => directories.push(entry),
+        (Some(position), false) => {
+            directories.remove(position);
+            if directories.is_empty() {
+                document.remove("directories");
+            }
+        }
+        _ => return Ok(()),
+    }
+
+    journal.write_file(fs, &manifest_path, document.to_string().as_bytes())
+}
+
+/// Adds the repo-relative file or directory `path` to `tag`, or removes it with `tagged` unset.
+/// The manifest is edited in place like in [`set_target`].
+pub fn set_tag(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    tag: &str,
+    path: &Path,
+    tagged: bool,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let (manifest_path, mut document) = load_document(fs, dotfiles_directory)?;
+    let entry = toml_path(path);
+
+    if !document.get("tags").is_some_and(Item::is_table_like) {
+        if !tagged {
+            return Ok(());
+        }
+        document.insert("tags", Item::Table(Table::new()));
+    }
+    let tags = document["tags"]
+        .as_table_like_mut()
+        .expect("tags was just made a table");
+    if tags.get(tag).and_then(Item::as_array).is_none() {
+        if !tagged {
+            return Ok(());
+        }
+        tags.insert(tag, value(Array::new()));
+    }
+    let paths = tags
+        .get_mut(tag)
+        .and_then(Item::as_array_mut)
+        .expect("the tag was just made an array");
+    let position = paths
+        .iter()
+        .position(|existing| existing.as_str() == Some(entry.as_str()));
+
+    match (position, tagged) {
+        (None, true) => paths.push(entry),
+        (Some(position), false) => {
+            paths.remove(position);
+            if paths.is_empty() {
+                tags.remove(tag);
+            }
+        }
+        _ => return Ok(()),
+    }
+
+    journal.write_file(fs, &manifest_path, document.to_string().as_bytes())
+}
+
+/// Requires the permission bits `mode` of the targets matching the glob `pattern` in the
+/// manifest, edited in place like in [`set_target`]
+pub fn set_mode(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    pattern: &str,
+    mode: u32,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let key = format!("modes.{}", Key::new(pattern));
+    set_setting(fs, dotfiles_directory, &key, &format!("{mode:#o}"), journal)
+}
+
+/// Limits the repo-relative dotfile `relative_file` to the machines named `hosts` in the
+/// manifest, edited in place like in [`set_target`]
+pub fn set_hosts(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    relative_file: &Path,
+    hosts: &[String],
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let key = format!(
+        "files.{}.hosts",
+        Key::new(relative_file.to_string_lossy().into_owned())
+    );
+    let hosts = Array::from_iter(hosts).to_string();
+    set_setting(fs, dotfiles_directory, &key, &hosts, journal)
+}
+
+/// Sets the dotted `key` in the manifest to `value`, a string unless it is a TOML value, like
+/// [`config::set_setting`](crate::config::set_setting). The manifest is edited in place like
+/// in [`set_target`] and only written if it stays valid.
+pub fn set_setting(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    key: &str,
+    value: &str,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let mut result = Ok(());
+    for value in config::candidate_values(value) {
+        let (path, mut document) = load_document(fs, dotfiles_directory)?;
+        config::set_value(&mut document, key, value)?;
+        let contents = document.to_string();
+        match Manifest::parse(&path, contents.clone()) {
+            Ok(_) => return journal.write_file(fs, &path, contents.as_bytes()),
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(_) => {}
+        }
+    }
+    result
+}
+
+/// Reads the manifest of `dotfiles_directory` for editing, a missing file is an empty document
+fn load_document(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+) -> Result<(PathBuf, DocumentMut), DofiError> {
+    let path = dotfiles_directory.join(MANIFEST_FILE);
+    let contents = fs
+        .read(&path)
+        .map(|contents| String::from_utf8_lossy(&contents).into_owned())
+        .unwrap_or_default();
+    let document = contents
+        .parse::<DocumentMut>()
+        .map_err(|e| DofiError::InvalidConfig {
+            message: e.message().to_string(),
+            span: e.span().map(SourceSpan::from),
+            source_code: NamedSource::new(path.display().to_string(), contents.clone()),
+        })?;
+
+    Ok((path, document))
+}
+
+/// `path` with every leading dot written as `dot_`
+fn encode_dots(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component.as_os_str().to_str() {
+            Some(name) if name.len() > 1 && name.starts_with('.') && name != ".." => {
+                format!("{DOT_PREFIX}{}", &name[1..]).into()
+            }
+            _ => component.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+/// Turns on `dot_prefix` in the manifest and moves the entries for repo-relative paths, in
+/// `targets`, `files`, `scripts`, `tags` and `directories`, to where `rename` puts the paths.
+/// The manifest is edited in place like in [`set_target`].
+pub fn enable_dot_prefix(
+    fs: &dyn Fs,
+    dotfiles_directory: &Path,
+    rename: impl Fn(&Path) -> PathBuf,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let (path, mut document) = load_document(fs, dotfiles_directory)?;
+    let renamed = |key: &str| toml_path(&rename(Path::new(key)));
+    let rename_all = |array: &mut Array| {
+        for entry in array.iter_mut() {
+            let Some(new) = entry
+                .as_str()
+                .map(renamed)
+                .filter(|new| entry.as_str() != Some(new))
+            else {
+                continue;
+            };
+            let decor = entry.decor().clone();
+            *entry = new.into();
+            *entry.decor_mut() = decor;
+        }
+    };
+
+    document.insert("dot_prefix", value(true));
+    for name in ["targets", "files", "scripts"] {
+        let Some(entries) = document.get_mut(name).and_then(Item::as_table_like_mut) else {
+            continue;
+        };
+        let keys = entries
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .collect::<Vec<_>>();
+        for key in keys {
+            let new = renamed(&key);
+            if new != key {
+                let item = entries.remove(&key).expect("the key was just listed");
+                entries.insert(&new, item);
+            }
+        }
+    }
+    if let Some(tags) = document.get_mut("tags").and_then(Item::as_table_like_mut) {
+        for (_, paths) in tags.iter_mut() {
+            if let Some(paths) = paths.as_array_mut() {
+                rename_all(paths);
+            }
+        }
+    }
+    if let Some(directories) = document.get_mut("directories").and_then(Item::as_array_mut) {
+        rename_all(directories);
+    }
+
+    journal.write_file(fs, &path, document.to_string().as_bytes())
+}
+
+/// Paths are stored with forward slashes so the manifest works across platforms
+fn toml_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
@@ -0,0 +1,118 @@
+//! Three-way merges of copied targets edited locally while their dotfile changed in the repo.
+//!
+//! `dofi merge <file>` takes what dofi last wrote to the target as the base, the target as
+//! the local side and what linking would write now as the new side. They are merged with the
+//! system `diff3`, leaving conflict markers where both sides changed the same lines, or with
+//! `$MERGE_TOOL` if it is set, which is run like git's merge tools with the local, base, new
+//! and merged files as arguments and edits the last one. The result is written to the target.
+//!
+//! The local edits stay local: the target still counts as edited afterwards, but against the
+//! new contents, so a later merge only deals with what changed since.
+
+use std::{path::Path, process::Command};
+
+use log::info;
+
+use crate::{checksum, fs::ScratchDirectory, DofiError, Fs, Journal};
+
+/// How a merge went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The sides were merged without conflicts
+    Clean,
+    /// The target holds conflict markers to resolve
+    Conflicts,
+}
+
+/// Merges the edits made to `target` since dofi last wrote the base of `sides` to it with the
+/// new side, writing the result to `target`, and records the new side as what was written.
+/// `label` names the new side, usually the dotfile it comes from.
+pub fn merge(
+    fs: &dyn Fs,
+    target: &Path,
+    sides: Sides,
+    label: &Path,
+    journal: &mut Journal,
+) -> Result<Outcome, DofiError> {
+    // The files handed to the merge tools, in the state directory as they may hold secrets
+    let scratch = ScratchDirectory::new(journal.directory(), "merge")?;
+    let [local, base, new, merged] =
+        ["local", "base", "new", "merged"].map(|name| scratch.path().join(name));
+    let local_contents = fs.read(target)?;
+    for (path, contents) in [
+        (&local, local_contents.as_slice()),
+        (&base, sides.base),
+        (&new, sides.new),
+        (&merged, local_contents.as_slice()),
+    ] {
+        std::fs::write(path, contents)?;
+    }
+
+    let outcome = match std::env::var("MERGE_TOOL")
+        .ok()
+        .filter(|tool| !tool.trim().is_empty())
+    {
+        Some(tool) => {
+            let mut words = tool.split_whitespace();
+            let program = words.next().expect("tool is not empty");
+            info!("Running '{tool}' on '{}'", target.display());
+            let status = Command::new(program)
+                .args(words)
+                .args([&local, &base, &new, &merged])
+                .status()
+                .map_err(|e| DofiError::ExternalCommandFailed(tool.clone(), e.to_string()))?;
+            if !status.success() {
+                return Err(DofiError::ExternalCommandFailed(
+                    tool,
+                    format!("exited with {status}"),
+                ));
+            }
+            Outcome::Clean
+        }
+        None => {
+            let output = Command::new("diff3")
+                .arg("-m")
+                .arg("-L")
+                .arg(target)
+                .arg("-L")
+                .arg("base")
+                .arg("-L")
+                .arg(label)
+                .args([&local, &base, &new])
+                .output()
+                .map_err(|e| {
+                    DofiError::ExternalCommandFailed("diff3".to_string(), e.to_string())
+                })?;
+            let outcome = match output.status.code() {
+                Some(0) => Outcome::Clean,
+                Some(1) => Outcome::Conflicts,
+                _ => {
+                    return Err(DofiError::ExternalCommandFailed(
+                        "diff3".to_string(),
+                        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    ))
+                }
+            };
+            std::fs::write(&merged, output.stdout)?;
+            outcome
+        }
+    };
+
+    let mode = fs.symlink_metadata(target)?.mode;
+    info!("Writing the merge to '{}'", target.display());
+    journal.write_file(fs, target, &std::fs::read(&merged)?)?;
+    fs.set_mode(target, mode)?;
+    checksum::record(fs, target, sides.new, sides.source, journal)?;
+
+    Ok(outcome)
+}
+
+/// The contents taking part in a merge besides the target
+pub struct Sides<'a> {
+    /// What dofi last wrote to the target
+    pub base: &'a [u8],
+    /// What linking the dotfile writes now
+    pub new: &'a [u8],
+    /// The contents of the dotfile itself
+    pub source: &'a [u8],
+}
@@ -0,0 +1,151 @@
+//! Moving a dotfiles directory of the flat layout, where every dotfile is stored under the
+//! name of its target, to `dot_prefix` of the [manifest](crate::manifest), where
+//! `.config/nvim/init.lua` is stored as `dot_config/nvim/init.lua`.
+//!
+//! `dofi migrate` moves the dotfiles, turns on `dot_prefix` in the manifest, writing it if
+//! there is none, with its entries following the dotfiles they are about, and points the
+//! symlinks to the moved dotfiles at their new location. Targets stay where they are.
+//! `--dry-run` only lists what would move. Every change is recorded in the journal, so
+//! `dofi undo` brings back the flat layout.
+//!
+//! The `.gitignore`, `.gitattributes` and `.gitmodules` at the root of the repo keep their
+//! names, git reads them from there. Migrating fails without changing anything if a name in
+//! the repo already starts with `dot_`, which would then stand for a dot.
+
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::{
+    journal::Action,
+    link_file, list_files,
+    manifest::{self, Manifest},
+    DofiError, Fs, Journal,
+};
+
+/// The files at the root of the repo that belong to git rather than to the dotfiles
+const GIT_FILES: &[&str] = &[".gitignore", ".gitattributes", ".gitmodules"];
+
+/// What migrating a dotfiles directory changes
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Migration {
+    /// The repo-relative dotfiles that move, with where to
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    /// The targets symlinked to a dotfile that moves, with the repo-relative dotfile after
+    /// the move
+    pub relinks: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Migration {
+    /// Whether the dotfiles directory already has the new layout
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+}
+
+/// What migrating `dotfiles_directory`, linked into `base_directory`, changes. Nothing if
+/// its manifest sets `dot_prefix` already.
+pub fn plan(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+) -> Result<Migration, DofiError> {
+    let manifest = Manifest::load(fs, dotfiles_directory)?;
+    let mut migration = Migration::default();
+    if manifest.dot_prefix {
+        return Ok(migration);
+    }
+
+    for source in list_files(fs, dotfiles_directory)? {
+        let relative = source
+            .strip_prefix(dotfiles_directory)
+            .expect("the dotfiles are listed inside their directory");
+        if manifest.has_dot_prefix(relative) {
+            return Err(DofiError::CannotMigrate(
+                source.clone(),
+                "a name in its path starts with `dot_`, which would stand for a dot".to_string(),
+            ));
+        }
+        if GIT_FILES.iter().any(|name| relative == Path::new(name)) {
+            continue;
+        }
+        let moved = manifest.prefixed_path(relative);
+        if moved == relative {
+            continue;
+        }
+        let destination = dotfiles_directory.join(&moved);
+        if fs.symlink_metadata(&destination).is_ok() {
+            return Err(DofiError::CannotMigrate(
+                source.clone(),
+                format!("'{}' already exists", destination.display()),
+            ));
+        }
+
+        let target = manifest.target_path(&source, base_directory, dotfiles_directory)?;
+        if fs
+            .read_link(&target)
+            .is_ok_and(|original| original == source)
+        {
+            migration.relinks.push((target, moved.clone()));
+        }
+        migration.moves.push((relative.to_path_buf(), moved));
+    }
+
+    Ok(migration)
+}
+
+/// Carries out `migration` in `dotfiles_directory`, rolling back what was changed if it fails
+/// halfway
+pub fn apply(
+    fs: &dyn Fs,
+    migration: &Migration,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let savepoint = journal.savepoint();
+    let result = apply_moves(fs, migration, dotfiles_directory, journal);
+    if let Err(e) = result {
+        warn!("Migrating failed, rolling back");
+        if let Err(e) = journal.rollback(fs, savepoint) {
+            warn!("Failed to roll back: {e}");
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn apply_moves(
+    fs: &dyn Fs,
+    migration: &Migration,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    for (from, to) in &migration.moves {
+        let (from, to) = (dotfiles_directory.join(from), dotfiles_directory.join(to));
+        if let Some(parent) = to.parent() {
+            journal.create_dir_all(fs, parent)?;
+        }
+        info!("Moving '{}' to '{}'", from.display(), to.display());
+        fs.rename(&from, &to)?;
+        journal.record(Action::Moved {
+            from: from.clone(),
+            to,
+        });
+        if let Some(parent) = from.parent() {
+            journal.remove_empty_directories(fs, parent, dotfiles_directory, |_| true)?;
+        }
+    }
+
+    let manifest = Manifest::load(fs, dotfiles_directory)?;
+    manifest::enable_dot_prefix(
+        fs,
+        dotfiles_directory,
+        |path| manifest.prefixed_path(path),
+        journal,
+    )?;
+
+    for (target, source) in &migration.relinks {
+        link_file(fs, &dotfiles_directory.join(source), target, true, journal)?;
+    }
+    Ok(())
+}
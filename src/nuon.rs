@@ -0,0 +1,158 @@
+//! NUON, the object notation of Nushell, for `list --format nuon` and `status --format nuon`.
+//!
+//! The dotfiles come out as a list of records, which nushell reads into a table:
+//!
+//! ```nu
+//! dofi status --format nuon | from nuon | where state == broken | each { |row| rm $row.target }
+//! ```
+//!
+//! Paths are strings, with the bytes that are not UTF-8 replaced, permission bits are octal
+//! strings like `"600"` and modification times are datetimes.
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::status::Entry;
+
+/// A NUON value, written out by [`Value::write`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nothing,
+    Bool(bool),
+    String(String),
+    Date(SystemTime),
+    List(Vec<Value>),
+    Record(Vec<(&'static str, Value)>),
+}
+
+impl Value {
+    pub fn path(path: &Path) -> Self {
+        Value::String(path.to_string_lossy().into_owned())
+    }
+
+    /// The value as NUON, lists of records spread over a line per record
+    pub fn write(&self) -> String {
+        match self {
+            Value::Nothing => "null".to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::String(text) => string(text),
+            Value::Date(time) => date(*time),
+            Value::List(values) if values.is_empty() => "[]".to_string(),
+            Value::List(values) => {
+                let items = values
+                    .iter()
+                    .map(|value| format!("  {}", value.write()))
+                    .collect::<Vec<_>>();
+                format!("[\n{}\n]", items.join(",\n"))
+            }
+            Value::Record(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = match value {
+                            // Nested lists stay on the line of their record
+                            Value::List(values) => format!(
+                                "[{}]",
+                                values
+                                    .iter()
+                                    .map(Value::write)
+                                    .collect::<Vec<_>>()
+                                    .join(", ")
+                            ),
+                            value => value.write(),
+                        };
+                        format!("{name}: {value}")
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nothing, Into::into)
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::String(text)
+    }
+}
+
+impl From<SystemTime> for Value {
+    fn from(time: SystemTime) -> Self {
+        Value::Date(time)
+    }
+}
+
+/// The fields of `entry`, those of [`Entry`] and its state
+pub fn entry(entry: &Entry) -> Vec<(&'static str, Value)> {
+    vec![
+        ("state", Value::String(entry.state.as_str().to_string())),
+        ("source", Value::path(&entry.source)),
+        ("target", Value::path(&entry.target)),
+        ("layer", Value::path(&entry.layer)),
+        (
+            "tags",
+            Value::List(entry.tags.iter().cloned().map(Value::String).collect()),
+        ),
+        ("strategy", Value::String(entry.strategy.to_string())),
+        ("mode", entry.mode.map(|mode| format!("{mode:o}")).into()),
+        ("private", Value::Bool(entry.private)),
+        ("modified", entry.modified.into()),
+    ]
+}
+
+/// `text` as a double-quoted string
+fn string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// `time` as a datetime in UTC, like `2024-03-01T12:30:00+00:00`
+fn date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // The proleptic Gregorian calendar from the days since the epoch, after Howard Hinnant
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}+00:00",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
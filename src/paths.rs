@@ -0,0 +1,259 @@
+//! Paths that are not plain text: names that are not UTF-8 or hold newlines and other control
+//! characters.
+//!
+//! Messages show paths through [`display`], which writes control characters as `\n`, `\t` or
+//! `\xNN` and the bytes that are not UTF-8 as `\xNN`, so a path always stays on one line and
+//! keeps every byte visible. Porcelain output goes through [`escape`], which also escapes
+//! backslashes so the original can be recovered, and `-z` output through [`bytes`], which is
+//! the path unchanged.
+//!
+//! The state files store paths with [`serde`]: a string if the path is UTF-8, its raw bytes
+//! (UTF-16 code units on Windows) as an array of numbers if not.
+
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// `path` for messages, on one line and without losing bytes that are not UTF-8
+pub fn display(path: &Path) -> Display<'_> {
+    Display(path)
+}
+
+/// The result of [`display`]
+pub struct Display<'a>(&'a Path);
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode(self.0, false))
+    }
+}
+
+/// `path` for porcelain output, backslashes are escaped as well so it can be decoded
+pub fn escape(path: &Path) -> String {
+    encode(path, true).into_owned()
+}
+
+/// The bytes of `path` as they are, for NUL-terminated output
+pub fn bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    {
+        match path.to_string_lossy() {
+            Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+            Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+        }
+    }
+}
+
+/// The path made of `bytes`, the reverse of [`bytes`]
+pub fn from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+fn encode(path: &Path, backslashes: bool) -> Cow<'_, str> {
+    let plain = |c: char| !c.is_control() && (c != '\\' || !backslashes);
+    if let Some(text) = path.to_str().filter(|text| text.chars().all(plain)) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut encoded = String::new();
+    for chunk in bytes(path).utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' if backslashes => encoded.push_str("\\\\"),
+                '\n' => encoded.push_str("\\n"),
+                '\t' => encoded.push_str("\\t"),
+                '\r' => encoded.push_str("\\r"),
+                c if c.is_control() => {
+                    let mut buffer = [0; 4];
+                    for byte in c.encode_utf8(&mut buffer).bytes() {
+                        encoded.push_str(&format!("\\x{byte:02X}"));
+                    }
+                }
+                c => encoded.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            encoded.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+    Cow::Owned(encoded)
+}
+
+/// Serializes paths losslessly, for `#[serde(with = "crate::paths::serde")]`
+pub mod serde {
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// A path as it is stored
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Stored<'a> {
+        Text(std::borrow::Cow<'a, str>),
+        #[cfg(unix)]
+        Raw(Vec<u8>),
+        #[cfg(windows)]
+        Raw(Vec<u16>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(text) => Stored::Text(text.into()),
+            #[cfg(unix)]
+            None => {
+                use std::os::unix::ffi::OsStrExt;
+                Stored::Raw(path.as_os_str().as_bytes().to_vec())
+            }
+            #[cfg(windows)]
+            None => {
+                use std::os::windows::ffi::OsStrExt;
+                Stored::Raw(path.as_os_str().encode_wide().collect())
+            }
+            #[cfg(not(any(unix, windows)))]
+            None => Stored::Text(path.to_string_lossy()),
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Text(text) => PathBuf::from(text.into_owned()),
+            #[cfg(unix)]
+            Stored::Raw(bytes) => {
+                use std::os::unix::ffi::OsStringExt;
+                PathBuf::from(std::ffi::OsString::from_vec(bytes))
+            }
+            #[cfg(windows)]
+            Stored::Raw(units) => {
+                use std::os::windows::ffi::OsStringExt;
+                PathBuf::from(std::ffi::OsString::from_wide(&units))
+            }
+        })
+    }
+
+    /// The same for optional paths
+    pub mod option {
+        use std::path::PathBuf;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapped(#[serde(with = "super")] PathBuf);
+
+            Ok(Option::<Wrapped>::deserialize(deserializer)?.map(|Wrapped(path)| path))
+        }
+    }
+
+    /// The same for sets of paths, stored as a list
+    pub mod set {
+        use std::{collections::BTreeSet, path::PathBuf};
+
+        use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            paths: &BTreeSet<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            #[derive(serde::Serialize)]
+            struct Wrapped<'a>(#[serde(with = "super")] &'a PathBuf);
+
+            let mut seq = serializer.serialize_seq(Some(paths.len()))?;
+            for path in paths {
+                seq.serialize_element(&Wrapped(path))?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<BTreeSet<PathBuf>, D::Error> {
+            #[derive(Deserialize)]
+            struct Wrapped(#[serde(with = "super")] PathBuf);
+
+            Ok(Vec::<Wrapped>::deserialize(deserializer)?
+                .into_iter()
+                .map(|Wrapped(path)| path)
+                .collect())
+        }
+    }
+
+    /// The same for maps keyed by path, stored as a list of `{path, value}` records since
+    /// the keys of a JSON object can only be text. Objects written before are still read.
+    pub mod map {
+        use std::{collections::BTreeMap, path::PathBuf};
+
+        use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+
+        #[derive(Serialize)]
+        struct Record<'a, V> {
+            #[serde(with = "super")]
+            path: &'a PathBuf,
+            value: &'a V,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored<V> {
+            Records(Vec<OwnedRecord<V>>),
+            Object(BTreeMap<PathBuf, V>),
+        }
+
+        #[derive(Deserialize)]
+        struct OwnedRecord<V> {
+            #[serde(with = "super")]
+            path: PathBuf,
+            value: V,
+        }
+
+        pub fn serialize<S: Serializer, V: Serialize>(
+            map: &BTreeMap<PathBuf, V>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(map.len()))?;
+            for (path, value) in map {
+                seq.serialize_element(&Record { path, value })?;
+            }
+            seq.end()
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+            deserializer: D,
+        ) -> Result<BTreeMap<PathBuf, V>, D::Error> {
+            Ok(match Stored::deserialize(deserializer)? {
+                Stored::Records(records) => records
+                    .into_iter()
+                    .map(|record| (record.path, record.value))
+                    .collect(),
+                Stored::Object(map) => map,
+            })
+        }
+    }
+}
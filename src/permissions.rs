@@ -0,0 +1,111 @@
+//! The permission bits of the directories dofi creates for targets, which otherwise come from
+//! the umask.
+//!
+//! The `directories` section of the user configuration maps glob patterns on base-relative
+//! directories to the mode a directory dofi creates there gets. The longest matching pattern
+//! wins, `"**"` sets the mode of all others:
+//!
+//! ```toml
+//! [directories.modes]
+//! ".ssh" = 0o700
+//! ".gnupg" = 0o700
+//! "**" = 0o755
+//! ```
+//!
+//! `doctor` reports the directories dofi created that no longer have their mode, and
+//! `doctor --fix` applies it again. Directories outside the base directory and those that
+//! existed before are left alone. Windows has no permission bits to set.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use globset::GlobMatcher;
+use serde::Deserialize;
+
+use crate::{fs::FileType, journal, manifest, DofiError, Fs};
+
+/// The modes new journals apply, set once by the binary
+static DEFAULT: Mutex<Option<DirectoryModes>> = Mutex::new(None);
+
+/// The `directories` section of the user configuration
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DirectoriesConfig {
+    /// Glob patterns on base-relative directories mapped to their permission bits
+    #[serde(default)]
+    pub modes: BTreeMap<String, u32>,
+}
+
+/// The compiled `directories.modes` of the configuration
+#[derive(Debug, Default, Clone)]
+pub struct DirectoryModes {
+    base_directory: PathBuf,
+    matchers: Vec<(GlobMatcher, u32)>,
+}
+
+impl DirectoryModes {
+    /// The modes of `config` for directories below `base_directory`
+    pub fn new(config: &DirectoriesConfig, base_directory: &Path) -> Result<Self, DofiError> {
+        for (pattern, mode) in &config.modes {
+            if *mode > 0o7777 {
+                return Err(DofiError::InvalidMode(pattern.clone(), *mode));
+            }
+        }
+        Ok(Self {
+            base_directory: base_directory.to_path_buf(),
+            matchers: manifest::target_matchers(&config.modes)?,
+        })
+    }
+
+    /// The mode a directory created at `directory` gets, if the configuration sets one
+    pub fn mode(&self, directory: &Path) -> Option<u32> {
+        if cfg!(windows) {
+            return None;
+        }
+        let relative = directory.strip_prefix(&self.base_directory).ok()?;
+        self.matchers
+            .iter()
+            .find(|(matcher, _)| matcher.is_match(relative))
+            .map(|(_, mode)| *mode)
+    }
+}
+
+/// Makes `modes` apply to the directories created through every [`Journal`](crate::Journal)
+/// made from now on
+pub fn init(modes: DirectoryModes) {
+    *DEFAULT.lock().unwrap_or_else(|e| e.into_inner()) = Some(modes);
+}
+
+/// The modes set with [`init`], none if it was not called
+pub(crate) fn default_modes() -> DirectoryModes {
+    DEFAULT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// The directories created according to the journal in `state_directory` that still exist
+/// with other permission bits than `modes` require, with the required and the actual ones
+pub fn violations(
+    fs: &dyn Fs,
+    modes: &DirectoryModes,
+    state_directory: &Path,
+) -> Result<Vec<(PathBuf, u32, u32)>, DofiError> {
+    let mut violations = Vec::new();
+    for directory in journal::created_directories(fs, state_directory)? {
+        let Some(expected) = modes.mode(&directory) else {
+            continue;
+        };
+        let Ok(metadata) = fs.symlink_metadata(&directory) else {
+            continue;
+        };
+        if metadata.file_type == FileType::Directory && metadata.mode != expected {
+            violations.push((directory, expected, metadata.mode));
+        }
+    }
+    Ok(violations)
+}
@@ -0,0 +1,200 @@
+//! Picking files interactively, with a line based fuzzy finder.
+//!
+//! `add --interactive` picks from the regular files below the base directory, up to a depth
+//! limit and leaving out those matched by `.gitignore` or `.ignore` files and the dotfiles
+//! directories themselves. `remove` and `edit` without a file pick from the targets of the
+//! dotfiles, `edit` a single one. Typing text filters the candidates down to those containing
+//! its characters in order, best matches first, typing the numbers of shown candidates (`1 4`,
+//! `2-5`) selects or deselects them and an empty line finishes.
+
+use std::{
+    collections::BTreeSet,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use ignore::WalkBuilder;
+
+use crate::DofiError;
+
+/// How many of the best matching candidates are shown
+const SHOWN: usize = 20;
+
+/// The files below `base_directory`, at most `max_depth` directories deep, that could be added
+pub fn candidates(
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    max_depth: usize,
+) -> Vec<PathBuf> {
+    let mut candidates = WalkBuilder::new(base_directory)
+        .hidden(false)
+        .require_git(false)
+        .max_depth(Some(max_depth))
+        .filter_entry({
+            let dotfiles_directories = dotfiles_directories.to_vec();
+            move |entry| {
+                entry.file_name() != ".git"
+                    && !dotfiles_directories
+                        .iter()
+                        .any(|directory| entry.path() == directory)
+            }
+        })
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+        })
+        .map(|entry| entry.into_path())
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates
+}
+
+/// How well `candidate` matches `query`, higher is better, `None` if it does not contain the
+/// characters of `query` in order. Matching ignores case and favours consecutive characters
+/// and characters starting a path component or word.
+pub fn score(query: &str, candidate: &str) -> Option<i64> {
+    let candidate = candidate.chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous = None;
+    for wanted in query.chars().filter(|c| !c.is_whitespace()) {
+        let found = (position..candidate.len())
+            .find(|&index| candidate[index].to_lowercase().eq(wanted.to_lowercase()))?;
+        score += 1;
+        if previous.is_some_and(|previous| previous + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || matches!(candidate[found - 1], '/' | '.' | '_' | '-') {
+            score += 3;
+        }
+        previous = Some(found);
+        position = found + 1;
+    }
+    // Shorter candidates are closer matches
+    Some(score * 100 - candidate.len() as i64)
+}
+
+/// Lets the user pick from `candidates`, shown relative to `base_directory`, reading their
+/// input from `input` and prompting on `output`. Returns the selected candidates.
+pub fn pick(
+    candidates: &[PathBuf],
+    base_directory: &Path,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<Vec<PathBuf>, DofiError> {
+    select(candidates, base_directory, input, output, true)
+}
+
+/// Like [`pick`], but typing the number of a shown candidate picks it and finishes
+pub fn pick_one(
+    candidates: &[PathBuf],
+    base_directory: &Path,
+    input: impl BufRead,
+    output: impl Write,
+) -> Result<Option<PathBuf>, DofiError> {
+    let picked = select(candidates, base_directory, input, output, false)?;
+    Ok(picked.into_iter().next())
+}
+
+fn select(
+    candidates: &[PathBuf],
+    base_directory: &Path,
+    mut input: impl BufRead,
+    mut output: impl Write,
+    multiple: bool,
+) -> Result<Vec<PathBuf>, DofiError> {
+    let labels = candidates
+        .iter()
+        .map(|candidate| {
+            candidate
+                .strip_prefix(base_directory)
+                .unwrap_or(candidate)
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<_>>();
+    let mut selected = BTreeSet::new();
+    let mut shown = filter(&labels, "");
+
+    loop {
+        for (number, &index) in shown.iter().enumerate() {
+            let mark = if selected.contains(&index) { '*' } else { ' ' };
+            writeln!(output, "{mark}{:>3}  {}", number + 1, labels[index])?;
+        }
+        if multiple {
+            write!(
+                output,
+                "{} selected, filter or toggle numbers, empty to finish> ",
+                selected.len()
+            )?;
+        } else {
+            write!(output, "filter or pick a number, empty to cancel> ")?;
+        }
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+
+        match numbers(line) {
+            Some(numbers) if !multiple => {
+                if let [number] = numbers[..] {
+                    if let Some(&index) = number.checked_sub(1).and_then(|n| shown.get(n)) {
+                        selected.insert(index);
+                        break;
+                    }
+                }
+            }
+            Some(numbers) => {
+                for number in numbers {
+                    if let Some(&index) = number.checked_sub(1).and_then(|n| shown.get(n)) {
+                        if !selected.remove(&index) {
+                            selected.insert(index);
+                        }
+                    }
+                }
+            }
+            None => shown = filter(&labels, line),
+        }
+    }
+
+    Ok(selected
+        .into_iter()
+        .map(|index| candidates[index].clone())
+        .collect())
+}
+
+/// The indices of the labels best matching `query`, at most [`SHOWN`]
+fn filter(labels: &[String], query: &str) -> Vec<usize> {
+    let mut matches = labels
+        .iter()
+        .enumerate()
+        .filter_map(|(index, label)| score(query, label).map(|score| (score, index)))
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a, a_index), (b, b_index)| b.cmp(a).then(a_index.cmp(b_index)));
+    matches.truncate(SHOWN);
+    matches.into_iter().map(|(_, index)| index).collect()
+}
+
+/// Parses a list of numbers and ranges like `1 3-5`, `None` if `line` is anything else
+fn numbers(line: &str) -> Option<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for word in line.split([' ', ',']).filter(|word| !word.is_empty()) {
+        match word.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+                numbers.extend(start..=end);
+            }
+            None => numbers.push(word.parse().ok()?),
+        }
+    }
+    Some(numbers)
+}
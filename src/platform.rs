@@ -0,0 +1,410 @@
+//! Platform specific filesystem and process primitives.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+
+#[cfg(unix)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+/// Creates a file or directory symlink depending on what `original` is.
+///
+/// Creating symlinks requires either administrator rights or developer mode on Windows, so
+/// directories fall back to a junction when the privilege is missing.
+#[cfg(windows)]
+pub fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+
+    // ERROR_PRIVILEGE_NOT_HELD
+    const PRIVILEGE_NOT_HELD: i32 = 1314;
+
+    if !original.is_dir() {
+        return symlink_file(original, link);
+    }
+
+    match symlink_dir(original, link) {
+        Err(e) if e.raw_os_error() == Some(PRIVILEGE_NOT_HELD) => junction(original, link),
+        result => result,
+    }
+}
+
+#[cfg(windows)]
+fn junction(original: &Path, link: &Path) -> io::Result<()> {
+    let status = std::process::Command::new("cmd")
+        .arg("/C")
+        .arg("mklink")
+        .arg("/J")
+        .arg(link)
+        .arg(original)
+        .stdout(std::process::Stdio::null())
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "mklink /J failed for '{}'",
+            link.display()
+        )))
+    }
+}
+
+#[cfg(unix)]
+pub fn remove_symlink(path: &Path) -> io::Result<()> {
+    std::fs::remove_file(path)
+}
+
+/// Removes a symlink or junction, directory links have to be removed as directories on Windows
+#[cfg(windows)]
+pub fn remove_symlink(path: &Path) -> io::Result<()> {
+    std::fs::remove_file(path).or_else(|_| std::fs::remove_dir(path))
+}
+
+#[cfg(unix)]
+pub fn mode(metadata: &std::fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+/// Windows has no permission bits, approximate them from the read-only flag
+#[cfg(windows)]
+pub fn mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o666
+    }
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+/// Only the write bits can be represented on Windows, as the read-only flag
+#[cfg(windows)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// Whether `a` and `b` are the same file, e.g. hard links to each other. Symlinks are not
+/// followed.
+#[cfg(unix)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (a.symlink_metadata(), b.symlink_metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Whether `a` and `b` are the same file. Telling hard links apart needs the file index, which
+/// is not stable on Windows yet, so regular files with the same contents count as the same.
+#[cfg(windows)]
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    let is_file = |path: &Path| path.symlink_metadata().is_ok_and(|m| m.is_file());
+    is_file(a)
+        && is_file(b)
+        && matches!((std::fs::read(a), std::fs::read(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Copies the extended attributes of `from` to `to`, like macOS quarantine flags or Linux file
+/// capabilities. Returns the names of those that could not be set, e.g. for lack of privilege.
+/// On FreeBSD and NetBSD these are the attributes of the user namespace.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd"
+))]
+pub fn copy_xattrs(from: &Path, to: &Path) -> io::Result<Vec<String>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (from, to) = (c_path(from)?, c_path(to)?);
+
+    // Sizes first, then the contents, retrying if they grew in between
+    let read = |fill: &dyn Fn(*mut libc::c_void, usize) -> isize| -> io::Result<Vec<u8>> {
+        loop {
+            let size = fill(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buffer = vec![0u8; size as usize];
+            let read = fill(buffer.as_mut_ptr().cast(), buffer.len());
+            if read >= 0 {
+                buffer.truncate(read as usize);
+                return Ok(buffer);
+            }
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ERANGE) {
+                return Err(error);
+            }
+        }
+    };
+
+    let names = match read(&|buffer, size| {
+        // SAFETY: `from` is NUL-terminated and `buffer` is valid for `size` bytes
+        unsafe { xattr::list(from.as_ptr(), buffer.cast(), size) }
+    }) {
+        Ok(names) => names,
+        // The filesystem has no extended attributes at all
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut failed = Vec::new();
+    for name in xattr::names(&names) {
+        let Ok(name) = CString::new(name) else {
+            continue;
+        };
+        let value = read(&|buffer, size| {
+            // SAFETY: both strings are NUL-terminated and `buffer` is valid for `size` bytes
+            unsafe { xattr::get(from.as_ptr(), name.as_ptr(), buffer, size) }
+        });
+        let copied = value.is_ok_and(|value| {
+            // SAFETY: both strings are NUL-terminated and `value` is valid for its length
+            unsafe {
+                xattr::set(
+                    to.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                )
+            }
+        });
+        if !copied {
+            failed.push(name.to_string_lossy().into_owned());
+        }
+    }
+    Ok(failed)
+}
+
+/// There are no extended attributes to copy on OpenBSD, which has none, or on Windows
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "netbsd"
+)))]
+pub fn copy_xattrs(_from: &Path, _to: &Path) -> io::Result<Vec<String>> {
+    Ok(Vec::new())
+}
+
+/// The NUL-terminated names of a list of extended attributes
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn nul_terminated_names(list: &[u8]) -> Vec<&[u8]> {
+    list.split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The extended attribute calls, which take extra position and option arguments on macOS and
+/// a namespace on the BSDs
+#[cfg(target_os = "linux")]
+mod xattr {
+    use libc::{c_char, c_void, size_t, ssize_t};
+
+    pub(super) use super::nul_terminated_names as names;
+
+    pub unsafe fn list(path: *const c_char, names: *mut c_char, size: size_t) -> ssize_t {
+        libc::listxattr(path, names, size)
+    }
+
+    pub unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        libc::getxattr(path, name, value, size)
+    }
+
+    pub unsafe fn set(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+    ) -> bool {
+        libc::setxattr(path, name, value, size, 0) == 0
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod xattr {
+    use libc::{c_char, c_void, size_t, ssize_t};
+
+    pub(super) use super::nul_terminated_names as names;
+
+    pub unsafe fn list(path: *const c_char, names: *mut c_char, size: size_t) -> ssize_t {
+        libc::listxattr(path, names, size, 0)
+    }
+
+    pub unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        libc::getxattr(path, name, value, size, 0, 0)
+    }
+
+    pub unsafe fn set(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+    ) -> bool {
+        libc::setxattr(path, name, value, size, 0, 0) == 0
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "netbsd"))]
+mod xattr {
+    use libc::{c_char, c_void, size_t, ssize_t, EXTATTR_NAMESPACE_USER};
+
+    /// The names of the list, each preceded by its length instead of NUL-terminated
+    pub fn names(list: &[u8]) -> Vec<&[u8]> {
+        let mut names = Vec::new();
+        let mut rest = list;
+        while let Some((&length, tail)) = rest.split_first() {
+            let Some((name, tail)) = tail.split_at_checked(usize::from(length)) else {
+                break;
+            };
+            names.push(name);
+            rest = tail;
+        }
+        names
+    }
+
+    pub unsafe fn list(path: *const c_char, names: *mut c_char, size: size_t) -> ssize_t {
+        libc::extattr_list_file(path, EXTATTR_NAMESPACE_USER, names.cast(), size)
+    }
+
+    pub unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        libc::extattr_get_file(path, EXTATTR_NAMESPACE_USER, name, value, size)
+    }
+
+    pub unsafe fn set(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+    ) -> bool {
+        libc::extattr_set_file(path, EXTATTR_NAMESPACE_USER, name, value, size) >= 0
+    }
+}
+
+/// The user's home directory, `%USERPROFILE%` on Windows and `$HOME` elsewhere
+pub fn home_directory() -> Option<PathBuf> {
+    let variable = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(variable)
+        .filter(|home| !home.is_empty())
+        .map(Into::into)
+}
+
+/// Finds the executable called `name` on the `PATH`
+pub fn find_executable(name: &OsStr) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .flat_map(|directory| executable_candidates(&directory.join(name)))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// The executables on the `PATH` whose names start with `prefix`, by their names without it,
+/// the first one found for each name
+pub fn find_executables(prefix: &str) -> BTreeMap<String, PathBuf> {
+    let mut executables = BTreeMap::new();
+    let Some(path) = std::env::var_os("PATH") else {
+        return executables;
+    };
+    for directory in std::env::split_paths(&path) {
+        let Ok(entries) = std::fs::read_dir(&directory) else {
+            continue;
+        };
+        for path in entries.flatten().map(|entry| entry.path()) {
+            let name = executable_name(&path).and_then(|name| name.strip_prefix(prefix));
+            if let Some(name) = name.filter(|name| !name.is_empty()) {
+                if is_executable(&path) {
+                    executables.entry(name.to_string()).or_insert(path);
+                }
+            }
+        }
+    }
+    executables
+}
+
+#[cfg(unix)]
+fn executable_candidates(path: &Path) -> Vec<PathBuf> {
+    vec![path.to_path_buf()]
+}
+
+#[cfg(unix)]
+fn executable_name(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()
+}
+
+/// Executables on Windows carry one of the extensions in `PATHEXT`
+#[cfg(windows)]
+fn executable_candidates(path: &Path) -> Vec<PathBuf> {
+    executable_extensions()
+        .split(';')
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| {
+            let mut candidate = path.as_os_str().to_os_string();
+            candidate.push(extension);
+            PathBuf::from(candidate)
+        })
+        .collect()
+}
+
+/// The name of the executable `path` is without its extension
+#[cfg(windows)]
+fn executable_name(path: &Path) -> Option<&str> {
+    let extension = path.extension()?.to_str()?;
+    let executable = executable_extensions().split(';').any(|known| {
+        known
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(extension)
+    });
+    if executable {
+        path.file_stem()?.to_str()
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+fn executable_extensions() -> String {
+    std::env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,132 @@
+//! External subcommands.
+//!
+//! Like with git and cargo, `dofi <name>` runs an executable called `dofi-<name>` from the
+//! `PATH` when `<name>` is none of dofi's own subcommands or [aliases](crate::config),
+//! passing the remaining arguments on and exiting like it does. The plugin gets the
+//! directories dofi resolved as environment variables:
+//!
+//! - `DOFI_BASE`: the base directory
+//! - `DOFI_DOTFILES` and `DOFI_DIR`: the dotfiles directory, so plugins running dofi again act
+//!   on the same dotfiles
+//! - `DOFI_LAYERS`: all layered dotfiles directories, separated like `PATH`
+//! - `DOFI_STATE`: the state directory holding the journal
+//!
+//! `dofi env` prints the same variables, for scripts that are not plugins.
+//!
+//! `dofi completions` completes the plugins on the `PATH` like dofi's own subcommands. It runs
+//! each with `--dofi-complete`, which a plugin answers by printing what it does on the first
+//! line, followed by a line for each of its subcommands and options, the word to complete and
+//! what it does separated by a tab. An option ending in `=` takes a value:
+//!
+//! ```sh
+//! if [ "$1" = --dofi-complete ]; then
+//!     printf 'Backs the dotfiles up to a remote\n'
+//!     printf 'restore\tRestores the last backup\n'
+//!     printf -- '--remote=\tThe remote to back up to\n'
+//!     printf -- '--dry-run\tOnly print what would be backed up\n'
+//!     exit
+//! fi
+//! ```
+//!
+//! Plugins that fail the handshake only get their name completed. The script knows the plugins
+//! installed when it was generated, `dofi completions install` again picks up new ones.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
+
+use crate::{platform, DofiError};
+
+/// What the executables of plugins are called before the subcommand name
+pub const PREFIX: &str = "dofi-";
+/// The argument asking a plugin for its completions
+pub const COMPLETE_FLAG: &str = "--dofi-complete";
+
+/// What a plugin completes, from its answer to [`COMPLETE_FLAG`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Completions {
+    /// What the plugin does
+    pub about: String,
+    /// The subcommands and options of the plugin, each with what it does
+    pub words: Vec<(String, String)>,
+}
+
+impl Completions {
+    /// Parses the answer of a plugin to [`COMPLETE_FLAG`], see the [module documentation](self)
+    pub fn parse(output: &str) -> Self {
+        let mut lines = output.lines();
+        let about = lines.next().unwrap_or_default().trim().to_string();
+        let words = lines
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| match line.split_once('\t') {
+                Some((word, help)) => (word.trim().to_string(), help.trim().to_string()),
+                None => (line.trim().to_string(), String::new()),
+            })
+            .collect();
+        Completions { about, words }
+    }
+}
+
+/// The directories a plugin runs against
+pub struct Context<'a> {
+    pub base_directory: &'a Path,
+    pub dotfiles_directories: &'a [PathBuf],
+    pub state_directory: &'a Path,
+}
+
+/// Finds the executable of the plugin providing the subcommand `name` on the `PATH`
+pub fn find(name: &OsStr) -> Option<PathBuf> {
+    let mut file_name = OsString::from(PREFIX);
+    file_name.push(name);
+    platform::find_executable(&file_name)
+}
+
+/// The plugins on the `PATH` by their subcommand names, with what they complete
+pub fn installed() -> Vec<(String, Completions)> {
+    platform::find_executables(PREFIX)
+        .into_iter()
+        .map(|(name, executable)| {
+            let completions = Command::new(&executable)
+                .arg(COMPLETE_FLAG)
+                .stdin(Stdio::null())
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| Completions::parse(&String::from_utf8_lossy(&output.stdout)))
+                .unwrap_or_default();
+            (name, completions)
+        })
+        .collect()
+}
+
+/// Runs the plugin `executable` with `args` in `context`, sharing dofi's stdio, and returns
+/// how it exited
+pub fn run(
+    executable: &Path,
+    args: &[OsString],
+    context: &Context,
+) -> Result<ExitStatus, DofiError> {
+    let dotfiles_directory = context
+        .dotfiles_directories
+        .first()
+        .map(PathBuf::as_path)
+        .unwrap_or(Path::new(""));
+    let layers = std::env::join_paths(context.dotfiles_directories).map_err(|e| {
+        DofiError::ExternalCommandFailed(executable.display().to_string(), e.to_string())
+    })?;
+
+    Command::new(executable)
+        .args(args)
+        .env("DOFI_BASE", context.base_directory)
+        .env("DOFI_DOTFILES", dotfiles_directory)
+        .env("DOFI_DIR", dotfiles_directory)
+        .env("DOFI_LAYERS", layers)
+        .env("DOFI_STATE", context.state_directory)
+        .status()
+        .map_err(|e| {
+            DofiError::ExternalCommandFailed(executable.display().to_string(), e.to_string())
+        })
+}
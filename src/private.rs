@@ -0,0 +1,32 @@
+//! The private subtree of a dotfiles directory.
+//!
+//! Dotfiles below `private/` target their path without the prefix, `<dotfiles>/private/.ssh/config`
+//! targets `<base>/.ssh/config`, but are never symlinked: linking copies them to their targets,
+//! readable only by the user, so they stay private even when the repo itself is readable by
+//! others. `list` leaves them out unless asked for with `--private`. Private dotfiles can also
+//! be [encrypted](crate::encryption) or [templates](crate::template), e.g.
+//! `private/.netrc.age`.
+
+use std::path::{Path, PathBuf};
+
+/// The repo-relative directory holding the private dotfiles
+pub const PRIVATE_DIRECTORY: &str = "private";
+/// The permission bits of private targets
+pub const PRIVATE_MODE: u32 = 0o600;
+
+/// Whether the repo-relative `path` lies in the private subtree
+pub fn is_private_path(path: &Path) -> bool {
+    path.starts_with(PRIVATE_DIRECTORY)
+}
+
+/// Whether the dotfile `source` lies in the private subtree of one of `dotfiles_directories`
+pub fn is_private(source: &Path, dotfiles_directories: &[PathBuf]) -> bool {
+    dotfiles_directories
+        .iter()
+        .any(|directory| source.strip_prefix(directory).is_ok_and(is_private_path))
+}
+
+/// The repo-relative `path` without the private prefix, if it has one
+pub fn strip_prefix(path: &Path) -> &Path {
+    path.strip_prefix(PRIVATE_DIRECTORY).unwrap_or(path)
+}
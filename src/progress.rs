@@ -0,0 +1,103 @@
+//! A progress bar on stderr for operations touching many dotfiles.
+//!
+//! The bar is only drawn once [`enable`] was called, which the binary does when stderr is a
+//! terminal and logging is not verbose enough for log lines to run into it. It shows the files
+//! processed and an estimate of the time left, and is cleared again when the operation finishes.
+
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long an operation runs before the bar appears, and how often it is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const WIDTH: usize = 30;
+
+static PROGRESS: Mutex<Option<Option<Bar>>> = Mutex::new(None);
+
+#[derive(Debug)]
+struct Bar {
+    label: &'static str,
+    total: usize,
+    done: usize,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Bar {
+    fn draw(&mut self) {
+        let now = Instant::now();
+        let since = self.drawn.unwrap_or(self.started);
+        if now.duration_since(since) < REDRAW_INTERVAL {
+            return;
+        }
+        self.drawn = Some(now);
+
+        let filled = (self.done * WIDTH).checked_div(self.total).unwrap_or(WIDTH);
+        let eta = match self.done {
+            0 => "?".to_string(),
+            done => {
+                let elapsed = now.duration_since(self.started).as_secs_f64();
+                let left = elapsed / done as f64 * self.total.saturating_sub(done) as f64;
+                format!("{}s", left.ceil() as u64)
+            }
+        };
+        let mut stderr = io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r{} [{}{}] {}/{} ETA {eta}\x1b[K",
+            self.label,
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            self.done,
+            self.total
+        )
+        .and_then(|()| stderr.flush());
+    }
+
+    fn clear(&self) {
+        if self.drawn.is_some() {
+            let mut stderr = io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K").and_then(|()| stderr.flush());
+        }
+    }
+}
+
+/// Draws the bar for all following operations
+pub fn enable() {
+    *PROGRESS.lock().unwrap_or_else(|e| e.into_inner()) = Some(None);
+}
+
+/// Starts showing `total` files being processed under `label`, if enabled
+pub fn start(label: &'static str, total: usize) {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bar) = progress.as_mut() {
+        *bar = Some(Bar {
+            label,
+            total,
+            done: 0,
+            started: Instant::now(),
+            drawn: None,
+        });
+    }
+}
+
+/// Counts one more file as processed
+pub fn advance() {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Some(bar)) = progress.as_mut() {
+        bar.done = (bar.done + 1).min(bar.total);
+        bar.draw();
+    }
+}
+
+/// Stops showing the operation and clears the bar
+pub fn finish() {
+    let mut progress = PROGRESS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bar) = progress.as_mut() {
+        if let Some(bar) = bar.take() {
+            bar.clear();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! A small query language for selecting dotfiles.
+//!
+//! A query is a whitespace separated list of terms which all have to match:
+//!
+//! - `state:<state>` matches entries in the given [`State`] (`linked`, `unlinked`, `conflict`,
+//!   `broken`)
+//! - `path:<text>` matches entries whose repo-relative path contains `text`
+//! - `tag:<tag>` matches entries carrying the tag in the [manifest](crate::manifest)
+//! - `package:<name>` matches the dotfiles of a [package](crate::package_dotfiles), once the
+//!   query is [resolved](Query::resolve) against the dotfiles directories
+//! - `changed:<7d` / `changed:>2h` matches sources modified within / before the given age,
+//!   using the units `s`, `m`, `h`, `d` and `w`
+//! - a bare word is shorthand for `path:<word>`
+//!
+//! Prefixing a term with `!` negates it.
+
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    package_dotfiles,
+    status::{Entry, State},
+    DofiError, Fs,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Filter {
+    State(State),
+    Path(String),
+    Tag(String),
+    /// A package by name and the dotfiles it holds, filled in by [`Query::resolve`]
+    Package(String, BTreeSet<PathBuf>),
+    ChangedWithin(Duration),
+    ChangedBefore(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    negated: bool,
+    filter: Filter,
+}
+
+/// A parsed query, see the [module documentation](self) for the syntax
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self, DofiError> {
+        let terms = query
+            .split_whitespace()
+            .map(parse_term)
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { terms })
+    }
+
+    /// Looks up the dotfiles of the packages the query names in the layered
+    /// `dotfiles_directories`, failing for a package none of them has
+    pub fn resolve(
+        mut self,
+        fs: &dyn Fs,
+        dotfiles_directories: &[PathBuf],
+    ) -> Result<Self, DofiError> {
+        for term in &mut self.terms {
+            let Filter::Package(name, dotfiles) = &mut term.filter else {
+                continue;
+            };
+            let mut error = None;
+            for layer in dotfiles_directories {
+                match package_dotfiles(fs, layer, name) {
+                    Ok(found) => dotfiles.extend(found),
+                    Err(e) => error = Some(e),
+                }
+            }
+            if let Some(e) = error.filter(|_| dotfiles.is_empty()) {
+                return Err(e);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Whether `entry` matches every term of the query, `now` is the reference for `changed:`
+    pub fn matches(&self, entry: &Entry, now: SystemTime) -> bool {
+        self.terms.iter().all(|term| {
+            let matched = match &term.filter {
+                Filter::State(state) => entry.state == *state,
+                Filter::Path(text) => entry
+                    .relative_source()
+                    .to_string_lossy()
+                    .contains(text.as_str()),
+                Filter::Tag(tag) => entry.tags.contains(tag),
+                Filter::Package(_, dotfiles) => dotfiles.contains(&entry.source),
+                Filter::ChangedWithin(age) => age_of(entry, now).is_some_and(|a| a <= *age),
+                Filter::ChangedBefore(age) => age_of(entry, now).is_some_and(|a| a > *age),
+            };
+            matched != term.negated
+        })
+    }
+}
+
+fn age_of(entry: &Entry, now: SystemTime) -> Option<Duration> {
+    entry
+        .modified
+        .map(|modified| now.duration_since(modified).unwrap_or_default())
+}
+
+fn parse_term(term: &str) -> Result<Term, DofiError> {
+    let invalid = |reason: &str| DofiError::InvalidQuery(term.to_string(), reason.to_string());
+
+    let (negated, term_body) = match term.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, term),
+    };
+
+    let filter = match term_body.split_once(':') {
+        None => Filter::Path(term_body.to_string()),
+        Some(("path", text)) => Filter::Path(text.to_string()),
+        Some(("tag", tag)) => Filter::Tag(tag.to_string()),
+        Some(("package", name)) => Filter::Package(name.to_string(), BTreeSet::new()),
+        Some(("state", state)) => Filter::State(state.parse().map_err(|_| {
+            invalid("expected one of 'linked', 'unlinked', 'conflict' or 'broken'")
+        })?),
+        Some(("changed", age)) => {
+            let (within, duration) = match (age.strip_prefix('<'), age.strip_prefix('>')) {
+                (Some(duration), _) => (true, duration),
+                (_, Some(duration)) => (false, duration),
+                _ => return Err(invalid("expected '<' or '>' followed by a duration")),
+            };
+            let duration =
+                parse_duration(duration).ok_or_else(|| invalid("expected a duration like '7d'"))?;
+            if within {
+                Filter::ChangedWithin(duration)
+            } else {
+                Filter::ChangedBefore(duration)
+            }
+        }
+        Some((key, _)) => {
+            return Err(invalid(&format!(
+                "unknown key '{key}', expected 'state', 'path', 'tag', 'package' or 'changed'"
+            )))
+        }
+    };
+
+    Ok(Term { negated, filter })
+}
+
+pub(crate) fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = duration.split_at(split);
+    let amount = amount.parse::<u64>().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    Some(Duration::from_secs(amount * seconds))
+}
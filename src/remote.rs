@@ -0,0 +1,140 @@
+//! Applying the dotfiles to another machine over SSH.
+//!
+//! `dofi push-remote user@host` needs nothing on the other machine but an SSH server and
+//! `tar`. If `dofi` is on the remote `PATH`, the dotfiles directories are copied without their
+//! `.git` to [`REMOTE_DIRECTORY`] in the remote home directory, replacing what an earlier push
+//! left there, and the remote dofi applies them, so templates are rendered for that machine and
+//! its targets are symlinks it can unlink again. Otherwise, or with `--copy`, the
+//! [exported](crate::export) tree is rendered here and unpacked into the remote home directory
+//! as plain files, overwriting what is there.
+//!
+//! Everything goes through the system `ssh`, so hosts, users and keys come from its
+//! configuration.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use log::info;
+
+use crate::{export, fs::ScratchDirectory, DofiError};
+
+/// Where the dotfiles directories are copied to, relative to the remote home directory
+pub const REMOTE_DIRECTORY: &str = ".local/share/dofi/remote";
+
+/// The exit status `ssh` itself fails with, as opposed to the remote command
+const SSH_FAILURE: i32 = 255;
+
+/// Whether `dofi` is on the `PATH` of `destination`
+pub fn has_dofi(destination: &str) -> Result<bool, DofiError> {
+    let command_line = format!("ssh {destination} command -v dofi");
+    let status = Command::new("ssh")
+        .arg(destination)
+        .arg("command -v dofi")
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(SSH_FAILURE) | None => Err(DofiError::ExternalCommandFailed(
+            command_line,
+            format!("exited with {status}"),
+        )),
+        Some(_) => Ok(false),
+    }
+}
+
+/// The remote command applying `layers` dotfiles directories copied by [`push_dotfiles`] to
+/// the remote home directory
+pub fn apply_command(layers: usize) -> String {
+    let mut command = format!("dofi -b \"$HOME\" -d \"$HOME/{REMOTE_DIRECTORY}/0\"");
+    for layer in 1..layers {
+        command.push_str(&format!(" -l \"$HOME/{REMOTE_DIRECTORY}/{layer}\""));
+    }
+    command.push_str(" apply");
+    command
+}
+
+/// Copies `dotfiles_directories` without their `.git` to [`REMOTE_DIRECTORY`] on
+/// `destination`, one numbered directory per layer, replacing what was there before
+pub fn push_dotfiles(destination: &str, dotfiles_directories: &[PathBuf]) -> Result<(), DofiError> {
+    for (index, layer) in dotfiles_directories.iter().enumerate() {
+        info!("Copying '{}' to {destination}", layer.display());
+        let command_line = format!("tar -czf - -C {} .", layer.display());
+        let mut tar = Command::new("tar")
+            .args(["--exclude=.git", "-czf", "-", "-C"])
+            .arg(layer)
+            .arg(".")
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+        let archive = tar.stdout.take().expect("stdout is piped");
+
+        // The first layer clears the directories of layers dropped since the last push
+        let cleared = if index == 0 {
+            REMOTE_DIRECTORY.to_string()
+        } else {
+            format!("{REMOTE_DIRECTORY}/{index}")
+        };
+        ssh(
+            destination,
+            &format!(
+                "rm -rf {cleared} && mkdir -p {REMOTE_DIRECTORY}/{index} && tar -xzf - -C {REMOTE_DIRECTORY}/{index}"
+            ),
+            archive.into(),
+        )?;
+
+        let status = tar
+            .wait()
+            .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+        if !status.success() {
+            return Err(DofiError::ExternalCommandFailed(
+                command_line,
+                format!("exited with {status}"),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Unpacks `files` into the home directory of `destination`, packing them in
+/// `state_directory` first as they may hold decrypted secrets
+pub fn push_tree(
+    destination: &str,
+    files: &[export::File],
+    state_directory: &Path,
+) -> Result<(), DofiError> {
+    let scratch = ScratchDirectory::new(state_directory, "push")?;
+    let archive = scratch.path().join("dotfiles.tar.gz");
+    export::write_archive(files, &archive, state_directory)?;
+
+    info!("Unpacking {} files on {destination}", files.len());
+    ssh(
+        destination,
+        "tar -xzf -",
+        std::fs::File::open(&archive)?.into(),
+    )
+}
+
+/// Runs `command` on `destination` with `stdin`, sharing dofi's stdout and stderr
+pub fn ssh(destination: &str, command: &str, stdin: Stdio) -> Result<(), DofiError> {
+    let command_line = format!("ssh {destination} {command}");
+    info!("Running '{command_line}'");
+    let status = Command::new("ssh")
+        .arg(destination)
+        .arg(command)
+        .stdin(stdin)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            command_line,
+            format!("exited with {status}"),
+        ))
+    }
+}
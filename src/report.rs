@@ -0,0 +1,128 @@
+//! A summary of a run written to the file given with `--report`, so provisioning systems can
+//! archive what dofi did on each machine.
+//!
+//! Nothing is collected until [`enable`] was called. Afterwards the [events](crate::events)
+//! of the run are gathered, whether or not they are streamed anywhere, and the binary
+//! [writes](write) them out as one JSON object once the command finished:
+//!
+//! ```json
+//! {
+//!   "version": "0.1.0",
+//!   "host": "laptop",
+//!   "started": 1718000000,
+//!   "duration_ms": 48.2,
+//!   "succeeded": true,
+//!   "error": null,
+//!   "commands": ["link"],
+//!   "actions": [{"elapsed_ms": 12.5, "action": {"Symlinked": {"link": "...", "target": "..."}}}],
+//!   "skipped": [{"elapsed_ms": 3.1, "target": "...", "reason": "it is not tagged"}],
+//!   "conflicts": [{"elapsed_ms": 3.4, "target": "...", "resolution": "force"}],
+//!   "phases": [{"name": "walk", "duration_ms": 2.0, "runs": 1}],
+//!   "operations": 118
+//! }
+//! ```
+//!
+//! `elapsed_ms` is the time since the run started, `error` the failure in the form of
+//! `--error-format json`.
+
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::{condition, events::Event, timings, DofiError};
+
+static REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+struct Report {
+    started: Instant,
+    timestamp: u64,
+    commands: Vec<String>,
+    actions: Vec<Value>,
+    skipped: Vec<Value>,
+    conflicts: Vec<Value>,
+}
+
+/// Collects the events of everything that follows, and the timings along with them
+pub fn enable() {
+    timings::enable();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    *REPORT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Report {
+        started: Instant::now(),
+        timestamp,
+        commands: Vec::new(),
+        actions: Vec::new(),
+        skipped: Vec::new(),
+        conflicts: Vec::new(),
+    });
+}
+
+/// Adds `event` to the report, if enabled
+pub(crate) fn observe(event: &Event) {
+    let mut report = REPORT.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(report) = report.as_mut() else {
+        return;
+    };
+    let entries = match event {
+        Event::Started { command } => {
+            report.commands.push(command.to_string());
+            return;
+        }
+        Event::Action { .. } => &mut report.actions,
+        Event::Skipped { .. } => &mut report.skipped,
+        Event::Conflict { .. } => &mut report.conflicts,
+        Event::Planned { .. } | Event::Summary { .. } | Event::Failed { .. } => return,
+    };
+    let Ok(Value::Object(mut fields)) = serde_json::to_value(event) else {
+        return;
+    };
+    fields.remove("event");
+    fields.insert("elapsed_ms".to_string(), milliseconds(report.started));
+    entries.push(Value::Object(fields));
+}
+
+/// Writes the report to `path`, with the `error` the run failed on if it did. Does nothing if
+/// it was not enabled.
+pub fn write(path: &Path, error: Option<Value>) -> Result<(), DofiError> {
+    let report = REPORT.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(report) = report.as_ref() else {
+        return Ok(());
+    };
+    let phases = timings::phases()
+        .into_iter()
+        .map(|(name, total, runs)| {
+            json!({
+                "name": name,
+                "duration_ms": total.as_secs_f64() * 1000.0,
+                "runs": runs,
+            })
+        })
+        .collect::<Vec<_>>();
+    let summary = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "host": condition::hostname(),
+        "started": report.timestamp,
+        "duration_ms": milliseconds(report.started),
+        "succeeded": error.is_none(),
+        "error": error,
+        "commands": report.commands,
+        "actions": report.actions,
+        "skipped": report.skipped,
+        "conflicts": report.conflicts,
+        "phases": phases,
+        "operations": timings::operations(),
+    });
+    let mut contents = serde_json::to_vec_pretty(&summary).expect("JSON values serialize");
+    contents.push(b'\n');
+    std::fs::write(path, contents).map_err(DofiError::GenericIoError)
+}
+
+fn milliseconds(since: Instant) -> Value {
+    json!(since.elapsed().as_secs_f64() * 1000.0)
+}
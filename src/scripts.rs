@@ -0,0 +1,392 @@
+//! Scripts that `apply` runs once per machine, e.g. to install fonts on a new one.
+//!
+//! Every file in the `scripts/` directory of a dotfiles directory, and every file whose name
+//! starts with `run_once_` anywhere in it, is a script rather than a dotfile and is never
+//! linked. After linking, `apply` runs the scripts that did not run on this machine yet, layer
+//! by layer in path order, and records the digest of each script that succeeded in
+//! `scripts.json` in the state directory. A script runs again only once its contents change.
+//! The first one to fail stops `apply`, it and those after it are retried by the next one, or
+//! by `apply --resume`, which runs them without linking again.
+//!
+//! Scripts are run directly, so they have to be executable, in their dotfiles directory with
+//! `DOFI_BASE`, `DOFI_DOTFILES` and the [variables](crate::vars) set like for hooks.
+//!
+//! The `scripts` section of the [`Manifest`] orders scripts that depend on each other, each
+//! runs after the scripts its `after` lists, by repo-relative path in any layer, and before
+//! those its `before` lists. Both can also name the [steps](Step) of `apply`: `pre-link` for
+//! the pre-link hooks, `link` for linking and `post-link` for the hooks run after it, so a
+//! script can provision what the dotfiles need before they are linked. Steps run as soon as
+//! the scripts they wait for did, and scripts no step waits for run after all of them. A
+//! cycle, or a script or step listed that does not exist, fails `apply` before any script
+//! runs. The `rollback` command of a script is run with the shell when the script fails, to
+//! undo what it got done so the retry starts afresh:
+//!
+//! ```toml
+//! [scripts."scripts/install-homebrew.sh"]
+//! before = ["scripts/brew-bundle.sh"]
+//!
+//! [scripts."scripts/brew-bundle.sh"]
+//! before = ["link"]
+//! rollback = "brew bundle cleanup --force"
+//! ```
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum, timings, vars, DofiError, Fs, Manifest};
+
+/// The repo-relative directory holding scripts
+pub const SCRIPTS_DIRECTORY: &str = "scripts";
+/// The file name prefix marking scripts outside of [`SCRIPTS_DIRECTORY`]
+pub const RUN_ONCE_PREFIX: &str = "run_once_";
+const STATE_FILE: &str = "scripts.json";
+
+/// The steps of `apply` scripts are ordered against, in the order they run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Running the pre-link hooks
+    PreLink,
+    /// Linking, pruning and generating the targets
+    Link,
+    /// Running the post-link hooks
+    PostLink,
+}
+
+impl Step {
+    /// The steps in the order `apply` takes them
+    pub const ALL: [Step; 3] = [Step::PreLink, Step::Link, Step::PostLink];
+
+    /// The name `after` and `before` list the step by
+    fn name(self) -> &'static Path {
+        Path::new(match self {
+            Step::PreLink => "pre-link",
+            Step::Link => "link",
+            Step::PostLink => "post-link",
+        })
+    }
+}
+
+/// How a script is ordered, from the `scripts` section of the manifest
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptOptions {
+    /// The repo-relative scripts and the steps that run before it
+    #[serde(default)]
+    pub after: Vec<PathBuf>,
+    /// The repo-relative scripts and the steps that run after it
+    #[serde(default)]
+    pub before: Vec<PathBuf>,
+    /// The command undoing the script when it fails
+    pub rollback: Option<String>,
+}
+
+/// A script of a dotfiles directory
+#[derive(Debug, PartialEq, Eq)]
+pub struct Script {
+    pub path: PathBuf,
+    /// The dotfiles directory holding the script
+    pub layer: PathBuf,
+    /// The digest of its contents
+    pub digest: String,
+    /// The command undoing the script when it fails, from the manifest
+    pub rollback: Option<String>,
+    /// The step of `apply` the script runs before, `None` if it runs after all of them
+    pub before: Option<Step>,
+}
+
+impl Script {
+    /// The path of the script relative to its dotfiles directory
+    pub fn relative_path(&self) -> &Path {
+        self.path.strip_prefix(&self.layer).unwrap_or(&self.path)
+    }
+}
+
+/// Whether the repo-relative `path` is a script, which is not linked
+pub fn is_script_path(path: &Path) -> bool {
+    path.starts_with(SCRIPTS_DIRECTORY)
+        || path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(RUN_ONCE_PREFIX))
+}
+
+/// The scripts of the layered `dotfiles_directories` that did not run on this machine yet,
+/// according to `state_directory`, in the order they run in: layer by layer in path order,
+/// except for scripts that run after others or before a step
+pub fn pending(
+    fs: &dyn Fs,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+) -> Result<Vec<Script>, DofiError> {
+    let done = load(fs, state_directory)?;
+
+    let mut scripts = Vec::new();
+    for layer in dotfiles_directories {
+        let manifest = Manifest::load(fs, layer)?;
+        let mut paths = fs
+            .walk(layer)?
+            .into_iter()
+            .filter(|path| path.strip_prefix(layer).is_ok_and(is_script_path))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        for path in paths {
+            let digest = checksum::digest(&fs.read(&path)?);
+            let options = path
+                .strip_prefix(layer)
+                .ok()
+                .and_then(|relative| manifest.scripts.get(relative));
+            let script = Script {
+                rollback: options.and_then(|options| options.rollback.clone()),
+                path,
+                layer: layer.clone(),
+                digest,
+                before: None,
+            };
+            let (after, before) = options
+                .map(|options| (options.after.clone(), options.before.clone()))
+                .unwrap_or_default();
+            scripts.push((script, after, before));
+        }
+    }
+
+    Ok(ordered(scripts)?
+        .into_iter()
+        .filter(|script| done.get(script.relative_path()) != Some(&script.digest))
+        .collect())
+}
+
+/// A script or a step of `apply`, as [`ordered`] orders them
+enum Node {
+    Script(Script),
+    Step(Step),
+}
+
+impl Node {
+    /// The name other nodes list it by
+    fn name(&self) -> &Path {
+        match self {
+            Node::Script(script) => script.relative_path(),
+            Node::Step(step) => step.name(),
+        }
+    }
+}
+
+/// Orders `scripts` among the steps of `apply`, each after the scripts and steps its `after`
+/// lists and before those its `before` lists, keeping their order otherwise. Each step runs as
+/// soon as it can, and the scripts no step waits for after all of them.
+fn ordered(scripts: Vec<(Script, Vec<PathBuf>, Vec<PathBuf>)>) -> Result<Vec<Script>, DofiError> {
+    let names = scripts
+        .iter()
+        .map(|(script, _, _)| script.relative_path())
+        .chain(Step::ALL.map(Step::name))
+        .collect::<BTreeSet<_>>();
+    for (script, after, before) in &scripts {
+        if let Some(missing) = after
+            .iter()
+            .chain(before)
+            .find(|path| !names.contains(path.as_path()))
+        {
+            return Err(DofiError::UnknownScript(
+                script.relative_path().to_path_buf(),
+                missing.clone(),
+            ));
+        }
+    }
+
+    // What each script and step runs after, by name
+    let mut waits = BTreeMap::<PathBuf, Vec<PathBuf>>::new();
+    for steps in Step::ALL.windows(2) {
+        waits
+            .entry(steps[1].name().to_path_buf())
+            .or_default()
+            .push(steps[0].name().to_path_buf());
+    }
+    for (script, after, before) in &scripts {
+        let name = script.relative_path();
+        waits
+            .entry(name.to_path_buf())
+            .or_default()
+            .extend(after.iter().cloned());
+        for later in before {
+            waits
+                .entry(later.clone())
+                .or_default()
+                .push(name.to_path_buf());
+        }
+    }
+
+    // The scripts a step waits for run among the steps, the others after them
+    let mut awaited = BTreeSet::new();
+    let mut unvisited = Step::ALL.map(|step| step.name().to_path_buf()).to_vec();
+    while let Some(name) = unvisited.pop() {
+        for earlier in waits.get(&name).into_iter().flatten() {
+            if awaited.insert(earlier.clone()) {
+                unvisited.push(earlier.clone());
+            }
+        }
+    }
+    let (among, after) = scripts
+        .into_iter()
+        .map(|(script, _, _)| script)
+        .partition::<Vec<_>, _>(|script| awaited.contains(script.relative_path()));
+
+    // Steps first, so each runs as soon as nothing it waits for is left
+    let mut remaining = Step::ALL
+        .map(Node::Step)
+        .into_iter()
+        .chain(among.into_iter().map(Node::Script))
+        .chain(after.into_iter().map(Node::Script))
+        .collect::<Vec<_>>();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    let mut next_step = Some(Step::PreLink);
+    while !remaining.is_empty() {
+        let ready = remaining
+            .iter()
+            .position(|node| waiting_for(node.name(), &waits, &remaining).is_none());
+        match ready.map(|index| remaining.remove(index)) {
+            Some(Node::Script(script)) => ordered.push(Script {
+                before: next_step,
+                ..script
+            }),
+            Some(Node::Step(step)) => {
+                let index = Step::ALL.iter().position(|other| *other == step);
+                next_step = index.and_then(|index| Step::ALL.get(index + 1)).copied();
+            }
+            None => return Err(DofiError::ScriptCycle(cycle(&remaining, &waits))),
+        }
+    }
+    Ok(ordered)
+}
+
+/// The first of what `name` runs after that is still `remaining`
+fn waiting_for(
+    name: &Path,
+    waits: &BTreeMap<PathBuf, Vec<PathBuf>>,
+    remaining: &[Node],
+) -> Option<PathBuf> {
+    waits
+        .get(name)?
+        .iter()
+        .find(|earlier| remaining.iter().any(|node| node.name() == *earlier))
+        .cloned()
+}
+
+/// A cycle among the `remaining` scripts and steps, which all wait for one of them, as
+/// `'a' runs after 'b' runs after 'a'`
+fn cycle(remaining: &[Node], waits: &BTreeMap<PathBuf, Vec<PathBuf>>) -> String {
+    let mut path = remaining[0].name().to_path_buf();
+    let mut visited = vec![path.clone()];
+    while let Some(next) = waiting_for(&path, waits, remaining) {
+        if let Some(start) = visited.iter().position(|visited| *visited == next) {
+            visited.drain(..start);
+            visited.push(next);
+            break;
+        }
+        visited.push(next.clone());
+        path = next;
+    }
+    visited
+        .iter()
+        .map(|path| format!("'{}'", path.display()))
+        .collect::<Vec<_>>()
+        .join(" runs after ")
+}
+
+/// Records in `state_directory` that `script` ran, so it is not run again until it changes
+pub fn record(fs: &dyn Fs, state_directory: &Path, script: &Script) -> Result<(), DofiError> {
+    let mut done = load(fs, state_directory)?;
+    done.insert(script.relative_path().to_path_buf(), script.digest.clone());
+
+    let contents = serde_json::to_vec_pretty(&Done(done)).map_err(DofiError::InvalidScriptState)?;
+    fs.create_dir_all(state_directory)?;
+    fs.write(&state_directory.join(STATE_FILE), &contents)?;
+    Ok(())
+}
+
+/// Runs `script`, with `base_directory` and `variables` in its environment, and its rollback
+/// command if it fails
+pub fn run(
+    script: &Script,
+    base_directory: &Path,
+    variables: &BTreeMap<String, String>,
+) -> Result<(), DofiError> {
+    let name = script.relative_path().display().to_string();
+    info!("Running script '{name}'");
+    let _scripts = timings::phase("scripts");
+
+    let result = execute(
+        &name,
+        &mut command(&script.path, script, base_directory, variables),
+    );
+    if let (Err(_), Some(rollback)) = (&result, &script.rollback) {
+        info!("Rolling back script '{name}' with '{rollback}'");
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let mut rollback_command = command(Path::new(shell), script, base_directory, variables);
+        if let Err(e) = execute(rollback, rollback_command.arg(flag).arg(rollback)) {
+            warn!("Failed to roll back script '{name}': {e}");
+        }
+    }
+    result
+}
+
+/// A command running `program` for `script`, in its dotfiles directory with `base_directory`
+/// and `variables` in its environment
+fn command(
+    program: &Path,
+    script: &Script,
+    base_directory: &Path,
+    variables: &BTreeMap<String, String>,
+) -> Command {
+    let mut command = Command::new(program);
+    command
+        .current_dir(&script.layer)
+        .env("DOFI_BASE", base_directory)
+        .env("DOFI_DOTFILES", &script.layer)
+        .envs(
+            variables
+                .iter()
+                .map(|(name, value)| (vars::environment_name(name), value)),
+        );
+    command
+}
+
+/// Runs `command`, failing unless it succeeds
+fn execute(name: &str, command: &mut Command) -> Result<(), DofiError> {
+    let status = command
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(name.to_string(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            name.to_string(),
+            format!("exited with {status}"),
+        ))
+    }
+}
+
+/// The digests of the scripts that ran as they are stored, by repo-relative path
+#[derive(Serialize, Deserialize)]
+struct Done(#[serde(with = "crate::paths::serde::map")] BTreeMap<PathBuf, String>);
+
+fn load(fs: &dyn Fs, state_directory: &Path) -> Result<BTreeMap<PathBuf, String>, DofiError> {
+    match fs.read(&state_directory.join(STATE_FILE)) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(|Done(done)| done)
+            .map_err(DofiError::InvalidScriptState),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
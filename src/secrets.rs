@@ -0,0 +1,79 @@
+//! Secrets looked up at render time by the `secret` template function.
+//!
+//! The provider is set in the user configuration and defaults to `pass`:
+//!
+//! ```toml
+//! [secrets]
+//! provider = "op"
+//! ```
+//!
+//! - `pass` runs `pass show <name>` and uses the first line
+//! - `op` runs `op read <name>`, the name being a reference like `op://Private/GitHub/token`
+//! - `command` runs the configured `command` with the name appended as the last argument
+//!   and uses its output, e.g. `command = "vault kv get -field=value"`
+
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::DofiError;
+
+/// Looks up secrets by name
+pub trait SecretProvider {
+    fn lookup(&self, name: &str) -> Result<String, DofiError>;
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    Pass,
+    Op,
+    Command,
+}
+
+/// The `[secrets]` section of the user configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretsConfig {
+    #[serde(default)]
+    pub provider: Provider,
+    /// The command run by the `command` provider
+    pub command: Option<String>,
+}
+
+impl SecretProvider for SecretsConfig {
+    fn lookup(&self, name: &str) -> Result<String, DofiError> {
+        let (program, arguments) = match self.provider {
+            Provider::Pass => ("pass".to_string(), vec!["show".to_string()]),
+            Provider::Op => ("op".to_string(), vec!["read".to_string()]),
+            Provider::Command => {
+                let command = self.command.as_deref().ok_or(DofiError::NoSecretProvider)?;
+                let mut words = command.split_whitespace().map(str::to_string);
+                let program = words.next().ok_or(DofiError::NoSecretProvider)?;
+                (program, words.collect())
+            }
+        };
+
+        let failed = |reason: String| DofiError::ExternalCommandFailed(program.clone(), reason);
+        let output = Command::new(&program)
+            .args(&arguments)
+            .arg(name)
+            .output()
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(format!(
+                "looking up '{name}' exited with {}",
+                output.status
+            )));
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        let secret = match self.provider {
+            Provider::Pass => output.lines().next().unwrap_or_default(),
+            Provider::Op | Provider::Command => output.trim_end_matches(['\r', '\n']),
+        };
+
+        Ok(secret.to_string())
+    }
+}
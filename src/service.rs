@@ -0,0 +1,276 @@
+//! A background service applying the dotfiles periodically.
+//!
+//! `service install --systemd` writes a user service running `dofi apply` and a timer starting
+//! it every interval to `$XDG_CONFIG_HOME/systemd/user`, then enables the timer. On macOS
+//! `service install --launchd` writes a LaunchAgent doing the same to `~/Library/LaunchAgents`
+//! and loads it, its output goes to `service.log` in the state directory. The service runs the
+//! dofi binary that installed it against the same dotfiles and base directories.
+//! `--uninstall` stops the service and removes its files again.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use log::info;
+
+use crate::{query, DofiError, Fs, Journal};
+
+/// The name of the units, `dofi.service` and `dofi.timer`
+pub const UNIT_NAME: &str = "dofi";
+/// The label of the LaunchAgent, also the name of its plist
+pub const LAUNCHD_LABEL: &str = "com.github.jsfr.dofi";
+/// The file in the state directory the LaunchAgent writes its output to
+pub const LOG_FILE: &str = "service.log";
+
+/// What the service runs and how often
+pub struct Service {
+    /// The command line, starting with the absolute path of the dofi binary
+    pub command: Vec<OsString>,
+    pub interval: Duration,
+}
+
+impl Service {
+    /// The service running `executable` with `arguments` every `interval`
+    pub fn new(executable: &Path, arguments: &[OsString], interval: Duration) -> Service {
+        Service {
+            command: std::iter::once(executable.as_os_str().to_os_string())
+                .chain(arguments.iter().cloned())
+                .collect(),
+            interval,
+        }
+    }
+}
+
+/// Parses an interval like `30m`, `1h` or `1d`
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    query::parse_duration(interval)
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| "expected a duration like '30m', '1h' or '1d'".to_string())
+}
+
+/// The directory holding the systemd user units, `$XDG_CONFIG_HOME/systemd/user`
+pub fn systemd_directory(base_directory: &Path) -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| base_directory.join(".config"))
+        .join("systemd/user")
+}
+
+/// The file names and contents of the systemd service and timer units for `service`
+pub fn systemd_units(service: &Service) -> [(String, String); 2] {
+    let command = service
+        .command
+        .iter()
+        .map(|word| systemd_quote(&word.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let interval = service.interval.as_secs();
+
+    [
+        (
+            format!("{UNIT_NAME}.service"),
+            format!(
+                "[Unit]\n\
+                 Description=Apply the dotfiles with dofi\n\
+                 \n\
+                 [Service]\n\
+                 Type=oneshot\n\
+                 ExecStart={command}\n"
+            ),
+        ),
+        (
+            format!("{UNIT_NAME}.timer"),
+            format!(
+                "[Unit]\n\
+                 Description=Apply the dotfiles with dofi periodically\n\
+                 \n\
+                 [Timer]\n\
+                 OnBootSec={interval}s\n\
+                 OnUnitActiveSec={interval}s\n\
+                 \n\
+                 [Install]\n\
+                 WantedBy=timers.target\n"
+            ),
+        ),
+    ]
+}
+
+/// Writes the systemd units of `service` to `directory`, recording it in `journal`. Returns
+/// the written files.
+pub fn write_systemd_units(
+    fs: &dyn Fs,
+    directory: &Path,
+    service: &Service,
+    journal: &mut Journal,
+) -> Result<Vec<PathBuf>, DofiError> {
+    journal.create_dir_all(fs, directory)?;
+
+    let mut written = Vec::new();
+    for (name, contents) in systemd_units(service) {
+        let path = directory.join(name);
+        info!("Writing '{}'", path.display());
+        journal.write_file(fs, &path, contents.as_bytes())?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Removes the systemd units from `directory`, recording it in `journal`. Returns the
+/// removed files.
+pub fn remove_systemd_units(
+    fs: &dyn Fs,
+    directory: &Path,
+    journal: &mut Journal,
+) -> Result<Vec<PathBuf>, DofiError> {
+    let mut removed = Vec::new();
+    for extension in ["service", "timer"] {
+        let path = directory.join(format!("{UNIT_NAME}.{extension}"));
+        if fs.symlink_metadata(&path).is_ok() {
+            info!("Removing '{}'", path.display());
+            journal.remove_file(fs, &path)?;
+            removed.push(path);
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Runs `systemctl --user` with `args`
+pub fn systemctl(args: &[&str]) -> Result<(), DofiError> {
+    let args = std::iter::once("--user")
+        .chain(args.iter().copied())
+        .map(OsStr::new)
+        .collect::<Vec<_>>();
+    run("systemctl", &args)
+}
+
+/// The plist of the LaunchAgent, in `~/Library/LaunchAgents`
+pub fn launchd_path(base_directory: &Path) -> PathBuf {
+    base_directory
+        .join("Library/LaunchAgents")
+        .join(format!("{LAUNCHD_LABEL}.plist"))
+}
+
+/// The plist of a LaunchAgent running `service`, writing its output to `log`
+pub fn launchd_agent(service: &Service, log: &Path) -> String {
+    let arguments = service
+        .command
+        .iter()
+        .map(|word| {
+            format!(
+                "        <string>{}</string>",
+                xml_escape(&word.to_string_lossy())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let log = xml_escape(&log.to_string_lossy());
+    let interval = service.interval.as_secs();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}
+    </array>
+    <key>StartInterval</key>
+    <integer>{interval}</integer>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{log}</string>
+    <key>StandardErrorPath</key>
+    <string>{log}</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// Writes the LaunchAgent of `service` to `path`, with its output going to `log`, recording
+/// it in `journal`
+pub fn write_launchd_agent(
+    fs: &dyn Fs,
+    path: &Path,
+    service: &Service,
+    log: &Path,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    if let Some(parent) = path.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    info!("Writing '{}'", path.display());
+    journal.write_file(fs, path, launchd_agent(service, log).as_bytes())
+}
+
+/// Removes the LaunchAgent at `path` if it exists, recording it in `journal`. Returns whether
+/// it existed.
+pub fn remove_launchd_agent(
+    fs: &dyn Fs,
+    path: &Path,
+    journal: &mut Journal,
+) -> Result<bool, DofiError> {
+    if fs.symlink_metadata(path).is_err() {
+        return Ok(false);
+    }
+    info!("Removing '{}'", path.display());
+    journal.remove_file(fs, path)?;
+    Ok(true)
+}
+
+/// Runs `launchctl` with `args`
+pub fn launchctl(args: &[&OsStr]) -> Result<(), DofiError> {
+    run("launchctl", args)
+}
+
+fn run(program: &str, args: &[&OsStr]) -> Result<(), DofiError> {
+    let command_line = std::iter::once(program.into())
+        .chain(args.iter().map(|arg| arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ");
+    info!("Running '{command_line}'");
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            command_line,
+            format!("exited with {status}"),
+        ))
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Quotes `word` for a systemd command line, which expands `%` specifiers and `$` variables
+fn systemd_quote(word: &str) -> String {
+    let escaped = word.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,195 @@
+//! Snapshots of the targets a link run replaces.
+//!
+//! Before [`link_files`](crate::link_files) overwrites existing files, with `--force-all`, a
+//! conflict policy or `apply`, their contents are copied into `snapshots/<id>` in the state
+//! directory and the snapshot is listed in `snapshots.jsonl` next to it. Unlike the journal,
+//! which only undoes the latest operation, any snapshot can be restored later.
+
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{fs::FileType, journal::Action, DofiError, Fs, Journal};
+
+const SNAPSHOTS_FILE: &str = "snapshots.jsonl";
+const SNAPSHOTS_DIRECTORY: &str = "snapshots";
+
+/// The replaced targets of a single run
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub id: u64,
+    pub command: String,
+    pub timestamp: u64,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// A target as it was before it was replaced
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotFile {
+    #[serde(with = "crate::paths::serde")]
+    pub path: PathBuf,
+    pub content: Content,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Content {
+    /// A regular file, its contents are kept at `stored`
+    File {
+        #[serde(with = "crate::paths::serde")]
+        stored: PathBuf,
+        mode: u32,
+    },
+    /// A symlink pointing to `target`
+    Symlink {
+        #[serde(with = "crate::paths::serde")]
+        target: PathBuf,
+    },
+}
+
+/// Copies the files and symlinks among `paths` into a new snapshot for `command` in
+/// `state_directory`. Returns `None` if none of them exist.
+pub fn take(
+    fs: &dyn Fs,
+    state_directory: &Path,
+    command: &str,
+    paths: &[PathBuf],
+) -> Result<Option<Snapshot>, DofiError> {
+    let mut snapshot = None;
+    extend(fs, state_directory, &mut snapshot, command, paths)?;
+    Ok(snapshot)
+}
+
+/// Copies the files and symlinks among `paths` into `snapshot`, for runs replacing targets a
+/// batch at a time. Takes a new snapshot for `command` like [`take`] if there is none yet.
+pub fn extend(
+    fs: &dyn Fs,
+    state_directory: &Path,
+    snapshot: &mut Option<Snapshot>,
+    command: &str,
+    paths: &[PathBuf],
+) -> Result<(), DofiError> {
+    let snapshots = list(fs, state_directory)?;
+    let id = match snapshot {
+        Some(snapshot) => snapshot.id,
+        None => snapshots.last().map_or(1, |snapshot| snapshot.id + 1),
+    };
+    let directory = state_directory
+        .join(SNAPSHOTS_DIRECTORY)
+        .join(id.to_string());
+    let stored = snapshot.as_ref().map_or(0, |snapshot| snapshot.files.len());
+
+    let mut files = Vec::new();
+    for path in paths {
+        let Ok(metadata) = fs.symlink_metadata(path) else {
+            continue;
+        };
+        let content = match metadata.file_type {
+            FileType::Symlink => Content::Symlink {
+                target: fs.read_link(path)?,
+            },
+            FileType::File => {
+                let stored = directory.join((stored + files.len()).to_string());
+                fs.create_dir_all(&directory)?;
+                fs.write(&stored, &fs.read(path)?)?;
+                Content::File {
+                    stored,
+                    mode: metadata.mode,
+                }
+            }
+            FileType::Directory => continue,
+        };
+        files.push(SnapshotFile {
+            path: path.clone(),
+            content,
+        });
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    info!("Saved {} replaced targets in snapshot {id}", files.len());
+    let snapshot = snapshot.get_or_insert_with(|| Snapshot {
+        id,
+        command: command.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        files: Vec::new(),
+    });
+    snapshot.files.extend(files);
+
+    let mut contents = String::new();
+    let mut listed = false;
+    for other in &snapshots {
+        let other = if other.id == id {
+            listed = true;
+            &*snapshot
+        } else {
+            other
+        };
+        contents.push_str(&serde_json::to_string(other).map_err(DofiError::InvalidSnapshot)?);
+        contents.push('\n');
+    }
+    if !listed {
+        contents.push_str(&serde_json::to_string(snapshot).map_err(DofiError::InvalidSnapshot)?);
+        contents.push('\n');
+    }
+    fs.create_dir_all(state_directory)?;
+    fs.write(&state_directory.join(SNAPSHOTS_FILE), contents.as_bytes())?;
+
+    Ok(())
+}
+
+/// The snapshots in `state_directory`, oldest first
+pub fn list(fs: &dyn Fs, state_directory: &Path) -> Result<Vec<Snapshot>, DofiError> {
+    let contents = match fs.read(&state_directory.join(SNAPSHOTS_FILE)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    String::from_utf8_lossy(&contents)
+        .lines()
+        .map(|line| serde_json::from_str(line).map_err(DofiError::InvalidSnapshot))
+        .collect()
+}
+
+/// Puts every target of the snapshot `id` back as it was, replacing whatever is there now.
+/// The restore is recorded in `journal`, so it can be undone in turn.
+pub fn restore(fs: &dyn Fs, id: u64, journal: &mut Journal) -> Result<Snapshot, DofiError> {
+    let snapshot = list(fs, journal.directory())?
+        .into_iter()
+        .find(|snapshot| snapshot.id == id)
+        .ok_or(DofiError::UnknownSnapshot(id))?;
+
+    for file in &snapshot.files {
+        info!("Restoring '{}'", file.path.display());
+        if fs.symlink_metadata(&file.path).is_ok() {
+            journal.remove_file(fs, &file.path)?;
+        }
+        if let Some(parent) = file.path.parent() {
+            journal.create_dir_all(fs, parent)?;
+        }
+        match &file.content {
+            Content::File { stored, mode } => {
+                journal.write_file(fs, &file.path, &fs.read(stored)?)?;
+                fs.set_mode(&file.path, *mode)?;
+            }
+            Content::Symlink { target } => {
+                fs.symlink(target, &file.path)?;
+                journal.record(Action::Symlinked {
+                    link: file.path.clone(),
+                    target: target.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
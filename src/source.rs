@@ -0,0 +1,179 @@
+//! Where the dotfiles are read from, for machines with no checkout of them.
+//!
+//! `--source` takes the place of the dotfiles directory and is one of:
+//!
+//! - a directory of dotfiles, the same as passing it with `-d`
+//! - a bare git repository, like the one yadm keeps, whose `HEAD` is read with `git archive`
+//! - the `http://` or `https://` URL of a tarball, downloaded with the system `curl`
+//!
+//! ```sh
+//! dofi apply --source https://example.com/dotfiles.tar.gz
+//! ```
+//!
+//! The sources that are no directory are unpacked into `sources` in the state directory,
+//! afresh on every run, and the targets link there. A downloaded tarball holding a single
+//! directory, like those GitHub serves, stands for its contents unless the directory is a
+//! dotfile itself. These sources are read-only, the commands changing the dotfiles refuse them.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::info;
+
+use crate::{checksum, fs::ScratchDirectory, DofiError};
+
+/// Where the dotfiles of a `--source` are kept
+pub trait Source {
+    /// The source as it was given
+    fn name(&self) -> String;
+
+    /// Whether dofi can change the dotfiles where the source keeps them
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// The directory holding the dotfiles, unpacking them into `cache` first if the source
+    /// keeps them elsewhere
+    fn directory(&self, cache: &Path) -> Result<PathBuf, DofiError>;
+}
+
+/// A directory of dotfiles
+pub struct Directory(pub PathBuf);
+
+impl Source for Directory {
+    fn name(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    fn directory(&self, _cache: &Path) -> Result<PathBuf, DofiError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// A git repository without a worktree
+pub struct BareRepository(pub PathBuf);
+
+impl Source for BareRepository {
+    fn name(&self) -> String {
+        self.0.display().to_string()
+    }
+
+    fn directory(&self, cache: &Path) -> Result<PathBuf, DofiError> {
+        unpack(cache, false, |archive| {
+            run(Command::new("git")
+                .arg("--git-dir")
+                .arg(&self.0)
+                .args(["archive", "--format=tar", "-o"])
+                .arg(archive)
+                .arg("HEAD"))
+        })
+    }
+}
+
+/// A tarball to download
+pub struct Archive(pub String);
+
+impl Source for Archive {
+    fn name(&self) -> String {
+        self.0.clone()
+    }
+
+    fn directory(&self, cache: &Path) -> Result<PathBuf, DofiError> {
+        unpack(cache, true, |archive| {
+            run(Command::new("curl")
+                .arg("-fsSL")
+                .arg(&self.0)
+                .arg("-o")
+                .arg(archive))
+        })
+    }
+}
+
+/// The source `source` names: a tarball for a URL, a bare repository for a directory with
+/// the layout of one, a directory of dotfiles otherwise
+pub fn parse(source: &str) -> Box<dyn Source> {
+    if source.starts_with("https://") || source.starts_with("http://") {
+        return Box::new(Archive(source.to_string()));
+    }
+    let path = PathBuf::from(source);
+    let is_bare =
+        path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir();
+    if is_bare {
+        Box::new(BareRepository(path))
+    } else {
+        Box::new(Directory(path))
+    }
+}
+
+/// The directory holding the dotfiles of `source`, unpacked below `state_directory` if need be
+pub fn open(source: &dyn Source, state_directory: &Path) -> Result<PathBuf, DofiError> {
+    let digest = checksum::digest(source.name().as_bytes());
+    source.directory(&state_directory.join("sources").join(&digest[..16]))
+}
+
+/// Fills `cache` with the tarball `fetch` writes to the path it is given, replacing what was
+/// there. With `strip_wrapper` a single directory the tarball holds stands for its contents,
+/// unless it is a dotfile. Returns the directory of the dotfiles in it.
+fn unpack(
+    cache: &Path,
+    strip_wrapper: bool,
+    fetch: impl FnOnce(&Path) -> Result<(), DofiError>,
+) -> Result<PathBuf, DofiError> {
+    let parent = cache.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+    let scratch = ScratchDirectory::new(parent, "unpack")?;
+    let archive = scratch.path().join("dotfiles.tar");
+    let tree = scratch.path().join("tree");
+    std::fs::create_dir(&tree)?;
+
+    fetch(&archive)?;
+    info!("Unpacking the dotfiles into '{}'", cache.display());
+    run(Command::new("tar")
+        .arg("-xf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&tree))?;
+
+    let entries = std::fs::read_dir(&tree)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let root = match entries.as_slice() {
+        [single] if strip_wrapper && single.is_dir() && !is_dotfile(single) => single.clone(),
+        _ => tree,
+    };
+    match std::fs::remove_dir_all(cache) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::fs::rename(root, cache)?;
+    Ok(cache.to_path_buf())
+}
+
+/// Whether the name of `path` starts with a dot
+fn is_dotfile(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+/// Runs `command`, failing with its error output
+fn run(command: &mut Command) -> Result<(), DofiError> {
+    let command_line = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            command_line,
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
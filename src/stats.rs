@@ -0,0 +1,92 @@
+//! An overview of the dotfiles for `dofi stats`: how many are in each state, how they spread
+//! over the top-level directories of the repo and which are the largest and the most recently
+//! changed, which is where accidentally added caches and logs tend to show up.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    status::{Entry, State},
+    Fs,
+};
+
+/// The summary of a set of dotfiles
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of dotfiles in each state, in the order of [`State::ALL`]
+    pub states: Vec<(State, usize)>,
+    /// The top-level directories of the repo, `.` for files at its root, with the number of
+    /// dotfiles and their total size in bytes
+    pub packages: BTreeMap<PathBuf, (usize, u64)>,
+    /// The total size of the dotfiles in bytes
+    pub size: u64,
+    /// Repo-relative sources with their sizes, the largest first
+    pub largest: Vec<(PathBuf, u64)>,
+    /// Repo-relative sources with when they were modified, the latest first
+    pub recent: Vec<(PathBuf, SystemTime)>,
+}
+
+/// Summarizes `entries`, listing the `top` largest and most recently modified of them
+pub fn summarize(fs: &dyn Fs, entries: &[Entry], top: usize) -> Stats {
+    let mut stats = Stats {
+        states: State::ALL
+            .into_iter()
+            .map(|state| {
+                let count = entries.iter().filter(|entry| entry.state == state).count();
+                (state, count)
+            })
+            .collect(),
+        ..Stats::default()
+    };
+    let mut sizes = Vec::new();
+    for entry in entries {
+        let source = entry.relative_source().to_path_buf();
+        let size = fs
+            .symlink_metadata(&entry.source)
+            .map_or(0, |metadata| metadata.len);
+        let (files, total) = stats.packages.entry(package(&source)).or_default();
+        *files += 1;
+        *total += size;
+        stats.size += size;
+
+        if let Some(modified) = entry.modified {
+            stats.recent.push((source.clone(), modified));
+        }
+        sizes.push((source, size));
+    }
+
+    sizes.sort_by(|(a, a_size), (b, b_size)| b_size.cmp(a_size).then_with(|| a.cmp(b)));
+    sizes.truncate(top);
+    stats.largest = sizes;
+    stats
+        .recent
+        .sort_by(|(a, a_time), (b, b_time)| b_time.cmp(a_time).then_with(|| a.cmp(b)));
+    stats.recent.truncate(top);
+    stats
+}
+
+/// `bytes` in the largest binary unit keeping it at least 1, like `3.2 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// The package of the repo-relative `source`, see [`Stats::packages`]
+fn package(source: &Path) -> PathBuf {
+    match source.components().count() {
+        0 | 1 => PathBuf::from("."),
+        _ => source.components().take(1).collect(),
+    }
+}
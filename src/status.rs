@@ -0,0 +1,403 @@
+//! The link state of each dotfile.
+//!
+//! `list --porcelain` and `status --porcelain` print one line per dotfile in a format that
+//! stays the same across versions, for scripts:
+//!
+//! ```text
+//! <state>\t<source>\t<target>
+//! ```
+//!
+//! The state is one of `linked`, `unlinked`, `conflict` and `broken`, the paths are absolute.
+//! Backslashes, tabs and newlines in paths are written as `\\`, `\t` and `\n`, other control
+//! characters and bytes that are not UTF-8 as `\xNN`. `list --porcelain -z` writes the fields
+//! unchanged instead, each followed by a NUL byte. New fields, if any, are only ever appended.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color, fs::FileType, git, layered_dotfiles, link_original, paths, DofiError, Dotfile, Fs,
+    Strategy,
+};
+
+/// How the target of a dotfile relates to the dotfile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// The target is a symlink to the dotfile
+    Linked,
+    /// Nothing exists at the target
+    Unlinked,
+    /// Something else exists at the target
+    Conflict,
+    /// A symlink to something that does not exist is at the target
+    Broken,
+}
+
+impl State {
+    pub const ALL: [State; 4] = [
+        State::Linked,
+        State::Unlinked,
+        State::Conflict,
+        State::Broken,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Linked => "linked",
+            State::Unlinked => "unlinked",
+            State::Conflict => "conflict",
+            State::Broken => "broken",
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for State {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        State::ALL
+            .into_iter()
+            .find(|state| state.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// A dotfile together with its target and the target's state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(with = "crate::paths::serde")]
+    pub source: PathBuf,
+    #[serde(with = "crate::paths::serde")]
+    pub target: PathBuf,
+    /// The dotfiles directory providing the dotfile
+    #[serde(with = "crate::paths::serde")]
+    pub layer: PathBuf,
+    pub tags: Vec<String>,
+    /// The permission bits the manifest requires of the dotfile
+    pub mode: Option<u32>,
+    /// Whether the dotfile is [private](crate::private)
+    pub private: bool,
+    /// How the dotfile is linked to its target
+    pub strategy: Strategy,
+    pub state: State,
+    /// When the source was last modified
+    pub modified: Option<SystemTime>,
+}
+
+impl Entry {
+    /// The entry as a line in the porcelain format, without the newline
+    pub fn porcelain(&self) -> String {
+        format!(
+            "{}\t{}\t{}",
+            self.state.as_str(),
+            paths::escape(&self.source),
+            paths::escape(&self.target)
+        )
+    }
+
+    /// The dotfile of the entry
+    pub fn dotfile(&self) -> Dotfile {
+        Dotfile {
+            source: self.source.clone(),
+            target: self.target.clone(),
+            layer: self.layer.clone(),
+            tags: self.tags.clone(),
+            mode: self.mode,
+            private: self.private,
+            strategy: self.strategy,
+        }
+    }
+
+    /// The source path relative to its dotfiles directory
+    pub fn relative_source(&self) -> &Path {
+        self.source
+            .strip_prefix(&self.layer)
+            .unwrap_or(&self.source)
+    }
+}
+
+/// Determines the state of the target of `source` linked with `strategy`. The target of a
+/// copied, encrypted or template dotfile counts as linked once it exists as a regular file, a
+/// hard linked one once it is the same file as `source`.
+pub fn state(fs: &dyn Fs, source: &Path, target: &Path, strategy: Strategy) -> State {
+    let original = fs.read_link(target);
+    if let Ok(original) = &original {
+        let resolved = target.parent().unwrap_or(target).join(original);
+        if original != source && !fs.exists(&resolved) {
+            return State::Broken;
+        }
+    }
+
+    match strategy {
+        _ if strategy.is_copied() => {
+            return match fs.symlink_metadata(target) {
+                Ok(metadata) if metadata.file_type == FileType::File => State::Linked,
+                Ok(_) => State::Conflict,
+                Err(_) => State::Unlinked,
+            };
+        }
+        Strategy::Hardlink if fs.same_file(source, target) => return State::Linked,
+        Strategy::Hardlink if fs.exists(target) => return State::Conflict,
+        Strategy::Hardlink => return State::Unlinked,
+        _ => {}
+    }
+
+    match original {
+        Ok(original) if original == link_original(fs, source) => State::Linked,
+        _ if fs.exists(target) => State::Conflict,
+        _ => State::Unlinked,
+    }
+}
+
+/// Lists the dotfiles of the layered `dotfiles_directories` with the state of their targets in
+/// `base_directory`
+pub fn entries(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+) -> Result<Vec<Entry>, DofiError> {
+    Ok(layered_dotfiles(fs, base_directory, dotfiles_directories)?
+        .into_iter()
+        .map(|dotfile| Entry {
+            state: state(fs, &dotfile.source, &dotfile.target, dotfile.strategy),
+            modified: fs
+                .symlink_metadata(&dotfile.source)
+                .ok()
+                .and_then(|metadata| metadata.modified),
+            source: dotfile.source,
+            target: dotfile.target,
+            layer: dotfile.layer,
+            tags: dotfile.tags,
+            mode: dotfile.mode,
+            private: dotfile.private,
+            strategy: dotfile.strategy,
+        })
+        .collect())
+}
+
+#[derive(Default)]
+struct TreeNode<'a> {
+    children: BTreeMap<&'a OsStr, TreeNode<'a>>,
+    /// The index of the entry ending at this node, if any
+    entry: Option<usize>,
+}
+
+/// A compact summary of the `entries` that are not linked for a shell prompt, like `+2 !1`:
+/// the number of unlinked dotfiles after `+`, of conflicts after `!` and of broken links
+/// after `x`. Empty if everything is linked.
+pub fn prompt(entries: &[Entry]) -> String {
+    [
+        (State::Unlinked, '+'),
+        (State::Conflict, '!'),
+        (State::Broken, 'x'),
+    ]
+    .into_iter()
+    .filter_map(|(state, symbol)| {
+        let count = entries.iter().filter(|entry| entry.state == state).count();
+        (count > 0).then(|| format!("{symbol}{count}"))
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// The placeholders of [`prompt_format`] that need the git repository
+pub const GIT_PLACEHOLDERS: [&str; 3] = ["{uncommitted}", "{ahead}", "{behind}"];
+
+/// Fills in the placeholders of `format` for a shell prompt, each empty unless there is
+/// something to show: `{drift}` is [`prompt`], `{dirty}` a `*` if any of the `entries` is not
+/// linked, `{unlinked}`, `{conflicts}` and `{broken}` its parts, and `{uncommitted}`, `{ahead}`
+/// and `{behind}` the uncommitted changes and commits of `sync` as `~N`, `⇡N` and `⇣N`. `{{`
+/// and `}}` stand for literal braces.
+pub fn prompt_format(
+    format: &str,
+    entries: &[Entry],
+    sync: Option<&git::SyncState>,
+) -> Result<String, DofiError> {
+    let count = |state| entries.iter().filter(|entry| entry.state == state).count();
+    let counted = |symbol: &str, count: usize| {
+        if count > 0 {
+            format!("{symbol}{count}")
+        } else {
+            String::new()
+        }
+    };
+    let (ahead, behind) = match sync.and_then(|sync| sync.upstream.as_ref()) {
+        Some((_, ahead, behind)) => (*ahead, *behind),
+        None => (0, 0),
+    };
+
+    let invalid = |reason: String| DofiError::InvalidPromptFormat(format.to_string(), reason);
+    let mut output = String::new();
+    let mut rest = format;
+    while let Some(index) = rest.find(['{', '}']) {
+        output.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            output.push_str(&rest[..1]);
+            rest = after;
+            continue;
+        }
+        if rest.starts_with('}') {
+            return Err(invalid("unmatched '}'".to_string()));
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| invalid("unclosed '{'".to_string()))?;
+        output.push_str(&match &rest[1..end] {
+            "drift" => prompt(entries),
+            "dirty" if entries.iter().any(|entry| entry.state != State::Linked) => "*".to_string(),
+            "dirty" => String::new(),
+            "unlinked" => counted("+", count(State::Unlinked)),
+            "conflicts" => counted("!", count(State::Conflict)),
+            "broken" => counted("x", count(State::Broken)),
+            "uncommitted" => counted("~", sync.map_or(0, |sync| sync.uncommitted)),
+            "ahead" => counted("⇡", ahead),
+            "behind" => counted("⇣", behind),
+            name => return Err(invalid(format!("unknown placeholder '{{{name}}}'"))),
+        });
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// What `dofi list` prints of each dotfile, from its `--targets`, `--mapping` and `--relative`
+/// flags
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Listing {
+    /// The target instead of the dotfile
+    pub targets: bool,
+    /// The dotfile and its target, taking precedence over `targets`
+    pub mapping: bool,
+    /// The dotfile relative to its dotfiles directory and the target relative to the base
+    /// directory
+    pub relative: bool,
+}
+
+impl Listing {
+    /// The paths listed for the dotfile at `source` in the dotfiles directory `layer`, linked
+    /// to `target`
+    pub fn paths<'a>(
+        &self,
+        source: &'a Path,
+        target: &'a Path,
+        layer: &Path,
+        base_directory: &Path,
+    ) -> Vec<&'a Path> {
+        let (source, target) = if self.relative {
+            (
+                source.strip_prefix(layer).unwrap_or(source),
+                target.strip_prefix(base_directory).unwrap_or(target),
+            )
+        } else {
+            (source, target)
+        };
+        match (self.mapping, self.targets) {
+            (true, _) => vec![source, target],
+            (false, true) => vec![target],
+            (false, false) => vec![source],
+        }
+    }
+
+    /// `paths` as a line of the listing, `<source> -> <target>` for a mapping
+    pub fn line(paths: &[&Path]) -> String {
+        paths
+            .iter()
+            .map(|path| paths::display(path).to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+}
+
+/// Renders `entries` as an indented tree of their repo-relative sources, one tree per layer,
+/// with the state of each dotfile after its name, colored if `color` is set
+pub fn tree(entries: &[Entry], color: bool) -> String {
+    render_tree(entries, color, false)
+}
+
+/// Renders `entries` like [`tree`], with the number of each entry, starting at 1, in front
+/// of its line
+pub fn numbered_tree(entries: &[Entry], color: bool) -> String {
+    render_tree(entries, color, true)
+}
+
+fn render_tree(entries: &[Entry], color: bool, numbered: bool) -> String {
+    let mut layers = Vec::<(&Path, TreeNode)>::new();
+    for (entry_index, entry) in entries.iter().enumerate() {
+        let index = match layers.iter().position(|(layer, _)| *layer == entry.layer) {
+            Some(index) => index,
+            None => {
+                layers.push((&entry.layer, TreeNode::default()));
+                layers.len() - 1
+            }
+        };
+        let mut node = &mut layers[index].1;
+        for component in entry.relative_source() {
+            node = node.children.entry(component).or_default();
+        }
+        node.entry = Some(entry_index);
+    }
+
+    let mut output = String::new();
+    for (layer, root) in &layers {
+        output.push_str(&format!("{}\n", layer.display()));
+        render_children(entries, root, "", color, numbered, &mut output);
+    }
+    output
+}
+
+fn render_children(
+    entries: &[Entry],
+    node: &TreeNode,
+    prefix: &str,
+    color: bool,
+    numbered: bool,
+    output: &mut String,
+) {
+    let last = node.children.len().saturating_sub(1);
+    for (index, (name, child)) in node.children.iter().enumerate() {
+        let (branch, indent) = if index == last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        if numbered {
+            match child.entry {
+                Some(entry) => output.push_str(&format!("{:>4} ", entry + 1)),
+                None => output.push_str("     "),
+            }
+        }
+        output.push_str(prefix);
+        output.push_str(branch);
+        output.push_str(&name.to_string_lossy());
+        if let Some(state) = child.entry.map(|entry| entries[entry].state) {
+            let label = format!("[{state}]");
+            output.push_str("  ");
+            output.push_str(&color::paint(&label, color::of_state(state), color));
+        }
+        output.push('\n');
+        render_children(
+            entries,
+            child,
+            &format!("{prefix}{indent}"),
+            color,
+            numbered,
+            output,
+        );
+    }
+}
@@ -0,0 +1,557 @@
+//! Dotfiles rendered from templates.
+//!
+//! A template carries a `.tmpl` extension in the repo, `<dotfiles>/.gitconfig.tmpl` targets
+//! `<base>/.gitconfig`. Instead of a symlink, linking writes the rendered template to the
+//! target. Everything between `{{` and `}}` is an expression:
+//!
+//! - `{{ name }}` inserts the [variable](crate::vars) `name`
+//! - `{{ dofi.hostname }}`, `dofi.os`, `dofi.distro`, `dofi.arch`, `dofi.username` and
+//!   `dofi.home` insert what dofi knows about this machine, see [`builtin_variables`]
+//! - `{{ "text" }}` inserts `text`, e.g. `{{ "{{" }}` for literal braces
+//! - `{{ env "EDITOR" }}` inserts an environment variable
+//! - `{{ has "brew" }}` inserts `true` if `brew` is on the `PATH` and `false` otherwise
+//! - `{{ secret "github_token" }}` inserts a secret from the configured
+//!   [secret provider](crate::secrets)
+//! - `{{ include "templates/aliases.sh" }}` inserts a partial, rendered with the same context
+//!
+//! Arguments are string literals or variable names.
+//!
+//! `{{#if has "brew" }}…{{else}}…{{/if}}` renders the first part if the expression after `#if`
+//! is true and the part after the optional `{{else}}` otherwise. Anything but an empty value,
+//! `false` and `0` is true, and conditionals can be nested. The expressions of the part that is
+//! left out are not evaluated, so they may use what this machine lacks.
+//!
+//! Partials are shared snippets, e.g. the aliases both `.zshrc.tmpl` and `.bashrc.tmpl`
+//! include. They live in the [`PARTIALS_DIRECTORY`] of a dotfiles directory, which is never
+//! linked itself, but `include` takes any path relative to the dotfiles directory. With
+//! layered dotfiles directories, the partial of the last layer having one wins. A partial may
+//! include other partials, but not itself, directly or through others.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use miette::{NamedSource, SourceSpan};
+
+use crate::{condition, manifest, platform, secrets::SecretProvider, DofiError, Fs};
+
+/// The extension of template dotfiles
+pub const TEMPLATE_EXTENSION: &str = "tmpl";
+
+/// The functions expressions can call
+const FUNCTIONS: [&str; 4] = ["env", "has", "secret", "include"];
+
+/// The directory of a dotfiles directory holding the partials templates include
+pub const PARTIALS_DIRECTORY: &str = "templates";
+
+/// Whether the repo-relative `path` is in the [`PARTIALS_DIRECTORY`]
+pub fn is_partial_path(path: &Path) -> bool {
+    path.starts_with(PARTIALS_DIRECTORY)
+}
+
+/// Whether `path` is a template dotfile
+pub fn is_template(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == TEMPLATE_EXTENSION)
+}
+
+/// The variables describing this machine, available to every template:
+///
+/// - `dofi.hostname`: the short hostname
+/// - `dofi.os`: the operating system, e.g. `linux`, `macos` or `windows`
+/// - `dofi.distro`: the `ID` of the Linux distribution in `/etc/os-release`, e.g. `fedora`
+/// - `dofi.arch`: the CPU architecture, e.g. `x86_64` or `aarch64`
+/// - `dofi.username`: the name of the user running dofi
+/// - `dofi.home`: the home directory of the user
+///
+/// Those that cannot be determined on this machine are left out.
+pub fn builtin_variables() -> BTreeMap<String, String> {
+    let username = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok();
+    let home = platform::home_directory().map(|home| home.to_string_lossy().into_owned());
+
+    [
+        ("hostname", condition::hostname().map(str::to_string)),
+        ("os", Some(std::env::consts::OS.to_string())),
+        ("distro", distro()),
+        ("arch", Some(std::env::consts::ARCH.to_string())),
+        ("username", username),
+        ("home", home),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((format!("dofi.{name}"), value?)))
+    .collect()
+}
+
+/// The `ID` of the Linux distribution
+fn distro() -> Option<String> {
+    let release = std::fs::read_to_string("/etc/os-release").ok()?;
+    release.lines().find_map(|line| {
+        let id = line.strip_prefix("ID=")?.trim_matches('"');
+        (!id.is_empty()).then(|| id.to_string())
+    })
+}
+
+/// The built-in variables too common as words to be [substituted](templatize)
+const UNSUBSTITUTED: [&str; 3] = ["dofi.os", "dofi.distro", "dofi.arch"];
+
+/// Turns `text` into a template rendering to `text` again with `variables`, escaping its
+/// braces and replacing the values of the variables by expressions inserting them where they
+/// appear as whole words, `/home/jane/.cache` becoming `{{ dofi.home }}/.cache`. Values
+/// shorter than three characters and the operating system, distribution and architecture are
+/// left alone.
+pub fn templatize(text: &str, variables: &BTreeMap<String, String>) -> String {
+    let mut substitutions = variables
+        .iter()
+        .filter(|(name, value)| {
+            value.chars().count() >= 3 && !UNSUBSTITUTED.contains(&name.as_str())
+        })
+        .collect::<Vec<_>>();
+    substitutions.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut template = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut before = None;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("{{") {
+            template.push_str("{{ \"{{\" }}");
+            rest = after;
+            before = Some('{');
+            continue;
+        }
+        let substitution = substitutions.iter().find(|(_, value)| {
+            rest.strip_prefix(value.as_str()).is_some_and(|after| {
+                // Part of a longer word on either side
+                let joined_before = is_word(before) && is_word(value.chars().next());
+                let joined_after =
+                    is_word(value.chars().next_back()) && is_word(after.chars().next());
+                !joined_before && !joined_after
+            })
+        });
+        match substitution {
+            Some((name, value)) => {
+                template.push_str(&format!("{{{{ {name} }}}}"));
+                rest = &rest[value.len()..];
+                before = value.chars().next_back();
+            }
+            None => {
+                template.push(c);
+                rest = &rest[c.len_utf8()..];
+                before = Some(c);
+            }
+        }
+    }
+    template
+}
+
+/// What templates can refer to
+#[derive(Default)]
+pub struct Context {
+    pub variables: BTreeMap<String, String>,
+    /// Looks up `secret` calls, they fail without one
+    pub secrets: Option<Box<dyn SecretProvider>>,
+    /// The layered dotfiles directories `include` looks for partials in, later ones win
+    pub dotfiles_directories: Vec<PathBuf>,
+}
+
+/// What a `{{ … }}` holds
+enum Tag<'a> {
+    /// `#if` and the expression of its condition
+    If(&'a str),
+    Else,
+    EndIf,
+    Expression(&'a str),
+}
+
+impl<'a> Tag<'a> {
+    fn parse(inside: &'a str) -> Tag<'a> {
+        let trimmed = inside.trim();
+        match trimmed.strip_prefix("#if") {
+            Some(condition)
+                if condition.is_empty() || condition.starts_with(char::is_whitespace) =>
+            {
+                Tag::If(condition)
+            }
+            _ if trimmed == "else" => Tag::Else,
+            _ if trimmed == "/if" => Tag::EndIf,
+            _ => Tag::Expression(inside),
+        }
+    }
+}
+
+/// An `{{#if}}` being rendered
+struct Conditional {
+    /// Whether the template around it is rendered
+    outer: bool,
+    condition: bool,
+    in_else: bool,
+    span: Range<usize>,
+}
+
+impl Conditional {
+    fn renders(&self) -> bool {
+        self.outer && self.condition != self.in_else
+    }
+}
+
+/// Whether the value of a condition counts as true
+fn is_true(value: &str) -> bool {
+    !value.is_empty() && value != "false" && value != "0"
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    String(String),
+}
+
+/// Renders `template`, read from `path`, with `context`
+pub fn render(
+    fs: &dyn Fs,
+    template: &str,
+    path: &Path,
+    context: &Context,
+) -> Result<String, DofiError> {
+    render_included(fs, template, path, context, &mut vec![path.to_path_buf()])
+}
+
+/// Renders `template` like [`render`], `including` the templates that included it, itself
+/// last
+fn render_included(
+    fs: &dyn Fs,
+    template: &str,
+    path: &Path,
+    context: &Context,
+    including: &mut Vec<PathBuf>,
+) -> Result<String, DofiError> {
+    let invalid = |message: &str, span: Range<usize>| DofiError::InvalidTemplate {
+        message: message.to_string(),
+        span: SourceSpan::from(span),
+        source_code: NamedSource::new(path.display().to_string(), template.to_string()),
+    };
+
+    let mut value_of = |expression: &str, span: &Range<usize>| {
+        let tokens = tokenize(expression).map_err(|message| invalid(message, span.clone()))?;
+        evaluate(fs, &tokens, context, including).map_err(|error| match error {
+            Evaluation::Invalid(message) => invalid(&message, span.clone()),
+            Evaluation::Failed(error) => error,
+        })
+    };
+
+    let mut rendered = String::with_capacity(template.len());
+    let mut conditionals: Vec<Conditional> = Vec::new();
+    let mut rest = 0;
+    while let Some(start) = template[rest..].find("{{").map(|start| rest + start) {
+        let renders = conditionals.last().is_none_or(Conditional::renders);
+        if renders {
+            rendered.push_str(&template[rest..start]);
+        }
+        let end = find_end(template, start + 2)
+            .ok_or_else(|| invalid("unclosed expression", start..start + 2))?;
+        let span = start..end + 2;
+        rest = end + 2;
+
+        match Tag::parse(&template[start + 2..end]) {
+            Tag::If(condition) => {
+                let condition = renders && is_true(&value_of(condition, &span)?);
+                conditionals.push(Conditional {
+                    outer: renders,
+                    condition,
+                    in_else: false,
+                    span,
+                });
+            }
+            Tag::Else => match conditionals.last_mut() {
+                Some(conditional) if !conditional.in_else => conditional.in_else = true,
+                _ => return Err(invalid("'else' outside of an 'if'", span)),
+            },
+            Tag::EndIf => {
+                conditionals
+                    .pop()
+                    .ok_or_else(|| invalid("'/if' without an 'if'", span))?;
+            }
+            Tag::Expression(expression) if renders => {
+                rendered.push_str(&value_of(expression, &span)?);
+            }
+            Tag::Expression(_) => {}
+        }
+    }
+    if let Some(conditional) = conditionals.pop() {
+        return Err(invalid("unclosed 'if'", conditional.span));
+    }
+    rendered.push_str(&template[rest..]);
+
+    Ok(rendered)
+}
+
+/// Finds the mistakes in `template`, read from `path`, without rendering it: syntax errors,
+/// variables `context` does not define, unknown functions and missing partials. Unlike
+/// [`render`] it goes on after the first mistake, and it neither looks at the environment nor
+/// asks for secrets.
+pub fn lint(fs: &dyn Fs, template: &str, path: &Path, context: &Context) -> Vec<DofiError> {
+    let invalid = |message: String, span: Range<usize>| DofiError::InvalidTemplate {
+        message,
+        span: SourceSpan::from(span),
+        source_code: NamedSource::new(path.display().to_string(), template.to_string()),
+    };
+
+    let mut errors = Vec::new();
+    let mut conditionals = Vec::new();
+    let mut rest = 0;
+    while let Some(start) = template[rest..].find("{{").map(|start| rest + start) {
+        let Some(end) = find_end(template, start + 2) else {
+            errors.push(invalid("unclosed expression".to_string(), start..start + 2));
+            break;
+        };
+        let span = start..end + 2;
+        rest = end + 2;
+        let expression = match Tag::parse(&template[start + 2..end]) {
+            Tag::If(condition) => {
+                conditionals.push(span.clone());
+                condition
+            }
+            Tag::Else if conditionals.is_empty() => {
+                errors.push(invalid("'else' outside of an 'if'".to_string(), span));
+                continue;
+            }
+            Tag::EndIf if conditionals.pop().is_none() => {
+                errors.push(invalid("'/if' without an 'if'".to_string(), span));
+                continue;
+            }
+            Tag::Else | Tag::EndIf => continue,
+            Tag::Expression(expression) => expression,
+        };
+        match tokenize(expression) {
+            Ok(tokens) => errors.extend(
+                lint_expression(fs, &tokens, context)
+                    .into_iter()
+                    .map(|message| invalid(message, span.clone())),
+            ),
+            Err(message) => errors.push(invalid(message.to_string(), span)),
+        }
+    }
+    errors.extend(
+        conditionals
+            .into_iter()
+            .map(|span| invalid("unclosed 'if'".to_string(), span)),
+    );
+    errors
+}
+
+/// What is wrong with the expression made of `tokens`, as [`evaluate`] would report it
+fn lint_expression(fs: &dyn Fs, tokens: &[Token], context: &Context) -> Vec<String> {
+    let undefined = |token: &Token| match token {
+        Token::Identifier(name) if !context.variables.contains_key(name) => {
+            Some(format!("undefined variable '{name}'"))
+        }
+        _ => None,
+    };
+
+    match tokens {
+        [] => vec!["empty expression".to_string()],
+        [token] => undefined(token).into_iter().collect(),
+        [Token::Identifier(function), _] if !FUNCTIONS.contains(&function.as_str()) => {
+            vec![unknown_function(function)]
+        }
+        [Token::Identifier(function), argument] => {
+            let mut problems = undefined(argument).into_iter().collect::<Vec<_>>();
+            if let ("include", Token::String(partial)) = (function.as_str(), argument) {
+                problems.extend(find_partial(fs, Path::new(partial), context).err());
+            }
+            problems
+        }
+        [Token::Identifier(function), ..] => {
+            vec![format!("'{function}' takes exactly one argument")]
+        }
+        [Token::String(_), ..] => {
+            vec!["expected a function name before the arguments".to_string()]
+        }
+    }
+}
+
+/// Finds the `}}` closing the expression starting at `from`, skipping string literals
+fn find_end(template: &str, from: usize) -> Option<usize> {
+    let bytes = template.as_bytes();
+    let mut index = from;
+    let mut in_string = false;
+    while index + 1 < bytes.len() {
+        match bytes[index] {
+            b'\\' if in_string => index += 1,
+            b'"' => in_string = !in_string,
+            b'}' if !in_string && bytes[index + 1] == b'}' => return Some(index),
+            _ => {}
+        }
+        index += 1;
+    }
+    None
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some(c) => string.push(c),
+                        None => return Err("unterminated string"),
+                    },
+                    Some(c) => string.push(c),
+                    None => return Err("unterminated string"),
+                }
+            }
+            tokens.push(Token::String(string));
+        } else if c.is_alphanumeric() || c == '_' {
+            let mut identifier = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.' || c == '-') {
+                    break;
+                }
+                identifier.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Identifier(identifier));
+        } else {
+            return Err("unexpected character, expected a name or a string");
+        }
+    }
+
+    Ok(tokens)
+}
+
+enum Evaluation {
+    /// The expression itself is wrong, reported with its span
+    Invalid(String),
+    /// Evaluating a valid expression failed
+    Failed(DofiError),
+}
+
+fn evaluate(
+    fs: &dyn Fs,
+    tokens: &[Token],
+    context: &Context,
+    including: &mut Vec<PathBuf>,
+) -> Result<String, Evaluation> {
+    let value = |token: &Token| match token {
+        Token::String(string) => Ok(string.clone()),
+        Token::Identifier(name) => context
+            .variables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Evaluation::Invalid(format!("undefined variable '{name}'"))),
+    };
+
+    match tokens {
+        [] => Err(Evaluation::Invalid("empty expression".to_string())),
+        [token] => value(token),
+        [Token::Identifier(function), arguments @ ..] => {
+            let arguments = arguments.iter().map(value).collect::<Result<Vec<_>, _>>()?;
+            call(fs, function, &arguments, context, including)
+        }
+        [Token::String(_), ..] => Err(Evaluation::Invalid(
+            "expected a function name before the arguments".to_string(),
+        )),
+    }
+}
+
+fn call(
+    fs: &dyn Fs,
+    function: &str,
+    arguments: &[String],
+    context: &Context,
+    including: &mut Vec<PathBuf>,
+) -> Result<String, Evaluation> {
+    let [argument] = arguments else {
+        return Err(Evaluation::Invalid(format!(
+            "'{function}' takes exactly one argument"
+        )));
+    };
+
+    match function {
+        "env" => std::env::var(argument).map_err(|_| {
+            Evaluation::Invalid(format!("environment variable '{argument}' is not set"))
+        }),
+        "has" => Ok(platform::find_executable(OsStr::new(argument))
+            .is_some()
+            .to_string()),
+        "secret" => context
+            .secrets
+            .as_ref()
+            .ok_or(Evaluation::Failed(DofiError::NoSecretProvider))?
+            .lookup(argument)
+            .map_err(Evaluation::Failed),
+        "include" => include(fs, Path::new(argument), context, including),
+        _ => Err(Evaluation::Invalid(unknown_function(function))),
+    }
+}
+
+fn unknown_function(function: &str) -> String {
+    format!("unknown function '{function}', expected 'env', 'has', 'secret' or 'include'")
+}
+
+/// The partial at the repo-relative `path` in the last layer having it
+fn find_partial(fs: &dyn Fs, path: &Path, context: &Context) -> Result<PathBuf, String> {
+    if manifest::validate_repo_path(path).is_err() {
+        return Err(format!(
+            "'{}' is not a path inside the dotfiles directory",
+            path.display()
+        ));
+    }
+    context
+        .dotfiles_directories
+        .iter()
+        .rev()
+        .map(|directory| directory.join(path))
+        .find(|partial| fs.exists(partial))
+        .ok_or_else(|| format!("there is no partial '{}'", path.display()))
+}
+
+/// Renders the partial at the repo-relative `path`
+fn include(
+    fs: &dyn Fs,
+    path: &Path,
+    context: &Context,
+    including: &mut Vec<PathBuf>,
+) -> Result<String, Evaluation> {
+    let partial = find_partial(fs, path, context).map_err(Evaluation::Invalid)?;
+
+    if let Some(first) = including.iter().position(|template| *template == partial) {
+        let cycle = including[first..]
+            .iter()
+            .chain([&partial])
+            .map(|template| format!("'{}'", template.display()))
+            .collect::<Vec<_>>();
+        return Err(Evaluation::Invalid(format!(
+            "include cycle, {}",
+            cycle.join(" includes ")
+        )));
+    }
+
+    let template = fs
+        .read(&partial)
+        .map_err(|e| Evaluation::Failed(e.into()))?;
+    including.push(partial.clone());
+    let rendered = render_included(
+        fs,
+        &String::from_utf8_lossy(&template),
+        &partial,
+        context,
+        including,
+    );
+    including.pop();
+    rendered.map_err(Evaluation::Failed)
+}
@@ -0,0 +1,234 @@
+//! Turning the per-host variants of a dotfile into a single template.
+//!
+//! Machines that each keep their own variant of a file, `.gitconfig.hostname-laptop` and
+//! `.gitconfig.hostname-desktop`, tend to drift apart in the lines they share as well.
+//! `dofi templatify .gitconfig` keeps the lines all variants have in common as they are and
+//! turns every stretch of lines where they differ into a [variable](crate::vars),
+//! `{{ gitconfig_1 }}`. The value each machine had goes to its `vars.d/<hostname>.toml`, and
+//! that of the unconditional `.gitconfig`, if there is one, to `vars.toml` for every other
+//! machine. Without an unconditional variant the template is limited to the hosts that had
+//! one in the manifest. The variants are removed, `dofi undo` brings them back.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    condition::{self, Condition},
+    diff, encryption, list_files, manifest, template, vars, DofiError, Fs, Journal,
+};
+
+/// What [`templatify`] made of the variants of a dotfile
+#[derive(Debug)]
+pub struct Templatified {
+    /// The template that took the place of the variants
+    pub template: PathBuf,
+    /// The variants that were removed
+    pub variants: Vec<PathBuf>,
+    /// The names of the variables holding the lines the variants differ in
+    pub variables: Vec<String>,
+}
+
+/// A variant of the dotfile, for the host it is named after or for every other one
+struct Variant {
+    host: Option<String>,
+    source: PathBuf,
+    contents: String,
+}
+
+/// Replaces the variants of the dotfile at the repo-relative `path` that are limited to single
+/// hosts in `dotfiles_directory` with a template, see the [module documentation](self).
+/// `path` may be a target in `base_directory` or any of the variants as well.
+pub fn templatify(
+    fs: &dyn Fs,
+    path: &Path,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    journal: &mut Journal,
+) -> Result<Templatified, DofiError> {
+    let relative = path
+        .strip_prefix(dotfiles_directory)
+        .map(condition::strip_suffix)
+        .or_else(|_| path.strip_prefix(base_directory).map(Path::to_path_buf))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let variants = variants(fs, &relative, dotfiles_directory)?;
+    let hosts = variants
+        .iter()
+        .filter_map(|variant| variant.host.clone())
+        .collect::<Vec<_>>();
+    if variants.len() < 2 || hosts.is_empty() {
+        return Err(DofiError::NoHostVariants(relative));
+    }
+
+    let mut template_path = dotfiles_directory.join(&relative).into_os_string();
+    template_path.push(".");
+    template_path.push(template::TEMPLATE_EXTENSION);
+    let template_path = PathBuf::from(template_path);
+    if fs.exists(&template_path) {
+        return Err(DofiError::FileExists(template_path));
+    }
+
+    let files = variants
+        .iter()
+        .map(|variant| vars::vars_file(dotfiles_directory, variant.host.as_deref()))
+        .collect::<Vec<_>>();
+    let mut taken = Vec::new();
+    for file in &files {
+        taken.extend(vars::names(fs, file)?);
+    }
+    let stem = variable_stem(&relative);
+    let mut names = (1..)
+        .map(|n| format!("{stem}_{n}"))
+        .filter(|name| !taken.contains(name));
+
+    let lines = variants
+        .iter()
+        .map(|variant| variant.contents.split_inclusive('\n').collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let common = lines[1..].iter().fold(lines[0].clone(), |common, lines| {
+        diff::common(&common, lines)
+    });
+
+    // The text all variants share is collected in `literal` and escaped in one go, so braces
+    // on either side of a line break still are
+    let mut template = String::new();
+    let mut literal = String::new();
+    let mut values = vec![BTreeMap::new(); variants.len()];
+    let mut variables = Vec::new();
+    let mut positions = vec![0; variants.len()];
+    for shared in common.iter().map(Some).chain([None]) {
+        // What each variant holds before the next shared line, or up to its end
+        let stretches = lines
+            .iter()
+            .zip(&mut positions)
+            .map(|(lines, position)| {
+                let start = *position;
+                while *position < lines.len() && Some(&lines[*position]) != shared {
+                    *position += 1;
+                }
+                let stretch = lines[start..*position].concat();
+                *position += 1;
+                stretch
+            })
+            .collect::<Vec<_>>();
+        if stretches.iter().all(|stretch| *stretch == stretches[0]) {
+            literal.push_str(&stretches[0]);
+        } else {
+            let name = names.next().expect("the variable names are unbounded");
+            let brace = literal.ends_with('{');
+            if brace {
+                literal.pop();
+            }
+            template.push_str(&template::templatize(&literal, &BTreeMap::new()));
+            if brace {
+                template.push_str("{{ \"{\" }}");
+            }
+            literal.clear();
+            template.push_str(&format!("{{{{ {name} }}}}"));
+            for (values, stretch) in values.iter_mut().zip(stretches) {
+                values.insert(name.clone(), stretch);
+            }
+            variables.push(name);
+        }
+        if let Some(shared) = shared {
+            literal.push_str(shared);
+        }
+    }
+    template.push_str(&template::templatize(&literal, &BTreeMap::new()));
+
+    info!(
+        "Turning {} variants of '{}' into '{}'",
+        variants.len(),
+        relative.display(),
+        template_path.display()
+    );
+    for (file, values) in files.iter().zip(&values) {
+        if !values.is_empty() {
+            vars::set(fs, file, values, journal)?;
+        }
+    }
+    journal.write_file(fs, &template_path, template.as_bytes())?;
+    if variants.iter().all(|variant| variant.host.is_some()) {
+        let relative_template = template_path
+            .strip_prefix(dotfiles_directory)
+            .unwrap_or(&template_path);
+        manifest::set_hosts(fs, dotfiles_directory, relative_template, &hosts, journal)?;
+    }
+    for variant in &variants {
+        journal.remove_file(fs, &variant.source)?;
+    }
+
+    Ok(Templatified {
+        template: template_path,
+        variants: variants.into_iter().map(|variant| variant.source).collect(),
+        variables,
+    })
+}
+
+/// The plain variants of the dotfile at the repo-relative `path`, the unconditional one first
+fn variants(
+    fs: &dyn Fs,
+    path: &Path,
+    dotfiles_directory: &Path,
+) -> Result<Vec<Variant>, DofiError> {
+    let mut variants = Vec::new();
+    for source in list_files(fs, dotfiles_directory)? {
+        let Ok(relative) = source.strip_prefix(dotfiles_directory) else {
+            continue;
+        };
+        let plain = !template::is_template(relative) && !encryption::is_encrypted(relative);
+        let name = if plain {
+            relative.to_path_buf()
+        } else {
+            relative.with_extension("")
+        };
+        if condition::strip_suffix(&name) != path {
+            continue;
+        }
+        let host = match condition::condition(relative) {
+            None => None,
+            Some(Condition::Hostname(host)) => Some(host),
+            Some(Condition::Os(_)) => continue,
+        };
+        if !plain {
+            return Err(DofiError::NotTemplatable(
+                source.clone(),
+                "it is a template or encrypted".to_string(),
+            ));
+        }
+        let contents = String::from_utf8(fs.read(&source)?)
+            .map_err(|_| DofiError::FileIsNotText(source.clone()))?;
+        variants.push(Variant {
+            host,
+            source,
+            contents,
+        });
+    }
+    variants.sort_by(|a, b| a.host.cmp(&b.host));
+    Ok(variants)
+}
+
+/// The start of the names of the variables for the dotfile at `path`, its name without the
+/// leading dot in lowercase letters, digits and underscores
+fn variable_stem(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name
+        .trim_start_matches('.')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if stem.is_empty() {
+        "dotfile".to_string()
+    } else {
+        stem
+    }
+}
@@ -0,0 +1,100 @@
+//! A summary of where a run spent its time, to diagnose slow runs on network home directories
+//! and huge repos.
+//!
+//! Nothing is recorded until [`enable`] was called, which the binary does for `--timings`.
+//! Afterwards every [`phase`] adds its duration to the total of its name, walking the
+//! dotfiles, planning and applying the links and running hooks, and every operation of the
+//! real filesystem is counted. The binary prints the [`report`] to stderr when the command
+//! finishes.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static OPERATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The total duration and number of runs of each phase, in the order they first ran
+static PHASES: Mutex<Vec<(&'static str, Duration, usize)>> = Mutex::new(Vec::new());
+
+/// Records the phases and operations of everything that follows
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// A phase of the run, its duration is recorded once it is dropped
+pub struct Phase {
+    name: &'static str,
+    started: Option<Instant>,
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        let Some(started) = self.started else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        let mut phases = PHASES.lock().unwrap_or_else(|e| e.into_inner());
+        match phases.iter_mut().find(|(name, ..)| *name == self.name) {
+            Some((_, total, runs)) => {
+                *total += elapsed;
+                *runs += 1;
+            }
+            None => phases.push((self.name, elapsed, 1)),
+        }
+    }
+}
+
+/// Starts the phase `name`, which lasts until the returned guard is dropped
+pub fn phase(name: &'static str) -> Phase {
+    Phase {
+        name,
+        started: ENABLED.load(Ordering::Relaxed).then(Instant::now),
+    }
+}
+
+/// Counts `count` more filesystem operations
+pub fn count_operations(count: usize) {
+    if ENABLED.load(Ordering::Relaxed) {
+        OPERATIONS.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// The total duration and number of runs of each recorded phase, in the order they first ran
+pub fn phases() -> Vec<(&'static str, Duration, usize)> {
+    PHASES.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The number of recorded filesystem operations
+pub fn operations() -> usize {
+    OPERATIONS.load(Ordering::Relaxed)
+}
+
+/// The recorded phases and operations, one per line, if enabled
+pub fn report() -> Option<String> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let phases = PHASES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut report = String::new();
+    for (name, total, runs) in phases.iter() {
+        let runs = if *runs > 1 {
+            format!(" ({runs} runs)")
+        } else {
+            String::new()
+        };
+        report.push_str(&format!(
+            "{name:<8}{:>9.1}ms{runs}\n",
+            total.as_secs_f64() * 1000.0
+        ));
+    }
+    report.push_str(&format!(
+        "{} filesystem operations\n",
+        OPERATIONS.load(Ordering::Relaxed)
+    ));
+    Some(report)
+}
@@ -0,0 +1,340 @@
+//! Noticing config files that appear in watched directories, so the configs of freshly
+//! installed tools do not silently go unmanaged.
+//!
+//! `dofi track --watch ~/.config` watches directories until interrupted, together with those
+//! listed in the `track` section of the user configuration:
+//!
+//! ```toml
+//! [track]
+//! review = ["~/.config"]
+//! add = ["~/.config/nvim"]
+//! ignore = ["**/*.log", "~/.config/*/Cache/**"]
+//! ```
+//!
+//! New files below a `review` directory are queued in `tracked.json` in the state directory,
+//! and `dofi status` lists them until they are added, deleted or dismissed with
+//! `dofi track --dismiss`. New files below an `add` directory are added right away, like with
+//! `dofi add`. If a file lies below several directories the deepest one decides. Relative
+//! paths and patterns are relative to the base directory. Files matching an `ignore` pattern,
+//! symlinks and files in the dotfiles or the state directory are left alone.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{info, warn};
+use notify::{event::ModifyKind, Event, EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    add_file, config::expand_path, fs::FileType, layered_dotfiles, lock, DofiError, Fs, Journal,
+    OsFs,
+};
+
+/// The file in the state directory holding the files queued for review
+const STATE_FILE: &str = "tracked.json";
+
+/// How long to wait for further files before sorting them out, tools tend to write their
+/// configuration in bursts
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The `track` section of the user configuration
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrackConfig {
+    /// Directories whose new files are queued for review
+    #[serde(default)]
+    pub review: Vec<PathBuf>,
+    /// Directories whose new files are added right away
+    #[serde(default)]
+    pub add: Vec<PathBuf>,
+    /// Glob patterns on the paths of files that are never tracked
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+/// What happens to a new file below a watched directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tracking {
+    /// It is queued for review in `dofi status`
+    Review,
+    /// It is added to the dotfiles
+    Add,
+}
+
+/// The watched directories, resolved against the base directory
+#[derive(Debug, Default)]
+pub struct Rules {
+    directories: Vec<(PathBuf, Tracking)>,
+    ignore: GlobSet,
+}
+
+impl Rules {
+    /// The rules of `config` together with the `watched` directories, whose files are tracked
+    /// with `tracking`
+    pub fn new(
+        config: &TrackConfig,
+        watched: &[PathBuf],
+        tracking: Tracking,
+        base_directory: &Path,
+    ) -> Result<Self, DofiError> {
+        let resolve = |path: &Path| base_directory.join(expand_path(path));
+        let directories = config
+            .review
+            .iter()
+            .map(|directory| (resolve(directory), Tracking::Review))
+            .chain(
+                config
+                    .add
+                    .iter()
+                    .map(|directory| (resolve(directory), Tracking::Add)),
+            )
+            .chain(
+                watched
+                    .iter()
+                    .map(|directory| (resolve(directory), tracking)),
+            )
+            .collect();
+
+        let mut ignore = GlobSetBuilder::new();
+        for pattern in &config.ignore {
+            let absolute = resolve(Path::new(pattern));
+            let glob = Glob::new(&absolute.to_string_lossy())
+                .map_err(|e| DofiError::InvalidPattern(pattern.clone(), e.to_string()))?;
+            ignore.add(glob);
+        }
+        let ignore = ignore
+            .build()
+            .map_err(|e| DofiError::InvalidPattern(config.ignore.join(", "), e.to_string()))?;
+
+        Ok(Self {
+            directories,
+            ignore,
+        })
+    }
+
+    /// The watched directories
+    pub fn directories(&self) -> impl Iterator<Item = &Path> {
+        self.directories
+            .iter()
+            .map(|(directory, _)| directory.as_path())
+    }
+
+    /// What happens to a new file at `path`, nothing if it is not below a watched directory or
+    /// is ignored
+    pub fn tracking(&self, path: &Path) -> Option<Tracking> {
+        if self.ignore.is_match(path) {
+            return None;
+        }
+        self.directories
+            .iter()
+            .filter(|(directory, _)| path.starts_with(directory))
+            .max_by_key(|(directory, _)| directory.components().count())
+            .map(|(_, tracking)| *tracking)
+    }
+}
+
+/// The new files [`notice`] added and queued
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Noticed {
+    pub added: Vec<PathBuf>,
+    pub queued: Vec<PathBuf>,
+}
+
+/// Sorts out the `created` files in `base_directory` by the `rules`: those to review are
+/// queued in the state directory of `journal`, those to add are added to
+/// `dotfiles_directory`. Files that are not regular files, that are already targets of the
+/// layered `dotfiles_directories` or that lie in one of them or the state directory are left
+/// alone.
+pub fn notice(
+    fs: &dyn Fs,
+    rules: &Rules,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    created: &[PathBuf],
+    journal: &mut Journal,
+) -> Result<Noticed, DofiError> {
+    let state_directory = journal.directory().to_path_buf();
+    let mut noticed = Noticed::default();
+    let mut candidates = created
+        .iter()
+        .filter(|path| {
+            !path.starts_with(&state_directory)
+                && !dotfiles_directories
+                    .iter()
+                    .any(|layer| path.starts_with(layer))
+                && fs
+                    .symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type == FileType::File)
+        })
+        .filter_map(|path| Some((path, rules.tracking(path)?)))
+        .peekable();
+    if candidates.peek().is_none() {
+        return Ok(noticed);
+    }
+
+    let managed = layered_dotfiles(fs, base_directory, dotfiles_directories)?
+        .into_iter()
+        .map(|dotfile| dotfile.target)
+        .collect::<BTreeSet<_>>();
+    for (path, tracking) in candidates.filter(|(path, _)| !managed.contains(*path)) {
+        match tracking {
+            Tracking::Review => noticed.queued.push(path.clone()),
+            Tracking::Add => {
+                info!("Adding '{}'", path.display());
+                add_file(fs, path, base_directory, dotfiles_directory, None, journal)?;
+                noticed.added.push(path.clone());
+            }
+        }
+    }
+
+    if !noticed.queued.is_empty() {
+        let mut queue = load(fs, &state_directory)?;
+        queue.extend(noticed.queued.iter().cloned());
+        save(fs, &state_directory, queue)?;
+    }
+    Ok(noticed)
+}
+
+/// The files queued for review in `state_directory` that still exist and are not yet targets
+/// of the layered `dotfiles_directories`
+pub fn pending(
+    fs: &dyn Fs,
+    state_directory: &Path,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+) -> Result<Vec<PathBuf>, DofiError> {
+    let queue = load(fs, state_directory)?;
+    if queue.is_empty() {
+        return Ok(Vec::new());
+    }
+    let managed = layered_dotfiles(fs, base_directory, dotfiles_directories)?
+        .into_iter()
+        .map(|dotfile| dotfile.target)
+        .collect::<BTreeSet<_>>();
+    Ok(queue
+        .into_iter()
+        .filter(|path| {
+            !managed.contains(path)
+                && fs
+                    .symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type == FileType::File)
+        })
+        .collect())
+}
+
+/// Removes `files` from the review queue in `state_directory`, returning how many were queued
+pub fn dismiss(fs: &dyn Fs, state_directory: &Path, files: &[PathBuf]) -> Result<usize, DofiError> {
+    let mut queue = load(fs, state_directory)?;
+    let queued = queue.len();
+    queue.retain(|path| !files.contains(path));
+    let dismissed = queued - queue.len();
+    if dismissed > 0 {
+        save(fs, state_directory, queue)?;
+    }
+    Ok(dismissed)
+}
+
+/// Watches the directories of `rules` and [notices](notice) the files created in them, until
+/// interrupted. Each batch of files holds the [lock](lock) and is recorded in the journal at
+/// `state_directory` as its own operation. `added` is called with the paths each batch
+/// touched, to commit them.
+pub fn run(
+    rules: &Rules,
+    base_directory: &Path,
+    dotfiles_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    state_directory: &Path,
+    added: &dyn Fn(&BTreeSet<PathBuf>, &[PathBuf]),
+) -> Result<(), DofiError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for directory in rules.directories() {
+        if OsFs.exists(directory) {
+            watcher.watch(directory, RecursiveMode::Recursive)?;
+            info!("Watching '{}' for new files", directory.display());
+        } else {
+            warn!("Not watching '{}', it does not exist", directory.display());
+        }
+    }
+
+    let mut created = Vec::new();
+    loop {
+        while created.is_empty() {
+            let Ok(event) = receiver.recv() else {
+                return Ok(());
+            };
+            add_creations(&mut created, event?);
+        }
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            add_creations(&mut created, event?);
+        }
+        created.sort();
+        created.dedup();
+
+        let lock = lock::acquire(&lock::lock_path(state_directory), true)?;
+        let mut journal = Journal::new(state_directory, "track");
+        let result = notice(
+            &OsFs,
+            rules,
+            base_directory,
+            dotfiles_directory,
+            dotfiles_directories,
+            &created,
+            &mut journal,
+        );
+        let touched = journal.touched_paths();
+        journal.commit(&OsFs)?;
+        drop(lock);
+        match result {
+            Ok(noticed) => {
+                for path in &noticed.queued {
+                    info!("Queued '{}' for review", path.display());
+                }
+                if !noticed.added.is_empty() {
+                    added(&touched, &noticed.added);
+                }
+            }
+            Err(e) => warn!("Failed to track the new files: {e}"),
+        }
+        created.clear();
+    }
+}
+
+/// Adds the files created or moved into place by `event`
+fn add_creations(created: &mut Vec<PathBuf>, event: Event) {
+    // Tools often write their configuration to a temporary file and rename it into place
+    if matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+    ) {
+        created.extend(event.paths);
+    }
+}
+
+/// The review queue as it is stored
+#[derive(Serialize, Deserialize)]
+struct Queue(#[serde(with = "crate::paths::serde::set")] BTreeSet<PathBuf>);
+
+fn load(fs: &dyn Fs, state_directory: &Path) -> Result<BTreeSet<PathBuf>, DofiError> {
+    match fs.read(&state_directory.join(STATE_FILE)) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(|Queue(queue)| queue)
+            .map_err(DofiError::InvalidTrackState),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(fs: &dyn Fs, state_directory: &Path, queue: BTreeSet<PathBuf>) -> Result<(), DofiError> {
+    let contents =
+        serde_json::to_vec_pretty(&Queue(queue)).map_err(DofiError::InvalidTrackState)?;
+    fs.create_dir_all(state_directory)?;
+    fs.write(&state_directory.join(STATE_FILE), &contents)?;
+    Ok(())
+}
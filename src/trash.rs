@@ -0,0 +1,199 @@
+//! Sending removed dotfiles to the trash, so an accidental `remove` can be recovered with the
+//! desktop's own tools long after `dofi undo` stopped being an option.
+//!
+//! On Linux and the BSDs a copy of the file goes to the home trash of the freedesktop.org
+//! specification, `$XDG_DATA_HOME/Trash`, with the `.trashinfo` file file managers need to put
+//! it back. The name keeps the bytes of the original, and the deletion date is written in local
+//! time, as the specification asks. On macOS the Finder and on Windows the Recycle Bin take the
+//! copy, through `osascript` and PowerShell, and only know it by its name.
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::{paths, DofiError, Fs};
+
+/// The home trash of the freedesktop.org specification
+pub fn directory(base_directory: &Path) -> PathBuf {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| base_directory.join(".local/share"))
+        .join("Trash")
+}
+
+/// Puts a copy of `file` into the trash, the caller removes the file itself
+pub fn trash(fs: &dyn Fs, file: &Path, base_directory: &Path) -> Result<(), DofiError> {
+    info!("Moving a copy of '{}' to the trash", file.display());
+    if cfg!(any(target_os = "macos", windows)) {
+        native::trash(file)
+    } else {
+        freedesktop(fs, file, &directory(base_directory))
+    }
+}
+
+fn freedesktop(fs: &dyn Fs, file: &Path, trash: &Path) -> Result<(), DofiError> {
+    let files = trash.join("files");
+    let infos = trash.join("info");
+    fs.create_dir_all(&files)?;
+    fs.create_dir_all(&infos)?;
+
+    let name = file.file_name().unwrap_or_default();
+    let (name, info) = (1..)
+        .map(|n| {
+            let mut numbered = name.to_os_string();
+            if n > 1 {
+                numbered.push(format!(".{n}"));
+            }
+            numbered
+        })
+        .map(|name| {
+            let mut info = name.clone();
+            info.push(".trashinfo");
+            (name, infos.join(info))
+        })
+        .find(|(name, info)| !fs.exists(&files.join(name)) && !fs.exists(info))
+        .expect("the names are unbounded");
+
+    // The info file goes first, it is what reserves the name
+    let contents = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        encode_path(file),
+        deletion_date()
+    );
+    fs.write(&info, contents.as_bytes())?;
+    fs.copy(file, &files.join(name))?;
+    Ok(())
+}
+
+/// Percent-encodes the bytes of `path` for the `Path` key, keeping the separators
+fn encode_path(path: &Path) -> String {
+    paths::bytes(path)
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                char::from(byte).to_string()
+            }
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// The current time as `YYYY-MM-DDThh:mm:ss` in local time, or in UTC where the time zone
+/// is unknown
+fn deletion_date() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    local_date(seconds).unwrap_or_else(|| utc_date(seconds))
+}
+
+/// `seconds` since the epoch as a date in the local time zone
+#[cfg(unix)]
+fn local_date(seconds: u64) -> Option<String> {
+    let time = libc::time_t::try_from(seconds).ok()?;
+    // SAFETY: `tm` is plain data, all zeroes is a valid value of it
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    // SAFETY: both pointers are valid for the duration of the call
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        i64::from(tm.tm_year) + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    ))
+}
+
+#[cfg(not(unix))]
+fn local_date(_seconds: u64) -> Option<String> {
+    None
+}
+
+/// `seconds` since the epoch as a date in UTC
+fn utc_date(seconds: u64) -> String {
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Days since the epoch to the civil date, after Howard Hinnant's `civil_from_days`
+    let days = days as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+mod native {
+    use std::{path::Path, process::Command};
+
+    use crate::{fs::ScratchDirectory, DofiError};
+
+    /// Hands a copy of `file` to the trash of the desktop, keeping its name
+    pub fn trash(file: &Path) -> Result<(), DofiError> {
+        let parent = std::env::temp_dir();
+        let scratch = ScratchDirectory::new(&parent, "dofi-trash")?;
+        let copy = scratch.path().join(file.file_name().unwrap_or_default());
+        std::fs::copy(file, &copy)?;
+
+        let copy = copy.to_string_lossy();
+        let (program, args) = if cfg!(windows) {
+            (
+                "powershell",
+                vec![
+                    "-NoProfile".to_string(),
+                    "-Command".to_string(),
+                    format!(
+                        "Add-Type -AssemblyName Microsoft.VisualBasic; \
+                         [Microsoft.VisualBasic.FileIO.FileSystem]::DeleteFile('{}', \
+                         'OnlyErrorDialogs', 'SendToRecycleBin')",
+                        copy.replace('\'', "''")
+                    ),
+                ],
+            )
+        } else {
+            (
+                "osascript",
+                vec![
+                    "-e".to_string(),
+                    format!(
+                        "tell application \"Finder\" to delete POSIX file \"{}\"",
+                        copy.replace('\\', "\\\\").replace('"', "\\\"")
+                    ),
+                ],
+            )
+        };
+
+        let status = Command::new(program)
+            .args(&args)
+            .stdout(std::process::Stdio::null())
+            .status()
+            .map_err(|e| DofiError::ExternalCommandFailed(program.to_string(), e.to_string()))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(DofiError::ExternalCommandFailed(
+                program.to_string(),
+                format!("exited with {status}"),
+            ))
+        }
+    }
+}
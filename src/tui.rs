@@ -0,0 +1,123 @@
+//! An interactive dashboard of the dotfiles, line based like the [picker](crate::picker).
+//!
+//! It shows the dotfile tree with the state of each dotfile, numbered, and reads commands
+//! acting on the numbered entries: `link 3` links a dotfile, `force 3` resolves a conflict by
+//! replacing the target, `unlink 3` removes a linked target, `diff 3` and `edit 3` show and
+//! open a dotfile, `apply` links everything, `undo` reverts the last operation and `quit`
+//! leaves. `filter state:conflict` only shows the dotfiles matching a [query](crate::query),
+//! `filter` alone shows all of them again. Commands other than `undo` and `filter` can be
+//! shortened to their first letter. The tree is shown again after every command, so it always
+//! reflects the current state.
+
+use log::info;
+
+use crate::{
+    checksum,
+    status::{self, Entry, State},
+    DofiError, Fs, Journal,
+};
+
+/// The commands of the dashboard, shown on `help`
+pub const HELP: &str = "\
+link <n>    link the dotfile
+force <n>   replace the target of the dotfile
+unlink <n>  remove the target of the dotfile
+diff <n>    show how a conflicting target differs from the dotfile
+edit <n>    open the dotfile in the editor
+apply       link all dotfiles
+undo        revert the last operation
+filter <q>  only show the dotfiles matching the query, all of them without one
+help        show this help
+quit        leave, as does an empty line";
+
+/// A command entered in the dashboard, with the index of the entry it acts on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Link(usize),
+    Force(usize),
+    Unlink(usize),
+    Diff(usize),
+    Edit(usize),
+    Apply,
+    Undo,
+    /// Show only the entries matching the query
+    Filter(String),
+    Help,
+    Quit,
+}
+
+impl Action {
+    /// Parses `line`, `None` if it is not a command or refers to an entry number below 1
+    pub fn parse(line: &str) -> Option<Action> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("quit");
+        if command == "filter" {
+            return Some(Action::Filter(words.collect::<Vec<_>>().join(" ")));
+        }
+        let entry = words
+            .next()
+            .and_then(|number| number.parse::<usize>().ok())
+            .and_then(|number| number.checked_sub(1));
+        if words.next().is_some() {
+            return None;
+        }
+
+        let matches = |name: &str| command == name || command == &name[..1];
+        let action = if matches("link") {
+            Action::Link(entry?)
+        } else if matches("force") {
+            Action::Force(entry?)
+        } else if matches("unlink") {
+            Action::Unlink(entry?)
+        } else if matches("diff") {
+            Action::Diff(entry?)
+        } else if matches("edit") {
+            Action::Edit(entry?)
+        } else if matches("apply") {
+            Action::Apply
+        } else if command == "undo" {
+            Action::Undo
+        } else if matches("help") || command == "?" {
+            Action::Help
+        } else if matches("quit") {
+            Action::Quit
+        } else {
+            return None;
+        };
+        Some(action)
+    }
+
+    /// The index of the entry the action refers to, if any
+    pub fn entry(&self) -> Option<usize> {
+        match self {
+            Action::Link(entry)
+            | Action::Force(entry)
+            | Action::Unlink(entry)
+            | Action::Diff(entry)
+            | Action::Edit(entry) => Some(*entry),
+            Action::Apply | Action::Undo | Action::Filter(_) | Action::Help | Action::Quit => None,
+        }
+    }
+}
+
+/// The dashboard showing `entries`, colored if `color` is set
+pub fn render(entries: &[Entry], color: bool) -> String {
+    let mut output = status::numbered_tree(entries, color);
+    let count = |state| entries.iter().filter(|entry| entry.state == state).count();
+    output.push_str(&format!(
+        "{} linked, {} unlinked, {} conflicts, {} broken\n",
+        count(State::Linked),
+        count(State::Unlinked),
+        count(State::Conflict),
+        count(State::Broken)
+    ));
+    output
+}
+
+/// Removes the target of the linked `entry`, recording it in `journal`
+pub fn unlink(fs: &dyn Fs, entry: &Entry, journal: &mut Journal) -> Result<(), DofiError> {
+    // Rendered, decrypted and private targets may have been changed since
+    checksum::ensure_unmodified(fs, &entry.target, journal)?;
+    info!("Unlinking '{}'", entry.target.display());
+    journal.remove_file(fs, &entry.target)
+}
@@ -0,0 +1,161 @@
+//! Updating the dofi binary from the GitHub releases, for machines no package manager carries
+//! it on.
+//!
+//! `dofi self-update` asks the GitHub API for the latest release of [`REPOSITORY`] and, if it
+//! is newer than the running binary, downloads the asset built for this platform, named like
+//! `dofi-x86_64-linux` or `dofi-aarch64-macos` (with `.exe` on Windows). The asset is only
+//! installed if its SHA-256 digest matches the one published next to it in
+//! `<asset>.sha256`, it then replaces the running binary by renaming it over it. The downloads
+//! go through the system `curl`.
+//!
+//! The digest is an integrity check only. It catches a download that broke on the way, but it
+//! comes from the same release as the binary, so whoever could replace one could replace the
+//! other: that the binary is genuine rests on HTTPS and on the GitHub repository alone.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::info;
+use serde::Deserialize;
+
+use crate::{checksum, platform, DofiError};
+
+/// The GitHub repository releasing dofi
+pub const REPOSITORY: &str = "jsfr/dofi";
+
+/// A release of dofi
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    /// The tag of the release, like `v0.2.0`
+    #[serde(rename = "tag_name")]
+    pub tag: String,
+    pub assets: Vec<Asset>,
+}
+
+/// A file attached to a release
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    #[serde(rename = "browser_download_url")]
+    pub url: String,
+}
+
+impl Release {
+    /// Parses a release as the GitHub API describes it
+    pub fn parse(json: &[u8]) -> Result<Release, DofiError> {
+        serde_json::from_slice(json).map_err(|e| DofiError::InvalidRelease(e.to_string()))
+    }
+
+    /// The version of the release, its tag without the leading `v`
+    pub fn version(&self) -> &str {
+        self.tag.strip_prefix('v').unwrap_or(&self.tag)
+    }
+
+    /// The asset called `name`
+    pub fn asset(&self, name: &str) -> Result<&Asset, DofiError> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| DofiError::InvalidRelease(format!("it has no asset '{name}'")))
+    }
+}
+
+/// The name of the release asset holding the binary for this platform
+pub fn asset_name() -> String {
+    format!(
+        "dofi-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// Whether the dotted `version` is newer than `current`, comparing numbers part by part
+pub fn is_newer(version: &str, current: &str) -> bool {
+    let parts = |version: &str| {
+        version
+            .split(['.', '-', '+'])
+            .map_while(|part| part.parse::<u64>().ok())
+            .collect::<Vec<_>>()
+    };
+    parts(version) > parts(current)
+}
+
+/// The latest release of [`REPOSITORY`]
+pub fn latest_release() -> Result<Release, DofiError> {
+    let url = format!("https://api.github.com/repos/{REPOSITORY}/releases/latest");
+    Release::parse(&curl(&url, None)?)
+}
+
+/// Downloads `asset` of `release`, checks its integrity against the digest published with it
+/// and renames it over `executable`
+pub fn install(release: &Release, asset: &Asset, executable: &Path) -> Result<(), DofiError> {
+    let published = curl(&release.asset(&format!("{}.sha256", asset.name))?.url, None)?;
+    let expected = String::from_utf8_lossy(&published)
+        .split_whitespace()
+        .next()
+        .map(str::to_lowercase)
+        .ok_or_else(|| DofiError::InvalidRelease(format!("'{}.sha256' is empty", asset.name)))?;
+
+    // Next to the binary, so the rename stays on one filesystem
+    let download = sibling(executable, ".dofi-update");
+    info!("Downloading '{}'", asset.url);
+    curl(&asset.url, Some(&download))?;
+    let result = verify_and_replace(&download, &expected, &asset.name, executable);
+    if result.is_err() {
+        let _ = std::fs::remove_file(&download);
+    }
+    result
+}
+
+fn verify_and_replace(
+    download: &Path,
+    expected: &str,
+    name: &str,
+    executable: &Path,
+) -> Result<(), DofiError> {
+    info!("Checking '{name}' against its published digest, for integrity only");
+    if checksum::digest(&std::fs::read(download)?) != expected {
+        return Err(DofiError::ChecksumMismatch(name.to_string()));
+    }
+    platform::set_mode(download, 0o755)?;
+
+    // Windows cannot replace a running binary, but it can rename it out of the way
+    if cfg!(windows) {
+        let old = sibling(executable, ".dofi-old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(executable, &old)?;
+    }
+    info!("Replacing '{}'", executable.display());
+    std::fs::rename(download, executable)?;
+    Ok(())
+}
+
+/// `name` in the directory of `path`
+fn sibling(path: &Path, name: &str) -> PathBuf {
+    path.parent().unwrap_or(Path::new(".")).join(name)
+}
+
+/// Fetches `url`, into `output` if given and returning the body otherwise
+fn curl(url: &str, output: Option<&Path>) -> Result<Vec<u8>, DofiError> {
+    let command_line = format!("curl {url}");
+    let mut command = Command::new("curl");
+    command.arg("-fsSL").arg(url);
+    if let Some(output) = output {
+        command.arg("-o").arg(output);
+    }
+    let result = command
+        .output()
+        .map_err(|e| DofiError::ExternalCommandFailed(command_line.clone(), e.to_string()))?;
+
+    if result.status.success() {
+        Ok(result.stdout)
+    } else {
+        Err(DofiError::ExternalCommandFailed(
+            command_line,
+            String::from_utf8_lossy(&result.stderr).trim().to_string(),
+        ))
+    }
+}
@@ -0,0 +1,155 @@
+//! Variables for templates and hooks, kept in the dotfiles directory.
+//!
+//! `vars.toml` holds the variables of every machine and `vars.d/<hostname>.toml` those of a
+//! single one, overriding them. With layered dotfiles directories the files of later layers
+//! override those of earlier ones:
+//!
+//! ```toml
+//! email = "me@example.com"
+//! font_size = 12
+//! ```
+//!
+//! Templates refer to the variables by name, `{{ email }}`, and hooks get them as environment
+//! variables, `DOFI_VAR_EMAIL`. Neither file is linked.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use miette::{NamedSource, SourceSpan};
+use serde::Deserialize;
+use toml_edit::{value, DocumentMut};
+
+use crate::{condition, config::invalid_config, DofiError, Fs, Journal};
+
+/// The variables of every machine, in the dotfiles directory
+pub const VARS_FILE: &str = "vars.toml";
+/// The directory holding the variables of single machines, `<hostname>.toml`
+pub const HOST_VARS_DIRECTORY: &str = "vars.d";
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    fn into_string(self) -> String {
+        match self {
+            Value::String(value) => value,
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+        }
+    }
+}
+
+/// Whether the repo-relative `path` is one of the variable files, which are never linked
+pub fn is_vars_path(path: &Path) -> bool {
+    path == Path::new(VARS_FILE) || path.starts_with(HOST_VARS_DIRECTORY)
+}
+
+/// Loads the variables of this machine from the layered `dotfiles_directories`, later files
+/// overriding earlier ones
+pub fn load(
+    fs: &dyn Fs,
+    dotfiles_directories: &[PathBuf],
+) -> Result<BTreeMap<String, String>, DofiError> {
+    let mut variables = BTreeMap::new();
+    for dotfiles_directory in dotfiles_directories {
+        let mut files = vec![dotfiles_directory.join(VARS_FILE)];
+        if let Some(hostname) = condition::hostname() {
+            files.push(
+                dotfiles_directory
+                    .join(HOST_VARS_DIRECTORY)
+                    .join(format!("{hostname}.toml")),
+            );
+        }
+
+        for file in files {
+            let Ok(contents) = fs.read(&file) else {
+                continue;
+            };
+            let contents = String::from_utf8_lossy(&contents).into_owned();
+            let values: BTreeMap<String, Value> =
+                toml::from_str(&contents).map_err(|e| invalid_config(&file, contents, e))?;
+            variables.extend(
+                values
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into_string())),
+            );
+        }
+    }
+
+    Ok(variables)
+}
+
+/// The variable file of `dotfiles_directory` for the machine `host`, or that of every machine
+pub fn vars_file(dotfiles_directory: &Path, host: Option<&str>) -> PathBuf {
+    match host {
+        Some(host) => dotfiles_directory
+            .join(HOST_VARS_DIRECTORY)
+            .join(format!("{host}.toml")),
+        None => dotfiles_directory.join(VARS_FILE),
+    }
+}
+
+/// The names of the variables in the variable `file`, none if it does not exist
+pub fn names(fs: &dyn Fs, file: &Path) -> Result<Vec<String>, DofiError> {
+    Ok(document(fs, file)?
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect())
+}
+
+/// Sets the string `variables` in the variable `file`, editing it in place and creating it if
+/// need be
+pub fn set(
+    fs: &dyn Fs,
+    file: &Path,
+    variables: &BTreeMap<String, String>,
+    journal: &mut Journal,
+) -> Result<(), DofiError> {
+    let mut document = document(fs, file)?;
+    for (name, variable) in variables {
+        document[name.as_str()] = value(variable.as_str());
+    }
+    if let Some(parent) = file.parent() {
+        journal.create_dir_all(fs, parent)?;
+    }
+    journal.write_file(fs, file, document.to_string().as_bytes())
+}
+
+/// Reads the variable `file` for editing, a missing file is an empty document
+fn document(fs: &dyn Fs, file: &Path) -> Result<DocumentMut, DofiError> {
+    let contents = fs
+        .read(file)
+        .map(|contents| String::from_utf8_lossy(&contents).into_owned())
+        .unwrap_or_default();
+    contents
+        .parse::<DocumentMut>()
+        .map_err(|e| DofiError::InvalidConfig {
+            message: e.message().to_string(),
+            span: e.span().map(SourceSpan::from),
+            source_code: NamedSource::new(file.display().to_string(), contents.clone()),
+        })
+}
+
+/// The name of the environment variable hooks get the variable `name` as
+pub fn environment_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!("DOFI_VAR_{name}")
+}
@@ -0,0 +1,191 @@
+//! Keeping the targets up to date while the dotfiles change.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use log::{info, warn};
+use notify::{Event, RecursiveMode, Watcher};
+
+use crate::{
+    fs, journal, link_entry, lock, status, status::State, DofiError, Fs, Journal, LinkOptions,
+    Manifest, OsFs,
+};
+
+/// How long to wait for further changes before syncing, editors tend to write in bursts
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Brings the targets in `base_directory` in line with the layered `dotfiles_directories` after
+/// the dotfiles in `changed` were created, modified or removed. Returns the number of
+/// updated targets.
+///
+/// Unlinked dotfiles are linked, encrypted and template dotfiles in `changed` are written to
+/// their targets again and symlinks to removed dotfiles in `changed` are pruned, along with
+/// the directories they leave empty if `prune_empty` is set. Conflicting targets are left
+/// alone and dotfiles that fail to link, like a half-written template, are skipped with a
+/// warning.
+pub fn sync(
+    fs: &dyn Fs,
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    changed: &[PathBuf],
+    options: &LinkOptions,
+    journal: &mut Journal,
+) -> Result<usize, DofiError> {
+    let mut updated = 0;
+
+    for entry in status::entries(fs, base_directory, dotfiles_directories)? {
+        match entry.state {
+            State::Unlinked => {}
+            State::Linked if changed.contains(&entry.source) && entry.strategy.is_copied() => {}
+            State::Linked => continue,
+            State::Conflict | State::Broken => {
+                if changed.contains(&entry.source) {
+                    warn!(
+                        "Not linking '{}', it already exists",
+                        entry.target.display()
+                    );
+                }
+                continue;
+            }
+        }
+        if !options.selects(&entry.tags) {
+            continue;
+        }
+
+        let force = entry.state == State::Linked;
+        match link_entry(fs, &entry.dotfile(), force, options, journal) {
+            Ok(()) => updated += 1,
+            Err(e) => warn!("Failed to update '{}': {e}", entry.target.display()),
+        }
+    }
+
+    for removed in changed.iter().filter(|path| !fs.exists(path)) {
+        let Some(layer) = dotfiles_directories
+            .iter()
+            .find(|layer| removed.starts_with(layer))
+        else {
+            continue;
+        };
+        let target = Manifest::load(fs, layer)?.target_path(removed, base_directory, layer)?;
+
+        if fs
+            .read_link(&target)
+            .is_ok_and(|original| original == *removed)
+        {
+            info!("Pruning '{}'", target.display());
+            journal.remove_file(fs, &target)?;
+            updated += 1;
+
+            if let Some(parent) = target.parent().filter(|_| options.prune_empty) {
+                let created = journal::created_directories(fs, journal.directory())?;
+                journal.remove_empty_directories(fs, parent, base_directory, |directory| {
+                    created.contains(directory)
+                })?;
+            }
+        }
+    }
+
+    Ok(updated)
+}
+
+/// Watches the layered `dotfiles_directories` and [syncs](sync) the targets whenever they
+/// change, until interrupted. Each sync holds the [lock](lock) and is recorded in the journal
+/// at `state_directory` as its own operation, failed syncs are logged and retried on the next
+/// change.
+pub fn run(
+    base_directory: &Path,
+    dotfiles_directories: &[PathBuf],
+    options: &LinkOptions,
+    state_directory: &Path,
+) -> Result<(), DofiError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for dotfiles_directory in dotfiles_directories {
+        watcher.watch(dotfiles_directory, RecursiveMode::Recursive)?;
+    }
+
+    let mut changed = Vec::new();
+    loop {
+        let lock = lock::acquire(&lock::lock_path(state_directory), true)?;
+        let mut journal = Journal::new(state_directory, "watch");
+        let result = sync(
+            &OsFs,
+            base_directory,
+            dotfiles_directories,
+            &changed,
+            options,
+            &mut journal,
+        );
+        journal.commit(&OsFs)?;
+        drop(lock);
+        match result {
+            Ok(0) => {}
+            Ok(updated) => info!("Updated {updated} targets"),
+            Err(e) => warn!("Failed to sync the dotfiles: {e}"),
+        }
+
+        info!("Watching for changes");
+        changed.clear();
+        // Reading the dotfiles while syncing is reported as access, which must not retrigger
+        while changed.is_empty() {
+            let Ok(event) = receiver.recv() else {
+                return Ok(());
+            };
+            add_changes(&mut changed, event?);
+        }
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            add_changes(&mut changed, event?);
+        }
+        changed.sort();
+        changed.dedup();
+    }
+}
+
+/// Calls `refresh` whenever something changes in the `recursive` directories or right inside
+/// the `shallow` ones, once the changes settle, until interrupted or `refresh` fails. Changes
+/// in `ignored`, like the state directory `refresh` may write to, do not count.
+pub fn observe(
+    recursive: &[PathBuf],
+    shallow: &[PathBuf],
+    ignored: &Path,
+    refresh: &mut dyn FnMut() -> Result<(), DofiError>,
+) -> Result<(), DofiError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for directory in recursive {
+        watcher.watch(directory, RecursiveMode::Recursive)?;
+    }
+    for directory in shallow {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    let mut changed = Vec::new();
+    loop {
+        while changed.is_empty() {
+            let Ok(event) = receiver.recv() else {
+                return Ok(());
+            };
+            add_changes(&mut changed, event?);
+            changed.retain(|path| !path.starts_with(ignored));
+        }
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            add_changes(&mut changed, event?);
+        }
+        refresh()?;
+        changed.clear();
+    }
+}
+
+fn add_changes(changed: &mut Vec<PathBuf>, event: Event) {
+    if event.kind.is_access() {
+        return;
+    }
+    changed.extend(event.paths.into_iter().filter(|path| {
+        !path
+            .components()
+            .any(|component| fs::is_vcs_directory(component.as_os_str()))
+    }));
+}
@@ -0,0 +1,4343 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+#[cfg(not(any(target_os = "macos", windows)))]
+use dofi::trash;
+use dofi::{
+    add_directory, add_encrypted_file, add_file, add_files, add_template_file, adopt, check,
+    checksum, config, confirm,
+    conflict::{ConflictPolicies, ConflictPolicy},
+    diff, doctor, dotfiles_under, elevate,
+    encryption::Encryption,
+    export,
+    fs::FileType,
+    git, grep, guard,
+    hooks::PluginManager,
+    index, init, journal, layered_dotfiles, link_files, list_files, managed_dotfile,
+    match_dotfiles, materialize_symlink, move_file, new_file, nuon, package_dotfiles, packages,
+    permissions::{self, DirectoryModes},
+    picker, plugin, prune_dangling_links,
+    query::Query,
+    relink_dotfiles, remote, remove_file,
+    scripts::{self, Step},
+    service, snapshot, source, stats,
+    status::{self, State},
+    tag_path, template, templatify, track, tui, update, vars, watch, AddKind, AddOptions, Fs,
+    Journal, LinkOptions, LinkSummary, Manifest, MemoryFs, RemoveOptions,
+};
+#[cfg(unix)]
+use dofi::{generate, paths, OsFs};
+
+const BASE: &str = "/home/user";
+const DOTFILES: &str = "/home/user/dotfiles";
+const STATE: &str = "/home/user/.local/state/dofi";
+
+fn setup(files: &[(&str, &str)]) -> MemoryFs {
+    let fs = MemoryFs::new();
+    fs.create_dir_all(Path::new(DOTFILES)).unwrap();
+    for (path, contents) in files {
+        let path = Path::new(path);
+        fs.create_dir_all(path.parent().unwrap()).unwrap();
+        fs.write(path, contents.as_bytes()).unwrap();
+    }
+    fs
+}
+
+fn link(fs: &MemoryFs, force_all: bool) -> Result<LinkSummary, dofi::DofiError> {
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    let result = link_files(
+        fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            force: force_all,
+            force_all,
+            ..Default::default()
+        },
+        &mut journal,
+    );
+    journal.commit(fs).unwrap();
+    result
+}
+
+fn file_type(fs: &MemoryFs, path: &str) -> Option<FileType> {
+    fs.symlink_metadata(Path::new(path))
+        .ok()
+        .map(|metadata| metadata.file_type)
+}
+
+#[test]
+fn add_moves_file_and_links_it_back() {
+    let fs = setup(&[("/home/user/.config/nvim/init.lua", "vim.opt.number = true")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        Path::new("/home/user/.config/nvim/init.lua"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.config/nvim/init.lua"))
+            .unwrap(),
+        b"vim.opt.number = true"
+    );
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/nvim/init.lua"))
+            .unwrap(),
+        PathBuf::from("/home/user/dotfiles/.config/nvim/init.lua")
+    );
+}
+
+#[test]
+fn add_files_records_the_mode_and_tags_and_relinks_managed_ones() {
+    let fs = setup(&[
+        ("/home/user/.ssh/config", "Host *"),
+        ("/home/user/.vimrc", "set number"),
+        ("/home/user/dotfiles/.zshrc", "setopt autocd"),
+    ]);
+    let tags = ["work".to_string()];
+    let relinked = layered_dotfiles(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_files(
+        &fs,
+        &[
+            PathBuf::from("/home/user/.ssh/config"),
+            PathBuf::from("/home/user/.vimrc"),
+        ],
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &AddOptions {
+            kind: AddKind::File,
+            repo_path: None,
+            adopted: &[],
+            mode: Some(0o600),
+            tags: &tags,
+        },
+        &mut journal,
+    )
+    .unwrap();
+    relink_dotfiles(&fs, &relinked, &LinkOptions::default(), &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::Symlink));
+    let manifest = Manifest::load(&fs, Path::new(DOTFILES)).unwrap();
+    for file in [".ssh/config", ".vimrc"] {
+        assert_eq!(
+            file_type(&fs, &format!("{BASE}/{file}")),
+            Some(FileType::Symlink)
+        );
+        assert_eq!(manifest.tags_of(Path::new(file)), ["work"]);
+        assert!(manifest
+            .mode_matchers()
+            .unwrap()
+            .iter()
+            .any(|(matcher, mode)| matcher.is_match(file) && *mode == 0o600));
+    }
+}
+
+#[test]
+fn listings_show_the_dotfile_the_target_or_both() {
+    let (source, target) = (
+        Path::new("/home/user/dotfiles/.zshrc"),
+        Path::new("/home/user/.zshrc"),
+    );
+    let paths = |listing: status::Listing| {
+        status::Listing::line(&listing.paths(source, target, Path::new(DOTFILES), Path::new(BASE)))
+    };
+
+    assert_eq!(paths(Default::default()), "/home/user/dotfiles/.zshrc");
+    let targets = status::Listing {
+        targets: true,
+        ..Default::default()
+    };
+    assert_eq!(paths(targets), "/home/user/.zshrc");
+    let mapping = status::Listing {
+        targets: true,
+        mapping: true,
+        relative: true,
+    };
+    assert_eq!(paths(mapping), ".zshrc -> .zshrc");
+}
+
+#[test]
+fn new_creates_the_dotfile_and_links_it() {
+    let fs = setup(&[("/home/user/.bashrc", "export EDITOR=vi")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "new");
+    let source = new_file(
+        &fs,
+        Path::new("/home/user/.config/foot/foot.ini"),
+        b"font=monospace:size=11\n",
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        source,
+        PathBuf::from("/home/user/dotfiles/.config/foot/foot.ini")
+    );
+    assert_eq!(fs.read(&source).unwrap(), b"font=monospace:size=11\n");
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/foot/foot.ini"))
+            .unwrap(),
+        source
+    );
+
+    let result = new_file(
+        &fs,
+        Path::new("/home/user/.bashrc"),
+        b"",
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    );
+    assert!(matches!(result, Err(dofi::DofiError::FileExists(_))));
+}
+
+#[test]
+fn add_copies_across_filesystems_keeping_the_mode() {
+    let fs = setup(&[("/home/user/.ssh/config", "Host *")]);
+    fs.set_mode(Path::new("/home/user/.ssh/config"), 0o600)
+        .unwrap();
+    fs.mount(Path::new(DOTFILES));
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        Path::new("/home/user/.ssh/config"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+
+    let moved = Path::new("/home/user/dotfiles/.ssh/config");
+    assert_eq!(fs.read(moved).unwrap(), b"Host *");
+    assert_eq!(fs.symlink_metadata(moved).unwrap().mode, 0o600);
+    assert_eq!(
+        file_type(&fs, "/home/user/.ssh/config"),
+        Some(FileType::Symlink)
+    );
+}
+
+#[test]
+fn add_adopts_the_file_behind_a_foreign_symlink() {
+    let fs = setup(&[("/home/user/stow/vim/.vimrc", "set number")]);
+    fs.symlink(Path::new("stow/vim/.vimrc"), Path::new("/home/user/.vimrc"))
+        .unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    let vimrc = Path::new("/home/user/.vimrc");
+    materialize_symlink(&fs, vimrc, Path::new(DOTFILES), &mut journal).unwrap();
+    add_file(
+        &fs,
+        vimrc,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.vimrc")).unwrap(),
+        b"set number"
+    );
+    assert_eq!(
+        fs.read_link(vimrc).unwrap(),
+        PathBuf::from("/home/user/dotfiles/.vimrc")
+    );
+    assert_eq!(file_type(&fs, "/home/user/stow/vim/.vimrc"), None);
+
+    // Adopting a link to a dotfile would move the dotfile out of the repo
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    assert!(materialize_symlink(&fs, vimrc, Path::new(DOTFILES), &mut journal).is_err());
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        fs.read_link(vimrc).unwrap(),
+        PathBuf::from("stow/vim/.vimrc")
+    );
+    assert_eq!(
+        fs.read(Path::new("/home/user/stow/vim/.vimrc")).unwrap(),
+        b"set number"
+    );
+}
+
+#[test]
+fn managed_files_are_recognized_before_adding_them() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/dofi.toml", ""),
+        ("/home/user/.bashrc", "set -o vi"),
+    ]);
+    link(&fs, false).unwrap();
+    let layers = [PathBuf::from(DOTFILES)];
+    let managed = |path: &str| managed_dotfile(&fs, Path::new(path), Path::new(BASE), &layers);
+
+    for path in ["/home/user/.zshrc", "/home/user/dotfiles/.zshrc"] {
+        assert_eq!(
+            managed(path).unwrap().unwrap().source,
+            PathBuf::from("/home/user/dotfiles/.zshrc")
+        );
+    }
+    assert!(managed("/home/user/.bashrc").unwrap().is_none());
+    assert!(matches!(
+        managed("/home/user/dotfiles/dofi.toml"),
+        Err(dofi::DofiError::FileIsNotADotfile(_))
+    ));
+}
+
+#[test]
+fn add_as_records_mapping_used_by_link_and_remove() {
+    let fs = setup(&[("/home/user/.config/karabiner/karabiner.json", "{}")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        Path::new("/home/user/.config/karabiner/karabiner.json"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        Some(Path::new("karabiner.json")),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![PathBuf::from("/home/user/dotfiles/karabiner.json")]
+    );
+
+    fs.remove_file(Path::new("/home/user/.config/karabiner/karabiner.json"))
+        .unwrap();
+    link(&fs, false).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/karabiner/karabiner.json"))
+            .unwrap(),
+        PathBuf::from("/home/user/dotfiles/karabiner.json")
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        Path::new("/home/user/dotfiles/karabiner.json"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/karabiner/karabiner.json"),
+        None
+    );
+    assert!(dofi::Manifest::load(&fs, Path::new(DOTFILES))
+        .unwrap()
+        .targets
+        .is_empty());
+}
+
+#[test]
+fn link_summarizes_linked_unchanged_and_skipped_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.vimrc", "set number"),
+        ("/home/user/dotfiles/.bashrc", "set -o vi"),
+    ]);
+
+    assert_eq!(
+        link(&fs, false).unwrap(),
+        LinkSummary {
+            linked: 2,
+            unchanged: 0,
+            skipped: 0
+        }
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[tags]\neditor = [\".vimrc\"]\n",
+    )
+    .unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    let summary = link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            tags: vec!["editor".to_string()],
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        summary,
+        LinkSummary {
+            linked: 0,
+            unchanged: 1,
+            skipped: 1
+        }
+    );
+}
+
+#[test]
+fn link_creates_nested_directories() {
+    let fs = setup(&[("/home/user/dotfiles/.config/git/config", "[user]")]);
+
+    link(&fs, false).unwrap();
+
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/git"),
+        Some(FileType::Directory)
+    );
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/git/config"))
+            .unwrap(),
+        PathBuf::from("/home/user/dotfiles/.config/git/config")
+    );
+}
+
+#[test]
+fn link_fails_on_conflicting_file_without_force() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "managed"),
+        ("/home/user/.zshrc", "local"),
+    ]);
+
+    assert!(link(&fs, false).is_err());
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::File));
+}
+
+#[test]
+fn link_with_force_replaces_conflicting_file_and_undo_restores_it() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "managed"),
+        ("/home/user/.zshrc", "local"),
+    ]);
+
+    link(&fs, true).unwrap();
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::Symlink));
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(fs.read(Path::new("/home/user/.zshrc")).unwrap(), b"local");
+}
+
+#[test]
+fn replaced_files_are_renamed_into_place_with_their_mode() {
+    let fs = setup(&[("/home/user/.ssh/config", "old")]);
+    let target = Path::new("/home/user/.ssh/config");
+    fs.set_mode(target, 0o644).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    journal.replace_file(&fs, target, b"new", 0o600).unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(fs.read(target).unwrap(), b"new");
+    assert_eq!(fs.symlink_metadata(target).unwrap().mode, 0o600);
+    assert_eq!(
+        fs.walk(Path::new("/home/user/.ssh")).unwrap(),
+        vec![target.to_path_buf()]
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(fs.read(target).unwrap(), b"old");
+    assert_eq!(fs.symlink_metadata(target).unwrap().mode, 0o644);
+}
+
+#[test]
+fn forced_links_snapshot_replaced_targets_for_restoring() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "managed"),
+        ("/home/user/dotfiles/.vimrc", "managed"),
+        ("/home/user/.zshrc", "local"),
+    ]);
+
+    link(&fs, true).unwrap();
+    // Nothing is replaced the second time around
+    link(&fs, true).unwrap();
+    let snapshots = snapshot::list(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].command, "link");
+    assert_eq!(
+        snapshots[0].files[0].path,
+        PathBuf::from("/home/user/.zshrc")
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "restore");
+    snapshot::restore(&fs, snapshots[0].id, &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(fs.read(Path::new("/home/user/.zshrc")).unwrap(), b"local");
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::Symlink));
+}
+
+#[test]
+fn force_only_replaces_symlinks_into_the_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "managed"),
+        ("/home/user/dotfiles/.old/zshrc", "stale"),
+        ("/home/user/dotfiles/.vimrc", "managed"),
+        ("/home/user/.vimrc", "local"),
+    ]);
+    fs.symlink(
+        Path::new("dotfiles/.old/zshrc"),
+        Path::new("/home/user/.zshrc"),
+    )
+    .unwrap();
+    let force = |fs: &MemoryFs| {
+        let mut journal = Journal::new(Path::new(STATE), "link");
+        link_files(
+            fs,
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+            &LinkOptions {
+                force: true,
+                ..Default::default()
+            },
+            &mut journal,
+        )
+    };
+
+    let error = force(&fs).unwrap_err();
+    assert!(
+        matches!(error, dofi::DofiError::FileNotOwned(path) if path == Path::new("/home/user/.vimrc"))
+    );
+
+    fs.remove_file(Path::new("/home/user/.vimrc")).unwrap();
+    force(&fs).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.zshrc")).unwrap(),
+        PathBuf::from("/home/user/dotfiles/.zshrc")
+    );
+}
+
+#[test]
+fn link_failing_halfway_rolls_back_its_changes() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/.config/nvim/init.lua",
+            "vim.opt.number = true",
+        ),
+        ("/home/user/dotfiles/.gitconfig", "[user]"),
+        ("/home/user/.gitconfig", "[old]"),
+        ("/home/user/dotfiles/.zshrc.tmpl", "{{ missing }}"),
+    ]);
+
+    let error = link(&fs, true).unwrap_err();
+    assert!(matches!(error, dofi::DofiError::InvalidTemplate { .. }));
+
+    assert_eq!(file_type(&fs, "/home/user/.config"), None);
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gitconfig")).unwrap(),
+        b"[old]"
+    );
+    assert!(matches!(
+        journal::undo(&fs, Path::new(STATE)),
+        Err(dofi::DofiError::NothingToUndo)
+    ));
+}
+
+#[test]
+fn remove_deletes_dotfile_and_symlink() {
+    let fs = setup(&[("/home/user/dotfiles/.vimrc", "set number")]);
+    link(&fs, false).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        Path::new("/home/user/dotfiles/.vimrc"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.vimrc"), None);
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), None);
+}
+
+// The Finder and the Recycle Bin are only reached through the real filesystem
+#[cfg(not(any(target_os = "macos", windows)))]
+#[test]
+fn remove_puts_a_copy_into_the_trash() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.vimrc", "set number"),
+        ("/home/user/dotfiles/nvim/.vimrc", "set hidden"),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    for file in [
+        "/home/user/dotfiles/.vimrc",
+        "/home/user/dotfiles/nvim/.vimrc",
+    ] {
+        remove_file(
+            &fs,
+            Path::new(file),
+            Path::new(BASE),
+            Path::new(DOTFILES),
+            RemoveOptions {
+                trash: true,
+                ..Default::default()
+            },
+            &mut journal,
+        )
+        .unwrap();
+    }
+
+    let trash = trash::directory(Path::new(BASE));
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.vimrc"), None);
+    assert_eq!(fs.read(&trash.join("files/.vimrc")).unwrap(), b"set number");
+    assert_eq!(
+        fs.read(&trash.join("files/.vimrc.2")).unwrap(),
+        b"set hidden"
+    );
+    let info = String::from_utf8(fs.read(&trash.join("info/.vimrc.2.trashinfo")).unwrap()).unwrap();
+    assert!(info.starts_with("[Trash Info]\nPath=/home/user/dotfiles/nvim/.vimrc\nDeletionDate="));
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+#[test]
+fn the_trash_keeps_names_that_are_not_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let fs = setup(&[]);
+    let file = Path::new(DOTFILES).join(OsStr::from_bytes(b".bad\xff rc"));
+    fs.write(&file, b"set number").unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        &file,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions {
+            trash: true,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+
+    let trash = trash::directory(Path::new(BASE));
+    let copy = trash.join("files").join(OsStr::from_bytes(b".bad\xff rc"));
+    assert_eq!(fs.read(&copy).unwrap(), b"set number");
+    let info = trash
+        .join("info")
+        .join(OsStr::from_bytes(b".bad\xff rc.trashinfo"));
+    let info = String::from_utf8(fs.read(&info).unwrap()).unwrap();
+    assert!(
+        info.contains("\nPath=/home/user/dotfiles/.bad%FF%20rc\n"),
+        "{info}"
+    );
+}
+
+#[test]
+fn remove_keeping_the_target_replaces_the_symlink_with_a_copy() {
+    let fs = setup(&[("/home/user/dotfiles/.vimrc", "set number")]);
+    link(&fs, false).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        Path::new("/home/user/dotfiles/.vimrc"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions {
+            keep_target: true,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.vimrc"), None);
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::File));
+    assert_eq!(
+        fs.read(Path::new("/home/user/.vimrc")).unwrap(),
+        b"set number"
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::Symlink));
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.vimrc")).unwrap(),
+        b"set number"
+    );
+}
+
+#[test]
+fn removing_a_package_can_keep_its_sources() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/.config/nvim/init.lua",
+            "vim.opt.number = true",
+        ),
+        ("/home/user/dotfiles/.config/nvim/lua/keys.lua", "-- keys"),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+    ]);
+    link(&fs, false).unwrap();
+
+    let files = package_dotfiles(&fs, Path::new(DOTFILES), ".config").unwrap();
+    assert_eq!(
+        files,
+        [
+            PathBuf::from("/home/user/dotfiles/.config/nvim/init.lua"),
+            PathBuf::from("/home/user/dotfiles/.config/nvim/lua/keys.lua"),
+        ]
+    );
+    for package in ["missing", ".zshrc", ".config/nvim", ".."] {
+        assert!(matches!(
+            package_dotfiles(&fs, Path::new(DOTFILES), package),
+            Err(dofi::DofiError::NoMatchingDotfile { .. })
+        ));
+    }
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    for file in &files {
+        remove_file(
+            &fs,
+            file,
+            Path::new(BASE),
+            Path::new(DOTFILES),
+            RemoveOptions {
+                keep_source: true,
+                prune_empty: true,
+                ..Default::default()
+            },
+            &mut journal,
+        )
+        .unwrap();
+    }
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/.config"), None);
+    assert_eq!(
+        file_type(&fs, "/home/user/dotfiles/.config/nvim/lua/keys.lua"),
+        Some(FileType::File)
+    );
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::Symlink));
+}
+
+#[test]
+fn packages_are_the_top_level_directories_holding_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/nvim/init.lua", "-- init"),
+        ("/home/user/dotfiles/zsh/.zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/.gitconfig", "[user]"),
+    ]);
+    fs.create_dir_all(Path::new("/home/user/dotfiles/empty/nested"))
+        .unwrap();
+
+    assert_eq!(
+        packages(&fs, Path::new(DOTFILES)).unwrap(),
+        [".config", "zsh"]
+    );
+}
+
+#[test]
+fn trees_larger_than_a_batch_are_linked_and_undone_whole() {
+    let fs = setup(&[]);
+    let sources = (0..5_000)
+        .map(|i| {
+            format!(
+                "/home/user/dotfiles/.vim/pack/vendor/{}/plugin{i}.vim",
+                i % 50
+            )
+        })
+        .collect::<Vec<_>>();
+    for source in &sources {
+        let source = Path::new(source);
+        fs.create_dir_all(source.parent().unwrap()).unwrap();
+        fs.write(source, b"\" plugin").unwrap();
+    }
+
+    let summary = link(&fs, false).unwrap();
+    assert_eq!(summary.linked, sources.len());
+    assert_eq!(
+        file_type(&fs, "/home/user/.vim/pack/vendor/49/plugin4999.vim"),
+        Some(FileType::Symlink)
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(file_type(&fs, "/home/user/.vim"), None);
+}
+
+#[test]
+fn layers_and_conflicts_are_resolved_across_batches() {
+    let fs = setup(&[("/home/user/work/.vim/pack/vendor/7/plugin7.vim", "\" work")]);
+    for i in 0..10_000 {
+        let source = format!(
+            "/home/user/dotfiles/.vim/pack/vendor/{}/plugin{i}.vim",
+            i % 50
+        );
+        let source = Path::new(&source);
+        fs.create_dir_all(source.parent().unwrap()).unwrap();
+        fs.write(source, b"\" plugin").unwrap();
+    }
+    let layers = [PathBuf::from(DOTFILES), PathBuf::from("/home/user/work")];
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    let summary = link_files(
+        &fs,
+        Path::new(BASE),
+        &layers,
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(summary.linked, 10_000);
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.vim/pack/vendor/7/plugin7.vim"))
+            .unwrap(),
+        PathBuf::from("/home/user/work/.vim/pack/vendor/7/plugin7.vim")
+    );
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+
+    // A conflict in any batch rolls back the batches linked before it
+    fs.create_dir_all(Path::new("/home/user/.vim/pack/vendor/3"))
+        .unwrap();
+    fs.write(
+        Path::new("/home/user/.vim/pack/vendor/3/plugin9953.vim"),
+        b"local",
+    )
+    .unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    let result = link_files(
+        &fs,
+        Path::new(BASE),
+        &layers,
+        &LinkOptions::default(),
+        &mut journal,
+    );
+    assert!(matches!(result, Err(dofi::DofiError::FileExists(_))));
+    assert_eq!(
+        file_type(&fs, "/home/user/.vim/pack/vendor/7/plugin7.vim"),
+        None
+    );
+    assert_eq!(
+        fs.walk(Path::new("/home/user/.vim")).unwrap(),
+        [PathBuf::from(
+            "/home/user/.vim/pack/vendor/3/plugin9953.vim"
+        )]
+    );
+}
+
+#[test]
+fn remove_prunes_the_empty_directories_dofi_created() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/foo/bar/foo.toml", "a = 1"),
+        ("/home/user/dotfiles/.config/keep.toml", "b = 2"),
+    ]);
+    link(&fs, false).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        Path::new("/home/user/dotfiles/.config/foo/bar/foo.toml"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions {
+            prune_empty: true,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.config/foo"), None);
+    assert_eq!(
+        file_type(&fs, "/home/user/dotfiles/.config"),
+        Some(FileType::Directory)
+    );
+    assert_eq!(file_type(&fs, "/home/user/.config/foo"), None);
+    // Created by the link, but it still holds the other symlink
+    assert_eq!(
+        file_type(&fs, "/home/user/.config"),
+        Some(FileType::Directory)
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/foo/bar/foo.toml"),
+        Some(FileType::Symlink)
+    );
+}
+
+#[test]
+fn remove_rejects_files_outside_dotfiles() {
+    let fs = setup(&[("/home/user/.vimrc", "set number")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    let result = remove_file(
+        &fs,
+        Path::new("/home/user/.vimrc"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions::default(),
+        &mut journal,
+    );
+
+    assert!(result.is_err());
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::File));
+}
+
+#[test]
+fn list_skips_git_directory() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.git/HEAD", "ref: refs/heads/main"),
+        ("/home/user/dotfiles/.tmux.conf", "set -g mouse on"),
+    ]);
+
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![PathBuf::from("/home/user/dotfiles/.tmux.conf")]
+    );
+}
+
+#[test]
+fn list_skips_vcs_metadata_and_excluded_paths() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.hg/store/data", ""),
+        ("/home/user/dotfiles/.config/.svn/entries", ""),
+        ("/home/user/dotfiles/README.md", ""),
+        ("/home/user/dotfiles/.vim/plugin/node_modules/x.js", ""),
+        ("/home/user/dotfiles/.vim/vimrc", ""),
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "exclude = [\"README.md\", \"**/node_modules\"]\n",
+        ),
+    ]);
+
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![PathBuf::from("/home/user/dotfiles/.vim/vimrc")]
+    );
+}
+
+#[test]
+fn documentation_at_the_repo_root_is_left_out_unless_asked_for() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/README.md", "# My dotfiles"),
+        ("/home/user/dotfiles/LICENSE", "MIT"),
+        ("/home/user/dotfiles/CHANGELOG.md", ""),
+        ("/home/user/dotfiles/NOTES.md", ""),
+        ("/home/user/dotfiles/.config/nvim/README.md", "Plugins"),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![
+            PathBuf::from("/home/user/dotfiles/.config/nvim/README.md"),
+            PathBuf::from("/home/user/dotfiles/.zshrc"),
+        ]
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"link_docs = true\n",
+    )
+    .unwrap();
+    assert_eq!(list_files(&fs, Path::new(DOTFILES)).unwrap().len(), 6);
+}
+
+#[test]
+fn list_honors_gitignore_files_when_the_manifest_asks() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.gitignore", "*.local\n/.cache/\n"),
+        (
+            "/home/user/dotfiles/.config/nvim/.gitignore",
+            "plugin/\n!keep.local\n",
+        ),
+        ("/home/user/dotfiles/.cache/zsh/dump", ""),
+        ("/home/user/dotfiles/.zshrc.local", ""),
+        ("/home/user/dotfiles/.config/nvim/init.lua", ""),
+        ("/home/user/dotfiles/.config/nvim/keep.local", ""),
+        ("/home/user/dotfiles/.config/nvim/plugin/packer.lua", ""),
+    ]);
+    assert_eq!(list_files(&fs, Path::new(DOTFILES)).unwrap().len(), 7);
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"exclude = [\"**/.gitignore\"]\ngitignore = true\n",
+    )
+    .unwrap();
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![
+            PathBuf::from("/home/user/dotfiles/.config/nvim/init.lua"),
+            PathBuf::from("/home/user/dotfiles/.config/nvim/keep.local"),
+        ]
+    );
+}
+
+#[test]
+fn relative_symlinks_in_the_repo_are_recreated_and_others_skipped() {
+    let fs = setup(&[("/home/user/dotfiles/.config/bash/rc", "export EDITOR=vi")]);
+    for (original, link) in [
+        (".config/bash/rc", ".bashrc"),
+        ("/etc/profile", ".profile"),
+        ("missing", ".inputrc"),
+    ] {
+        fs.symlink(Path::new(original), &Path::new(DOTFILES).join(link))
+            .unwrap();
+    }
+
+    let summary = link(&fs, false).unwrap();
+    assert_eq!((summary.linked, summary.skipped), (2, 2));
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.bashrc")).unwrap(),
+        PathBuf::from(".config/bash/rc")
+    );
+    assert_eq!(file_type(&fs, "/home/user/.profile"), None);
+    assert_eq!(file_type(&fs, "/home/user/.inputrc"), None);
+    assert!(
+        status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)])
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.target == Path::new("/home/user/.bashrc"))
+            .all(|entry| entry.state == State::Linked)
+    );
+}
+
+#[test]
+fn nested_repos_are_linked_whole_or_skipped() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/dofi.toml", "nested_repos = \"link\"\n"),
+        ("/home/user/dotfiles/.vimrc", ""),
+        ("/home/user/dotfiles/.vim/pack/fugitive/.git/HEAD", ""),
+        (
+            "/home/user/dotfiles/.vim/pack/fugitive/plugin/fugitive.vim",
+            "",
+        ),
+        (
+            "/home/user/dotfiles/.vim/pack/fugitive/doc/fugitive.txt",
+            "",
+        ),
+    ]);
+
+    link(&fs, false).unwrap();
+    assert_eq!(
+        file_type(&fs, "/home/user/.vim/pack/fugitive"),
+        Some(FileType::Symlink)
+    );
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::Symlink));
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"nested_repos = \"skip\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        vec![PathBuf::from("/home/user/dotfiles/.vimrc")]
+    );
+}
+
+#[test]
+fn directories_added_as_a_whole_are_linked_and_removed_as_one() {
+    let fs = setup(&[
+        ("/home/user/.config/nvim/init.lua", "require('plugins')"),
+        ("/home/user/.config/nvim/lua/plugins.lua", "return {}"),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_directory(
+        &fs,
+        Path::new("/home/user/.config/nvim"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/nvim")).unwrap(),
+        PathBuf::from("/home/user/dotfiles/.config/nvim")
+    );
+    assert_eq!(
+        Manifest::load(&fs, Path::new(DOTFILES))
+            .unwrap()
+            .directories,
+        vec![PathBuf::from(".config/nvim")]
+    );
+    assert!(matches!(
+        add_directory(
+            &fs,
+            Path::new("/home/user/dotfiles/dofi.toml"),
+            Path::new(BASE),
+            Path::new(DOTFILES),
+            None,
+            &mut Journal::new(Path::new(STATE), "add"),
+        ),
+        Err(dofi::DofiError::NotADirectory(_))
+    ));
+
+    // What a plugin manager installs through the symlink stays part of the one dotfile
+    fs.create_dir_all(Path::new("/home/user/dotfiles/.config/nvim/pack/lazy"))
+        .unwrap();
+    fs.write(
+        Path::new("/home/user/dotfiles/.config/nvim/pack/lazy/lazy.lua"),
+        b"",
+    )
+    .unwrap();
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| (entry.source.clone(), entry.state))
+            .collect::<Vec<_>>(),
+        [(
+            PathBuf::from("/home/user/dotfiles/.config/nvim"),
+            State::Linked
+        )]
+    );
+
+    fs.remove_file(Path::new("/home/user/.config/nvim"))
+        .unwrap();
+    assert_eq!(link(&fs, false).unwrap().linked, 1);
+
+    let mut journal = Journal::new(Path::new(STATE), "remove");
+    remove_file(
+        &fs,
+        Path::new("/home/user/dotfiles/.config/nvim"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        RemoveOptions {
+            keep_target: true,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/nvim/pack/lazy/lazy.lua"),
+        Some(FileType::File)
+    );
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.config/nvim"), None);
+    assert!(Manifest::load(&fs, Path::new(DOTFILES))
+        .unwrap()
+        .directories
+        .is_empty());
+}
+
+#[test]
+fn import_chezmoi_translates_attributes_and_skips_templates() {
+    let fs = setup(&[
+        ("/src/chezmoi/dot_config/git/config", "[user]"),
+        ("/src/chezmoi/private_dot_netrc", "machine example.com"),
+        ("/src/chezmoi/dot_profile.tmpl", "export EDITOR=nvim"),
+        ("/src/chezmoi/dot_zshrc.tmpl", "{{ .chezmoi.hostname }}"),
+        ("/src/chezmoi/.chezmoi.toml.tmpl", "[data]"),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "import");
+    let imported = adopt::import_chezmoi(
+        &fs,
+        Path::new("/src/chezmoi"),
+        Path::new(DOTFILES),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(imported, 3);
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.config/git/config"))
+            .unwrap(),
+        b"[user]"
+    );
+    assert_eq!(
+        fs.symlink_metadata(Path::new("/home/user/dotfiles/.netrc"))
+            .unwrap()
+            .mode,
+        0o600
+    );
+    assert!(fs.exists(Path::new("/home/user/dotfiles/.profile")));
+    assert!(!fs.exists(Path::new("/home/user/dotfiles/.zshrc")));
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert!(list_files(&fs, Path::new(DOTFILES)).unwrap().is_empty());
+}
+
+#[test]
+fn move_relocates_dotfile_and_relinks_it() {
+    let fs = setup(&[("/home/user/dotfiles/.vimrc", "set number")]);
+    link(&fs, false).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "mv");
+    move_file(
+        &fs,
+        Path::new("/home/user/.vimrc"),
+        Path::new("/home/user/.config/vim/vimrc"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), None);
+    assert_eq!(file_type(&fs, "/home/user/dotfiles/.vimrc"), None);
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/vim/vimrc"))
+            .unwrap(),
+        PathBuf::from("/home/user/dotfiles/.config/vim/vimrc")
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.vimrc")).unwrap(),
+        PathBuf::from("/home/user/dotfiles/.vimrc")
+    );
+    assert_eq!(file_type(&fs, "/home/user/.config/vim/vimrc"), None);
+}
+
+#[test]
+fn link_honors_conflict_policies() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.ssh/config", "Host *"),
+        ("/home/user/.ssh/config", "Host old"),
+        ("/home/user/dotfiles/.config/Code/settings.json", "{}"),
+        ("/home/user/.config/Code/settings.json", "{\"old\": true}"),
+    ]);
+    let policies = ConflictPolicies::new(
+        &[
+            ("/home/user/.ssh/**".to_string(), ConflictPolicy::NeverForce),
+            (".config/Code/**".to_string(), ConflictPolicy::CopyBackup),
+        ]
+        .into(),
+        Path::new(BASE),
+    )
+    .unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            force: true,
+            policies,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/.ssh/config")).unwrap(),
+        b"Host old"
+    );
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/Code/settings.json"),
+        Some(FileType::Symlink)
+    );
+    assert_eq!(
+        fs.read(Path::new(
+            "/home/user/.config/Code/settings.json.dofi-backup"
+        ))
+        .unwrap(),
+        b"{\"old\": true}"
+    );
+}
+
+#[test]
+fn force_paths_replace_only_the_targets_they_match() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/home/user/.zshrc", "bindkey -e"),
+        ("/home/user/dotfiles/.bashrc", "set -o vi"),
+        ("/home/user/.bashrc", "set -o emacs"),
+    ]);
+    let link_forcing = |patterns: &[&str]| {
+        let mut policies = ConflictPolicies::default();
+        policies
+            .force(
+                &patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+                Path::new(BASE),
+            )
+            .unwrap();
+        let mut journal = Journal::new(Path::new(STATE), "link");
+        let result = link_files(
+            &fs,
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+            &LinkOptions {
+                policies,
+                ..Default::default()
+            },
+            &mut journal,
+        );
+        journal.commit(&fs).unwrap();
+        result
+    };
+
+    assert!(matches!(
+        link_forcing(&[".zshrc"]),
+        Err(dofi::DofiError::FileExists(target)) if target == Path::new("/home/user/.bashrc")
+    ));
+    assert_eq!(
+        fs.read(Path::new("/home/user/.zshrc")).unwrap(),
+        b"bindkey -e"
+    );
+
+    link_forcing(&[".zshrc", ".bashrc"]).unwrap();
+    for target in ["/home/user/.zshrc", "/home/user/.bashrc"] {
+        assert_eq!(file_type(&fs, target), Some(FileType::Symlink));
+    }
+}
+
+#[test]
+fn force_paths_with_a_tilde_are_relative_to_the_base_directory() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/srv/home/.zshrc", "bindkey -e"),
+    ]);
+    let base = Path::new("/srv/home");
+    let mut policies = ConflictPolicies::default();
+    policies.force(&["~/.zshrc".to_string()], base).unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        base,
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            policies,
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(file_type(&fs, "/srv/home/.zshrc"), Some(FileType::Symlink));
+}
+
+/// Stands in for age by reversing the contents
+struct Reverse;
+
+impl Encryption for Reverse {
+    fn extension(&self) -> &'static str {
+        "age"
+    }
+
+    fn encrypt(&self, plaintext: &[u8], _: &[String]) -> Result<Vec<u8>, dofi::DofiError> {
+        Ok(plaintext.iter().rev().copied().collect())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, dofi::DofiError> {
+        self.encrypt(ciphertext, &[])
+    }
+}
+
+/// Stands in for age on a machine holding the key `.0`, encrypting to the recipients by
+/// prefixing them
+struct Keyed(&'static str);
+
+impl Encryption for Keyed {
+    fn extension(&self) -> &'static str {
+        "age"
+    }
+
+    fn encrypt(&self, plaintext: &[u8], recipients: &[String]) -> Result<Vec<u8>, dofi::DofiError> {
+        Ok([recipients.join(",").as_bytes(), b":", plaintext].concat())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, dofi::DofiError> {
+        let separator = ciphertext.iter().position(|&byte| byte == b':').unwrap();
+        Ok(ciphertext[separator + 1..].to_vec())
+    }
+
+    fn is_recipient(&self, recipients: &[String]) -> bool {
+        recipients.iter().any(|recipient| recipient == self.0)
+    }
+}
+
+#[test]
+fn tagged_secrets_are_only_decrypted_by_their_recipients() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[tags]\nwork = [\".work\"]\n\n[recipients]\nwork = [\"laptop\", \"desktop\"]\n",
+        ),
+        ("/home/user/.work/token", "secret"),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_encrypted_file(
+        &fs,
+        Path::new("/home/user/.work/token"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &Keyed("laptop"),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.work/token.age"))
+            .unwrap(),
+        b"laptop,desktop:secret"
+    );
+
+    fs.remove_file(Path::new("/home/user/.work/token")).unwrap();
+    for (machine, linked) in [("personal", false), ("desktop", true)] {
+        let mut journal = Journal::new(Path::new(STATE), "link");
+        let options = LinkOptions {
+            encryption: vec![Box::new(Keyed(machine))],
+            ..Default::default()
+        };
+        let summary = link_files(
+            &fs,
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+            &options,
+            &mut journal,
+        )
+        .unwrap();
+        assert_eq!(summary.skipped, usize::from(!linked), "{machine}");
+        assert_eq!(
+            fs.exists(Path::new("/home/user/.work/token")),
+            linked,
+            "{machine}"
+        );
+    }
+}
+
+#[test]
+fn encrypted_dotfiles_are_decrypted_to_their_targets() {
+    let fs = setup(&[("/home/user/.netrc", "machine example.com")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_encrypted_file(
+        &fs,
+        Path::new("/home/user/.netrc"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &Reverse,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.netrc.age"))
+            .unwrap(),
+        b"moc.elpmaxe enihcam"
+    );
+    assert_eq!(file_type(&fs, "/home/user/.netrc"), Some(FileType::File));
+
+    fs.remove_file(Path::new("/home/user/.netrc")).unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            encryption: vec![Box::new(Reverse)],
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/.netrc")).unwrap(),
+        b"machine example.com"
+    );
+    assert_eq!(
+        fs.symlink_metadata(Path::new("/home/user/.netrc"))
+            .unwrap()
+            .mode,
+        0o600
+    );
+}
+
+#[test]
+fn strategies_choose_how_targets_are_linked() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[strategies]\n\".gnupg/*\" = \"copy\"\n\".vimrc\" = \"hardlink\"\n\".config/app/config\" = \"template\"\n",
+        ),
+        ("/home/user/dotfiles/.gnupg/gpg-agent.conf", "pinentry-program"),
+        ("/home/user/dotfiles/.vimrc", "set number"),
+        ("/home/user/dotfiles/.config/app/config", "a {{ \"{{\" }} b"),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+    ]);
+
+    link(&fs, false).unwrap();
+
+    assert_eq!(
+        file_type(&fs, "/home/user/.gnupg/gpg-agent.conf"),
+        Some(FileType::File)
+    );
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gnupg/gpg-agent.conf"))
+            .unwrap(),
+        b"pinentry-program"
+    );
+    assert!(fs.same_file(
+        Path::new("/home/user/dotfiles/.vimrc"),
+        Path::new("/home/user/.vimrc")
+    ));
+    assert_eq!(
+        fs.read(Path::new("/home/user/.config/app/config")).unwrap(),
+        b"a {{ b"
+    );
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::Symlink));
+
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    assert!(entries.iter().all(|entry| entry.state == State::Linked));
+
+    // Edits to the source show through the hard link, and linking again changes nothing
+    fs.write(
+        Path::new("/home/user/dotfiles/.vimrc"),
+        b"set relativenumber",
+    )
+    .unwrap();
+    assert_eq!(
+        fs.read(Path::new("/home/user/.vimrc")).unwrap(),
+        b"set relativenumber"
+    );
+    let summary = link(&fs, false).unwrap();
+    assert_eq!(summary.unchanged, 4);
+    assert_eq!(summary.linked, 0);
+}
+
+#[test]
+fn drift_tells_local_edits_from_repo_changes() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[strategies]\n\".gnupg/*\" = \"copy\"\n",
+        ),
+        (
+            "/home/user/dotfiles/.gnupg/gpg-agent.conf",
+            "pinentry-program",
+        ),
+    ]);
+    let source = Path::new("/home/user/dotfiles/.gnupg/gpg-agent.conf");
+    let target = Path::new("/home/user/.gnupg/gpg-agent.conf");
+    let drift = || {
+        checksum::drift(
+            &fs,
+            &checksum::load(&fs, Path::new(STATE)).unwrap(),
+            &checksum::load_sources(&fs, Path::new(STATE)).unwrap(),
+            source,
+            target,
+        )
+    };
+
+    link(&fs, false).unwrap();
+    assert_eq!(drift(), None);
+
+    fs.write(source, b"pinentry-program /usr/bin/pinentry")
+        .unwrap();
+    assert_eq!(drift(), Some(checksum::Drift::Source));
+
+    fs.write(target, b"default-cache-ttl 600").unwrap();
+    assert_eq!(drift(), Some(checksum::Drift::Both));
+
+    fs.write(source, b"pinentry-program").unwrap();
+    assert_eq!(drift(), Some(checksum::Drift::Target));
+}
+
+#[test]
+fn written_copies_are_kept_as_merge_bases() {
+    let fs = setup(&[("/home/user/dotfiles/.netrc.tmpl", "machine {{ \"host\" }}")]);
+    let target = Path::new("/home/user/.netrc");
+
+    link(&fs, false).unwrap();
+    fs.write(target, b"machine host\nlogin jane").unwrap();
+
+    let checksums = checksum::load(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        checksum::base(&fs, Path::new(STATE), &checksums, target).unwrap(),
+        b"machine host"
+    );
+    let base = Path::new(STATE)
+        .join(checksum::BASES_DIRECTORY)
+        .join(checksum::digest(b"machine host"));
+    assert_eq!(fs.symlink_metadata(&base).unwrap().mode, 0o600);
+}
+
+#[test]
+fn export_resolves_the_tree_as_it_is_linked() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[modes]\n\"bin/*\" = 0o755\n",
+        ),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/bin/backup", "#!/bin/sh"),
+        ("/home/user/dotfiles/.gitconfig.tmpl", "name = {{ name }}"),
+        ("/home/user/dotfiles/private/.netrc", "machine example.com"),
+        ("/home/user/dotfiles/.env.age", "ciphertext"),
+    ]);
+    let options = LinkOptions {
+        templates: template::Context {
+            variables: [("name".to_string(), "Jane".to_string())].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let files = export::tree(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &options,
+        false,
+    )
+    .unwrap();
+
+    let file = |path: &str, contents: &str, mode| export::File {
+        path: PathBuf::from(path),
+        contents: contents.as_bytes().to_vec(),
+        mode,
+    };
+    assert_eq!(
+        files,
+        [
+            file(".gitconfig", "name = Jane", 0o644),
+            file(".netrc", "machine example.com", 0o600),
+            file(".zshrc", "bindkey -v", 0o644),
+            file("bin/backup", "#!/bin/sh", 0o755),
+        ]
+    );
+}
+
+#[test]
+fn home_manager_export_declares_the_targets() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[modes]\n\"bin/*\" = 0o755\n",
+        ),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/bin/backup", "#!/bin/sh"),
+        ("/home/user/dotfiles/.config/my app/settings", "{}"),
+        (
+            "/home/user/dotfiles/.gitconfig.tmpl",
+            "name = \"{{ name }}\"",
+        ),
+        ("/home/user/dotfiles/private/.netrc", "machine example.com"),
+        ("/home/user/dotfiles/.env.age", "ciphertext"),
+    ]);
+    let options = LinkOptions {
+        templates: template::Context {
+            variables: [("name".to_string(), "Jane".to_string())].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let module = export::home_manager(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &options,
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        module,
+        "# Generated by `dofi export --format home-manager`\n\
+         { config, ... }:\n\
+         {\n  \
+         xdg.configFile.\"my app/settings\".source = /. + \"/home/user/dotfiles/.config/my app/settings\";\n  \
+         home.file.\".gitconfig\".text = \"name = \\\"Jane\\\"\";\n  \
+         home.file.\".zshrc\".source = /home/user/dotfiles/.zshrc;\n  \
+         home.file.\"bin/backup\".source = /home/user/dotfiles/bin/backup;\n  \
+         home.file.\"bin/backup\".executable = true;\n\
+         }\n"
+    );
+
+    let module = export::home_manager(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &options,
+        true,
+    )
+    .unwrap();
+    assert!(module.contains(
+        "home.file.\".zshrc\".source = \
+         config.lib.file.mkOutOfStoreSymlink \"/home/user/dotfiles/.zshrc\";"
+    ));
+}
+
+#[test]
+fn remote_dofi_applies_every_pushed_layer() {
+    assert_eq!(
+        remote::apply_command(2),
+        "dofi -b \"$HOME\" -d \"$HOME/.local/share/dofi/remote/0\" \
+         -l \"$HOME/.local/share/dofi/remote/1\" apply"
+    );
+}
+
+#[test]
+fn self_update_picks_newer_releases_for_the_platform() {
+    let release = update::Release::parse(
+        br#"{
+            "tag_name": "v0.10.0",
+            "assets": [
+                {"name": "dofi-x86_64-linux", "browser_download_url": "https://example.com/a"},
+                {"name": "dofi-x86_64-linux.sha256", "browser_download_url": "https://example.com/b"}
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(release.version(), "0.10.0");
+    assert!(update::is_newer(release.version(), "0.9.3"));
+    assert!(!update::is_newer(release.version(), "0.10.0"));
+    assert!(!update::is_newer("0.10.0-rc.1", "0.10.0"));
+    assert_eq!(
+        release.asset("dofi-x86_64-linux").unwrap().url,
+        "https://example.com/a"
+    );
+    assert!(release.asset("dofi-aarch64-macos").is_err());
+}
+
+#[test]
+fn sources_are_read_only_unless_they_are_a_directory_of_dotfiles() {
+    let archive = source::parse("https://example.com/dotfiles.tar.gz");
+    assert_eq!(archive.name(), "https://example.com/dotfiles.tar.gz");
+    assert!(!archive.is_writable());
+
+    let directory = std::env::temp_dir().join(format!("dofi-source-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let dotfiles = source::parse(directory.to_str().unwrap());
+    assert!(dotfiles.is_writable());
+    assert_eq!(
+        source::open(dotfiles.as_ref(), Path::new(STATE)).unwrap(),
+        directory
+    );
+
+    std::fs::create_dir_all(directory.join("objects")).unwrap();
+    std::fs::create_dir_all(directory.join("refs")).unwrap();
+    std::fs::write(directory.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+    assert!(!source::parse(directory.to_str().unwrap()).is_writable());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn a_bare_repository_keeps_its_single_dot_directory() {
+    let directory = std::env::temp_dir().join(format!("dofi-bare-{}", std::process::id()));
+    let (repository, worktree, state) = (
+        directory.join("dotfiles.git"),
+        directory.join("worktree"),
+        directory.join("state"),
+    );
+    std::fs::create_dir_all(worktree.join(".config/nvim")).unwrap();
+    std::fs::write(
+        worktree.join(".config/nvim/init.lua"),
+        "vim.o.number = true\n",
+    )
+    .unwrap();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .arg("--git-dir")
+            .arg(&repository)
+            .arg("--work-tree")
+            .arg(&worktree)
+            .args(["-c", "user.name=dofi", "-c", "user.email=dofi@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+    };
+    std::process::Command::new("git")
+        .args(["init", "--bare", "-q"])
+        .arg(&repository)
+        .output()
+        .unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "nvim"]);
+
+    let bare = source::parse(repository.to_str().unwrap());
+    let dotfiles = source::open(bare.as_ref(), &state).unwrap();
+    assert!(dotfiles.join(".config/nvim/init.lua").is_file());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn pending_pushes_are_kept_and_retried_per_repository() {
+    let directory = std::env::temp_dir().join(format!("dofi-push-{}", std::process::id()));
+    let state = directory.join("state");
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=dofi", "-c", "user.email=dofi@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+    };
+    let repository = |name: &str| {
+        let (worktree, remote) = (directory.join(name), directory.join(format!("{name}.git")));
+        let (worktree_arg, remote_arg) = (worktree.to_str().unwrap(), remote.to_str().unwrap());
+        git(&["init", "-q", worktree_arg]);
+        git(&["-C", worktree_arg, "config", "push.default", "current"]);
+        git(&["-C", worktree_arg, "remote", "add", "origin", remote_arg]);
+        git(&[
+            "-C",
+            worktree_arg,
+            "commit",
+            "-q",
+            "--allow-empty",
+            "-m",
+            name,
+        ]);
+        (worktree, remote)
+    };
+    let (offline, offline_remote) = repository("offline");
+    let (online, online_remote) = repository("online");
+    git(&["init", "-q", "--bare", online_remote.to_str().unwrap()]);
+
+    git::push_or_queue(&offline, &state).unwrap();
+    git::push_or_queue(&online, &state).unwrap();
+    assert!(git::is_push_pending(&offline, &state));
+    assert!(!git::is_push_pending(&online, &state));
+
+    assert_eq!(git::push_pending(&state).unwrap(), 0);
+    assert!(git::is_push_pending(&offline, &state));
+
+    git(&["init", "-q", "--bare", offline_remote.to_str().unwrap()]);
+    assert_eq!(git::push_pending(&state).unwrap(), 1);
+    assert!(!git::is_push_pending(&offline, &state));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn the_index_is_used_until_the_dotfiles_change() {
+    let fs = setup(&[("/home/user/dotfiles/.zshrc", "bindkey -v")]);
+    let entries = |cached| {
+        index::entries(
+            &fs,
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+            Path::new(STATE),
+            cached,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.target, entry.state))
+        .collect::<Vec<_>>()
+    };
+    let zshrc = PathBuf::from("/home/user/.zshrc");
+
+    assert_eq!(entries(true), [(zshrc.clone(), State::Unlinked)]);
+
+    // Linked behind dofi's back, only a full scan notices
+    fs.symlink(
+        Path::new("/home/user/dotfiles/.zshrc"),
+        Path::new("/home/user/.zshrc"),
+    )
+    .unwrap();
+    assert_eq!(entries(true), [(zshrc.clone(), State::Unlinked)]);
+    assert_eq!(entries(false), [(zshrc.clone(), State::Linked)]);
+
+    fs.write(Path::new("/home/user/dotfiles/.vimrc"), b"set nu")
+        .unwrap();
+    assert_eq!(
+        entries(true),
+        [
+            (PathBuf::from("/home/user/.vimrc"), State::Unlinked),
+            (zshrc, State::Linked)
+        ]
+    );
+}
+
+#[test]
+fn dotfiles_are_matched_by_repo_relative_patterns() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/zsh/zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/nvim/init.lua", "vim.o.number = true"),
+        ("/home/user/dotfiles/nvim/lua/plugins.lua", "return {}"),
+    ]);
+    let matches = |pattern| match_dotfiles(&fs, Path::new(DOTFILES), pattern);
+
+    assert_eq!(
+        matches("zsh/zshrc").unwrap(),
+        [PathBuf::from("/home/user/dotfiles/zsh/zshrc")]
+    );
+    assert_eq!(
+        matches("nvim/**").unwrap(),
+        [
+            PathBuf::from("/home/user/dotfiles/nvim/init.lua"),
+            PathBuf::from("/home/user/dotfiles/nvim/lua/plugins.lua"),
+        ]
+    );
+
+    let error = matches("zsh/zshr").unwrap_err();
+    assert!(
+        matches!(&error, dofi::DofiError::NoMatchingDotfile { help, .. } if help.contains("'zsh/zshrc'")),
+        "{error:?}"
+    );
+}
+
+#[test]
+fn which_finds_the_dotfiles_of_a_target_in_every_layer() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.gitconfig", "[user]"),
+        (
+            "/home/user/work/.gitconfig.tmpl",
+            "[user]\n\temail = {{ email }}",
+        ),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+    ]);
+    let layers = [PathBuf::from(DOTFILES), PathBuf::from("/home/user/work")];
+    let sources = |path| {
+        dofi::dotfiles_linked_to(&fs, Path::new(BASE), &layers, Path::new(path))
+            .unwrap()
+            .into_iter()
+            .map(|dotfile| (dotfile.source, dotfile.strategy))
+            .collect::<Vec<_>>()
+    };
+
+    let gitconfig = [
+        (
+            PathBuf::from("/home/user/dotfiles/.gitconfig"),
+            dofi::Strategy::Symlink,
+        ),
+        (
+            PathBuf::from("/home/user/work/.gitconfig.tmpl"),
+            dofi::Strategy::Template,
+        ),
+    ];
+    assert_eq!(sources("/home/user/.gitconfig"), gitconfig);
+    assert_eq!(sources("/home/user/dotfiles/.gitconfig"), gitconfig);
+    assert!(sources("/home/user/.vimrc").is_empty());
+}
+
+#[test]
+fn migrating_moves_dotfiles_to_dot_names_keeping_their_targets() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        (
+            "/home/user/dotfiles/.config/nvim/init.lua",
+            "vim.o.nu = true",
+        ),
+        ("/home/user/dotfiles/.gitignore", "*.swp"),
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[tags]\nterm = [\".config/nvim\"]\n",
+        ),
+    ]);
+    link(&fs, false).unwrap();
+
+    let migration = dofi::migrate::plan(&fs, Path::new(BASE), Path::new(DOTFILES)).unwrap();
+    assert_eq!(
+        migration.moves,
+        [
+            (
+                PathBuf::from(".config/nvim/init.lua"),
+                PathBuf::from("dot_config/nvim/init.lua")
+            ),
+            (PathBuf::from(".zshrc"), PathBuf::from("dot_zshrc")),
+        ]
+    );
+    let mut journal = Journal::new(Path::new(STATE), "migrate");
+    dofi::migrate::apply(&fs, &migration, Path::new(DOTFILES), &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert!(!fs.exists(Path::new("/home/user/dotfiles/.config")));
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.zshrc")).unwrap(),
+        Path::new("/home/user/dotfiles/dot_zshrc")
+    );
+    let manifest = Manifest::load(&fs, Path::new(DOTFILES)).unwrap();
+    assert!(manifest.dot_prefix);
+    assert_eq!(manifest.tags["term"], [PathBuf::from("dot_config/nvim")]);
+    let summary = link(&fs, false).unwrap();
+    assert_eq!((summary.linked, summary.unchanged), (0, 3));
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/nvim/init.lua"))
+            .unwrap(),
+        Path::new("/home/user/dotfiles/.config/nvim/init.lua")
+    );
+    assert!(!fs.exists(Path::new("/home/user/dotfiles/dot_config")));
+    assert!(!Manifest::load(&fs, Path::new(DOTFILES)).unwrap().dot_prefix);
+}
+
+#[test]
+fn doctor_fixes_broken_symlinks() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        ("/home/user/dotfiles/.config/git/config", "[user]"),
+        ("/home/user/dotfiles/.vimrc", "set nu"),
+    ]);
+    link(&fs, false).unwrap();
+    fs.remove_file(Path::new("/home/user/.config/git/config"))
+        .unwrap();
+    fs.remove_file(Path::new("/home/user/dotfiles/.vimrc"))
+        .unwrap();
+    fs.remove_file(Path::new("/home/user/.zshrc")).unwrap();
+    fs.symlink(
+        Path::new("/home/user/old-dotfiles/.zshrc"),
+        Path::new("/home/user/.zshrc"),
+    )
+    .unwrap();
+
+    let findings = doctor::diagnose(
+        &fs,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        Path::new(STATE),
+        &DirectoryModes::default(),
+    )
+    .unwrap();
+    let targets = findings
+        .iter()
+        .map(|finding| finding.target.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        targets,
+        [
+            PathBuf::from("/home/user/.config/git/config"),
+            PathBuf::from("/home/user/.zshrc"),
+            PathBuf::from("/home/user/.vimrc"),
+        ]
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "doctor");
+    for finding in &findings {
+        assert!(doctor::fix(&fs, finding, &mut journal).unwrap());
+    }
+    journal.commit(&fs).unwrap();
+
+    for name in [".zshrc", ".config/git/config"] {
+        assert_eq!(
+            fs.read_link(&Path::new(BASE).join(name)).unwrap(),
+            Path::new(DOTFILES).join(name)
+        );
+    }
+    assert!(!fs.exists(Path::new("/home/user/.vimrc")));
+    assert!(doctor::diagnose(
+        &fs,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        Path::new(STATE),
+        &DirectoryModes::default(),
+    )
+    .unwrap()
+    .is_empty());
+}
+
+#[test]
+fn mount_points_are_decoded_byte_by_byte() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/café/rc", ""),
+        (
+            "/proc/self/mounts",
+            "/dev/sda1 / ext4 rw 0 0\n\
+             /dev/sdb1 /home/user/caf\\303\\251 ext4 rw,nosymfollow 0 0\n",
+        ),
+    ]);
+
+    let findings = doctor::diagnose(
+        &fs,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        Path::new(STATE),
+        &DirectoryModes::default(),
+    )
+    .unwrap();
+    assert!(matches!(
+        &findings[..],
+        [doctor::Finding {
+            problem: doctor::Problem::NoSymfollow { mount_point },
+            ..
+        }] if mount_point == Path::new("/home/user/café")
+    ));
+}
+
+#[test]
+fn created_directories_get_their_configured_mode() {
+    let fs = setup(&[("/home/user/dotfiles/.ssh/config", "Host *")]);
+    fs.create_dir_all(Path::new("/home/user/.config")).unwrap();
+    let config = permissions::DirectoriesConfig {
+        modes: BTreeMap::from([(".ssh".to_string(), 0o700), ("**".to_string(), 0o755)]),
+    };
+    let modes = DirectoryModes::new(&config, Path::new(BASE)).unwrap();
+    let mode = |path: &str| fs.symlink_metadata(Path::new(path)).unwrap().mode;
+
+    let mut journal = Journal::new(Path::new(STATE), "link").with_directory_modes(modes.clone());
+    journal
+        .create_dir_all(&fs, Path::new("/home/user/.ssh"))
+        .unwrap();
+    journal
+        .create_dir_all(&fs, Path::new("/home/user/.config/nvim/lua"))
+        .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(mode("/home/user/.ssh"), 0o700);
+    assert_eq!(mode("/home/user/.config/nvim/lua"), 0o755);
+
+    fs.set_mode(Path::new("/home/user/.ssh"), 0o755).unwrap();
+    fs.set_mode(Path::new("/home/user/.config"), 0o777).unwrap();
+    let findings = doctor::diagnose(
+        &fs,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        Path::new(STATE),
+        &modes,
+    )
+    .unwrap();
+    let targets = findings
+        .iter()
+        .map(|finding| finding.target.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(targets, [PathBuf::from("/home/user/.ssh")]);
+
+    let mut journal = Journal::new(Path::new(STATE), "doctor");
+    assert!(doctor::fix(&fs, &findings[0], &mut journal).unwrap());
+    assert_eq!(mode("/home/user/.ssh"), 0o700);
+}
+
+#[test]
+fn templates_are_rendered_to_their_targets() {
+    let fs = setup(&[(
+        "/home/user/dotfiles/.gitconfig.tmpl",
+        "[user]\n\tname = {{ name }}\n\tbraces = {{ \"{{\" }}\n",
+    )]);
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            templates: template::Context {
+                variables: [("name".to_string(), "Jane".to_string())].into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gitconfig")).unwrap(),
+        b"[user]\n\tname = Jane\n\tbraces = {{\n"
+    );
+}
+
+#[test]
+fn target_contents_are_rendered_without_writing_the_target() {
+    let fs = setup(&[(
+        "/home/user/dotfiles/.gitconfig.tmpl",
+        "[user]\n\tname = {{ name }}\n",
+    )]);
+    let layers = [PathBuf::from(DOTFILES)];
+    let options = LinkOptions {
+        templates: template::Context {
+            variables: [("name".to_string(), "Jane".to_string())].into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let dotfile = dofi::dotfiles_linked_to(
+        &fs,
+        Path::new(BASE),
+        &layers,
+        Path::new("/home/user/.gitconfig"),
+    )
+    .unwrap()
+    .pop()
+    .unwrap();
+    assert_eq!(
+        dofi::target_contents(&fs, &dotfile, &options).unwrap(),
+        b"[user]\n\tname = Jane\n"
+    );
+    assert!(!fs.exists(Path::new("/home/user/.gitconfig")));
+}
+
+#[test]
+fn templates_report_undefined_variables() {
+    let error = template::render(
+        &MemoryFs::new(),
+        "a {{ missing }}",
+        Path::new("t.tmpl"),
+        &Default::default(),
+    )
+    .unwrap_err();
+
+    assert!(matches!(error, dofi::DofiError::InvalidTemplate { .. }));
+}
+
+#[test]
+fn added_templates_substitute_variables_and_render_to_the_file() {
+    let contents = "[user]\n    name = Jane\n    nick = Janet\n[include]\n    path = /home/user/.gitconfig.local\n# {{ not an expression }}\n";
+    let fs = setup(&[("/home/user/.gitconfig", contents)]);
+    let variables = BTreeMap::from([
+        ("name".to_string(), "Jane".to_string()),
+        ("dofi.home".to_string(), "/home/user".to_string()),
+        ("dofi.os".to_string(), "linux".to_string()),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_template_file(
+        &fs,
+        Path::new("/home/user/.gitconfig"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        Some(&variables),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        String::from_utf8(fs.read(Path::new("/home/user/dotfiles/.gitconfig.tmpl")).unwrap()).unwrap(),
+        "[user]\n    name = {{ name }}\n    nick = Janet\n[include]\n    path = {{ dofi.home }}/.gitconfig.local\n# {{ \"{{\" }} not an expression }}\n"
+    );
+    let options = LinkOptions {
+        templates: template::Context {
+            variables,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &options,
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gitconfig")).unwrap(),
+        contents.as_bytes()
+    );
+}
+
+#[test]
+fn templates_include_partials_and_reject_cycles() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/.zshrc.tmpl",
+            "# zsh\n{{ include \"templates/aliases\" }}",
+        ),
+        (
+            "/home/user/dotfiles/templates/aliases",
+            "alias e={{ editor }}\n{{ include \"templates/git\" }}",
+        ),
+        ("/home/user/dotfiles/templates/git", "alias g=git\n"),
+        (
+            "/home/user/dotfiles/templates/loop",
+            "{{ include \"templates/loop\" }}",
+        ),
+    ]);
+    let options = LinkOptions {
+        templates: template::Context {
+            variables: [("editor".to_string(), "nvim".to_string())].into(),
+            dotfiles_directories: vec![PathBuf::from(DOTFILES)],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &options,
+        &mut journal,
+    )
+    .unwrap();
+
+    assert_eq!(
+        fs.read(Path::new("/home/user/.zshrc")).unwrap(),
+        b"# zsh\nalias e=nvim\nalias g=git\n"
+    );
+    assert_eq!(file_type(&fs, "/home/user/templates/git"), None);
+
+    let error = template::render(
+        &fs,
+        "{{ include \"templates/loop\" }}",
+        Path::new("/home/user/dotfiles/.loop.tmpl"),
+        &options.templates,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("includes"), "{error}");
+}
+
+#[test]
+fn templates_know_the_machine() {
+    let context = template::Context {
+        variables: template::builtin_variables(),
+        ..Default::default()
+    };
+
+    let rendered = template::render(
+        &MemoryFs::new(),
+        "{{ dofi.os }}/{{ dofi.arch }} {{ has \"dofi-missing-command\" }}",
+        Path::new("t.tmpl"),
+        &context,
+    )
+    .unwrap();
+
+    assert_eq!(
+        rendered,
+        format!("{}/{} false", std::env::consts::OS, std::env::consts::ARCH)
+    );
+}
+
+#[test]
+fn template_conditionals_render_one_of_their_parts() {
+    let context = template::Context {
+        variables: BTreeMap::from([
+            ("work".to_string(), "true".to_string()),
+            ("email".to_string(), "jane@example.com".to_string()),
+        ]),
+        ..Default::default()
+    };
+    let render =
+        |template| template::render(&MemoryFs::new(), template, Path::new("t.tmpl"), &context);
+
+    assert_eq!(
+        render("{{#if work}}{{ email }}{{else}}{{ undefined }}{{/if}}\n").unwrap(),
+        "jane@example.com\n"
+    );
+    assert_eq!(
+        render(
+            "{{#if has \"dofi-missing-command\"}}brew{{else}}\
+             {{#if work}}apt{{/if}}{{/if}}"
+        )
+        .unwrap(),
+        "apt"
+    );
+    assert!(matches!(
+        render("{{#if work}}unclosed"),
+        Err(dofi::DofiError::InvalidTemplate { message, .. }) if message == "unclosed 'if'"
+    ));
+}
+
+#[test]
+fn later_layers_win() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "base"),
+        ("/home/user/dotfiles/.vimrc", "base"),
+        ("/home/user/work/.zshrc", "work"),
+    ]);
+    let layers = [PathBuf::from(DOTFILES), PathBuf::from("/home/user/work")];
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &layers,
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.zshrc")).unwrap(),
+        Path::new("/home/user/work/.zshrc")
+    );
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.vimrc")).unwrap(),
+        Path::new("/home/user/dotfiles/.vimrc")
+    );
+
+    let layers = dofi::status::entries(&fs, Path::new(BASE), &layers)
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.target, entry.layer))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        layers,
+        [
+            (PathBuf::from("/home/user/.vimrc"), PathBuf::from(DOTFILES)),
+            (
+                PathBuf::from("/home/user/.zshrc"),
+                PathBuf::from("/home/user/work")
+            ),
+        ]
+    );
+}
+
+#[test]
+fn hand_written_manifest_targets_are_honored_and_validated() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/karabiner.json", "{}"),
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[targets]\n\"karabiner.json\" = \".config/karabiner/karabiner.json\"\n",
+        ),
+    ]);
+
+    link(&fs, false).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/karabiner/karabiner.json"))
+            .unwrap(),
+        Path::new("/home/user/dotfiles/karabiner.json")
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[targets]\n\"karabiner.json\" = \"../../etc/karabiner.json\"\n",
+    )
+    .unwrap();
+    assert!(matches!(
+        link(&fs, false),
+        Err(dofi::DofiError::InvalidManifestTarget(..))
+    ));
+}
+
+#[test]
+fn os_suffixed_dotfiles_are_linked_on_matching_platforms() {
+    let current = format!("/home/user/dotfiles/.gitconfig.{}", std::env::consts::OS);
+    let other = if std::env::consts::OS == "freebsd" {
+        "/home/user/dotfiles/.profile.netbsd"
+    } else {
+        "/home/user/dotfiles/.profile.freebsd"
+    };
+    let fs = setup(&[
+        ("/home/user/dotfiles/.gitconfig", "generic"),
+        (&current, "specific"),
+        (other, "other"),
+    ]);
+
+    link(&fs, false).unwrap();
+
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.gitconfig")).unwrap(),
+        Path::new(&current)
+    );
+    assert_eq!(file_type(&fs, "/home/user/.profile"), None);
+}
+
+#[test]
+fn link_with_tags_only_links_tagged_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/alacritty/alacritty.toml", ""),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+    let mut journal = Journal::new(Path::new(STATE), "tag");
+    tag_path(
+        &fs,
+        Path::new("/home/user/.config/alacritty"),
+        "gui",
+        true,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(
+        dofi::Manifest::load(&fs, Path::new(DOTFILES)).unwrap().tags["gui"],
+        [PathBuf::from(".config/alacritty")]
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            tags: vec!["server".to_string()],
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions {
+            tags: vec!["gui".to_string()],
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/alacritty/alacritty.toml"),
+        Some(FileType::Symlink)
+    );
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), None);
+}
+
+#[test]
+fn sync_links_new_rerenders_changed_and_prunes_removed() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", ""),
+        ("/home/user/dotfiles/.gitconfig.tmpl", "old"),
+    ]);
+    link(&fs, false).unwrap();
+
+    fs.write(Path::new("/home/user/dotfiles/.vimrc"), b"")
+        .unwrap();
+    fs.write(Path::new("/home/user/dotfiles/.gitconfig.tmpl"), b"new")
+        .unwrap();
+    fs.remove_file(Path::new("/home/user/dotfiles/.zshrc"))
+        .unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "watch");
+    let updated = watch::sync(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &[
+            PathBuf::from("/home/user/dotfiles/.gitconfig.tmpl"),
+            PathBuf::from("/home/user/dotfiles/.vimrc"),
+            PathBuf::from("/home/user/dotfiles/.zshrc"),
+        ],
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(updated, 3);
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::Symlink));
+    assert_eq!(fs.read(Path::new("/home/user/.gitconfig")).unwrap(), b"new");
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), None);
+}
+
+#[test]
+fn new_files_in_tracked_directories_are_queued_or_added() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/git/config", "[user]"),
+        ("/home/user/.config/fish/config.fish", "set -x EDITOR nvim"),
+        ("/home/user/.config/fish/fish_history", "- cmd: ls"),
+        ("/home/user/.config/nvim/init.lua", "vim.opt.number = true"),
+        ("/home/user/.config/tool/debug.log", "started"),
+    ]);
+    link(&fs, false).unwrap();
+    let rules = track::Rules::new(
+        &track::TrackConfig {
+            review: vec![PathBuf::from(".config")],
+            ignore: vec![
+                "**/*.log".to_string(),
+                ".config/fish/fish_history".to_string(),
+            ],
+            ..Default::default()
+        },
+        &[PathBuf::from("/home/user/.config/nvim")],
+        track::Tracking::Add,
+        Path::new(BASE),
+    )
+    .unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "track");
+    let noticed = track::notice(
+        &fs,
+        &rules,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &[PathBuf::from(DOTFILES)],
+        &[
+            "/home/user/.config/fish/config.fish",
+            "/home/user/.config/fish/fish_history",
+            "/home/user/.config/git/config",
+            "/home/user/.config/nvim/init.lua",
+            "/home/user/.config/tool/debug.log",
+            "/home/user/.zshrc",
+        ]
+        .map(PathBuf::from),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        noticed,
+        track::Noticed {
+            added: vec![PathBuf::from("/home/user/.config/nvim/init.lua")],
+            queued: vec![PathBuf::from("/home/user/.config/fish/config.fish")],
+        }
+    );
+    assert_eq!(
+        file_type(&fs, "/home/user/.config/nvim/init.lua"),
+        Some(FileType::Symlink)
+    );
+    let pending = |fs: &MemoryFs| {
+        track::pending(
+            fs,
+            Path::new(STATE),
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+        )
+        .unwrap()
+    };
+    assert_eq!(
+        pending(&fs),
+        [PathBuf::from("/home/user/.config/fish/config.fish")]
+    );
+
+    let dismissed = track::dismiss(
+        &fs,
+        Path::new(STATE),
+        &[PathBuf::from("/home/user/.config/fish/config.fish")],
+    )
+    .unwrap();
+    assert_eq!(dismissed, 1);
+    assert!(pending(&fs).is_empty());
+}
+
+#[test]
+fn tree_shows_nested_dotfiles_with_their_state() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/nvim/init.lua", ""),
+        ("/home/user/dotfiles/.config/git/config", ""),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+    link(&fs, false).unwrap();
+    fs.remove_file(Path::new("/home/user/.zshrc")).unwrap();
+
+    let entries = dofi::status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+
+    assert_eq!(
+        dofi::status::tree(&entries, false),
+        "/home/user/dotfiles
+├── .config
+│   ├── git
+│   │   └── config  [linked]
+│   └── nvim
+│       └── init.lua  [linked]
+└── .zshrc  [unlinked]
+"
+    );
+}
+
+#[test]
+fn dangling_symlinks_at_targets_are_broken() {
+    let fs = setup(&[("/home/user/dotfiles/.zshrc", "")]);
+    fs.symlink(Path::new("/nowhere/.zshrc"), Path::new("/home/user/.zshrc"))
+        .unwrap();
+
+    let entries = dofi::status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+
+    assert_eq!(entries[0].state, dofi::status::State::Broken);
+}
+
+#[test]
+fn unchanged_rendered_targets_are_not_written_again() {
+    let fs = setup(&[("/home/user/dotfiles/.gitconfig.tmpl", "[user]\n")]);
+    assert_eq!(link(&fs, false).unwrap().linked, 1);
+    let journal = fs.read(&Path::new(STATE).join("journal.jsonl")).unwrap();
+
+    let summary = link(&fs, false).unwrap();
+    assert_eq!((summary.linked, summary.unchanged), (0, 1));
+    assert_eq!(
+        fs.read(&Path::new(STATE).join("journal.jsonl")).unwrap(),
+        journal
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/.gitconfig.tmpl"),
+        b"[user]\n\tname = Jane\n",
+    )
+    .unwrap();
+    assert_eq!(link(&fs, false).unwrap().linked, 1);
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gitconfig")).unwrap(),
+        b"[user]\n\tname = Jane\n"
+    );
+}
+
+#[test]
+fn changed_rendered_targets_are_detected_and_not_overwritten() {
+    let fs = setup(&[("/home/user/dotfiles/.gitconfig.tmpl", "[user]\n")]);
+    link(&fs, false).unwrap();
+
+    let target = Path::new("/home/user/.gitconfig");
+    let checksums = checksum::load(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(checksum::modified(&fs, &checksums, target), Some(false));
+
+    fs.write(target, b"[user]\n\tname = Jane\n").unwrap();
+    assert_eq!(checksum::modified(&fs, &checksums, target), Some(true));
+
+    let error = link(&fs, true).unwrap_err();
+    assert!(matches!(error, dofi::DofiError::TargetModified(_)));
+    assert_eq!(fs.read(target).unwrap(), b"[user]\n\tname = Jane\n");
+}
+
+#[test]
+fn init_scaffolds_a_dotfiles_directory_without_overwriting() {
+    let fs = MemoryFs::new();
+    let dotfiles = Path::new("/home/user/new-dotfiles");
+
+    let mut journal = Journal::new(Path::new(STATE), "init");
+    let written = init::scaffold(&fs, dotfiles, &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(
+        written,
+        [dotfiles.join("dofi.toml"), dotfiles.join(".gitignore")]
+    );
+    Manifest::load(&fs, dotfiles).unwrap();
+    assert!(list_files(&fs, dotfiles).unwrap().is_empty());
+
+    fs.write(&dotfiles.join(".gitignore"), b"secret\n").unwrap();
+    fs.remove_file(&dotfiles.join("dofi.toml")).unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "init");
+    let written = init::scaffold(&fs, dotfiles, &mut journal).unwrap();
+    assert_eq!(written, [dotfiles.join("dofi.toml")]);
+    assert_eq!(fs.read(&dotfiles.join(".gitignore")).unwrap(), b"secret\n");
+}
+
+#[test]
+fn link_applies_manifest_modes_and_undo_restores_them() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[modes]\n\".ssh/config\" = 0o600\n\"bin/*\" = 0o755\n\"bin/private\" = 0o700\n\".profile\" = 0o640\n",
+        ),
+        ("/home/user/dotfiles/.ssh/config", "Host *"),
+        ("/home/user/dotfiles/bin/backup", "#!/bin/sh"),
+        ("/home/user/dotfiles/bin/private", "#!/bin/sh"),
+        ("/home/user/dotfiles/.profile.tmpl", "export EDITOR=nvim"),
+    ]);
+    let mode = |path: &str| fs.symlink_metadata(Path::new(path)).unwrap().mode;
+    let ssh_violation = || {
+        dofi::mode_violation(
+            &fs,
+            Path::new("/home/user/dotfiles/.ssh/config"),
+            Path::new("/home/user/.ssh/config"),
+            dofi::Strategy::Symlink,
+            Some(0o600),
+        )
+    };
+
+    assert_eq!(ssh_violation(), Some(0o644));
+    link(&fs, false).unwrap();
+
+    assert_eq!(ssh_violation(), None);
+    assert_eq!(mode("/home/user/dotfiles/bin/backup"), 0o755);
+    assert_eq!(mode("/home/user/dotfiles/bin/private"), 0o700);
+    assert_eq!(mode("/home/user/.profile"), 0o640);
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(mode("/home/user/dotfiles/.ssh/config"), 0o644);
+    assert_eq!(mode("/home/user/dotfiles/bin/backup"), 0o644);
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[modes]\n\".ssh/config\" = 0o17777\n",
+    )
+    .unwrap();
+    let error = Manifest::load(&fs, Path::new(DOTFILES)).unwrap_err();
+    assert!(matches!(error, dofi::DofiError::InvalidMode(_, 0o17777)));
+}
+
+#[test]
+fn system_targets_need_elevation_and_list_the_commands() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[targets]\n\"etc/hosts\" = \"/etc/hosts\"\n",
+        ),
+        ("/home/user/dotfiles/etc/hosts", "127.0.0.1 localhost"),
+        ("/home/user/dotfiles/.zshrc", "autoload -U compinit"),
+    ]);
+
+    let error = link(&fs, false).unwrap_err();
+    let dofi::DofiError::ElevationRequired(commands, 1) = error else {
+        panic!("unexpected error {error:?}");
+    };
+    assert_eq!(
+        commands,
+        "  sudo mkdir -p /etc\n  sudo ln -s /home/user/dotfiles/etc/hosts /etc/hosts"
+    );
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), None);
+
+    fs.create_dir_all(Path::new("/etc")).unwrap();
+    fs.symlink(
+        Path::new("/home/user/dotfiles/etc/hosts"),
+        Path::new("/etc/hosts"),
+    )
+    .unwrap();
+    let commands = elevate::plan(
+        &fs,
+        Path::new("/home/user/dotfiles/etc/hosts"),
+        Path::new("/etc/hosts"),
+        false,
+    )
+    .unwrap();
+    assert!(commands.is_empty());
+}
+
+#[test]
+fn roots_map_repo_directories_to_their_own_bases() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[roots]\nhome = \"~\"\nconfig = \"~/.config\"\netc = \"/etc\"\n",
+        ),
+        ("/home/user/dotfiles/home/.zshrc", "autoload -U compinit"),
+        (
+            "/home/user/dotfiles/config/nvim/init.lua",
+            "vim.opt.number = true",
+        ),
+        ("/home/user/dotfiles/etc/hosts", "127.0.0.1 localhost"),
+        ("/home/user/.config/git/config", "[user]"),
+    ]);
+
+    let targets = layered_dotfiles(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)])
+        .unwrap()
+        .into_iter()
+        .map(|dotfile| dotfile.target)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        targets,
+        [
+            PathBuf::from("/etc/hosts"),
+            PathBuf::from("/home/user/.config/nvim/init.lua"),
+            PathBuf::from("/home/user/.zshrc"),
+        ]
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        Path::new("/home/user/.config/git/config"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.config/git/config"))
+            .unwrap(),
+        Path::new("/home/user/dotfiles/config/git/config")
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[roots]\nhome = \"$DOFI_UNSET_ROOT/home\"\n",
+    )
+    .unwrap();
+    let error = layered_dotfiles(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap_err();
+    assert!(matches!(error, dofi::DofiError::InvalidManifestRoot(..)));
+}
+
+#[test]
+fn variables_of_later_layers_override_and_are_not_linked() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.gitconfig.tmpl", "email = {{ email }}"),
+        (
+            "/home/user/dotfiles/vars.toml",
+            "email = \"me@example.com\"\nfont_size = 12\n",
+        ),
+        (
+            "/home/user/dotfiles/vars.d/not-this-machine.toml",
+            "email = \"other@example.com\"\n",
+        ),
+        ("/home/user/work/vars.toml", "email = \"me@work.com\"\n"),
+    ]);
+    let layers = [PathBuf::from(DOTFILES), PathBuf::from("/home/user/work")];
+
+    let variables = vars::load(&fs, &layers).unwrap();
+    assert_eq!(variables["email"], "me@work.com");
+    assert_eq!(variables["font_size"], "12");
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        [PathBuf::from("/home/user/dotfiles/.gitconfig.tmpl")]
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    link_files(
+        &fs,
+        Path::new(BASE),
+        &layers,
+        &LinkOptions {
+            templates: template::Context {
+                variables,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        fs.read(Path::new("/home/user/.gitconfig")).unwrap(),
+        b"email = me@work.com"
+    );
+
+    fs.write(Path::new("/home/user/work/vars.toml"), b"email = [1]\n")
+        .unwrap();
+    assert!(matches!(
+        vars::load(&fs, &layers),
+        Err(dofi::DofiError::InvalidConfig { .. })
+    ));
+}
+
+#[test]
+fn manifest_targets_expand_environment_variables() {
+    std::env::set_var("DOFI_TEST_CONFIG_HOME", "/home/user/.xdg");
+    let fs = setup(&[
+        ("/home/user/dotfiles/gitconfig", "[user]"),
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[targets]\ngitconfig = \"${DOFI_TEST_CONFIG_HOME}/git/config\"\n",
+        ),
+    ]);
+
+    link(&fs, false).unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.xdg/git/config"))
+            .unwrap(),
+        Path::new("/home/user/dotfiles/gitconfig")
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[targets]\ngitconfig = \"$DOFI_TEST_UNSET/git/config\"\n",
+    )
+    .unwrap();
+    let error = link(&fs, false).unwrap_err();
+    assert!(matches!(error, dofi::DofiError::UnsetVariable(name, _) if name == "DOFI_TEST_UNSET"));
+}
+
+#[test]
+fn apply_prunes_symlinks_to_removed_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/foo/config", "foo"),
+        ("/home/user/dotfiles/.vimrc", "set number"),
+    ]);
+    link(&fs, false).unwrap();
+
+    fs.remove_file(Path::new("/home/user/dotfiles/.config/foo/config"))
+        .unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "apply");
+    let pruned = prune_dangling_links(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        true,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(pruned, 1);
+    assert_eq!(file_type(&fs, "/home/user/.config/foo/config"), None);
+    assert_eq!(file_type(&fs, "/home/user/.config/foo"), None);
+    assert_eq!(file_type(&fs, "/home/user/.vimrc"), Some(FileType::Symlink));
+}
+
+#[test]
+fn expired_operations_lose_their_backups_but_not_their_links() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/foo/config", "foo"),
+        ("/home/user/.notes", "0"),
+    ]);
+    link(&fs, false).unwrap();
+    for n in 1..=journal::HISTORY_LIMIT + 5 {
+        let mut journal = Journal::new(Path::new(STATE), "edit");
+        journal
+            .write_file(
+                &fs,
+                Path::new("/home/user/.notes"),
+                n.to_string().as_bytes(),
+            )
+            .unwrap();
+        journal.commit(&fs).unwrap();
+    }
+    let backups = fs.walk(&Path::new(STATE).join("backups")).unwrap();
+    assert_eq!(backups.len(), journal::HISTORY_LIMIT);
+
+    fs.remove_file(Path::new("/home/user/dotfiles/.config/foo/config"))
+        .unwrap();
+    let mut journal = Journal::new(Path::new(STATE), "apply");
+    let pruned = prune_dangling_links(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        true,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(pruned, 1);
+    assert_eq!(file_type(&fs, "/home/user/.config/foo"), None);
+
+    for _ in 0..journal::HISTORY_LIMIT {
+        journal::undo(&fs, Path::new(STATE)).unwrap();
+    }
+    assert!(matches!(
+        journal::undo(&fs, Path::new(STATE)),
+        Err(dofi::DofiError::NothingToUndo)
+    ));
+}
+
+#[test]
+fn linking_refuses_two_dotfiles_of_a_layer_with_the_same_target() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.gitconfig", "[user]"),
+        (
+            "/home/user/dotfiles/.gitconfig.tmpl",
+            "[user]\n\tname = {{ name }}",
+        ),
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+    ]);
+
+    let mut journal = Journal::new(Path::new(STATE), "link");
+    let error = link_files(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap_err();
+
+    match error {
+        dofi::DofiError::DuplicateTarget(target, first, second) => {
+            assert_eq!(target, PathBuf::from("/home/user/.gitconfig"));
+            assert_eq!(
+                [first, second],
+                [
+                    PathBuf::from("/home/user/dotfiles/.gitconfig"),
+                    PathBuf::from("/home/user/dotfiles/.gitconfig.tmpl"),
+                ]
+            );
+        }
+        error => panic!("unexpected error {error:?}"),
+    }
+    assert!(!fs.exists(Path::new("/home/user/.zshrc")));
+}
+
+#[test]
+fn check_reports_broken_templates_missing_manifest_files_and_shared_targets() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[targets]\n\"gone\" = \".gone\"\n",
+        ),
+        ("/home/user/dotfiles/.gitconfig", "plain"),
+        (
+            "/home/user/dotfiles/.gitconfig.tmpl",
+            "fine\n{{ \"unclosed }}",
+        ),
+        ("/home/user/dotfiles/.zshrc.linux", "linux"),
+        ("/home/user/dotfiles/.zshrc.macos", "macos"),
+    ]);
+
+    let findings = check::check(
+        &fs,
+        Path::new(BASE),
+        &[PathBuf::from(DOTFILES)],
+        &LinkOptions::default(),
+    )
+    .unwrap();
+    let findings = findings
+        .iter()
+        .map(|finding| format!("{}: {}", finding.path.display(), finding.problem))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        findings,
+        [
+            "/home/user/dotfiles/gone: listed in [targets] of the manifest but does not exist",
+            "/home/user/dotfiles/.gitconfig.tmpl: does not render, line 2: unclosed expression",
+            "/home/user/dotfiles/.gitconfig.tmpl: targets '/home/user/.gitconfig' just like \
+             '/home/user/dotfiles/.gitconfig'",
+        ]
+    );
+}
+
+#[test]
+fn templatify_keeps_the_shared_lines_and_moves_the_rest_to_host_variables() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/.gitconfig.hostname-laptop",
+            "[user]\nemail = me@home.com\n[core]\neditor = vim\n",
+        ),
+        (
+            "/home/user/dotfiles/.gitconfig.hostname-work",
+            "[user]\nemail = me@work.com\nname = Me\n[core]\neditor = vim\n{{\n",
+        ),
+        (
+            "/home/user/dotfiles/vars.d/work.toml",
+            "gitconfig_1 = \"x\"\n",
+        ),
+    ]);
+    let mut journal = Journal::new(Path::new(STATE), "templatify");
+    let templatified = templatify::templatify(
+        &fs,
+        Path::new("/home/user/.gitconfig"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(templatified.variables, ["gitconfig_2", "gitconfig_3"]);
+    let read = |path: &str| String::from_utf8(fs.read(Path::new(path)).unwrap()).unwrap();
+    assert_eq!(
+        read("/home/user/dotfiles/.gitconfig.tmpl"),
+        "[user]\n{{ gitconfig_2 }}[core]\neditor = vim\n{{ gitconfig_3 }}"
+    );
+    assert_eq!(
+        read("/home/user/dotfiles/vars.d/laptop.toml"),
+        "gitconfig_2 = \"\"\"\nemail = me@home.com\n\"\"\"\ngitconfig_3 = \"\"\n"
+    );
+    assert_eq!(
+        read("/home/user/dotfiles/vars.d/work.toml"),
+        "gitconfig_1 = \"x\"\ngitconfig_2 = \"\"\"\nemail = me@work.com\nname = Me\n\"\"\"\n\
+         gitconfig_3 = \"\"\"\n{{\n\"\"\"\n"
+    );
+    let manifest = Manifest::load(&fs, Path::new(DOTFILES)).unwrap();
+    assert!(manifest.is_linked_on(Path::new(".gitconfig.tmpl"), Some("work")));
+    assert!(!manifest.is_linked_on(Path::new(".gitconfig.tmpl"), Some("other")));
+    assert!(!fs.exists(Path::new("/home/user/dotfiles/.gitconfig.hostname-work")));
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert!(fs.exists(Path::new("/home/user/dotfiles/.gitconfig.hostname-work")));
+    assert!(!fs.exists(Path::new("/home/user/dotfiles/.gitconfig.tmpl")));
+    assert!(matches!(
+        templatify::templatify(
+            &fs,
+            Path::new(".bashrc"),
+            Path::new(BASE),
+            Path::new(DOTFILES),
+            &mut Journal::new(Path::new(STATE), "templatify"),
+        ),
+        Err(dofi::DofiError::NoHostVariants(_))
+    ));
+}
+
+#[test]
+fn templates_are_linted_for_every_mistake() {
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/vars.toml",
+            "email = \"me@example.com\"\n",
+        ),
+        (
+            "/home/user/dotfiles/.gitconfig.tmpl",
+            "email = {{ email }}\nname = {{ name }}\n{{ include \"templates/none\" }}\n\
+             {{ upper email }}\n{{ env \"UNSET_IN_LINT\" }}\n",
+        ),
+    ]);
+    let options = LinkOptions {
+        templates: template::Context {
+            variables: vars::load(&fs, &[PathBuf::from(DOTFILES)]).unwrap(),
+            dotfiles_directories: vec![PathBuf::from(DOTFILES)],
+            ..Default::default()
+        },
+        ..LinkOptions::default()
+    };
+
+    let findings = check::check(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)], &options)
+        .unwrap()
+        .iter()
+        .map(|finding| finding.problem.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        findings,
+        [
+            "does not render, line 2: undefined variable 'name'",
+            "does not render, line 3: there is no partial 'templates/none'",
+            "does not render, line 4: unknown function 'upper', expected 'env', 'has', 'secret' \
+             or 'include'",
+        ]
+    );
+}
+
+#[test]
+fn files_are_limited_to_hosts_by_the_deepest_entry() {
+    let fs = setup(&[(
+        "/home/user/dotfiles/dofi.toml",
+        "[files.\".config\"]\nexclude_hosts = [\"work-laptop\"]\n\n\
+         [files.\".config/work\"]\nhosts = [\"work-laptop\"]\n",
+    )]);
+    let manifest = Manifest::load(&fs, Path::new(DOTFILES)).unwrap();
+
+    let vpn = Path::new(".config/work/vpn.conf");
+    assert!(manifest.is_linked_on(vpn, Some("Work-Laptop")));
+    assert!(!manifest.is_linked_on(vpn, Some("home")));
+    assert!(!manifest.is_linked_on(vpn, None));
+    let git = Path::new(".config/git/config");
+    assert!(!manifest.is_linked_on(git, Some("work-laptop")));
+    assert!(manifest.is_linked_on(git, Some("home")));
+    assert!(manifest.is_linked_on(Path::new(".zshrc"), Some("work-laptop")));
+}
+
+#[test]
+fn adopting_a_target_takes_its_contents_into_the_dotfile() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.vimrc", "set number"),
+        ("/home/user/.vimrc", "set number\nset list"),
+        ("/home/user/dotfiles/.gitconfig.tmpl", "[user]"),
+        ("/home/user/.gitconfig", "[user]\nname = me"),
+    ]);
+    let layers = [PathBuf::from(DOTFILES)];
+    let mut journal = Journal::new(Path::new(STATE), "adopt");
+    let adopted = adopt::adopt_target(
+        &fs,
+        Path::new("/home/user/.vimrc"),
+        Path::new(BASE),
+        &layers,
+        false,
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        adopted,
+        adopt::Adopted::Linked(PathBuf::from("/home/user/dotfiles/.vimrc"))
+    );
+    assert_eq!(
+        fs.read(Path::new("/home/user/dotfiles/.vimrc")).unwrap(),
+        b"set number\nset list"
+    );
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.vimrc")).unwrap(),
+        PathBuf::from("/home/user/dotfiles/.vimrc")
+    );
+
+    let rendered = adopt::adopt_target(
+        &fs,
+        Path::new("/home/user/.gitconfig"),
+        Path::new(BASE),
+        &layers,
+        false,
+        &LinkOptions::default(),
+        &mut journal,
+    );
+    assert!(matches!(
+        rendered,
+        Err(dofi::DofiError::CannotAdoptRendered(_))
+    ));
+    journal.commit(&fs).unwrap();
+}
+
+#[test]
+fn large_binary_and_history_files_are_flagged() {
+    let fs = setup(&[
+        ("/home/user/.vimrc", "set number"),
+        ("/home/user/.zsh_history", ": 1700000000:0;ls"),
+        (
+            "/home/user/.config/app/settings.json",
+            "{\"theme\": \"dark\"}",
+        ),
+        ("/home/user/.config/app/state.db", "SQLite\0\0"),
+        ("/home/user/.config/app/app.log", "started"),
+    ]);
+
+    assert_eq!(
+        guard::inspect(&fs, Path::new("/home/user/.vimrc"), 1024).unwrap(),
+        []
+    );
+    assert_eq!(
+        guard::inspect(&fs, Path::new("/home/user/.vimrc"), 4).unwrap(),
+        [(
+            PathBuf::from("/home/user/.vimrc"),
+            guard::Concern::Large(10)
+        )]
+    );
+    assert_eq!(
+        guard::inspect(&fs, Path::new("/home/user/.zsh_history"), 1024).unwrap(),
+        [(
+            PathBuf::from("/home/user/.zsh_history"),
+            guard::Concern::Volatile
+        )]
+    );
+    assert_eq!(
+        guard::inspect(&fs, Path::new("/home/user/.config/app"), 1024).unwrap(),
+        [
+            (
+                PathBuf::from("/home/user/.config/app/app.log"),
+                guard::Concern::Volatile
+            ),
+            (
+                PathBuf::from("/home/user/.config/app/state.db"),
+                guard::Concern::Binary
+            ),
+        ]
+    );
+}
+
+#[test]
+fn picker_filters_fuzzily_and_toggles_selections() {
+    assert!(picker::score("nvim", ".config/nvim/init.lua").is_some());
+    assert!(picker::score("lua.init", ".config/nvim/init.lua").is_none());
+    assert!(picker::score("zshrc", ".zshrc") > picker::score("zshrc", ".config/zsh/rc.d/extra"));
+
+    let candidates = [
+        PathBuf::from("/home/user/.bashrc"),
+        PathBuf::from("/home/user/.config/nvim/init.lua"),
+        PathBuf::from("/home/user/.zshrc"),
+    ];
+    let input = b"rc\n1 2\n2\nnvim\n1\n\n";
+    let mut output = Vec::new();
+    let selected = picker::pick(&candidates, Path::new(BASE), &input[..], &mut output).unwrap();
+
+    assert_eq!(
+        selected,
+        [
+            PathBuf::from("/home/user/.config/nvim/init.lua"),
+            PathBuf::from("/home/user/.zshrc"),
+        ]
+    );
+}
+
+#[test]
+fn picking_one_finishes_at_the_first_number() {
+    let candidates = [
+        PathBuf::from("/home/user/.bashrc"),
+        PathBuf::from("/home/user/.config/nvim/init.lua"),
+        PathBuf::from("/home/user/.zshrc"),
+    ];
+    let input = b"1 2\nzsh\n1\n";
+    let mut output = Vec::new();
+    let picked = picker::pick_one(&candidates, Path::new(BASE), &input[..], &mut output).unwrap();
+    assert_eq!(picked, Some(PathBuf::from("/home/user/.zshrc")));
+
+    let picked = picker::pick_one(&candidates, Path::new(BASE), &b"\n"[..], Vec::new()).unwrap();
+    assert_eq!(picked, None);
+}
+
+#[test]
+fn confirmation_lists_the_affected_paths_and_defaults_to_no() {
+    let affected = [PathBuf::from("/home/user/dotfiles/.vimrc")];
+    let mut output = Vec::new();
+    assert!(confirm::confirm(
+        "Remove these dotfiles?",
+        &affected,
+        &b"Yes\n"[..],
+        &mut output
+    )
+    .unwrap());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "  /home/user/dotfiles/.vimrc\nRemove these dotfiles? [y/N] "
+    );
+
+    for answer in ["\n", "nope\n", ""] {
+        let answer = confirm::confirm("Remove?", &affected, answer.as_bytes(), Vec::new());
+        assert!(!answer.unwrap());
+    }
+}
+
+#[test]
+fn porcelain_lines_are_tab_separated_and_escaped() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.vimrc", "set number"),
+        ("/home/user/dotfiles/tab\there", "odd"),
+    ]);
+    link(&fs, false).unwrap();
+    fs.remove_file(Path::new("/home/user/.vimrc")).unwrap();
+
+    let lines = dofi::status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)])
+        .unwrap()
+        .iter()
+        .map(|entry| entry.porcelain())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "unlinked\t/home/user/dotfiles/.vimrc\t/home/user/.vimrc",
+            "linked\t/home/user/dotfiles/tab\\there\t/home/user/tab\\there",
+        ]
+    );
+}
+
+#[test]
+fn private_dotfiles_are_copied_readable_only_by_the_user() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/private/.netrc", "machine example.com"),
+        (
+            "/home/user/dotfiles/private/.config/app.tmpl",
+            "token = secret",
+        ),
+        ("/home/user/dotfiles/.zshrc", "export EDITOR=nvim"),
+    ]);
+
+    link(&fs, false).unwrap();
+    for (target, contents) in [
+        ("/home/user/.netrc", "machine example.com"),
+        ("/home/user/.config/app", "token = secret"),
+    ] {
+        assert_eq!(file_type(&fs, target), Some(FileType::File));
+        assert_eq!(fs.symlink_metadata(Path::new(target)).unwrap().mode, 0o600);
+        assert_eq!(fs.read(Path::new(target)).unwrap(), contents.as_bytes());
+    }
+    assert_eq!(file_type(&fs, "/home/user/.zshrc"), Some(FileType::Symlink));
+
+    fs.write(
+        Path::new("/home/user/dotfiles/private/.netrc"),
+        b"machine example.org",
+    )
+    .unwrap();
+    link(&fs, false).unwrap();
+    assert_eq!(
+        fs.read(Path::new("/home/user/.netrc")).unwrap(),
+        b"machine example.org"
+    );
+
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    assert!(entries.iter().all(|entry| entry.state == State::Linked));
+    let private = entries
+        .iter()
+        .filter(|entry| entry.private)
+        .map(|entry| entry.target.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        private,
+        [
+            PathBuf::from("/home/user/.config/app"),
+            PathBuf::from("/home/user/.netrc")
+        ]
+    );
+}
+
+#[test]
+fn grep_searches_linked_dotfiles_and_skips_binaries() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/dofi.toml", "exclude = [\"notes/*\"]\n"),
+        (
+            "/home/user/dotfiles/.zshrc",
+            "export EDITOR=nvim\nalias vi=nvim\nexport PAGER=less",
+        ),
+        ("/home/user/dotfiles/.config/app", "editor = \"NVIM\""),
+        ("/home/user/dotfiles/notes/todo", "try nvim"),
+        ("/home/user/dotfiles/bin/tool", "nvim\0"),
+    ]);
+    let search = |pattern, ignore_case| {
+        let pattern = grep::pattern(pattern, ignore_case).unwrap();
+        grep::grep(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)], &pattern)
+            .unwrap()
+            .into_iter()
+            .map(|found| (found.source, found.line_number))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        search("nvim", false),
+        [(PathBuf::from(".zshrc"), 1), (PathBuf::from(".zshrc"), 2)]
+    );
+    assert_eq!(search("NVIM", false), [(PathBuf::from(".config/app"), 1)]);
+    assert_eq!(search("NVIM", true).len(), 3);
+    assert!(matches!(
+        grep::pattern("(", false),
+        Err(dofi::DofiError::InvalidSearchPattern(..))
+    ));
+}
+
+#[test]
+fn queries_select_the_dotfiles_of_a_package() {
+    use std::time::SystemTime;
+
+    let fs = setup(&[
+        ("/home/user/dotfiles/nvim/.config/nvim/init.lua", ""),
+        ("/home/user/dotfiles/zsh/.zshrc", ""),
+    ]);
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    let selected = |query: &str| {
+        let query =
+            Query::parse(query).and_then(|query| query.resolve(&fs, &[PathBuf::from(DOTFILES)]))?;
+        Ok::<_, dofi::DofiError>(
+            entries
+                .iter()
+                .filter(|entry| query.matches(entry, SystemTime::now()))
+                .map(|entry| entry.relative_source().to_path_buf())
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    assert_eq!(
+        selected("package:nvim").unwrap(),
+        [PathBuf::from("nvim/.config/nvim/init.lua")]
+    );
+    assert_eq!(
+        selected("!package:nvim").unwrap(),
+        [PathBuf::from("zsh/.zshrc")]
+    );
+    assert!(matches!(
+        selected("package:emacs"),
+        Err(dofi::DofiError::NoMatchingDotfile { .. })
+    ));
+}
+
+#[test]
+fn dashboard_numbers_entries_and_unlinks_them() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/git/config", ""),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+    link(&fs, false).unwrap();
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+
+    assert_eq!(tui::Action::parse("u 2"), Some(tui::Action::Unlink(1)));
+    assert_eq!(tui::Action::parse("undo"), Some(tui::Action::Undo));
+    assert_eq!(tui::Action::parse(""), Some(tui::Action::Quit));
+    assert_eq!(tui::Action::parse("link 0"), None);
+    assert_eq!(tui::Action::parse("link 1 2"), None);
+    assert_eq!(
+        tui::Action::parse("filter state:linked  nvim"),
+        Some(tui::Action::Filter("state:linked nvim".to_string()))
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "unlink");
+    tui::unlink(&fs, &entries[1], &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    assert_eq!(
+        tui::render(&entries, false),
+        "/home/user/dotfiles
+     ├── .config
+     │   └── git
+   1 │       └── config  [linked]
+   2 └── .zshrc  [unlinked]
+1 linked, 1 unlinked, 0 conflicts, 0 broken
+"
+    );
+}
+
+#[test]
+fn systemd_units_run_apply_periodically_and_are_removed_again() {
+    let fs = setup(&[]);
+    let directory = Path::new("/home/user/.config/systemd/user");
+    let service = service::Service::new(
+        Path::new("/usr/local/bin/dofi"),
+        &["-d".into(), "/home/user/my dotfiles".into(), "apply".into()],
+        service::parse_interval("30m").unwrap(),
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "service");
+    service::write_systemd_units(&fs, directory, &service, &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+    let unit = |name: &str| String::from_utf8(fs.read(&directory.join(name)).unwrap()).unwrap();
+    assert!(unit("dofi.service")
+        .contains("ExecStart=/usr/local/bin/dofi -d \"/home/user/my dotfiles\" apply\n"));
+    assert!(unit("dofi.timer").contains("OnUnitActiveSec=1800s\n"));
+    assert!(service::parse_interval("0h").is_err());
+
+    let mut journal = Journal::new(Path::new(STATE), "service");
+    let removed = service::remove_systemd_units(&fs, directory, &mut journal).unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(removed.len(), 2);
+    assert!(!fs.exists(&directory.join("dofi.timer")));
+}
+
+#[test]
+fn launchd_agents_run_apply_periodically_with_a_log() {
+    let fs = setup(&[]);
+    let path = service::launchd_path(Path::new(BASE));
+    let service = service::Service::new(
+        Path::new("/usr/local/bin/dofi"),
+        &["-d".into(), "/home/user/R&D".into(), "apply".into()],
+        service::parse_interval("1h").unwrap(),
+    );
+
+    let mut journal = Journal::new(Path::new(STATE), "service");
+    service::write_launchd_agent(
+        &fs,
+        &path,
+        &service,
+        &Path::new(STATE).join(service::LOG_FILE),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    let plist = String::from_utf8(fs.read(&path).unwrap()).unwrap();
+    assert!(path.starts_with("/home/user/Library/LaunchAgents"));
+    assert!(plist
+        .contains("        <string>/home/user/R&amp;D</string>\n        <string>apply</string>\n"));
+    assert!(plist.contains("<key>StartInterval</key>\n    <integer>3600</integer>"));
+    assert!(plist.contains("<string>/home/user/.local/state/dofi/service.log</string>"));
+
+    let mut journal = Journal::new(Path::new(STATE), "service");
+    assert!(service::remove_launchd_agent(&fs, &path, &mut journal).unwrap());
+    assert!(!service::remove_launchd_agent(&fs, &path, &mut journal).unwrap());
+    journal.commit(&fs).unwrap();
+}
+
+#[test]
+fn plugins_advertise_their_subcommands_and_options() {
+    let completions = plugin::Completions::parse(
+        "Backs the dotfiles up\nrestore\tRestores the last backup\n\n--remote=\tThe remote\n\
+         --dry-run\n",
+    );
+    assert_eq!(completions.about, "Backs the dotfiles up");
+    assert_eq!(
+        completions.words,
+        [
+            (
+                "restore".to_string(),
+                "Restores the last backup".to_string()
+            ),
+            ("--remote=".to_string(), "The remote".to_string()),
+            ("--dry-run".to_string(), String::new()),
+        ]
+    );
+    assert_eq!(plugin::Completions::parse(""), Default::default());
+}
+
+#[test]
+fn packages_select_the_hooks_of_their_plugin_managers() {
+    let fs = setup(&[(
+        "/home/user/dotfiles/dofi.toml",
+        "[hooks.packages]\nnvim = [\"lazy\"]\nzsh = [\"zinit\", \"zplug\"]\n",
+    )]);
+    let manifest = Manifest::load(&fs, Path::new(DOTFILES)).unwrap();
+    assert_eq!(manifest.hooks.packages["nvim"], [PluginManager::Lazy]);
+    assert_eq!(
+        manifest.hooks.packages["zsh"]
+            .iter()
+            .map(|manager| manager.command())
+            .collect::<Vec<_>>(),
+        [
+            "zsh -ic \"zinit compile --all\"",
+            "zsh -ic \"zplug check || zplug install\""
+        ]
+    );
+
+    fs.write(
+        Path::new("/home/user/dotfiles/dofi.toml"),
+        b"[hooks.packages]\nvim = [\"vundle\"]\n",
+    )
+    .unwrap();
+    assert!(Manifest::load(&fs, Path::new(DOTFILES)).is_err());
+}
+
+#[test]
+fn scripts_are_not_linked_and_pending_until_recorded_or_changed() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/scripts/10-fonts.sh", "fc-cache -f"),
+        (
+            "/home/user/dotfiles/.config/run_once_defaults.sh",
+            "defaults write",
+        ),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+    let layers = [PathBuf::from(DOTFILES)];
+    let pending = || {
+        scripts::pending(&fs, &layers, Path::new(STATE))
+            .unwrap()
+            .into_iter()
+            .map(|script| script.relative_path().to_path_buf())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        list_files(&fs, Path::new(DOTFILES)).unwrap(),
+        [PathBuf::from("/home/user/dotfiles/.zshrc")]
+    );
+    assert_eq!(
+        pending(),
+        [
+            PathBuf::from(".config/run_once_defaults.sh"),
+            PathBuf::from("scripts/10-fonts.sh")
+        ]
+    );
+
+    for script in scripts::pending(&fs, &layers, Path::new(STATE)).unwrap() {
+        scripts::record(&fs, Path::new(STATE), &script).unwrap();
+    }
+    assert!(pending().is_empty());
+
+    fs.write(
+        Path::new("/home/user/dotfiles/scripts/10-fonts.sh"),
+        b"fc-cache -fv",
+    )
+    .unwrap();
+    assert_eq!(pending(), [PathBuf::from("scripts/10-fonts.sh")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn failed_scripts_are_rolled_back_and_stay_pending() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = std::env::temp_dir().join(format!("dofi-scripts-{}", std::process::id()));
+    let state = directory.join("state");
+    std::fs::create_dir_all(directory.join("scripts")).unwrap();
+    std::fs::write(
+        directory.join(dofi::manifest::MANIFEST_FILE),
+        "[scripts.\"scripts/10-fail.sh\"]\nrollback = \"touch rolled-back\"\n",
+    )
+    .unwrap();
+    for (name, contents) in [
+        ("10-fail.sh", "#!/bin/sh\ntouch partial\nexit 1\n"),
+        ("20-after.sh", "#!/bin/sh\n"),
+    ] {
+        let path = directory.join("scripts").join(name);
+        std::fs::write(&path, contents).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let layers = [directory.clone()];
+
+    let pending = scripts::pending(&OsFs, &layers, &state).unwrap();
+    assert_eq!(pending[0].rollback.as_deref(), Some("touch rolled-back"));
+    assert!(scripts::run(&pending[0], Path::new(BASE), &BTreeMap::new()).is_err());
+    assert!(directory.join("partial").exists());
+    assert!(directory.join("rolled-back").exists());
+    scripts::record(&OsFs, &state, &pending[1]).unwrap();
+    assert_eq!(
+        scripts::pending(&OsFs, &layers, &state)
+            .unwrap()
+            .iter()
+            .map(|script| script.relative_path().to_path_buf())
+            .collect::<Vec<_>>(),
+        [PathBuf::from("scripts/10-fail.sh")]
+    );
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn config_settings_are_changed_in_place_and_validated() {
+    let directory = std::env::temp_dir().join(format!("dofi-config-{}", std::process::id()));
+    let path = directory.join("config.toml");
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(&path, "# mine\ndefault_workspace = \"home\" # kept\n").unwrap();
+
+    config::set_setting(&path, "default_workspace", "work").unwrap();
+    config::set_setting(&path, "git.auto_push", "true").unwrap();
+    config::set_setting(&path, "conflicts.\"~/.ssh/**\"", "never-force").unwrap();
+    assert!(config::set_setting(&path, "git.auto_pull", "true").is_err());
+    assert!(config::set_setting(&path, "git.auto_push.x", "true").is_err());
+
+    assert_eq!(
+        config::setting(&path, "git.auto_push").unwrap().as_deref(),
+        Some("true")
+    );
+    assert_eq!(config::setting(&path, "git.auto_commit").unwrap(), None);
+    assert_eq!(
+        config::settings(&path).unwrap(),
+        [
+            ("default_workspace".to_string(), "\"work\"".to_string()),
+            ("git.auto_push".to_string(), "true".to_string()),
+            (
+                "conflicts.\"~/.ssh/**\"".to_string(),
+                "\"never-force\"".to_string()
+            ),
+        ]
+    );
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(contents.starts_with("# mine\ndefault_workspace = \"work\" # kept\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn apply_writes_generated_targets_and_caches_the_output() {
+    // The commands run in the dotfiles directory, which has to exist on disk
+    let layers = [std::env::temp_dir()];
+    let fs = setup(&[
+        ("/home/user/.zsh/_git", "mine"),
+        (
+            layers[0]
+                .join(dofi::manifest::MANIFEST_FILE)
+                .to_str()
+                .unwrap(),
+            "[generated]\n\".zsh/_pid\" = \"echo $$\"\n\
+             \".zsh/_fresh\" = { command = \"echo fresh $$\", ttl = \"0s\" }\n\
+             \".zsh/_git\" = \"echo git\"\n",
+        ),
+    ]);
+    let generate = |force| {
+        let mut journal = Journal::new(Path::new(STATE), "apply");
+        let result = generate::generate(&fs, Path::new(BASE), &layers, force, &mut journal);
+        journal.commit(&fs).unwrap();
+        result
+    };
+    let read = |path: &str| String::from_utf8(fs.read(Path::new(path)).unwrap()).unwrap();
+
+    assert!(matches!(
+        generate(false),
+        Err(dofi::DofiError::FileExists(_))
+    ));
+    assert_eq!(generate(true).unwrap(), 3);
+    assert_eq!(read("/home/user/.zsh/_git"), "git\n");
+    let (pid, fresh) = (read("/home/user/.zsh/_pid"), read("/home/user/.zsh/_fresh"));
+
+    assert_eq!(generate(false).unwrap(), 1);
+    assert_eq!(read("/home/user/.zsh/_pid"), pid);
+    assert_ne!(read("/home/user/.zsh/_fresh"), fresh);
+
+    fs.write(Path::new("/home/user/.zsh/_fresh"), b"edited")
+        .unwrap();
+    assert!(generate(false).is_err());
+}
+
+#[test]
+fn prompt_counts_the_dotfiles_that_are_not_linked() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", ""),
+        ("/home/user/dotfiles/.vimrc", ""),
+        ("/home/user/dotfiles/.gitconfig", ""),
+    ]);
+    let prompt = || {
+        let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+        status::prompt(&entries)
+    };
+
+    assert_eq!(prompt(), "+3");
+    link(&fs, false).unwrap();
+    assert_eq!(prompt(), "");
+
+    fs.remove_file(Path::new("/home/user/.vimrc")).unwrap();
+    fs.write(Path::new("/home/user/.vimrc"), b"mine").unwrap();
+    fs.remove_file(Path::new("/home/user/.zshrc")).unwrap();
+    assert_eq!(prompt(), "+1 !1");
+
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    let sync = git::SyncState {
+        upstream: Some(("origin/main".to_string(), 0, 2)),
+        uncommitted: 1,
+    };
+    assert_eq!(
+        status::prompt_format(
+            "{{{dirty}}} {conflicts}{behind}{ahead}{uncommitted}",
+            &entries,
+            Some(&sync)
+        )
+        .unwrap(),
+        "{*} !1⇣2~1"
+    );
+    assert!(matches!(
+        status::prompt_format("{dirty", &entries, None),
+        Err(dofi::DofiError::InvalidPromptFormat(..))
+    ));
+    assert!(status::prompt_format("{branch}", &entries, None).is_err());
+}
+
+#[test]
+fn link_only_walks_the_given_paths() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/nvim/init.lua", ""),
+        ("/home/user/dotfiles/nvim/lua/plugins.lua", ""),
+        ("/home/user/dotfiles/nvim/.gitignore", "*.log\n"),
+        ("/home/user/dotfiles/nvim/lua/debug.log", ""),
+        ("/home/user/dotfiles/zsh/zshrc", ""),
+        ("/home/user/dotfiles/zsh/zprofile", ""),
+        ("/home/user/dotfiles/dofi.toml", "gitignore = true\n"),
+    ]);
+    let link_paths = |paths: &[&str]| {
+        let mut journal = Journal::new(Path::new(STATE), "link");
+        let result = link_files(
+            &fs,
+            Path::new(BASE),
+            &[PathBuf::from(DOTFILES)],
+            &LinkOptions {
+                paths: paths.iter().map(PathBuf::from).collect(),
+                ..Default::default()
+            },
+            &mut journal,
+        );
+        journal.commit(&fs).unwrap();
+        result
+    };
+
+    let summary = link_paths(&["nvim/lua/", "zsh/zshrc"]).unwrap();
+    assert_eq!(summary.linked, 2);
+    assert!(fs.exists(Path::new("/home/user/nvim/lua/plugins.lua")));
+    assert!(fs.exists(Path::new("/home/user/zsh/zshrc")));
+    assert!(!fs.exists(Path::new("/home/user/nvim/lua/debug.log")));
+    assert!(!fs.exists(Path::new("/home/user/nvim/init.lua")));
+    assert!(!fs.exists(Path::new("/home/user/zsh/zprofile")));
+
+    assert!(matches!(
+        link_paths(&["vim"]),
+        Err(dofi::DofiError::NoMatchingDotfile { .. })
+    ));
+}
+
+#[test]
+fn dotfiles_under_a_directory_are_found_from_sources_and_targets() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.config/nvim/init.lua", ""),
+        ("/home/user/dotfiles/.config/nvim/lua/plugins.lua", ""),
+        ("/home/user/dotfiles/.config/nvimrc", ""),
+        ("/home/user/dotfiles/.zshrc", ""),
+    ]);
+    link(&fs, false).unwrap();
+    let under = |directory: &str| {
+        dotfiles_under(
+            &fs,
+            Path::new(BASE),
+            Path::new(DOTFILES),
+            Path::new(directory),
+        )
+        .unwrap()
+    };
+
+    let nvim = [
+        PathBuf::from("/home/user/dotfiles/.config/nvim/init.lua"),
+        PathBuf::from("/home/user/dotfiles/.config/nvim/lua/plugins.lua"),
+    ];
+    assert_eq!(under("/home/user/.config/nvim"), nvim);
+    assert_eq!(under("/home/user/dotfiles/.config/nvim"), nvim);
+    assert!(under("/home/user/.cache").is_empty());
+}
+
+#[test]
+fn diffs_are_unified_with_context_and_counted_by_stat() {
+    let old = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+    let new = b"a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\nn";
+
+    assert_eq!(
+        diff::unified(old, new, Path::new("old"), Path::new("new"), false),
+        "--- old\n+++ new\n\
+         @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+         @@ -11,3 +11,4 @@\n k\n l\n m\n+n\n\\ No newline at end of file\n"
+    );
+    assert_eq!(
+        diff::unified(b"", b"x\n", Path::new("old"), Path::new("new"), false),
+        "--- old\n+++ new\n@@ -0,0 +1 @@\n+x\n"
+    );
+    assert!(diff::unified(old, old, Path::new("old"), Path::new("new"), false).is_empty());
+
+    let stat = diff::stat(old, new);
+    assert_eq!((stat.insertions, stat.deletions), (2, 1));
+    assert_eq!(
+        diff::format_stats(&[("zshrc".to_string(), stat)], false),
+        " zshrc | 3 ++-\n 1 file changed, 2 insertions(+), 1 deletion(-)\n"
+    );
+}
+
+#[test]
+fn scripts_run_after_their_dependencies_and_cycles_are_reported() {
+    let manifest = "/home/user/dotfiles/dofi.toml";
+    let fs = setup(&[
+        ("/home/user/dotfiles/scripts/a-brew-bundle.sh", ""),
+        ("/home/user/dotfiles/scripts/b-fish.sh", ""),
+        ("/home/user/dotfiles/scripts/c-homebrew.sh", ""),
+        (
+            manifest,
+            "[scripts.\"scripts/a-brew-bundle.sh\"]\nafter = [\"scripts/c-homebrew.sh\"]\n\
+             [scripts.\"scripts/b-fish.sh\"]\nafter = [\"scripts/a-brew-bundle.sh\"]\n",
+        ),
+    ]);
+    let pending = || {
+        scripts::pending(&fs, &[PathBuf::from(DOTFILES)], Path::new(STATE)).map(|scripts| {
+            scripts
+                .into_iter()
+                .map(|script| script.relative_path().to_path_buf())
+                .collect::<Vec<_>>()
+        })
+    };
+
+    assert_eq!(
+        pending().unwrap(),
+        [
+            PathBuf::from("scripts/c-homebrew.sh"),
+            PathBuf::from("scripts/a-brew-bundle.sh"),
+            PathBuf::from("scripts/b-fish.sh"),
+        ]
+    );
+
+    fs.write(
+        Path::new(manifest),
+        b"[scripts.\"scripts/a-brew-bundle.sh\"]\nafter = [\"scripts/b-fish.sh\"]\n\
+          [scripts.\"scripts/b-fish.sh\"]\nafter = [\"scripts/a-brew-bundle.sh\"]\n",
+    )
+    .unwrap();
+    assert_eq!(
+        pending().unwrap_err().to_string(),
+        "Scripts depend on each other in a cycle, 'scripts/a-brew-bundle.sh' runs after \
+         'scripts/b-fish.sh' runs after 'scripts/a-brew-bundle.sh'"
+    );
+
+    fs.write(
+        Path::new(manifest),
+        b"[scripts.\"scripts/b-fish.sh\"]\nafter = [\"scripts/fisher.sh\"]\n",
+    )
+    .unwrap();
+    assert!(matches!(pending(), Err(dofi::DofiError::UnknownScript(..))));
+}
+
+#[test]
+fn scripts_run_among_the_steps_of_apply_they_are_ordered_against() {
+    let manifest = "/home/user/dotfiles/dofi.toml";
+    let fs = setup(&[
+        ("/home/user/dotfiles/scripts/a-fonts.sh", ""),
+        ("/home/user/dotfiles/scripts/b-brew-bundle.sh", ""),
+        ("/home/user/dotfiles/scripts/c-fisher.sh", ""),
+        ("/home/user/dotfiles/scripts/d-homebrew.sh", ""),
+        (
+            manifest,
+            "[scripts.\"scripts/b-brew-bundle.sh\"]\nafter = [\"scripts/d-homebrew.sh\"]\n\
+             before = [\"link\"]\n\
+             [scripts.\"scripts/c-fisher.sh\"]\nafter = [\"link\"]\nbefore = [\"post-link\"]\n",
+        ),
+    ]);
+    let pending = || {
+        scripts::pending(&fs, &[PathBuf::from(DOTFILES)], Path::new(STATE)).map(|scripts| {
+            scripts
+                .into_iter()
+                .map(|script| (script.relative_path().to_path_buf(), script.before))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    assert_eq!(
+        pending().unwrap(),
+        [
+            (PathBuf::from("scripts/d-homebrew.sh"), Some(Step::Link)),
+            (PathBuf::from("scripts/b-brew-bundle.sh"), Some(Step::Link)),
+            (PathBuf::from("scripts/c-fisher.sh"), Some(Step::PostLink)),
+            (PathBuf::from("scripts/a-fonts.sh"), None),
+        ]
+    );
+
+    fs.write(
+        Path::new(manifest),
+        b"[scripts.\"scripts/a-fonts.sh\"]\nafter = [\"post-link\"]\nbefore = [\"link\"]\n",
+    )
+    .unwrap();
+    assert_eq!(
+        pending().unwrap_err().to_string(),
+        "Scripts depend on each other in a cycle, 'link' runs after 'scripts/a-fonts.sh' \
+         runs after 'post-link' runs after 'link'"
+    );
+
+    fs.write(
+        Path::new(manifest),
+        b"[scripts.\"scripts/a-fonts.sh\"]\nbefore = [\"unlink\"]\n",
+    )
+    .unwrap();
+    assert!(matches!(pending(), Err(dofi::DofiError::UnknownScript(..))));
+}
+
+#[cfg(unix)]
+#[test]
+fn copied_metadata_keeps_permissions_and_timestamps() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let directory = std::env::temp_dir().join(format!("dofi-metadata-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let (from, to) = (directory.join("from"), directory.join("to"));
+    std::fs::write(&from, "mail").unwrap();
+    std::fs::write(&to, "mail").unwrap();
+    let modified = UNIX_EPOCH + Duration::from_secs(1_577_934_245);
+    std::fs::File::options()
+        .write(true)
+        .open(&from)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    OsFs.set_mode(&from, 0o640).unwrap();
+
+    let lost = OsFs.copy_metadata(&from, &to).unwrap();
+    let metadata = OsFs.symlink_metadata(&to).unwrap();
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(lost.is_empty(), "{lost:?}");
+    assert_eq!(metadata.mode, 0o640);
+    assert_eq!(metadata.modified, Some(modified));
+}
+
+#[cfg(unix)]
+#[test]
+fn paths_that_are_not_utf8_survive_the_journal_and_stay_visible() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let file = Path::new(BASE).join(OsStr::from_bytes(b".caf\xe9\nrc"));
+    let fs = setup(&[]);
+    fs.write(&file, b"set -o vi").unwrap();
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        &file,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+    assert_eq!(
+        fs.symlink_metadata(&file).unwrap().file_type,
+        FileType::Symlink
+    );
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(fs.read(&file).unwrap(), b"set -o vi");
+    assert_eq!(
+        paths::display(&file).to_string(),
+        "/home/user/.caf\\xE9\\nrc"
+    );
+    assert_eq!(
+        paths::escape(Path::new("C:\\tab\there")),
+        "C:\\\\tab\\there"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn state_files_keep_paths_that_are_not_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let fs = setup(&[]);
+    let template = Path::new(DOTFILES).join(OsStr::from_bytes(b"bad\xff.tmpl"));
+    let script = Path::new(DOTFILES).join(OsStr::from_bytes(b"scripts/run\xff.sh"));
+    fs.create_dir_all(script.parent().unwrap()).unwrap();
+    fs.write(&template, b"plain").unwrap();
+    fs.write(&script, b"true").unwrap();
+
+    link(&fs, false).unwrap();
+    let target = Path::new(BASE).join(OsStr::from_bytes(b"bad\xff"));
+    let checksums = checksum::load(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(checksums.get(&target), Some(&checksum::digest(b"plain")));
+    assert_eq!(link(&fs, false).unwrap().unchanged, 1);
+
+    let layers = [PathBuf::from(DOTFILES)];
+    for script in scripts::pending(&fs, &layers, Path::new(STATE)).unwrap() {
+        scripts::record(&fs, Path::new(STATE), &script).unwrap();
+    }
+    assert!(scripts::pending(&fs, &layers, Path::new(STATE))
+        .unwrap()
+        .is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn the_review_queue_keeps_paths_that_are_not_utf8() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let fs = setup(&[]);
+    let file = Path::new(BASE).join(OsStr::from_bytes(b".config/tool/bad\xff.toml"));
+    fs.create_dir_all(file.parent().unwrap()).unwrap();
+    fs.write(&file, b"").unwrap();
+    let rules = track::Rules::new(
+        &track::TrackConfig {
+            review: vec![PathBuf::from(".config")],
+            ..Default::default()
+        },
+        &[],
+        track::Tracking::Add,
+        Path::new(BASE),
+    )
+    .unwrap();
+
+    let layers = [PathBuf::from(DOTFILES)];
+    let mut journal = Journal::new(Path::new(STATE), "track");
+    let noticed = track::notice(
+        &fs,
+        &rules,
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        &layers,
+        std::slice::from_ref(&file),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&fs).unwrap();
+
+    assert_eq!(noticed.queued, std::slice::from_ref(&file));
+    assert_eq!(
+        track::pending(&fs, Path::new(STATE), Path::new(BASE), &layers).unwrap(),
+        std::slice::from_ref(&file)
+    );
+    assert_eq!(track::dismiss(&fs, Path::new(STATE), &[file]).unwrap(), 1);
+}
+
+#[test]
+fn manifest_settings_are_edited_in_place_and_kept_valid() {
+    let manifest = "/home/user/dotfiles/dofi.toml";
+    let fs = setup(&[(
+        manifest,
+        "# Shared by every machine\n[tags]\ngui = [\".config/kitty\"]\n",
+    )]);
+    let dotfiles = Path::new(DOTFILES);
+
+    let mut journal = Journal::new(Path::new(STATE), "config");
+    dofi::manifest::set_mode(&fs, dotfiles, ".ssh/config", 0o600, &mut journal).unwrap();
+    dofi::manifest::set_setting(&fs, dotfiles, "gitignore", "true", &mut journal).unwrap();
+    let error = dofi::manifest::set_setting(&fs, dotfiles, "modes.bin", "0o17777", &mut journal)
+        .unwrap_err();
+    assert!(matches!(error, dofi::DofiError::InvalidMode(_, 0o17777)));
+    journal.commit(&fs).unwrap();
+
+    let contents = String::from_utf8(fs.read(Path::new(manifest)).unwrap()).unwrap();
+    assert!(
+        contents.contains("# Shared by every machine\n[tags]\n"),
+        "{contents}"
+    );
+    let loaded = Manifest::load(&fs, dotfiles).unwrap();
+    assert_eq!(
+        loaded.modes,
+        BTreeMap::from([(".ssh/config".to_string(), 0o600)])
+    );
+    assert!(loaded.gitignore);
+    assert!(contents.contains("\".ssh/config\" = 0o600"), "{contents}");
+
+    journal::undo(&fs, Path::new(STATE)).unwrap();
+    assert_eq!(
+        fs.read(Path::new(manifest)).unwrap(),
+        b"# Shared by every machine\n[tags]\ngui = [\".config/kitty\"]\n"
+    );
+}
+
+#[test]
+fn dot_prefixed_dotfiles_link_to_hidden_targets() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/dofi.toml", "dot_prefix = true\n"),
+        ("/home/user/dotfiles/dot_zshrc", "bindkey -v"),
+        (
+            "/home/user/dotfiles/dot_config/nvim/init.lua",
+            "vim.g.mapleader = ' '",
+        ),
+        ("/home/user/dotfiles/.tmux.conf", "set -g mouse on"),
+        ("/home/user/.gitconfig", "[user]"),
+    ]);
+
+    link(&fs, false).unwrap();
+    for (target, source) in [
+        ("/home/user/.zshrc", "dot_zshrc"),
+        (
+            "/home/user/.config/nvim/init.lua",
+            "dot_config/nvim/init.lua",
+        ),
+        ("/home/user/.tmux.conf", ".tmux.conf"),
+    ] {
+        assert_eq!(
+            fs.read_link(Path::new(target)).unwrap(),
+            Path::new(DOTFILES).join(source)
+        );
+    }
+
+    let mut journal = Journal::new(Path::new(STATE), "add");
+    add_file(
+        &fs,
+        Path::new("/home/user/.gitconfig"),
+        Path::new(BASE),
+        Path::new(DOTFILES),
+        None,
+        &mut journal,
+    )
+    .unwrap();
+    assert_eq!(
+        fs.read_link(Path::new("/home/user/.gitconfig")).unwrap(),
+        PathBuf::from("/home/user/dotfiles/dot_gitconfig")
+    );
+}
+
+#[test]
+fn stats_count_states_packages_and_the_largest_dotfiles() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", "bindkey -v"),
+        (
+            "/home/user/dotfiles/.config/nvim/init.lua",
+            "vim.opt.number = true",
+        ),
+        (
+            "/home/user/dotfiles/.config/app/cache.db",
+            &"x".repeat(4096),
+        ),
+    ]);
+    link(&fs, false).unwrap();
+    fs.remove_file(Path::new("/home/user/.zshrc")).unwrap();
+
+    let entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    let summary = stats::summarize(&fs, &entries, 2);
+    assert_eq!(
+        summary.states,
+        vec![
+            (State::Linked, 2),
+            (State::Unlinked, 1),
+            (State::Conflict, 0),
+            (State::Broken, 0)
+        ]
+    );
+    assert_eq!(
+        summary.packages,
+        BTreeMap::from([
+            (PathBuf::from("."), (1, 10)),
+            (PathBuf::from(".config"), (2, 4117))
+        ])
+    );
+    assert_eq!(summary.size, 4127);
+    assert_eq!(
+        summary.largest,
+        vec![
+            (PathBuf::from(".config/app/cache.db"), 4096),
+            (PathBuf::from(".config/nvim/init.lua"), 21)
+        ]
+    );
+    assert_eq!(stats::format_size(4127), "4.0 KiB");
+    assert_eq!(stats::format_size(1023), "1023 B");
+}
+
+#[test]
+fn entries_are_written_as_nuon_records() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let fs = setup(&[
+        (
+            "/home/user/dotfiles/dofi.toml",
+            "[tags]\nshell = [\"say \\\"hi\\\"\"]\n[modes]\n\"say*\" = 0o600\n",
+        ),
+        ("/home/user/dotfiles/say \"hi\"", "echo hi"),
+    ]);
+    let mut entries = status::entries(&fs, Path::new(BASE), &[PathBuf::from(DOTFILES)]).unwrap();
+    entries[0].modified = Some(UNIX_EPOCH + Duration::from_secs(1_577_934_245));
+
+    let records = entries
+        .iter()
+        .map(|entry| nuon::Value::Record(nuon::entry(entry)))
+        .collect();
+    assert_eq!(
+        nuon::Value::List(records).write(),
+        "[\n  {state: \"unlinked\", source: \"/home/user/dotfiles/say \\\"hi\\\"\", \
+         target: \"/home/user/say \\\"hi\\\"\", layer: \"/home/user/dotfiles\", \
+         tags: [\"shell\"], strategy: \"symlink\", mode: \"600\", private: false, \
+         modified: 2020-01-02T03:04:05+00:00}\n]"
+    );
+    assert_eq!(nuon::Value::List(Vec::new()).write(), "[]");
+}
+
+#[test]
+fn errors_name_the_paths_they_are_about() {
+    let fs = setup(&[
+        ("/home/user/dotfiles/.zshrc", ""),
+        ("/home/user/.zshrc", ""),
+    ]);
+
+    let error = link(&fs, false).unwrap_err();
+    assert_eq!(error.paths(), vec![Path::new("/home/user/.zshrc")]);
+    assert!(dofi::DofiError::NoBaseDirectory.paths().is_empty());
+}
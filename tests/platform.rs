@@ -0,0 +1,155 @@
+//! The filesystem layer against the real filesystem of the machine, to catch where Linux with
+//! glibc or musl, the BSDs and macOS disagree. Run with `cargo test --features os-tests`.
+
+#![cfg(all(unix, feature = "os-tests"))]
+
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::PathBuf, process::Command};
+
+use dofi::{fs::FileType, journal, link_files, Fs, Journal, LinkOptions, OsFs};
+
+/// A directory in the temp directory removed again when dropped
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("dofi-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        // The temp directory is a symlink itself on macOS
+        Self(path.canonicalize().unwrap())
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn symlinks_have_the_same_metadata_on_every_platform() {
+    let scratch = Scratch::new("symlinks");
+    let (file, link) = (scratch.0.join("file"), scratch.0.join("link"));
+    std::fs::write(&file, "contents").unwrap();
+    OsFs.set_mode(&file, 0o640).unwrap();
+    OsFs.symlink(&file, &link).unwrap();
+
+    let metadata = OsFs.symlink_metadata(&link).unwrap();
+    assert_eq!(metadata.file_type, FileType::Symlink);
+    assert_eq!(metadata.mode, 0o777);
+    assert_eq!(OsFs.read_link(&link).unwrap(), file);
+    assert_eq!(OsFs.symlink_metadata(&file).unwrap().mode, 0o640);
+    assert!(!OsFs.same_file(&file, &link));
+
+    let hard_link = scratch.0.join("hard");
+    OsFs.hard_link(&file, &hard_link).unwrap();
+    assert!(OsFs.same_file(&file, &hard_link));
+}
+
+#[test]
+fn walking_keeps_names_that_are_not_utf8_and_does_not_follow_symlinks() {
+    let scratch = Scratch::new("walk");
+    let name = scratch.0.join(OsStr::from_bytes(b"caf\xe9"));
+    std::fs::create_dir_all(scratch.0.join("outside")).unwrap();
+    std::fs::write(scratch.0.join("outside/file"), "").unwrap();
+    std::fs::create_dir_all(scratch.0.join("tree")).unwrap();
+    std::fs::write(scratch.0.join("tree").join(name.file_name().unwrap()), "").unwrap();
+    OsFs.symlink(&scratch.0.join("outside"), &scratch.0.join("tree/link"))
+        .unwrap();
+
+    let files = OsFs.walk(&scratch.0.join("tree")).unwrap();
+    assert_eq!(
+        files,
+        [
+            scratch.0.join("tree").join(name.file_name().unwrap()),
+            scratch.0.join("tree/link"),
+        ]
+    );
+}
+
+// OpenBSD has no extended attributes
+#[cfg(not(target_os = "openbsd"))]
+#[test]
+fn user_extended_attributes_are_copied() {
+    let scratch = Scratch::new("xattrs");
+    let (from, to) = (scratch.0.join("from"), scratch.0.join("to"));
+    std::fs::write(&from, "").unwrap();
+    std::fs::write(&to, "").unwrap();
+    let set = if cfg!(target_os = "linux") {
+        Command::new("setfattr")
+            .args(["-n", "user.dofi", "-v", "kept"])
+            .arg(&from)
+            .status()
+    } else if cfg!(target_os = "macos") {
+        Command::new("xattr")
+            .args(["-w", "user.dofi", "kept"])
+            .arg(&from)
+            .status()
+    } else {
+        Command::new("setextattr")
+            .args(["user", "dofi", "kept"])
+            .arg(&from)
+            .status()
+    };
+    if !set.is_ok_and(|status| status.success()) {
+        eprintln!("Skipping, the temp directory does not take extended attributes");
+        return;
+    }
+
+    let lost = OsFs.copy_metadata(&from, &to).unwrap();
+    assert!(lost.is_empty(), "{lost:?}");
+    let get = if cfg!(target_os = "linux") {
+        Command::new("getfattr")
+            .args(["--only-values", "-n", "user.dofi"])
+            .arg(&to)
+            .output()
+    } else if cfg!(target_os = "macos") {
+        Command::new("xattr")
+            .args(["-p", "user.dofi"])
+            .arg(&to)
+            .output()
+    } else {
+        Command::new("getextattr")
+            .args(["-q", "user", "dofi"])
+            .arg(&to)
+            .output()
+    }
+    .unwrap();
+    assert_eq!(String::from_utf8_lossy(&get.stdout).trim(), "kept");
+}
+
+#[test]
+fn linking_and_undoing_leave_the_real_filesystem_as_it_was() {
+    let scratch = Scratch::new("link");
+    let (base, dotfiles) = (scratch.0.join("home"), scratch.0.join("dotfiles"));
+    let state = base.join(".local/state/dofi");
+    for file in [".zshrc", ".config/nvim/init.lua"] {
+        let path = dotfiles.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, file).unwrap();
+    }
+    std::fs::create_dir_all(&base).unwrap();
+
+    let mut journal = Journal::new(&state, "link");
+    link_files(
+        &OsFs,
+        &base,
+        std::slice::from_ref(&dotfiles),
+        &LinkOptions::default(),
+        &mut journal,
+    )
+    .unwrap();
+    journal.commit(&OsFs).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(base.join(".config/nvim/init.lua")).unwrap(),
+        ".config/nvim/init.lua"
+    );
+    assert_eq!(
+        OsFs.read_link(&base.join(".zshrc")).unwrap(),
+        dotfiles.join(".zshrc")
+    );
+
+    journal::undo(&OsFs, &state).unwrap();
+    assert!(base.join(".zshrc").symlink_metadata().is_err());
+    assert!(!base.join(".config").exists());
+}